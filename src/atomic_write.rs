@@ -0,0 +1,172 @@
+//! Writing files atomically, so that readers (e.g. pypx's `smdb.py`) never observe
+//! a partially written file.
+//!
+//! Data is written to a hidden sibling file `.<name>.partial`, then renamed to `<name>`.
+//! While being written, the partial file is locked (see `flock(2)`). A partial file left
+//! behind by a crash is recognizable by its name and by not being locked. It is discarded
+//! the next time the same destination is written to, or by [remove_stale_partials].
+use camino::{Utf8Path, Utf8PathBuf};
+use fs4::FileExt;
+use hashbrown::HashSet;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::sync::Mutex;
+
+/// Write to `dst` atomically by calling `write` on a partial file which is renamed to `dst`.
+///
+/// If `durable` is true, the partial file is flushed to disk (see `fsync(2)`) before renaming.
+pub(crate) fn write_atomically<E, F>(dst: &Utf8Path, durable: bool, write: F) -> Result<(), E>
+where
+    F: FnOnce(&mut fs_err::File) -> Result<(), E>,
+    E: From<io::Error>,
+{
+    let partial = partial_path_of(dst);
+    let mut file = lock_partial(&partial)?;
+    let result = file
+        .set_len(0)
+        .map_err(E::from)
+        .and_then(|_| write(&mut file))
+        .and_then(|_| if durable { file.sync_all() } else { Ok(()) }.map_err(E::from))
+        .and_then(|_| fs_err::rename(&partial, dst).map_err(E::from));
+    if result.is_err() {
+        fs_err::remove_file(&partial).ok();
+    }
+    // lock is released when file is closed
+    result
+}
+
+/// Delete partial files in `dir` which are not being written to, i.e. which were left
+/// behind by a crashed process.
+///
+/// Each directory is only checked the first time it is seen by this process.
+pub(crate) fn remove_stale_partials(dir: &Utf8Path) -> io::Result<()> {
+    let first_time = SWEPT_DIRS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashSet::new)
+        .insert(dir.to_path_buf());
+    if !first_time {
+        return Ok(());
+    }
+    for entry in dir.read_dir_utf8()? {
+        let path = entry?.into_path();
+        let is_partial = path
+            .file_name()
+            .map(|n| n.starts_with('.') && n.ends_with(".partial"))
+            .unwrap_or(false);
+        if !is_partial {
+            continue;
+        }
+        // a partial file which cannot be locked is being written to by someone else
+        let file = match fs_err::OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if file.file().try_lock_exclusive().is_ok() && is_same_file(&file, &path)? {
+            fs_err::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Directories already checked by [remove_stale_partials].
+static SWEPT_DIRS: Mutex<Option<HashSet<Utf8PathBuf>>> = Mutex::new(None);
+
+/// Produces the path `dir/.name.partial` for a file `dir/name`.
+pub(crate) fn partial_path_of(p: &Utf8Path) -> Utf8PathBuf {
+    hidden_sibling(p, "partial")
+}
+
+/// Produces the path `dir/.name.suffix` for a file `dir/name`.
+pub(crate) fn hidden_sibling(p: &Utf8Path, suffix: &str) -> Utf8PathBuf {
+    let fname = p.file_name().unwrap_or_default();
+    p.with_file_name(format!(".{fname}.{suffix}"))
+}
+
+/// Open and exclusively lock the partial file.
+///
+/// Another thread or process might be writing to the same partial file. In that case,
+/// wait for it to finish. Once the lock is acquired, the other writer might have
+/// already renamed the file we opened, so we need to check that it is still there.
+fn lock_partial(partial: &Utf8Path) -> io::Result<fs_err::File> {
+    loop {
+        let file = fs_err::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(partial)?;
+        file.file().lock_exclusive()?;
+        if is_same_file(&file, partial)? {
+            return Ok(file);
+        }
+    }
+}
+
+fn is_same_file(file: &fs_err::File, p: &Utf8Path) -> io::Result<bool> {
+    let opened = file.metadata()?;
+    match fs_err::metadata(p) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_write_atomically() {
+        let tempdir = TempDir::new("atomic_write_test").unwrap();
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let dst = dir.join("bubble_tea.txt");
+        write_atomically(&dst, true, |f| f.write_all(b"taro")).unwrap();
+        assert_eq!(fs_err::read_to_string(&dst).unwrap(), "taro");
+        assert!(!partial_path_of(&dst).exists());
+    }
+
+    #[test]
+    fn test_leftover_partial_is_discarded() {
+        let tempdir = TempDir::new("atomic_write_test").unwrap();
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let dst = dir.join("bubble_tea.txt");
+        fs_err::write(partial_path_of(&dst), "left behind by a crash, very long").unwrap();
+        write_atomically(&dst, false, |f| f.write_all(b"taro")).unwrap();
+        assert_eq!(fs_err::read_to_string(&dst).unwrap(), "taro");
+        assert!(!partial_path_of(&dst).exists());
+    }
+
+    #[test]
+    fn test_remove_stale_partials() {
+        let tempdir = TempDir::new("atomic_write_test").unwrap();
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let stale = partial_path_of(&dir.join("crashed.txt"));
+        let in_progress = partial_path_of(&dir.join("writing.txt"));
+        let unrelated = dir.join("unrelated.partial");
+        fs_err::write(&stale, "crash").unwrap();
+        fs_err::write(&in_progress, "still writing").unwrap();
+        fs_err::write(&unrelated, "user data").unwrap();
+        let _writer = lock_partial(&in_progress).unwrap();
+        remove_stale_partials(dir).unwrap();
+        assert!(!stale.exists());
+        assert!(in_progress.exists());
+        assert!(unrelated.exists());
+    }
+
+    #[test]
+    fn test_failed_write_leaves_nothing() {
+        let tempdir = TempDir::new("atomic_write_test").unwrap();
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let dst = dir.join("bubble_tea.txt");
+        let result: io::Result<()> = write_atomically(&dst, false, |f| {
+            f.write_all(b"tap")?;
+            Err(io::ErrorKind::BrokenPipe.into())
+        });
+        assert!(result.is_err());
+        assert!(!dst.exists());
+        assert!(!partial_path_of(&dst).exists());
+    }
+}
@@ -0,0 +1,118 @@
+//! Repacking of many files in one process.
+use crate::errors::{ErrorKind, RepackError};
+use crate::repack::{repack, RepackOptions, RepackOutcome};
+use camino::{Utf8Path, Utf8PathBuf};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+
+/// Counts of what happened during [batch].
+#[derive(Debug, Default, Serialize)]
+pub struct BatchSummary {
+    /// Number of files found.
+    pub total: usize,
+    /// Number of files which were repacked.
+    pub succeeded: usize,
+    /// Number of files which could not be repacked.
+    pub failed: usize,
+    /// Number of files which could not be repacked, by kind of error.
+    pub errors: BTreeMap<ErrorKind, usize>,
+}
+
+/// Repack every file under `dir` using a pool of `jobs` worker threads
+/// (or the number of CPUs, if `jobs` is zero).
+///
+/// Each file is processed by [repack], and its outcome is passed to `on_outcome`,
+/// which is called from the worker threads.
+pub fn batch<F>(
+    dir: &Utf8Path,
+    cleanup: bool,
+    jobs: usize,
+    options: &RepackOptions,
+    on_outcome: F,
+) -> anyhow::Result<BatchSummary>
+where
+    F: Fn(&Utf8Path, &Result<RepackOutcome, RepackError>) + Sync,
+{
+    let FoundFiles { files, unreadable } = find_files(dir)?;
+    let total = files.len() + unreadable.len();
+    let errors = Mutex::new(BTreeMap::new());
+    for (p, e) in unreadable {
+        let outcome = Err(RepackError::from(e));
+        *errors.lock().unwrap().entry(ErrorKind::Io).or_insert(0) += 1;
+        on_outcome(&p, &outcome);
+    }
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    pool.install(|| {
+        files.par_iter().for_each(|dicom_file| {
+            // a panic in one file must not take down the whole batch
+            let outcome =
+                std::panic::catch_unwind(AssertUnwindSafe(|| repack(dicom_file, cleanup, options)))
+                    .unwrap_or(Err(RepackError::Panic));
+            if let Err(e) = &outcome {
+                *errors.lock().unwrap().entry(e.kind()).or_insert(0) += 1;
+            }
+            on_outcome(dicom_file, &outcome);
+        })
+    });
+    let errors = errors.into_inner().unwrap();
+    let failed = errors.values().sum();
+    Ok(BatchSummary {
+        total,
+        succeeded: total - failed,
+        failed,
+        errors,
+    })
+}
+
+/// Files found by [find_files].
+pub(crate) struct FoundFiles {
+    /// Regular files, sorted by path.
+    pub files: Vec<Utf8PathBuf>,
+    /// Entries which could not be walked, e.g. unreadable directories or names which
+    /// are not valid UTF-8, which are to be reported as failed files.
+    pub unreadable: Vec<(Utf8PathBuf, io::Error)>,
+}
+
+/// Find all regular files under a directory, recursively.
+///
+/// Fails only if `dir` itself cannot be read.
+pub(crate) fn find_files(dir: &Utf8Path) -> anyhow::Result<FoundFiles> {
+    let mut found = FoundFiles {
+        files: Vec::new(),
+        unreadable: Vec::new(),
+    };
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.depth() == 0 => return Err(e.into()),
+            Err(e) => {
+                let p = e
+                    .path()
+                    .map(lossy_path)
+                    .unwrap_or_else(|| dir.to_path_buf());
+                found.unreadable.push((p, e.into()));
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match Utf8PathBuf::from_path_buf(entry.into_path()) {
+            Ok(p) => found.files.push(p),
+            Err(p) => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "Path is not valid UTF-8");
+                found.unreadable.push((lossy_path(&p), e));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Convert a path to UTF-8, replacing invalid sequences with U+FFFD.
+fn lossy_path(p: &std::path::Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(p.to_string_lossy().into_owned())
+}
@@ -0,0 +1,422 @@
+//! [BIDS](https://bids-specification.readthedocs.io) layout of the data directory, as an
+//! alternative to path templates which neuroimaging tools can consume.
+//!
+//! The DICOM files of a series are put in a directory named like the BIDS file of the series,
+//! e.g. `sub-1449c1d/ses-20130308/anat/sub-1449c1d_ses-20130308_acq-eaf4f78_run-5_T1w/`,
+//! so that the NIfTI file written next to it by [crate::series_to_nifti] is where BIDS
+//! expects it.
+use crate::dicom_data::{CommonElements, MaybeU32};
+use crate::dry_run::Output;
+use crate::helpers::sanitize;
+use crate::pack_path::PypxPath;
+use crate::path_template::hash;
+use camino::Utf8Path;
+use dicom::core::DataDictionary;
+use dicom::dictionary_std::{tags, StandardDataDictionary};
+use dicom::object::{DefaultDicomObject, Tag};
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
+use std::str::FromStr;
+
+/// Heuristics used when none are given, which recognize common series descriptions.
+///
+/// Heuristics are a JSON list of rules. The first rule for which every element matches
+/// its regular expression decides the BIDS datatype and suffix of a series.
+/// Series which no rule matches go to the `misc` directory, which is ignored by BIDS tools.
+pub const DEFAULT_HEURISTICS: &str = r#"[
+  {"datatype": "dwi", "suffix": "dwi",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)dwi|dti|diffusion"}},
+  {"datatype": "fmap", "suffix": "phasediff",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)field.?map", "ImageType": "(^|\\\\)(P|PHASE)(\\\\|$)"}},
+  {"datatype": "fmap", "suffix": "magnitude",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)field.?map"}},
+  {"datatype": "func", "suffix": "bold",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)bold|fmri|resting|task"}},
+  {"datatype": "anat", "suffix": "T1w",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)mprage|t1"}},
+  {"datatype": "anat", "suffix": "FLAIR",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)flair"}},
+  {"datatype": "anat", "suffix": "T2w",
+   "match": {"Modality": "^MR$", "SeriesDescription": "(?i)t2"}}
+]"#;
+
+/// Datatype directory of series which are not classified by any rule.
+const UNCLASSIFIED: &str = "misc";
+
+/// BIDS version of the written `dataset_description.json`.
+const BIDS_VERSION: &str = "1.8.0";
+
+/// Maps DICOM files to a BIDS layout: PatientID to `sub-<label>`, StudyDate (or
+/// StudyInstanceUID if there is no StudyDate) to `ses-<label>`, a hash of SeriesInstanceUID to
+/// `acq-<label>`, SeriesNumber to `run-<index>`, and Modality, SeriesDescription, ImageType
+/// etc. to a datatype and suffix by heuristics.
+#[derive(Debug, Clone)]
+pub struct BidsLayout {
+    rules: Vec<Rule>,
+}
+
+/// A rule of the heuristics for classifying series.
+#[derive(Debug, Clone)]
+struct Rule {
+    datatype: String,
+    suffix: String,
+    conditions: Vec<(Tag, Regex)>,
+}
+
+/// A rule as written in JSON.
+#[derive(Deserialize)]
+struct RuleSpec {
+    datatype: String,
+    suffix: String,
+    #[serde(rename = "match")]
+    conditions: BTreeMap<String, String>,
+}
+
+/// Error parsing the heuristics of a [BidsLayout].
+#[derive(thiserror::Error, Debug)]
+pub enum BidsHeuristicsError {
+    #[error("invalid BIDS heuristics: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unknown DICOM tag keyword: {0:?}")]
+    UnknownTag(String),
+    #[error("invalid regular expression for {keyword}: {source}")]
+    Regex {
+        keyword: String,
+        source: regex::Error,
+    },
+    #[error("BIDS datatype and suffix must be alphanumeric, got {0:?}")]
+    InvalidLabel(String),
+}
+
+impl Default for BidsLayout {
+    fn default() -> Self {
+        DEFAULT_HEURISTICS.parse().unwrap()
+    }
+}
+
+impl FromStr for BidsLayout {
+    type Err = BidsHeuristicsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let specs: Vec<RuleSpec> = serde_json::from_str(s)?;
+        let rules = specs
+            .into_iter()
+            .map(Rule::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+impl TryFrom<RuleSpec> for Rule {
+    type Error = BidsHeuristicsError;
+
+    fn try_from(spec: RuleSpec) -> Result<Self, Self::Error> {
+        for label in [&spec.datatype, &spec.suffix] {
+            if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(BidsHeuristicsError::InvalidLabel(label.to_string()));
+            }
+        }
+        let conditions = spec
+            .conditions
+            .into_iter()
+            .map(|(keyword, re)| {
+                let tag = StandardDataDictionary
+                    .parse_tag(&keyword)
+                    .ok_or_else(|| BidsHeuristicsError::UnknownTag(keyword.clone()))?;
+                let re = Regex::new(&re)
+                    .map_err(|source| BidsHeuristicsError::Regex { keyword, source })?;
+                Ok((tag, re))
+            })
+            .collect::<Result<_, BidsHeuristicsError>>()?;
+        Ok(Self {
+            datatype: spec.datatype,
+            suffix: spec.suffix,
+            conditions,
+        })
+    }
+}
+
+impl Rule {
+    fn matches(&self, dcm: &DefaultDicomObject) -> bool {
+        self.conditions.iter().all(|(tag, re)| {
+            element_str(dcm, *tag)
+                .map(|value| re.is_match(&value))
+                .unwrap_or(false)
+        })
+    }
+}
+
+impl BidsLayout {
+    /// Decide the datatype and suffix of the series of a DICOM object.
+    fn classify<'a>(&'a self, dcm: &DefaultDicomObject) -> (&'a str, Cow<'a, str>) {
+        if let Some(rule) = self.rules.iter().find(|r| r.matches(dcm)) {
+            return (&rule.datatype, Cow::Borrowed(&rule.suffix));
+        }
+        let description = element_str(dcm, tags::SERIES_DESCRIPTION).unwrap_or_default();
+        (UNCLASSIFIED, Cow::Owned(label(&description, "unknown")))
+    }
+
+    /// Decide where a DICOM file goes.
+    pub(crate) fn path(
+        &self,
+        dcm: &DefaultDicomObject,
+        common: &CommonElements,
+        data_dir: &Utf8Path,
+    ) -> PypxPath {
+        let subject = subject_label(common);
+        let session = common
+            .StudyDate
+            .map(|date| label(date, ""))
+            .filter(|date| !date.is_empty())
+            .unwrap_or_else(|| short_hash(&common.StudyInstanceUID));
+        // SeriesNumber is not unique, e.g. for two studies on the same date,
+        // so series are always told apart by acquisition label
+        let acq = format!("acq-{}", short_hash(&common.SeriesInstanceUID));
+        let entities = match common.SeriesNumber {
+            Some(MaybeU32::U32(n)) => format!("{acq}_run-{n}"),
+            _ => acq,
+        };
+        let (datatype, suffix) = self.classify(dcm);
+        let series_name = format!("sub-{subject}_ses-{session}_{entities}_{suffix}");
+        let dir = data_dir
+            .join(format!("sub-{subject}"))
+            .join(format!("ses-{session}"))
+            .join(datatype)
+            .join(series_name);
+        let instance_number = element_str(dcm, tags::INSTANCE_NUMBER);
+        let fname = sanitize(format!(
+            "{:0>4}-{}.dcm",
+            instance_number.as_deref().unwrap_or("InstanceNumber"),
+            common.SOPInstanceUID
+        ));
+        PypxPath {
+            path: dir.join(&fname),
+            dir,
+            fname,
+        }
+    }
+}
+
+/// Write `dataset_description.json` and `.bidsignore` if they do not exist yet,
+/// and add the subject of a DICOM object to `participants.tsv`.
+pub(crate) fn write_dataset_files(
+    dcm: &DefaultDicomObject,
+    common: &CommonElements,
+    data_dir: &Utf8Path,
+    out: &Output,
+) -> io::Result<()> {
+    let description = data_dir.join("dataset_description.json");
+    if !description.is_file() {
+        let data = serde_json::json!({
+            "Name": "rx-repack",
+            "BIDSVersion": BIDS_VERSION,
+            "DatasetType": "raw",
+            "GeneratedBy": [{"Name": "rx-repack", "Version": env!("CARGO_PKG_VERSION")}],
+        });
+        out.write_json(data, &description)?;
+    } else {
+        out.untouched(&description);
+    }
+    let bidsignore = data_dir.join(".bidsignore");
+    if !bidsignore.is_file() {
+        let patterns = format!("**/*.dcm\n**/{UNCLASSIFIED}/\n");
+        out.write_bytes(&bidsignore, patterns.as_bytes())?;
+    } else {
+        out.untouched(&bidsignore);
+    }
+
+    let participants = data_dir.join("participants.tsv");
+    let participant_id = format!("sub-{}", subject_label(common));
+    out.with_lock(&participants, || {
+        let mut tsv = match fs_err::read_to_string(&participants) {
+            Ok(tsv) => tsv,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => "participant_id\tsex\tage\n".into(),
+            Err(e) => return Err(e),
+        };
+        let known = tsv
+            .lines()
+            .any(|line| line.split('\t').next() == Some(participant_id.as_str()));
+        if known {
+            out.untouched(&participants);
+            return Ok(());
+        }
+        let sex = element_str(dcm, tags::PATIENT_SEX)
+            .filter(|s| ["M", "F", "O"].contains(&s.as_ref()))
+            .unwrap_or(Cow::Borrowed("n/a"));
+        let age = element_str(dcm, tags::PATIENT_AGE)
+            .and_then(|a| a.strip_suffix('Y')?.parse::<u32>().ok())
+            .map(|years| years.to_string())
+            .unwrap_or_else(|| "n/a".to_string());
+        tsv.push_str(&format!("{participant_id}\t{sex}\t{age}\n"));
+        out.write_bytes(&participants, tsv.as_bytes())
+    })
+}
+
+fn subject_label(common: &CommonElements) -> String {
+    label(common.PatientID, "unknown")
+}
+
+/// Make a BIDS label, which may only have alphanumeric characters.
+fn label(s: &str, default: &str) -> String {
+    let label: String = sanitize(s)
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    if label.is_empty() {
+        default.to_string()
+    } else {
+        label
+    }
+}
+
+fn short_hash(s: &str) -> String {
+    hash(s).chars().take(7).collect()
+}
+
+/// Get the trimmed string value of an element.
+fn element_str(dcm: &DefaultDicomObject, tag: Tag) -> Option<Cow<'_, str>> {
+    let value = dcm.element(tag).ok()?.to_str().ok()?;
+    match value.trim_matches(|c: char| c.is_whitespace() || c == '\0') {
+        trimmed if trimmed.len() == value.len() => Some(value),
+        trimmed => Some(Cow::Owned(trimmed.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::uids;
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+
+    fn example_dicom(series_description: &str, image_type: &str) -> DefaultDicomObject {
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, PrimitiveValue::from("1449c1d")),
+            DataElement::new(tags::STUDY_DATE, VR::DA, PrimitiveValue::from("20130308")),
+            DataElement::new(tags::MODALITY, VR::CS, PrimitiveValue::from("MR")),
+            DataElement::new(tags::SERIES_NUMBER, VR::IS, PrimitiveValue::from("5 ")),
+            DataElement::new(
+                tags::SERIES_DESCRIPTION,
+                VR::LO,
+                PrimitiveValue::from(series_description),
+            ),
+            DataElement::new(tags::IMAGE_TYPE, VR::CS, PrimitiveValue::from(image_type)),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3"),
+            ),
+            DataElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3.4"),
+            ),
+            DataElement::new(tags::INSTANCE_NUMBER, VR::IS, PrimitiveValue::from("61")),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3.4.61"),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::MR_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid("1.2.3.4.61")
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_default_heuristics() {
+        let layout = BidsLayout::default();
+        let cases = [
+            (
+                "SAG MPRAGE 220 FOV",
+                "ORIGINAL\\PRIMARY\\M\\ND",
+                "anat",
+                "T1w",
+            ),
+            ("AX T2 FLAIR", "ORIGINAL\\PRIMARY\\M", "anat", "FLAIR"),
+            (
+                "ep2d_bold_resting",
+                "ORIGINAL\\PRIMARY\\M\\MB",
+                "func",
+                "bold",
+            ),
+            (
+                "DTI 30 directions",
+                "ORIGINAL\\PRIMARY\\DIFFUSION",
+                "dwi",
+                "dwi",
+            ),
+            (
+                "gre_field_mapping",
+                "ORIGINAL\\PRIMARY\\P\\ND",
+                "fmap",
+                "phasediff",
+            ),
+            (
+                "gre_field_mapping",
+                "ORIGINAL\\PRIMARY\\M\\ND",
+                "fmap",
+                "magnitude",
+            ),
+            ("Localizer", "ORIGINAL\\PRIMARY\\M", "misc", "Localizer"),
+        ];
+        for (description, image_type, datatype, suffix) in cases {
+            let dcm = example_dicom(description, image_type);
+            assert_eq!(
+                layout.classify(&dcm),
+                (datatype, Cow::Borrowed(suffix)),
+                "{description}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_path() {
+        let dcm = example_dicom("SAG MPRAGE 220 FOV", "ORIGINAL\\PRIMARY\\M\\ND");
+        let common: CommonElements = (&dcm).try_into().unwrap();
+        let actual = BidsLayout::default().path(&dcm, &common, Utf8Path::new("/data"));
+        assert_eq!(
+            actual.dir,
+            "/data/sub-1449c1d/ses-20130308/anat/sub-1449c1d_ses-20130308_acq-eaf4f78_run-5_T1w"
+        );
+        assert_eq!(actual.fname, "0061-1.2.3.4.61.dcm");
+        assert_eq!(actual.path, actual.dir.join(&actual.fname));
+    }
+
+    #[test]
+    fn test_custom_heuristics() {
+        let layout: BidsLayout =
+            r#"[{"datatype": "anat", "suffix": "T2starw", "match": {"SeriesDescription": "SWI"}}]"#
+                .parse()
+                .unwrap();
+        let dcm = example_dicom("AX SWI", "ORIGINAL\\PRIMARY\\M");
+        assert_eq!(layout.classify(&dcm), ("anat", Cow::Borrowed("T2starw")));
+        // default heuristics are replaced
+        let dcm = example_dicom("SAG MPRAGE", "ORIGINAL\\PRIMARY\\M");
+        assert_eq!(layout.classify(&dcm), ("misc", Cow::Borrowed("SAGMPRAGE")));
+
+        let errors = [
+            r#"[{"datatype": "anat", "suffix": "T1w", "match": {"NotATag": "x"}}]"#,
+            r#"[{"datatype": "anat", "suffix": "T1w", "match": {"Modality": "("}}]"#,
+            r#"[{"datatype": "anat", "suffix": "T1_w", "match": {}}]"#,
+            r#"{"datatype": "anat"}"#,
+        ];
+        for heuristics in errors {
+            assert!(heuristics.parse::<BidsLayout>().is_err(), "{heuristics}");
+        }
+    }
+
+    #[test]
+    fn test_label() {
+        assert_eq!(label("1449c1d", "unknown"), "1449c1d");
+        assert_eq!(label("Anon^Patient 01", "unknown"), "AnonPatient01");
+        assert_eq!(label("^_^", "unknown"), "unknown");
+    }
+}
@@ -0,0 +1,371 @@
+//! Validation of DICOM files, which reports problems without repacking them.
+//!
+//! Besides the extraction of elements done by [crate::repack], elements are checked against
+//! the rules of their value representation (DICOM PS3.5 Table 6.2-1) and the Type 1
+//! attributes of common SOP classes (DICOM PS3.3). Only top-level elements are checked.
+use crate::dicom_data::{header_elements, name_of, CommonElements, TagExtractor};
+use crate::errors::{ErrorKind, RepackError};
+use crate::log_models::{InstanceData, PatientData, SeriesDataMeta, StudyDataMeta};
+use crate::tolerant_read;
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::core::header::{HasLength, Header};
+use dicom::core::value::Value;
+use dicom::core::{PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::object::DefaultDicomObject;
+use serde::{Serialize, Serializer};
+use std::panic::catch_unwind;
+
+/// How bad a [Problem] is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The file is fine, but a value is changed when used in paths,
+    /// or an element is written as "Not defined" in the pypx logs.
+    Info,
+    /// The file does not conform to the DICOM standard, but it can be repacked.
+    Warning,
+    /// The file cannot be repacked.
+    Error,
+}
+
+/// A problem found in a DICOM file.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// The element which has the problem, if any.
+    #[serde(
+        serialize_with = "serialize_tag",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tag: Option<Tag>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'static str>,
+    pub message: String,
+}
+
+/// Problems found in a DICOM file by [check].
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub src: Utf8PathBuf,
+    /// Severity of the worst problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    /// Kind of error [crate::repack] would fail with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<ErrorKind>,
+    pub problems: Vec<Problem>,
+}
+
+impl Problem {
+    fn new(severity: Severity, tag: Option<Tag>, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            tag,
+            name: tag.and_then(name_of),
+            message: message.into(),
+        }
+    }
+}
+
+pub(crate) fn serialize_tag<S: Serializer>(
+    tag: &Option<Tag>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match tag {
+        Some(tag) => serializer.collect_str(tag),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Check a DICOM file for problems, without writing anything.
+pub fn check(dicom_file: &Utf8Path) -> CheckReport {
+    let mut problems = Vec::new();
+    // dicom-rs may panic on malformed files
+    let dcm = catch_unwind(|| tolerant_read::open_file(dicom_file, None))
+        .unwrap_or(Err(RepackError::Panic));
+    let error_kind = match dcm {
+        Ok((dcm, repairs)) => {
+            for repair in repairs {
+                problems.push(Problem::new(Severity::Warning, None, repair.to_string()));
+            }
+            check_object(&dcm, &mut problems)
+        }
+        Err(e) => {
+            problems.push(Problem::new(Severity::Error, None, e.to_string()));
+            Some(e.kind())
+        }
+    };
+    CheckReport {
+        src: dicom_file.to_path_buf(),
+        severity: problems.iter().map(|p| p.severity).max(),
+        error_kind,
+        problems,
+    }
+}
+
+/// Check a DICOM object, returning the kind of error [crate::repack] would fail with.
+fn check_object(dcm: &DefaultDicomObject, problems: &mut Vec<Problem>) -> Option<ErrorKind> {
+    let error_kind = match CommonElements::try_from(dcm) {
+        Ok(common) => {
+            let d = TagExtractor::new(dcm);
+            PatientData::new(&d, &common);
+            StudyDataMeta::new(&d, &common);
+            SeriesDataMeta::new(&d, &common);
+            InstanceData::new(&d, &common, "", "", None, None);
+            for e in d.errors.into_inner() {
+                let message = format!("Written as \"Not defined\" in logs: {}", e.error);
+                problems.push(Problem::new(Severity::Info, Some(e.tag), message));
+            }
+            None
+        }
+        Err(e) => {
+            let e = RepackError::from(e);
+            let tag = match &e {
+                RepackError::MissingElement { tag, .. } => Some(*tag),
+                _ => None,
+            };
+            problems.push(Problem::new(Severity::Error, tag, e.to_string()));
+            Some(e.kind())
+        }
+    };
+    for tag in type1_attributes(dcm) {
+        let present = dcm
+            .element_opt(*tag)
+            .ok()
+            .flatten()
+            .is_some_and(|e| !e.is_empty());
+        if !present {
+            let message = "Type 1 attribute is missing or empty";
+            problems.push(Problem::new(Severity::Warning, Some(*tag), message));
+        }
+    }
+    for element in header_elements(dcm) {
+        if let Value::Primitive(PrimitiveValue::Strs(values)) = element.value() {
+            check_strings(element.tag(), element.vr(), values, problems);
+        }
+    }
+    error_kind
+}
+
+/// Elements which are used in paths by the default template and the pypx logs,
+/// with leading and trailing spaces and NUL bytes removed.
+const PATH_ELEMENTS: &[Tag] = &[
+    tags::SOP_INSTANCE_UID,
+    tags::PATIENT_ID,
+    tags::PATIENT_NAME,
+    tags::PATIENT_BIRTH_DATE,
+    tags::ACCESSION_NUMBER,
+    tags::STUDY_DESCRIPTION,
+    tags::STUDY_DATE,
+    tags::SERIES_NUMBER,
+    tags::SERIES_DESCRIPTION,
+    tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID,
+    tags::INSTANCE_NUMBER,
+];
+
+/// Check the values of a string element.
+fn check_strings(tag: Tag, vr: VR, values: &[String], problems: &mut Vec<Problem>) {
+    let Some(rule) = VrRule::of(vr) else {
+        return;
+    };
+    let mut warn =
+        |message: String| problems.push(Problem::new(Severity::Warning, Some(tag), message));
+    // values are padded to an even length, with NUL for UI and a space otherwise
+    let padding = if vr == VR::UI { '\0' } else { ' ' };
+    let last = values.len().saturating_sub(1);
+    let mut padded = false;
+    for (i, value) in values.iter().enumerate() {
+        let value = match value.strip_suffix(padding) {
+            Some(v) if i == last => v,
+            _ => value.as_str(),
+        };
+        let length = if vr == VR::PN {
+            value
+                .split('=')
+                .map(|g| g.chars().count())
+                .max()
+                .unwrap_or(0)
+        } else {
+            value.chars().count()
+        };
+        if let Some(max) = rule.max_length.filter(|max| length > *max) {
+            warn(format!(
+                "Value \"{}\" is longer than {max} characters",
+                value.escape_debug()
+            ));
+        }
+        if value.contains('\0') {
+            warn(format!(
+                "Value \"{}\" contains NUL bytes",
+                value.escape_debug()
+            ));
+        } else if let Some(c) = value.chars().find(|c| !(rule.allowed)(*c)) {
+            warn(format!(
+                "Value \"{}\" contains '{}', which is not allowed for {vr}",
+                value.escape_debug(),
+                c.escape_debug()
+            ));
+        }
+        padded |= value.trim() != value;
+    }
+    if padded && PATH_ELEMENTS.contains(&tag) {
+        let message = "Leading or trailing spaces are removed in paths";
+        problems.push(Problem::new(Severity::Info, Some(tag), message));
+    }
+}
+
+/// Rules for the values of a string value representation.
+struct VrRule {
+    /// Maximum length in characters (for PN, of each component group).
+    max_length: Option<usize>,
+    /// Characters allowed in values.
+    allowed: fn(char) -> bool,
+}
+
+impl VrRule {
+    fn of(vr: VR) -> Option<Self> {
+        let (max_length, allowed): (_, fn(char) -> bool) = match vr {
+            VR::AE => (Some(16), |c| !c.is_control() && c != '\\'),
+            VR::AS => (Some(4), |c| c.is_ascii_digit() || "DWMY".contains(c)),
+            VR::CS => (Some(16), |c| {
+                c.is_ascii_uppercase() || c.is_ascii_digit() || c == ' ' || c == '_'
+            }),
+            VR::DA => (Some(8), |c| c.is_ascii_digit()),
+            VR::DS => (Some(16), |c| c.is_ascii_digit() || "+-Ee. ".contains(c)),
+            VR::DT => (Some(26), |c| c.is_ascii_digit() || "+-. ".contains(c)),
+            VR::IS => (Some(12), |c| c.is_ascii_digit() || "+- ".contains(c)),
+            VR::LO | VR::PN | VR::SH | VR::UC => {
+                let max_length = match vr {
+                    VR::LO | VR::PN => Some(64),
+                    VR::SH => Some(16),
+                    _ => None,
+                };
+                (max_length, |c| !c.is_control() || c == '\x1b')
+            }
+            VR::LT | VR::ST | VR::UT => {
+                let max_length = match vr {
+                    VR::LT => Some(10240),
+                    VR::ST => Some(1024),
+                    _ => None,
+                };
+                (max_length, |c| {
+                    !c.is_control() || "\t\n\x0c\r\x1b".contains(c)
+                })
+            }
+            VR::TM => (Some(14), |c| c.is_ascii_digit() || c == '.' || c == ' '),
+            VR::UI => (Some(64), |c| c.is_ascii_digit() || c == '.'),
+            _ => return None,
+        };
+        Some(Self {
+            max_length,
+            allowed,
+        })
+    }
+}
+
+/// SOP Common, General Study and General Series.
+const COMMON_TYPE1: &[Tag] = &[
+    tags::SOP_CLASS_UID,
+    tags::SOP_INSTANCE_UID,
+    tags::STUDY_INSTANCE_UID,
+    tags::SERIES_INSTANCE_UID,
+    tags::MODALITY,
+];
+
+/// Image Pixel.
+const IMAGE_PIXEL_TYPE1: &[Tag] = &[
+    tags::SAMPLES_PER_PIXEL,
+    tags::PHOTOMETRIC_INTERPRETATION,
+    tags::ROWS,
+    tags::COLUMNS,
+    tags::BITS_ALLOCATED,
+    tags::BITS_STORED,
+    tags::HIGH_BIT,
+    tags::PIXEL_REPRESENTATION,
+];
+
+/// MR Image.
+const MR_IMAGE_TYPE1: &[Tag] = &[
+    tags::IMAGE_TYPE,
+    tags::SCANNING_SEQUENCE,
+    tags::SEQUENCE_VARIANT,
+];
+
+/// CT Image.
+const CT_IMAGE_TYPE1: &[Tag] = &[
+    tags::IMAGE_TYPE,
+    tags::RESCALE_INTERCEPT,
+    tags::RESCALE_SLOPE,
+];
+
+/// Type 1 attributes of the modules common to all instances of the object's SOP class.
+fn type1_attributes(dcm: &DefaultDicomObject) -> impl Iterator<Item = &'static Tag> {
+    let sop_class_uid = dcm.meta().media_storage_sop_class_uid();
+    let modules: &[&[Tag]] = match sop_class_uid {
+        uids::MR_IMAGE_STORAGE => &[COMMON_TYPE1, IMAGE_PIXEL_TYPE1, MR_IMAGE_TYPE1],
+        uids::CT_IMAGE_STORAGE => &[COMMON_TYPE1, IMAGE_PIXEL_TYPE1, CT_IMAGE_TYPE1],
+        uids::ENHANCED_MR_IMAGE_STORAGE
+        | uids::ENHANCED_CT_IMAGE_STORAGE
+        | uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE
+        | uids::COMPUTED_RADIOGRAPHY_IMAGE_STORAGE
+        | uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION
+        | uids::ULTRASOUND_IMAGE_STORAGE
+        | uids::SECONDARY_CAPTURE_IMAGE_STORAGE => &[COMMON_TYPE1, IMAGE_PIXEL_TYPE1],
+        _ => &[COMMON_TYPE1],
+    };
+    modules.iter().flat_map(|m| m.iter())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_values(vr: VR, values: &[&str]) -> Vec<Problem> {
+        let values: Vec<_> = values.iter().map(|s| s.to_string()).collect();
+        let mut problems = Vec::new();
+        check_strings(tags::PATIENT_ID, vr, &values, &mut problems);
+        problems
+    }
+
+    #[test]
+    fn test_check_strings() {
+        let cases: &[(VR, &[&str], &[Severity])] = &[
+            (VR::LO, &["patient1 "], &[]),
+            (VR::LO, &["patient1  "], &[Severity::Info]),
+            (VR::LO, &[" patient1"], &[Severity::Info]),
+            (VR::LO, &["patient1\0"], &[Severity::Warning]),
+            (VR::UI, &["1.2.3.4\0"], &[]),
+            (VR::UI, &["1.2.3.4 "], &[Severity::Warning, Severity::Info]),
+            (VR::CS, &["ORIGINAL", "PRIMARY "], &[]),
+            (VR::CS, &["original"], &[Severity::Warning]),
+            (VR::SH, &["12345678901234567"], &[Severity::Warning]),
+            (VR::PN, &["Anon^Patient"], &[]),
+            (VR::DA, &["2013-3-8"], &[Severity::Warning]),
+            (VR::LT, &["line 1\r\nline 2"], &[]),
+        ];
+        for (vr, values, expected) in cases {
+            let actual: Vec<_> = check_values(*vr, values)
+                .iter()
+                .map(|p| p.severity)
+                .collect();
+            assert_eq!(&actual, expected, "{vr} {values:?}");
+        }
+    }
+
+    #[test]
+    fn test_path_elements_of_default_template() {
+        let cases = [
+            (tags::PATIENT_NAME, VR::PN, "Anon^Patient  "),
+            (tags::ACCESSION_NUMBER, VR::SH, " 12345"),
+            (tags::INSTANCE_NUMBER, VR::IS, "  7 "),
+        ];
+        for (tag, vr, value) in cases {
+            let mut problems = Vec::new();
+            check_strings(tag, vr, &[value.to_string()], &mut problems);
+            let severities: Vec<_> = problems.iter().map(|p| p.severity).collect();
+            assert_eq!(severities, [Severity::Info], "{tag}");
+        }
+    }
+}
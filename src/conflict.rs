@@ -0,0 +1,219 @@
+//! What to do when a DICOM file already exists at its destination,
+//! e.g. because a PACS re-sent a series.
+use crate::errors::RepackError;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Serialize;
+
+/// Policy for handling a destination file which already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Keep the existing file, discarding the new one.
+    Skip,
+    /// Keep the existing file, and write the new one next to it with a numbered name.
+    KeepBoth,
+    /// Fail to repack the new file.
+    Error,
+    /// Compare content hashes: skip identical duplicates, keep both if they differ.
+    Verify,
+}
+
+/// How a DICOM file was placed at its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Placement {
+    /// The destination did not exist.
+    New,
+    /// The destination existed and was replaced.
+    Overwritten,
+    /// The destination existed and was kept as-is.
+    Skipped,
+    /// The destination existed, so the file was written to a numbered sibling instead.
+    KeptBoth,
+    /// The destination existed with identical content.
+    Identical,
+    /// The destination existed with different content, so the file was written to a
+    /// numbered sibling instead.
+    Differing,
+}
+
+/// Error for [OnConflict::Error].
+#[derive(thiserror::Error, Debug)]
+#[error("Destination already exists: {0}")]
+pub struct DestinationExists(pub Utf8PathBuf);
+
+/// Where to write a file, if anywhere, and why.
+#[derive(Debug)]
+pub(crate) struct Resolution {
+    /// Path to write the file to, or `None` if it should not be written.
+    pub dst: Option<Utf8PathBuf>,
+    pub placement: Placement,
+}
+
+/// Decide what to do with a file destined for `dst`.
+///
+/// `content_hash` is called for [OnConflict::Verify] to get the hash of the new file,
+/// which is compared with [hash_file] of the existing one and of its numbered siblings,
+/// so that a file which was already kept as a differing copy is not kept again.
+///
+/// This is not atomic: if two processes repack to the same destination at the same time,
+/// both may see it as [Placement::New].
+pub(crate) fn resolve<F>(
+    dst: &Utf8Path,
+    on_conflict: OnConflict,
+    content_hash: F,
+) -> Result<Resolution, RepackError>
+where
+    F: FnOnce() -> Result<u64, RepackError>,
+{
+    if !dst.exists() {
+        return Ok(Resolution {
+            dst: Some(dst.to_path_buf()),
+            placement: Placement::New,
+        });
+    }
+    let (dst, placement) = match on_conflict {
+        OnConflict::Overwrite => (Some(dst.to_path_buf()), Placement::Overwritten),
+        OnConflict::Skip => (None, Placement::Skipped),
+        OnConflict::KeepBoth => (Some(free_sibling(dst)), Placement::KeptBoth),
+        OnConflict::Error => return Err(DestinationExists(dst.to_path_buf()).into()),
+        OnConflict::Verify => {
+            if has_identical(dst, content_hash()?)? {
+                (None, Placement::Identical)
+            } else {
+                (Some(free_sibling(dst)), Placement::Differing)
+            }
+        }
+    };
+    Ok(Resolution { dst, placement })
+}
+
+/// Whether `dst` or any of its numbered siblings, up to the first which does not exist,
+/// has content with the hash `hash`.
+fn has_identical(dst: &Utf8Path, hash: u64) -> std::io::Result<bool> {
+    let existing = std::iter::once(dst.to_path_buf())
+        .chain((1..).map(|n| numbered_sibling(dst, n)))
+        .take_while(|candidate| candidate.exists());
+    for p in existing {
+        if hash_file(&p)? == hash {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Hash the content of a file.
+pub(crate) fn hash_file(p: &Utf8Path) -> std::io::Result<u64> {
+    fs_err::read(p).map(|data| seahash::hash(&data))
+}
+
+/// Find the first of `name-1.ext`, `name-2.ext`, ... which does not exist.
+fn free_sibling(p: &Utf8Path) -> Utf8PathBuf {
+    (1..)
+        .map(|n| numbered_sibling(p, n))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// Produces the path `dir/name-n.ext` for a file `dir/name.ext`.
+fn numbered_sibling(p: &Utf8Path, n: usize) -> Utf8PathBuf {
+    let stem = p.file_stem().unwrap_or_default();
+    let fname = match p.extension() {
+        Some(ext) => format!("{stem}-{n}.{ext}"),
+        None => format!("{stem}-{n}"),
+    };
+    p.with_file_name(fname)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_resolve() {
+        let cases = [
+            (
+                OnConflict::Overwrite,
+                "taro",
+                Some("bubble_tea.dcm"),
+                Placement::Overwritten,
+            ),
+            (OnConflict::Skip, "taro", None, Placement::Skipped),
+            (
+                OnConflict::KeepBoth,
+                "taro",
+                Some("bubble_tea-2.dcm"),
+                Placement::KeptBoth,
+            ),
+            (OnConflict::Verify, "taro", None, Placement::Identical),
+            (
+                OnConflict::Verify,
+                "mango",
+                Some("bubble_tea-2.dcm"),
+                Placement::Differing,
+            ),
+        ];
+        for (on_conflict, new_content, expected_fname, expected_placement) in cases {
+            let tempdir = TempDir::new("conflict_test").unwrap();
+            let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+            let dst = dir.join("bubble_tea.dcm");
+            fs_err::write(&dst, "taro").unwrap();
+            fs_err::write(dir.join("bubble_tea-1.dcm"), "matcha").unwrap();
+
+            let actual = resolve(&dst, on_conflict, || {
+                Ok(seahash::hash(new_content.as_bytes()))
+            })
+            .unwrap();
+            assert_eq!(actual.dst, expected_fname.map(|f| dir.join(f)));
+            assert_eq!(actual.placement, expected_placement);
+        }
+    }
+
+    #[test]
+    fn test_verify_same_differing_content_twice() {
+        let tempdir = TempDir::new("conflict_test").unwrap();
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let dst = dir.join("bubble_tea.dcm");
+        fs_err::write(&dst, "taro").unwrap();
+        let content_hash = || Ok(seahash::hash(b"mango"));
+
+        let first = resolve(&dst, OnConflict::Verify, content_hash).unwrap();
+        assert_eq!(first.placement, Placement::Differing);
+        let sibling = first.dst.unwrap();
+        assert_eq!(sibling, dir.join("bubble_tea-1.dcm"));
+        fs_err::write(&sibling, "mango").unwrap();
+
+        let second = resolve(&dst, OnConflict::Verify, content_hash).unwrap();
+        assert_eq!(second.placement, Placement::Identical);
+        assert_eq!(second.dst, None);
+    }
+
+    #[test]
+    fn test_resolve_new_and_error() {
+        let tempdir = TempDir::new("conflict_test").unwrap();
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let dst = dir.join("bubble_tea.dcm");
+        let actual = resolve(&dst, OnConflict::Error, || unreachable!()).unwrap();
+        assert_eq!(actual.dst.as_ref(), Some(&dst));
+        assert_eq!(actual.placement, Placement::New);
+
+        fs_err::write(&dst, "taro").unwrap();
+        let error = resolve(&dst, OnConflict::Error, || unreachable!()).unwrap_err();
+        assert!(matches!(error, RepackError::DestinationExists(_)));
+    }
+
+    #[test]
+    fn test_numbered_sibling() {
+        assert_eq!(
+            numbered_sibling(Utf8Path::new("/data/0061-1.2.3.dcm"), 4),
+            "/data/0061-1.2.3-4.dcm"
+        );
+        assert_eq!(
+            numbered_sibling(Utf8Path::new("/data/no_extension"), 1),
+            "/data/no_extension-1"
+        );
+    }
+}
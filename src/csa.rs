@@ -0,0 +1,266 @@
+//! Decoding of Siemens CSA headers, which are where Siemens MRI scanners put metadata such as
+//! slice timing, B-values, diffusion directions and phase-encoding direction.
+//!
+//! The format is not documented by Siemens. This implementation follows
+//! [nibabel](https://nipy.org/nibabel/dicom/siemens_csa.html).
+use crate::private_dict::private_block;
+use dicom::core::Tag;
+use dicom::object::InMemDicomObject;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Private Creator of the CSA header elements.
+const CSA_CREATOR: &str = "SIEMENS CSA HEADER";
+
+/// Decoded CSA header: values by name.
+pub(crate) type CsaHeader = BTreeMap<String, Value>;
+
+/// The CSA headers of a DICOM object.
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub(crate) struct CsaHeaders {
+    /// CSA Image Header Info (0029,xx10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Image: Option<CsaHeader>,
+    /// CSA Series Header Info (0029,xx20)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Series: Option<CsaHeader>,
+}
+
+impl CsaHeaders {
+    /// Decode the CSA headers of a DICOM object, if it has any.
+    ///
+    /// A CSA header which cannot be decoded is treated the same as if it were not present.
+    pub fn from_dicom(dcm: &InMemDicomObject) -> Option<Self> {
+        let block = private_block(dcm, 0x0029, CSA_CREATOR)?;
+        let decode = |element| {
+            let bytes = dcm.element(Tag(0x0029, (block << 8) | element)).ok()?;
+            parse_csa(&bytes.to_bytes().ok()?).ok()
+        };
+        let headers = Self {
+            Image: decode(0x10),
+            Series: decode(0x20),
+        };
+        Some(headers).filter(|h| h.Image.is_some() || h.Series.is_some())
+    }
+}
+
+/// Error decoding a CSA header.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub(crate) enum CsaError {
+    #[error("CSA header is truncated")]
+    Truncated,
+    #[error("invalid CSA header: expected 77 at byte {0}")]
+    BadCheckValue(usize),
+}
+
+/// Decode a CSA header, in either the "CSA2" format (starting with `SV10`) or the older "CSA1" format.
+pub(crate) fn parse_csa(bytes: &[u8]) -> Result<CsaHeader, CsaError> {
+    let csa2 = bytes.starts_with(b"SV10");
+    let mut reader = Reader {
+        bytes,
+        pos: if csa2 { 8 } else { 0 },
+    };
+    let n_tags = reader.u32()?;
+    if reader.u32()? != 77 {
+        return Err(CsaError::BadCheckValue(reader.pos - 4));
+    }
+    let mut header = CsaHeader::new();
+    let mut first_n_items = None;
+    for _ in 0..n_tags {
+        let name = null_terminated(reader.take(64)?);
+        let vm = reader.i32()?;
+        let vr = null_terminated(reader.take(4)?);
+        let _syngodt = reader.i32()?;
+        let n_items = reader.i32()?;
+        let _check = reader.i32()?;
+        let n_items = usize::try_from(n_items).unwrap_or(0);
+        // every item has a header of 16 bytes
+        if n_items > reader.remaining() / 16 {
+            return Err(CsaError::Truncated);
+        }
+        let first_n_items = *first_n_items.get_or_insert(n_items);
+        let n_values = if vm > 0 { vm as usize } else { n_items };
+
+        let mut values = Vec::with_capacity(n_values.min(n_items));
+        for item_no in 0..n_items {
+            let item_header = [reader.i32()?, reader.i32()?, reader.i32()?, reader.i32()?];
+            let item_len = if csa2 {
+                item_header[1]
+            } else {
+                item_header[0] - first_n_items as i32
+            };
+            let Ok(item_len) = usize::try_from(item_len) else {
+                break;
+            };
+            let item = reader.take(item_len)?;
+            reader.take((4 - item_len % 4) % 4).ok();
+            if item_no < n_values {
+                values.push(null_terminated(item));
+            }
+        }
+        // trailing items are often empty
+        while values.last().is_some_and(|v| v.trim().is_empty()) {
+            values.pop();
+        }
+        let mut values: Vec<_> = values.into_iter().map(|v| convert(vr, v)).collect();
+        let value = match (vm, values.len()) {
+            (_, 0) => continue,
+            (1, 1) => values.pop().unwrap(),
+            _ => Value::Array(values),
+        };
+        header.insert(name.to_string(), value);
+    }
+    Ok(header)
+}
+
+/// Convert a CSA item to a JSON value according to its VR.
+fn convert(vr: &str, s: &str) -> Value {
+    let s = s.trim();
+    let number = match vr {
+        "DS" | "FL" | "FD" => s.parse::<f64>().ok().and_then(serde_json::Number::from_f64),
+        "IS" | "SL" | "SS" | "UL" | "US" => s.parse::<i64>().ok().map(serde_json::Number::from),
+        _ => None,
+    };
+    number
+        .map(Value::Number)
+        .unwrap_or_else(|| Value::String(s.to_string()))
+}
+
+/// Get the string before the first null byte.
+fn null_terminated(bytes: &[u8]) -> &str {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).unwrap_or_default()
+}
+
+/// Little-endian reader of CSA header bytes.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CsaError> {
+        let end = self.pos.checked_add(n).ok_or(CsaError::Truncated)?;
+        let slice = self.bytes.get(self.pos..end).ok_or(CsaError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.pos)
+    }
+
+    fn u32(&mut self) -> Result<u32, CsaError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn i32(&mut self) -> Result<i32, CsaError> {
+        self.u32().map(|u| u as i32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use serde_json::json;
+
+    /// Encode a CSA2 header.
+    fn encode_csa2(tags: &[(&str, i32, &str, &[&str])]) -> Vec<u8> {
+        let mut bytes = b"SV10\x04\x03\x02\x01".to_vec();
+        bytes.extend((tags.len() as u32).to_le_bytes());
+        bytes.extend(77_u32.to_le_bytes());
+        for (name, vm, vr, items) in tags {
+            let mut padded_name = name.as_bytes().to_vec();
+            padded_name.resize(64, 0);
+            bytes.extend(padded_name);
+            bytes.extend(vm.to_le_bytes());
+            let mut padded_vr = vr.as_bytes().to_vec();
+            padded_vr.resize(4, 0);
+            bytes.extend(padded_vr);
+            bytes.extend(0_i32.to_le_bytes());
+            bytes.extend((items.len() as i32).to_le_bytes());
+            bytes.extend(77_i32.to_le_bytes());
+            for item in items.iter() {
+                let mut data = item.as_bytes().to_vec();
+                if !data.is_empty() {
+                    data.push(0);
+                }
+                let len = data.len() as i32;
+                for x in [len, len, 77, len] {
+                    bytes.extend(x.to_le_bytes());
+                }
+                data.resize(data.len() + (4 - data.len() % 4) % 4, 0);
+                bytes.extend(data);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_parse_csa2() {
+        let bytes = encode_csa2(&[
+            ("B_value", 1, "IS", &["1000", "", ""]),
+            (
+                "DiffusionGradientDirection",
+                3,
+                "FD",
+                &["0.6", "-0.8", "0.0"],
+            ),
+            ("PhaseEncodingDirectionPositive", 1, "IS", &["1"]),
+            ("SliceTimingStuff", 0, "FD", &["0", "512.5"]),
+            ("ImaCoilString", 1, "LO", &["HEA;HEP"]),
+            ("Empty", 1, "LO", &["", ""]),
+        ]);
+        let expected = json!({
+            "B_value": 1000,
+            "DiffusionGradientDirection": [0.6, -0.8, 0.0],
+            "PhaseEncodingDirectionPositive": 1,
+            "SliceTimingStuff": [0.0, 512.5],
+            "ImaCoilString": "HEA;HEP",
+        });
+        assert_eq!(
+            serde_json::to_value(parse_csa(&bytes).unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_csa_errors() {
+        let bytes = encode_csa2(&[("B_value", 1, "IS", &["1000"])]);
+        assert_eq!(
+            parse_csa(&bytes[..bytes.len() - 6]),
+            Err(CsaError::Truncated)
+        );
+        // number of items of the first tag, which is far more than the bytes left
+        let mut too_many_items = bytes.clone();
+        too_many_items[92..96].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert_eq!(parse_csa(&too_many_items), Err(CsaError::Truncated));
+        assert_eq!(
+            parse_csa(b"SV10\x04\x03\x02\x01\x01\0\0\0\x05\0\0\0"),
+            Err(CsaError::BadCheckValue(12))
+        );
+    }
+
+    #[test]
+    fn test_from_dicom() {
+        let image = encode_csa2(&[("B_value", 1, "IS", &["1000"])]);
+        let dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                Tag(0x0029, 0x0011),
+                VR::LO,
+                PrimitiveValue::from("SIEMENS CSA HEADER"),
+            ),
+            DataElement::new(Tag(0x0029, 0x1110), VR::OB, PrimitiveValue::from(image)),
+        ]);
+        let expected = CsaHeaders {
+            Image: Some([("B_value".to_string(), json!(1000))].into()),
+            Series: None,
+        };
+        assert_eq!(CsaHeaders::from_dicom(&dcm), Some(expected));
+        assert_eq!(CsaHeaders::from_dicom(&InMemDicomObject::new_empty()), None);
+    }
+}
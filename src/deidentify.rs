@@ -0,0 +1,303 @@
+//! De-identification of DICOM objects, based on the DICOM PS3.15 Basic Application Level
+//! Confidentiality Profile:
+//! https://dicom.nema.org/medical/dicom/current/output/chtml/part15/chapter_E.html
+//!
+//! UIDs and PatientID are replaced with a keyed hash (HMAC-SHA256) of their original values,
+//! so that instances of the same study, series or patient remain linked across runs
+//! (given the same key) while the original values cannot be recovered without the key.
+use dicom::core::header::Header;
+use dicom::core::value::{DataSetSequence, Value};
+use dicom::core::{DataElement, Length, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::{DefaultDicomObject, InMemDicomObject};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// De-identification profile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DeidProfile {
+    /// DICOM PS3.15 Basic Application Level Confidentiality Profile
+    #[default]
+    Basic,
+}
+
+/// What to do with an element.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Action {
+    /// Remove the element ("X").
+    Remove,
+    /// Replace the value with a zero-length value ("Z").
+    Empty,
+}
+
+/// OtherPatientIDs, which is retired but still found in files from older equipment.
+const OTHER_PATIENT_IDS: Tag = Tag(0x0010, 0x1000);
+
+/// Actions of the Basic Profile, besides removing private elements and replacing
+/// PatientID, PatientName and UIDs.
+///
+/// This is a subset of PS3.15 Table E.1-1 covering the attributes which are commonly
+/// found in MR, CT and PET images. Elements with the VR PN which are not listed here
+/// are emptied, and curves and overlay data are removed, see [is_curve_or_overlay].
+const BASIC_PROFILE: &[(Tag, Action)] = &[
+    (tags::ACCESSION_NUMBER, Action::Empty),
+    (tags::ACQUISITION_DATE, Action::Remove),
+    (tags::ACQUISITION_DATE_TIME, Action::Remove),
+    (tags::ACQUISITION_TIME, Action::Remove),
+    (tags::ADDITIONAL_PATIENT_HISTORY, Action::Remove),
+    (tags::ADMITTING_DIAGNOSES_DESCRIPTION, Action::Remove),
+    (tags::BRANCH_OF_SERVICE, Action::Remove),
+    (tags::CONTENT_DATE, Action::Empty),
+    (tags::CONTENT_TIME, Action::Empty),
+    (tags::COUNTRY_OF_RESIDENCE, Action::Remove),
+    (tags::DERIVATION_DESCRIPTION, Action::Remove),
+    (tags::DEVICE_SERIAL_NUMBER, Action::Remove),
+    (tags::ETHNIC_GROUP, Action::Remove),
+    (tags::IMAGE_COMMENTS, Action::Remove),
+    (tags::INSTANCE_CREATION_DATE, Action::Remove),
+    (tags::INSTANCE_CREATION_TIME, Action::Remove),
+    (tags::INSTITUTION_ADDRESS, Action::Remove),
+    (tags::INSTITUTION_NAME, Action::Remove),
+    (tags::INSTITUTIONAL_DEPARTMENT_NAME, Action::Remove),
+    (tags::ISSUER_OF_PATIENT_ID, Action::Remove),
+    (tags::MILITARY_RANK, Action::Remove),
+    (tags::NAME_OF_PHYSICIANS_READING_STUDY, Action::Remove),
+    (tags::OCCUPATION, Action::Remove),
+    (tags::OPERATORS_NAME, Action::Remove),
+    (OTHER_PATIENT_IDS, Action::Remove),
+    (tags::OTHER_PATIENT_I_DS_SEQUENCE, Action::Remove),
+    (tags::OTHER_PATIENT_NAMES, Action::Remove),
+    (tags::PATIENT_ADDRESS, Action::Remove),
+    (tags::PATIENT_AGE, Action::Remove),
+    (tags::PATIENT_BIRTH_DATE, Action::Empty),
+    (tags::PATIENT_BIRTH_NAME, Action::Remove),
+    (tags::PATIENT_BIRTH_TIME, Action::Remove),
+    (tags::PATIENT_COMMENTS, Action::Remove),
+    (tags::PATIENT_MOTHER_BIRTH_NAME, Action::Remove),
+    (tags::PATIENT_RELIGIOUS_PREFERENCE, Action::Remove),
+    (tags::PATIENT_SEX, Action::Empty),
+    (tags::PATIENT_SIZE, Action::Remove),
+    (tags::PATIENT_TELEPHONE_NUMBERS, Action::Remove),
+    (tags::PATIENT_WEIGHT, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_ID, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_START_DATE, Action::Remove),
+    (tags::PERFORMED_PROCEDURE_STEP_START_TIME, Action::Remove),
+    (tags::PERFORMING_PHYSICIAN_NAME, Action::Remove),
+    (tags::PHYSICIANS_OF_RECORD, Action::Remove),
+    (tags::PROTOCOL_NAME, Action::Remove),
+    (tags::REFERENCED_PATIENT_SEQUENCE, Action::Remove),
+    (tags::REFERRING_PHYSICIAN_ADDRESS, Action::Remove),
+    (tags::REFERRING_PHYSICIAN_NAME, Action::Empty),
+    (tags::REFERRING_PHYSICIAN_TELEPHONE_NUMBERS, Action::Remove),
+    (tags::REGION_OF_RESIDENCE, Action::Remove),
+    (tags::REQUEST_ATTRIBUTES_SEQUENCE, Action::Remove),
+    (tags::REQUESTING_PHYSICIAN, Action::Remove),
+    (tags::RESPONSIBLE_ORGANIZATION, Action::Remove),
+    (tags::RESPONSIBLE_PERSON, Action::Remove),
+    (tags::SERIES_DATE, Action::Remove),
+    (tags::SERIES_DESCRIPTION, Action::Remove),
+    (tags::SERIES_TIME, Action::Remove),
+    (tags::STATION_NAME, Action::Remove),
+    (tags::STUDY_DATE, Action::Empty),
+    (tags::STUDY_DESCRIPTION, Action::Remove),
+    (tags::STUDY_ID, Action::Empty),
+    (tags::STUDY_TIME, Action::Empty),
+];
+
+/// Prefix of UIDs defined by the DICOM standard (e.g. SOP classes, transfer syntaxes),
+/// which do not identify anything and are not remapped.
+const DICOM_UID_ROOT: &str = "1.2.840.10008.";
+
+/// Applies a de-identification profile to DICOM objects.
+#[derive(Clone)]
+pub struct Deidentifier {
+    profile: DeidProfile,
+    mac: Hmac<Sha256>,
+}
+
+impl Deidentifier {
+    /// Create a de-identifier which remaps identifiers using the given secret key.
+    pub fn new(profile: DeidProfile, key: &[u8]) -> Self {
+        let mac = Hmac::new_from_slice(key).expect("HMAC accepts keys of any size");
+        Self { profile, mac }
+    }
+
+    /// De-identify a DICOM object, including its file meta information.
+    ///
+    /// PatientID is replaced by [Deidentifier::remap_patient_id],
+    /// and PatientName is replaced by the same pseudonym.
+    pub fn apply(&self, dcm: DefaultDicomObject) -> DefaultDicomObject {
+        let pseudonym = dcm
+            .element(tags::PATIENT_ID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|patient_id| self.remap_patient_id(&patient_id))
+            .unwrap_or_default();
+        let mut meta = dcm.meta().clone();
+        meta.media_storage_sop_instance_uid = self.remap_uid(&meta.media_storage_sop_instance_uid);
+        meta.update_information_group_length();
+        let mut obj = self.deidentify(dcm.into_inner());
+        for (tag, vr) in [(tags::PATIENT_ID, VR::LO), (tags::PATIENT_NAME, VR::PN)] {
+            obj.put(DataElement::new(
+                tag,
+                vr,
+                PrimitiveValue::from(pseudonym.as_str()),
+            ));
+        }
+        obj.put(DataElement::new(
+            tags::PATIENT_IDENTITY_REMOVED,
+            VR::CS,
+            PrimitiveValue::from("YES"),
+        ));
+        obj.put(DataElement::new(
+            tags::DEIDENTIFICATION_METHOD,
+            VR::LO,
+            PrimitiveValue::from(self.method()),
+        ));
+        obj.with_exact_meta(meta)
+    }
+
+    /// Replacement of a UID: a UUID-derived UID (see PS3.5 B.2) made from the keyed hash.
+    pub fn remap_uid(&self, uid: &str) -> String {
+        let uid = trim(uid);
+        if uid.is_empty() || uid.starts_with(DICOM_UID_ROOT) {
+            return uid.to_string();
+        }
+        let digest = self.keyed_hash("uid", uid);
+        let n = u128::from_be_bytes(digest[..16].try_into().unwrap());
+        format!("2.25.{n}")
+    }
+
+    /// Replacement of a PatientID: 16 hexadecimal digits of the keyed hash.
+    pub fn remap_patient_id(&self, patient_id: &str) -> String {
+        let digest = self.keyed_hash("patient", trim(patient_id));
+        digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// Value of DeidentificationMethod.
+    fn method(&self) -> &'static str {
+        match self.profile {
+            DeidProfile::Basic => {
+                "rx-repack PS3.15 Basic Application Level Confidentiality Profile"
+            }
+        }
+    }
+
+    /// HMAC of a value, where `domain` prevents equal values of different kinds
+    /// from being remapped to the same value.
+    fn keyed_hash(&self, domain: &str, value: &str) -> [u8; 32] {
+        let mut mac = self.mac.clone();
+        mac.update(domain.as_bytes());
+        mac.update(&[0]);
+        mac.update(value.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    /// De-identify a data set, recursively.
+    fn deidentify(&self, obj: InMemDicomObject) -> InMemDicomObject {
+        InMemDicomObject::from_element_iter(
+            obj.into_iter()
+                .filter_map(|element| self.deidentify_element(element)),
+        )
+    }
+
+    fn deidentify_element(
+        &self,
+        element: DataElement<InMemDicomObject>,
+    ) -> Option<DataElement<InMemDicomObject>> {
+        let tag = element.tag();
+        let vr = element.vr();
+        if tag.group() % 2 == 1 {
+            // private elements may contain anything
+            return None;
+        }
+        if tag == tags::PATIENT_ID || tag == tags::PATIENT_NAME {
+            // replaced in Deidentifier::apply
+            return None;
+        }
+        match BASIC_PROFILE
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, a)| a)
+        {
+            Some(Action::Remove) => return None,
+            Some(Action::Empty) => return Some(DataElement::empty(tag, vr)),
+            None if is_curve_or_overlay(tag) => return None,
+            // any person's name, e.g. of a physician or operator
+            None if vr == VR::PN => return Some(DataElement::empty(tag, vr)),
+            None => (),
+        }
+        match element.into_value() {
+            Value::Primitive(value) if vr == VR::UI => {
+                let uids: Vec<_> = value
+                    .to_multi_str()
+                    .iter()
+                    .map(|uid| self.remap_uid(uid))
+                    .collect();
+                Some(DataElement::new(tag, vr, PrimitiveValue::Strs(uids.into())))
+            }
+            Value::Sequence(seq) => {
+                let items: Vec<_> = seq
+                    .into_items()
+                    .into_iter()
+                    .map(|item| self.deidentify(item))
+                    .collect();
+                let seq = DataSetSequence::new(items, Length::UNDEFINED);
+                Some(DataElement::new(tag, vr, Value::Sequence(seq)))
+            }
+            value => Some(DataElement::new(tag, vr, value)),
+        }
+    }
+}
+
+/// Whether an element is curve data (group 50xx) or overlay data or comments
+/// (60xx,3000 and 60xx,4000), which PS3.15 Table E.1-1 lists for removal.
+fn is_curve_or_overlay(tag: Tag) -> bool {
+    match tag.group() & 0xFF00 {
+        0x5000 => true,
+        0x6000 => tag.element() == 0x3000 || tag.element() == 0x4000,
+        _ => false,
+    }
+}
+
+/// Remove padding from a value.
+fn trim(s: &str) -> &str {
+    s.trim_end_matches(['\0', ' ']).trim_start()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remap_is_keyed_and_consistent() {
+        let a = Deidentifier::new(DeidProfile::Basic, b"secret");
+        let b = Deidentifier::new(DeidProfile::Basic, b"another secret");
+        let uid = "1.3.12.2.1107.5.2.19.45152.2013030808061520200285270.0.0.0";
+        assert_eq!(a.remap_uid(uid), a.remap_uid(&format!("{uid}\0")));
+        assert_ne!(a.remap_uid(uid), b.remap_uid(uid));
+        assert!(a.remap_uid(uid).starts_with("2.25."));
+        assert!(a.remap_uid(uid).len() <= 64);
+        assert_eq!(a.remap_patient_id("1449c1d").len(), 16);
+        assert_ne!(a.remap_patient_id(uid), a.remap_uid(uid));
+    }
+
+    #[test]
+    fn test_is_curve_or_overlay() {
+        assert!(is_curve_or_overlay(Tag(0x5002, 0x3000)));
+        assert!(is_curve_or_overlay(Tag(0x6000, 0x3000)));
+        assert!(is_curve_or_overlay(Tag(0x601E, 0x4000)));
+        assert!(!is_curve_or_overlay(Tag(0x6000, 0x0010)));
+        assert!(!is_curve_or_overlay(tags::PIXEL_DATA));
+    }
+
+    #[test]
+    fn test_standard_uids_are_not_remapped() {
+        let deid = Deidentifier::new(DeidProfile::Basic, b"secret");
+        assert_eq!(
+            deid.remap_uid(dicom::dictionary_std::uids::MR_IMAGE_STORAGE),
+            dicom::dictionary_std::uids::MR_IMAGE_STORAGE
+        );
+    }
+}
@@ -1,7 +1,10 @@
 //! Everything related to DICOM tag data extraction.
+use crate::transcode::TranscodeError;
+use dicom::core::header::Header;
 use dicom::core::value::{CastValueError, ConvertValueError};
 use dicom::core::DataDictionary;
 use dicom::dictionary_std::{tags, StandardDataDictionary};
+use dicom::object::mem::InMemElement;
 use dicom::object::{DefaultDicomObject, Tag};
 use std::borrow::Cow;
 use std::cell::RefCell;
@@ -22,6 +25,7 @@ pub(crate) struct TagExtractor<'a> {
 }
 
 /// A DICOM tag and the error which occurred when trying to read its value.
+#[derive(Debug)]
 pub struct DicomTagAndError {
     pub tag: Tag,
     pub error: DicomTagError,
@@ -36,22 +40,21 @@ pub enum DicomTagError {
     CastValue(#[from] CastValueError),
     #[error(transparent)]
     ConvertValue(#[from] ConvertValueError),
+    #[error(transparent)]
+    Transcode(#[from] TranscodeError),
 }
 
-/// DICOM elements which a [PypxPath] is comprised of.
+/// DICOM elements which are needed by the default path template and the log files.
 ///
 /// Some elements are assumed to must exist, some are allowed to not be defined.
 /// I'm just really, really hoping that UID and ID numbers exist!
 #[allow(non_snake_case)]
 pub(crate) struct CommonElements<'a> {
-    // these are all part of the path name.
-    pub InstanceNumber: Option<&'a str>,
+    // these are all part of the default path name.
     pub SOPInstanceUID: &'a str,
     pub PatientID: &'a str,
-    pub PatientName: Option<&'a str>,
     pub PatientBirthDate: Option<&'a str>,
     pub StudyDescription: Option<&'a str>,
-    pub AccessionNumber: Option<&'a str>,
     pub StudyDate: Option<&'a str>,
 
     pub SeriesNumber: Option<MaybeU32<'a>>,
@@ -72,18 +75,15 @@ pub(crate) enum MaybeU32<'a> {
 
 impl<'a> From<&'a str> for MaybeU32<'a> {
     fn from(value: &'a str) -> Self {
-        value
-            .parse()
-            .map(Self::U32)
-            .unwrap_or_else(|_| MaybeU32::Str(value))
+        value.parse().map(Self::U32).unwrap_or(MaybeU32::Str(value))
     }
 }
 
-impl<'a> ToString for MaybeU32<'a> {
-    fn to_string(&self) -> String {
+impl<'a> std::fmt::Display for MaybeU32<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MaybeU32::U32(num) => num.to_string(),
-            MaybeU32::Str(s) => s.to_string(),
+            MaybeU32::U32(num) => num.fmt(f),
+            MaybeU32::Str(s) => s.fmt(f),
         }
     }
 }
@@ -98,7 +98,7 @@ impl<'a> TagExtractor<'a> {
 
     /// Get the value of a tag as a str. In case of failure,
     /// record the error in `self.errors` and return `""`.
-    pub fn get(&self, tag: Tag) -> Cow<str> {
+    pub fn get(&self, tag: Tag) -> Cow<'_, str> {
         self.dcm
             .element(tag)
             .map_err(DicomTagError::from)
@@ -127,19 +127,16 @@ impl<'a> TagExtractor<'a> {
 }
 
 impl<'a> TryFrom<&'a DefaultDicomObject> for CommonElements<'a> {
-    type Error = DicomTagError;
+    type Error = DicomTagAndError;
     fn try_from(dcm: &'a DefaultDicomObject) -> Result<Self, Self::Error> {
         // NOTE: the implementation here is optimized based on implementation details of dicom-rs v0.5.4.
         // - dcm.element(...)?.string() produces a reference to the data w/o cloning nor parsing
         // - dcm.element is more efficient than dcm.element_by_name, since the latter does a map lookup
         let data = Self {
-            InstanceNumber: tt(dcm, tags::INSTANCE_NUMBER).ok(),
             SOPInstanceUID: tt(dcm, tags::SOP_INSTANCE_UID)?,
             PatientID: tt(dcm, tags::PATIENT_ID)?,
-            PatientName: tt(dcm, tags::PATIENT_NAME).ok(),
             PatientBirthDate: tt(dcm, tags::PATIENT_BIRTH_DATE).ok(),
             StudyDescription: tt(dcm, tags::STUDY_DESCRIPTION).ok(),
-            AccessionNumber: tt(dcm, tags::ACCESSION_NUMBER).ok(),
             StudyDate: tt(dcm, tags::STUDY_DATE).ok(),
             SeriesNumber: tt(dcm, tags::SERIES_NUMBER).map(MaybeU32::from).ok(),
             SeriesDescription: tt(dcm, tags::SERIES_DESCRIPTION).ok(),
@@ -153,18 +150,25 @@ impl<'a> TryFrom<&'a DefaultDicomObject> for CommonElements<'a> {
 /// Get the trimmed `&str` to a DICOM object.
 ///
 /// I tried to make this helper function low-cost.
-fn tt(dcm: &DefaultDicomObject, tag: Tag) -> Result<&str, DicomTagError> {
+fn tt(dcm: &DefaultDicomObject, tag: Tag) -> Result<&str, DicomTagAndError> {
     dcm.element(tag)
         .map_err(DicomTagError::from)
         .and_then(|e| e.string().map(|s| s.trim()).map_err(DicomTagError::from))
+        .map_err(|error| DicomTagAndError { tag, error })
 }
 
-fn tts(dcm: &DefaultDicomObject, tag: Tag) -> Result<String, DicomTagError> {
+fn tts(dcm: &DefaultDicomObject, tag: Tag) -> Result<String, DicomTagAndError> {
     tt(dcm, tag).map(|s| s.replace('\0', ""))
 }
 
 /// Get the standard name of a tag.
+///
+/// Private tags, e.g. (0019,0010) in SAG-anon, are not found here: see [crate::PrivateDictionary].
 pub(crate) fn name_of(tag: Tag) -> Option<&'static str> {
-    // WHY SAG-anon has a DICOM tag (0019,0010)?
     StandardDataDictionary.by_tag(tag).map(|e| e.alias)
 }
+
+/// Iterate over the elements of a DICOM object, except for its pixel data.
+pub(crate) fn header_elements(dcm: &DefaultDicomObject) -> impl Iterator<Item = &InMemElement> {
+    dcm.iter().filter(|ele| ele.tag() != tags::PIXEL_DATA)
+}
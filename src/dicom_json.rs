@@ -0,0 +1,354 @@
+//! Serialization of DICOM headers in the DICOM JSON Model, for tools other than pypx:
+//! https://dicom.nema.org/medical/dicom/current/output/chtml/part18/sect_F.2.html
+use crate::dicom_data::header_elements;
+use base64::Engine;
+use camino::Utf8Path;
+use dicom::core::header::Header;
+use dicom::core::value::Value;
+use dicom::core::{PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use dicom::encoding::text::SpecificCharacterSet;
+use dicom::encoding::transfer_syntax::Codec;
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::mem::InMemElement;
+use dicom::object::{DefaultDicomObject, FileMetaTable, InMemDicomObject};
+use dicom::parser::dataset::lazy_read::LazyDataSetReader;
+use dicom::parser::dataset::LazyDataToken;
+use dicom::parser::DynStatefulDecoder;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use serde_json::{json, Map, Value as JsonValue};
+use std::cell::Cell;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+/// Which DICOM headers are written in the DICOM JSON Model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DicomJsonMode {
+    /// One file per instance
+    Instance,
+    /// One file per series, from its first instance
+    Series,
+}
+
+/// Produce the DICOM JSON of a DICOM object's header.
+///
+/// Pixel data is not included. Instead, it is referred to by a BulkDataURI pointing
+/// to the DICOM file at `path`, which must already be written: the value of PixelData
+/// is located within the file by `offset` and `length` query parameters. If it cannot
+/// be located, e.g. because the file does not exist yet, the URI points at the whole file.
+pub(crate) fn to_dicom_json(dcm: &DefaultDicomObject, path: &Utf8Path) -> JsonValue {
+    let mut obj: Map<_, _> = header_elements(dcm)
+        .filter_map(|ele| Some((tag_key(ele.tag()), element_json(ele)?)))
+        .collect();
+    if let Ok(pixel_data) = dcm.element(tags::PIXEL_DATA) {
+        let uri = match pixel_data_range(path) {
+            Some((offset, length)) => format!("{}?offset={offset}&length={length}", file_uri(path)),
+            None => file_uri(path),
+        };
+        obj.insert(
+            tag_key(tags::PIXEL_DATA),
+            json!({"vr": pixel_data.vr().to_string(), "BulkDataURI": uri}),
+        );
+    }
+    JsonValue::Object(obj)
+}
+
+/// The URI of a file without query: `file://` for absolute paths,
+/// or else the path itself as a relative reference.
+pub(crate) fn file_uri(path: &Utf8Path) -> String {
+    if path.is_absolute() {
+        format!("file://{path}")
+    } else {
+        path.to_string()
+    }
+}
+
+/// Find the offset and length of the value of the top-level PixelData in a DICOM file.
+///
+/// For encapsulated pixel data, the range spans its items, without the sequence delimiter.
+/// Returns `None` if the file cannot be parsed, has no pixel data, or is deflated.
+fn pixel_data_range(path: &Utf8Path) -> Option<(u64, u64)> {
+    let mut file = BufReader::new(fs_err::File::open(path).ok()?);
+    // skip the preamble
+    file.seek(SeekFrom::Start(128)).ok()?;
+    let meta = FileMetaTable::from_reader(&mut file).ok()?;
+    let ts = TransferSyntaxRegistry.get(meta.transfer_syntax())?;
+    if matches!(ts.codec(), Codec::Dataset(_)) {
+        return None;
+    }
+    let position = Rc::new(Cell::new(file.stream_position().ok()?));
+    let source = CountingReader {
+        inner: file,
+        position: Rc::clone(&position),
+    };
+    let decoder =
+        DynStatefulDecoder::new_with(source, ts, SpecificCharacterSet::default(), position.get())
+            .ok()?;
+    let mut reader = LazyDataSetReader::new(decoder);
+    let mut depth = 0;
+    let mut start = None;
+    while let Some(token) = reader.advance() {
+        match token.ok()? {
+            LazyDataToken::SequenceStart { .. } => depth += 1,
+            LazyDataToken::PixelSequenceStart => {
+                if depth == 0 {
+                    start = Some(position.get());
+                }
+                depth += 1;
+            }
+            LazyDataToken::SequenceEnd => {
+                depth -= 1;
+                if let (0, Some(start)) = (depth, start) {
+                    // the sequence delimitation item is 8 bytes long
+                    return Some((start, position.get() - 8 - start));
+                }
+            }
+            LazyDataToken::ElementHeader(header)
+                if depth == 0 && header.tag == tags::PIXEL_DATA =>
+            {
+                return Some((position.get(), u64::from(header.len.get()?)));
+            }
+            token => token.skip().ok()?,
+        }
+    }
+    None
+}
+
+/// A reader which keeps count of its position, so that it can be known while a
+/// parser owns the reader.
+struct CountingReader<R> {
+    inner: R,
+    position: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position.set(self.position.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Produce the DICOM JSON of a sequence item, recursively.
+fn item_json(item: &InMemDicomObject) -> JsonValue {
+    item.iter()
+        .filter_map(|ele| Some((tag_key(ele.tag()), element_json(ele)?)))
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// Produce the DICOM JSON of an element, or `None` if it cannot be represented
+/// (i.e. encapsulated pixel data in a sequence item).
+fn element_json(ele: &InMemElement) -> Option<JsonValue> {
+    let vr = ele.vr();
+    let mut obj = Map::new();
+    obj.insert("vr".to_string(), vr.to_string().into());
+    match ele.value() {
+        Value::Sequence(seq) => {
+            let items: Vec<_> = seq.items().iter().map(item_json).collect();
+            obj.insert("Value".to_string(), items.into());
+        }
+        Value::Primitive(PrimitiveValue::Empty) => (),
+        Value::Primitive(value) => {
+            if let Some(bytes) = is_binary(vr).then(|| value.to_bytes()) {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+                obj.insert("InlineBinary".to_string(), encoded.into());
+            } else {
+                obj.insert("Value".to_string(), primitive_json(value, vr).into());
+            }
+        }
+        Value::PixelSequence(_) => return None,
+    }
+    Some(obj.into())
+}
+
+/// Produce the values of a primitive element.
+fn primitive_json(value: &PrimitiveValue, vr: VR) -> Vec<JsonValue> {
+    if let PrimitiveValue::Tags(tags) = value {
+        return tags.iter().map(|t| tag_key(*t).into()).collect();
+    }
+    let strs = value.to_multi_str();
+    // text VRs are single-valued, and may contain backslashes
+    let single_valued = matches!(vr, VR::LT | VR::ST | VR::UT | VR::UR);
+    let strs = strs
+        .iter()
+        .flat_map(|s| {
+            if single_valued {
+                vec![s.as_str()]
+            } else {
+                s.split('\\').collect()
+            }
+        })
+        .map(|s| s.trim_end_matches(['\0', ' ']));
+    match vr {
+        VR::PN => strs.map(person_name_json).collect(),
+        VR::IS | VR::DS | VR::SS | VR::US | VR::SL | VR::UL | VR::SV | VR::UV | VR::FL | VR::FD => {
+            strs.map(number_json).collect()
+        }
+        _ => strs
+            .map(|s| {
+                if s.is_empty() {
+                    JsonValue::Null
+                } else {
+                    s.into()
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Person names are objects with up to three component groups.
+fn person_name_json(s: &str) -> JsonValue {
+    let groups = ["Alphabetic", "Ideographic", "Phonetic"];
+    let obj: Map<_, _> = groups
+        .iter()
+        .zip(s.split('='))
+        .filter(|(_, v)| !v.is_empty())
+        .map(|(k, v)| (k.to_string(), v.into()))
+        .collect();
+    obj.into()
+}
+
+/// Numbers are JSON numbers, unless they are invalid.
+fn number_json(s: &str) -> JsonValue {
+    let s = s.trim();
+    if s.is_empty() {
+        JsonValue::Null
+    } else if let Ok(i) = s.parse::<i64>() {
+        i.into()
+    } else if let Ok(u) = s.parse::<u64>() {
+        u.into()
+    } else {
+        s.parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(JsonValue::Number)
+            .unwrap_or_else(|| s.into())
+    }
+}
+
+/// VRs which are represented as InlineBinary.
+fn is_binary(vr: VR) -> bool {
+    matches!(
+        vr,
+        VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN
+    )
+}
+
+/// Tags are written as 8 uppercase hexadecimal digits, e.g. `"0020000D"`.
+fn tag_key(tag: Tag) -> String {
+    format!("{:04X}{:04X}", tag.group(), tag.element())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dicom::core::value::{DataSetSequence, PixelFragmentSequence};
+    use dicom::core::{DataElement, Length};
+    use dicom::dictionary_std::uids;
+    use dicom::object::FileMetaTableBuilder;
+
+    #[test]
+    fn test_to_dicom_json() {
+        let item = InMemDicomObject::from_element_iter([DataElement::new(
+            tags::REFERENCED_SOP_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from("1.2.3.4.5\0"),
+        )]);
+        let dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::PATIENT_NAME,
+                VR::PN,
+                PrimitiveValue::from("Anon^Patient"),
+            ),
+            DataElement::new(tags::SERIES_NUMBER, VR::IS, PrimitiveValue::from("5 ")),
+            DataElement::new(
+                tags::IMAGE_POSITION_PATIENT,
+                VR::DS,
+                PrimitiveValue::from("-1.5\\2\\3.25"),
+            ),
+            DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(256_u16)),
+            DataElement::new(tags::STUDY_ID, VR::SH, PrimitiveValue::Empty),
+            DataElement::new(
+                tags::REFERENCED_IMAGE_SEQUENCE,
+                VR::SQ,
+                Value::Sequence(DataSetSequence::new(vec![item], Length::UNDEFINED)),
+            ),
+            DataElement::new(
+                tags::PIXEL_DATA,
+                VR::OW,
+                PrimitiveValue::U16([0_u16; 4][..].into()),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::MR_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid("1.2.3")
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap();
+
+        let actual = to_dicom_json(&dcm, Utf8Path::new("/data/a.dcm"));
+        let expected = json!({
+            "00100010": {"vr": "PN", "Value": [{"Alphabetic": "Anon^Patient"}]},
+            "00200011": {"vr": "IS", "Value": [5]},
+            "00200032": {"vr": "DS", "Value": [-1.5, 2, 3.25]},
+            "00280010": {"vr": "US", "Value": [256]},
+            "00200010": {"vr": "SH"},
+            "00081140": {"vr": "SQ", "Value": [
+                {"00081155": {"vr": "UI", "Value": ["1.2.3.4.5"]}}
+            ]},
+            "7FE00010": {"vr": "OW", "BulkDataURI": "file:///data/a.dcm"},
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_inline_binary() {
+        let ele = DataElement::new(
+            tags::ENCAPSULATED_DOCUMENT,
+            VR::OB,
+            PrimitiveValue::from(vec![1_u8, 2, 3]),
+        );
+        assert_eq!(
+            element_json(&ele),
+            Some(json!({"vr": "OB", "InlineBinary": "AQID"}))
+        );
+    }
+
+    #[test]
+    fn test_pixel_data_range_encapsulated() {
+        let tmp_dir = tempdir::TempDir::new("dicom_json_test").unwrap();
+        let path = Utf8Path::from_path(tmp_dir.path()).unwrap().join("a.dcm");
+        let fragment = vec![0xFF_u8, 0xD8, 0xFF, 0xD9];
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::ROWS, VR::US, PrimitiveValue::from(1_u16)),
+            DataElement::new(
+                tags::PIXEL_DATA,
+                VR::OB,
+                Value::PixelSequence(PixelFragmentSequence::new(
+                    Vec::<u32>::new(),
+                    vec![fragment.clone()],
+                )),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::SECONDARY_CAPTURE_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid("1.2.3")
+                .transfer_syntax(uids::JPEG_BASELINE8_BIT),
+        )
+        .unwrap()
+        .write_to_file(&path)
+        .unwrap();
+
+        let (offset, length) = pixel_data_range(&path).unwrap();
+        let bytes = fs_err::read(&path).unwrap();
+        let items = &bytes[offset as usize..(offset + length) as usize];
+        // an empty basic offset table, followed by the fragment
+        let mut expected = vec![0xFE, 0xFF, 0x00, 0xE0, 0, 0, 0, 0];
+        expected.extend([0xFE, 0xFF, 0x00, 0xE0, 4, 0, 0, 0]);
+        expected.extend(fragment);
+        assert_eq!(items, expected);
+    }
+}
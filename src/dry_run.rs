@@ -0,0 +1,288 @@
+//! Dry runs, which report what repacking would do to files instead of doing it.
+//!
+//! Every file operation of [crate::repack] goes through [Output], so that a dry run
+//! takes the same code paths as a real run, up to the point where a file would be written.
+use crate::atomic_write::{remove_stale_partials, write_atomically};
+use crate::index::{self, IndexError, IndexRecord};
+use crate::log_write::{with_lock, write_json};
+use crate::quarantine::{merge_salvaged, quarantine_path, salvage, Quarantined};
+use crate::repack::copy_or_mv;
+use crate::series_progress::unix_time;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::io;
+
+/// What repacking would do to a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileAction {
+    /// The file does not exist, and would be created.
+    Create,
+    /// The file exists, and would be replaced.
+    Overwrite,
+    /// The file exists, and would be left as it is.
+    Untouched,
+    /// The file would be removed, e.g. the input file with `--cleanup`.
+    Remove,
+}
+
+/// A file in the plan of a dry run.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedFile {
+    pub path: Utf8PathBuf,
+    pub action: FileAction,
+}
+
+/// Where the file operations of repacking one DICOM object go.
+pub(crate) struct Output {
+    /// In a dry run, the plan which file operations go to, without touching the file system.
+    /// Otherwise, they go to the file system.
+    plan: Option<RefCell<Vec<PlannedFile>>>,
+    /// Corrupt files which were moved to quarantine, see [Output::load_json].
+    quarantined: RefCell<Vec<Quarantined>>,
+}
+
+impl Output {
+    pub fn new(dry_run: bool) -> Self {
+        Self {
+            plan: dry_run.then(|| RefCell::new(Vec::new())),
+            quarantined: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The files planned by a dry run, or `None` if this is not a dry run,
+    /// and the files which were moved to quarantine.
+    pub fn into_parts(self) -> (Option<Vec<PlannedFile>>, Vec<Quarantined>) {
+        (
+            self.plan.map(RefCell::into_inner),
+            self.quarantined.into_inner(),
+        )
+    }
+
+    fn record(&self, path: &Utf8Path, action: FileAction) {
+        if let Some(plan) = &self.plan {
+            plan.borrow_mut().push(PlannedFile {
+                path: path.to_path_buf(),
+                action,
+            });
+        }
+    }
+
+    /// Record that an existing file is not written to.
+    pub fn untouched(&self, path: &Utf8Path) {
+        self.record(path, FileAction::Untouched)
+    }
+
+    pub fn create_dir_all(&self, dir: &Utf8Path) -> io::Result<()> {
+        match &self.plan {
+            None => fs_err::create_dir_all(dir),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// See [remove_stale_partials].
+    pub fn remove_stale_partials(&self, dir: &Utf8Path) -> io::Result<()> {
+        match &self.plan {
+            None => remove_stale_partials(dir),
+            Some(_) => Ok(()),
+        }
+    }
+
+    pub fn remove_file(&self, path: &Utf8Path) -> io::Result<()> {
+        match &self.plan {
+            None => fs_err::remove_file(path),
+            Some(_) => {
+                self.record(path, FileAction::Remove);
+                Ok(())
+            }
+        }
+    }
+
+    /// See [with_lock]. In a dry run, no lock is taken.
+    pub fn with_lock<T, E, F>(&self, p: &Utf8Path, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<io::Error>,
+    {
+        match &self.plan {
+            None => with_lock(p, f),
+            Some(_) => f(),
+        }
+    }
+
+    /// Read a JSON file which is about to be modified and written back,
+    /// or return `default()` if it does not exist.
+    ///
+    /// A file which is not valid JSON of type `D` is not lost by being overwritten. It is moved
+    /// to quarantine in `log_dir`, and the values which can be salvaged from it are merged into
+    /// `default()`. In a dry run, the file is only planned to be moved.
+    pub fn load_json<D, F>(&self, p: &Utf8Path, log_dir: &Utf8Path, default: F) -> io::Result<D>
+    where
+        D: Serialize + DeserializeOwned,
+        F: FnOnce() -> D,
+    {
+        let content = match fs_err::read(p) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(default()),
+            Err(e) => return Err(e),
+        };
+        let error = match serde_json::from_slice(&content) {
+            Ok(data) => return Ok(data),
+            Err(e) => e,
+        };
+        let fresh = default();
+        let mut data = serde_json::to_value(&fresh)?;
+        let salvaged = merge_salvaged(&mut data, &salvage(&String::from_utf8_lossy(&content)));
+        let (data, salvaged) = match serde_json::from_value(data) {
+            Ok(data) => (data, salvaged),
+            Err(_) => (fresh, Vec::new()),
+        };
+        let quarantine = quarantine_path(p, log_dir, unix_time());
+        match &self.plan {
+            None => {
+                if let Some(parent) = quarantine.parent() {
+                    fs_err::create_dir_all(parent)?;
+                }
+                fs_err::rename(p, &quarantine)?;
+            }
+            Some(_) => {
+                self.record(p, FileAction::Remove);
+                self.record(&quarantine, FileAction::Create);
+            }
+        }
+        self.quarantined.borrow_mut().push(Quarantined {
+            path: p.to_path_buf(),
+            quarantine,
+            reason: error.to_string(),
+            salvaged,
+        });
+        Ok(data)
+    }
+
+    /// See [write_json]. In a dry run, the file is untouched if it has the same content.
+    pub fn write_json<S: Serialize>(&self, data: S, p: &Utf8Path) -> io::Result<()> {
+        match &self.plan {
+            None => write_json(data, p),
+            Some(_) => {
+                let content = serde_json::to_vec_pretty(&data)?;
+                self.record(p, action_for_content(p, &content));
+                Ok(())
+            }
+        }
+    }
+
+    /// Write `content` to `dst` atomically. In a dry run, the file is untouched
+    /// if it has the same content.
+    pub fn write_bytes(&self, dst: &Utf8Path, content: &[u8]) -> io::Result<()> {
+        match &self.plan {
+            None => write_atomically(dst, false, |file| io::Write::write_all(file, content)),
+            Some(_) => {
+                self.record(dst, action_for_content(dst, content));
+                Ok(())
+            }
+        }
+    }
+
+    /// See [write_atomically]. In a dry run, `write` is not called.
+    pub fn write_with<E, F>(&self, dst: &Utf8Path, write: F) -> Result<(), E>
+    where
+        F: FnOnce(&mut fs_err::File) -> Result<(), E>,
+        E: From<io::Error>,
+    {
+        match &self.plan {
+            None => write_atomically(dst, true, write),
+            Some(_) => {
+                self.record(dst, action_for_existence(dst));
+                Ok(())
+            }
+        }
+    }
+
+    /// Add an instance to the SQLite index at `db`, see [index::record].
+    pub fn record_in_index(&self, db: &Utf8Path, record: &IndexRecord) -> Result<(), IndexError> {
+        match &self.plan {
+            None => index::record(db, record),
+            Some(_) => {
+                self.record(db, action_for_existence(db));
+                Ok(())
+            }
+        }
+    }
+
+    /// Copy (or move, if `cleanup` is true) a file.
+    pub fn copy_file(&self, src: &Utf8Path, dst: &Utf8Path, cleanup: bool) -> io::Result<()> {
+        match &self.plan {
+            None => copy_or_mv(src, dst, cleanup),
+            Some(_) => {
+                self.record(dst, action_for_existence(dst));
+                if cleanup {
+                    self.record(src, FileAction::Remove);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn action_for_existence(p: &Utf8Path) -> FileAction {
+    if p.exists() {
+        FileAction::Overwrite
+    } else {
+        FileAction::Create
+    }
+}
+
+fn action_for_content(p: &Utf8Path, content: &[u8]) -> FileAction {
+    match fs_err::read(p) {
+        Ok(existing) if existing == content => FileAction::Untouched,
+        Ok(_) => FileAction::Overwrite,
+        Err(_) => FileAction::Create,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_plan_touches_nothing() {
+        let tmp_dir = TempDir::new("dry_run").unwrap();
+        let dir = Utf8Path::from_path(tmp_dir.path()).unwrap();
+        let same = dir.join("same.json");
+        let different = dir.join("different.json");
+        fs_err::write(&same, serde_json::to_vec_pretty(&[1, 2]).unwrap()).unwrap();
+        fs_err::write(&different, "[]").unwrap();
+        let new = dir.join("sub").join("new.json");
+
+        let out = Output::new(true);
+        out.create_dir_all(new.parent().unwrap()).unwrap();
+        for p in [&same, &different, &new] {
+            out.with_lock(p, || out.write_json([1, 2], p)).unwrap();
+        }
+        out.remove_file(&same).unwrap();
+
+        let actions: Vec<_> = out
+            .into_parts()
+            .0
+            .unwrap()
+            .into_iter()
+            .map(|f| f.action)
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                FileAction::Untouched,
+                FileAction::Overwrite,
+                FileAction::Create,
+                FileAction::Remove
+            ]
+        );
+        assert!(same.is_file());
+        assert_eq!(fs_err::read_to_string(&different).unwrap(), "[]");
+        assert!(!dir.join("sub").exists());
+        assert_eq!(fs_err::read_dir(dir).unwrap().count(), 2);
+    }
+}
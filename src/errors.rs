@@ -1,3 +1,7 @@
+use crate::conflict::DestinationExists;
+use crate::dicom_data::{DicomTagAndError, DicomTagError};
+use crate::index::IndexError;
+
 /// Error decoding a DICOM tag's value.
 #[derive(thiserror::Error, Debug)]
 pub(crate) enum ElementSerializationError {
@@ -10,3 +14,138 @@ pub(crate) enum ElementSerializationError {
     #[error(transparent)]
     CastValueError(#[from] dicom::core::value::CastValueError),
 }
+
+/// Error repacking a DICOM file.
+///
+/// Use [RepackError::kind] to tell errors apart programmatically.
+#[derive(thiserror::Error, Debug)]
+pub enum RepackError {
+    #[error(transparent)]
+    ReadDicom(#[from] dicom::object::ReadError),
+    #[error("Invalid DICOM data: {0}")]
+    InvalidDicom(String),
+    #[error("Missing or invalid element {tag}: {source}")]
+    MissingElement {
+        tag: dicom::core::Tag,
+        source: DicomTagError,
+    },
+    #[error(transparent)]
+    DestinationExists(#[from] DestinationExists),
+    #[error(transparent)]
+    WriteDicom(#[from] dicom::object::WriteError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Index(#[from] IndexError),
+    #[error("Panicked while repacking")]
+    Panic,
+}
+
+impl From<DicomTagAndError> for RepackError {
+    fn from(value: DicomTagAndError) -> Self {
+        Self::MissingElement {
+            tag: value.tag,
+            source: value.error,
+        }
+    }
+}
+
+/// Stable, machine-readable category of a [RepackError].
+///
+/// Serialized as kebab-case strings, e.g. `"not-dicom"`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The input is not a DICOM file, or it is malformed.
+    NotDicom,
+    /// An element required for repacking (e.g. SOPInstanceUID) is missing or invalid.
+    MissingElement,
+    /// The destination already exists, and `--on-conflict=error` was given.
+    DestinationExists,
+    /// No space left on device, or disk quota exceeded.
+    DiskFull,
+    /// Any other I/O error, e.g. file not found or permission denied,
+    /// or an error writing the SQLite index.
+    Io,
+    /// A bug.
+    Panic,
+}
+
+impl ErrorKind {
+    /// Process exit code of `rx-repack` when it fails because of this kind of error.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::NotDicom => 10,
+            ErrorKind::MissingElement => 11,
+            ErrorKind::DestinationExists => 12,
+            ErrorKind::DiskFull => 13,
+            ErrorKind::Io => 14,
+            ErrorKind::Panic => 15,
+        }
+    }
+}
+
+impl RepackError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RepackError::ReadDicom(e @ dicom::object::ReadError::OpenFile { .. }) => {
+                io_error_kind(e)
+            }
+            // a file which is too short to be DICOM
+            RepackError::ReadDicom(dicom::object::ReadError::ReadFile { source, .. })
+                if source.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                ErrorKind::NotDicom
+            }
+            RepackError::ReadDicom(e @ dicom::object::ReadError::ReadFile { .. }) => {
+                io_error_kind(e)
+            }
+            RepackError::ReadDicom(_) | RepackError::InvalidDicom(_) => ErrorKind::NotDicom,
+            RepackError::MissingElement { .. } => ErrorKind::MissingElement,
+            RepackError::DestinationExists(_) => ErrorKind::DestinationExists,
+            RepackError::WriteDicom(e) => io_error_kind(e),
+            RepackError::Io(e) => io_error_kind(e),
+            RepackError::Index(e) if e.is_disk_full() => ErrorKind::DiskFull,
+            RepackError::Index(_) => ErrorKind::Io,
+            RepackError::Panic => ErrorKind::Panic,
+        }
+    }
+}
+
+/// Linux error numbers for "No space left on device" and "Disk quota exceeded".
+const ENOSPC: i32 = 28;
+const EDQUOT: i32 = 122;
+
+/// Distinguish [ErrorKind::DiskFull] from other [ErrorKind::Io] errors
+/// by looking for an [std::io::Error] in the chain of sources.
+fn io_error_kind(e: &(dyn std::error::Error + 'static)) -> ErrorKind {
+    let mut current = Some(e);
+    while let Some(e) = current {
+        if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+            if matches!(io_error.raw_os_error(), Some(ENOSPC | EDQUOT)) {
+                return ErrorKind::DiskFull;
+            }
+        }
+        current = e.source();
+    }
+    ErrorKind::Io
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        let disk_full = RepackError::Io(std::io::Error::from_raw_os_error(ENOSPC));
+        assert_eq!(disk_full.kind(), ErrorKind::DiskFull);
+        let not_found = RepackError::Io(std::io::ErrorKind::NotFound.into());
+        assert_eq!(not_found.kind(), ErrorKind::Io);
+        let exists = RepackError::from(DestinationExists("/data/a.dcm".into()));
+        assert_eq!(exists.kind(), ErrorKind::DestinationExists);
+        assert_eq!(
+            serde_json::to_string(&ErrorKind::MissingElement).unwrap(),
+            r#""missing-element""#
+        );
+    }
+}
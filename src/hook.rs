@@ -0,0 +1,40 @@
+//! Running a command when a series is complete, e.g. to push it to ChRIS or convert it.
+use crate::series_progress::SeriesComplete;
+use std::io;
+use std::os::fd::AsFd;
+use std::process::{Command, ExitStatus, Stdio};
+
+/// Run a shell command for a complete series, and wait for it to exit.
+///
+/// The command is given the series directory, SeriesInstanceUID and StudyInstanceUID as
+/// arguments, and the same as environment variables `RX_SERIES_DIR`, `RX_SERIES_INSTANCE_UID`
+/// and `RX_STUDY_INSTANCE_UID`, along with `RX_SERIES_RECEIVED`, `RX_SERIES_EXPECTED` (empty
+/// if unknown) and `RX_SERIES_COMPLETE_REASON`.
+///
+/// The command's stdout is redirected to stderr, so that it does not interfere with NDJSON output.
+pub fn run_series_command(command: &str, series: &SeriesComplete) -> io::Result<ExitStatus> {
+    let stdout = io::stderr().as_fd().try_clone_to_owned()?;
+    let reason = serde_json::to_value(series.reason)?;
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("{command} \"$@\""))
+        .arg("sh")
+        .arg(&series.SeriesBaseDir)
+        .arg(&series.SeriesInstanceUID)
+        .arg(&series.StudyInstanceUID)
+        .env("RX_SERIES_DIR", &series.SeriesBaseDir)
+        .env("RX_SERIES_INSTANCE_UID", &series.SeriesInstanceUID)
+        .env("RX_STUDY_INSTANCE_UID", &series.StudyInstanceUID)
+        .env("RX_SERIES_RECEIVED", series.received.to_string())
+        .env(
+            "RX_SERIES_EXPECTED",
+            series.expected.map(|n| n.to_string()).unwrap_or_default(),
+        )
+        .env(
+            "RX_SERIES_COMPLETE_REASON",
+            reason.as_str().unwrap_or_default(),
+        )
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .status()
+}
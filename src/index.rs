@@ -0,0 +1,280 @@
+//! SQLite index of repacked patients, studies, series and instances.
+//!
+//! The index is fed from the same models as the pypx JSON files in the log dir
+//! (see [crate::log_models]), and can be queried with [query].
+#![allow(non_snake_case)]
+use crate::dicom_data::MaybeU32;
+use crate::log_models::{InstanceData, PatientData, SeriesDataMeta, StudyDataMeta};
+use camino::Utf8Path;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, ToSql};
+use std::time::Duration;
+
+/// Error reading or writing the SQLite index.
+#[derive(thiserror::Error, Debug)]
+#[error("SQLite index error: {0}")]
+pub struct IndexError(#[from] rusqlite::Error);
+
+impl IndexError {
+    /// Whether the database could not be written because the disk is full.
+    pub fn is_disk_full(&self) -> bool {
+        self.0.sqlite_error_code() == Some(rusqlite::ErrorCode::DiskFull)
+    }
+}
+
+/// How long to wait for other processes writing to the index, e.g. `storescp --fork`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS patients (
+    PatientID TEXT PRIMARY KEY,
+    PatientName TEXT,
+    PatientAge TEXT,
+    PatientSex TEXT,
+    PatientBirthDate TEXT
+);
+CREATE TABLE IF NOT EXISTS studies (
+    StudyInstanceUID TEXT PRIMARY KEY,
+    PatientID TEXT NOT NULL,
+    StudyDescription TEXT,
+    StudyDate TEXT,
+    PerformedStationAETitle TEXT
+);
+CREATE TABLE IF NOT EXISTS series (
+    SeriesInstanceUID TEXT PRIMARY KEY,
+    StudyInstanceUID TEXT NOT NULL,
+    PatientID TEXT NOT NULL,
+    SeriesDescription TEXT,
+    SeriesNumber,
+    SeriesDate TEXT,
+    Modality TEXT,
+    SeriesBaseDir TEXT
+);
+CREATE TABLE IF NOT EXISTS instances (
+    path TEXT PRIMARY KEY,
+    SOPInstanceUID TEXT NOT NULL,
+    SeriesInstanceUID TEXT NOT NULL,
+    size INTEGER,
+    TransferSyntaxUID TEXT
+);
+CREATE INDEX IF NOT EXISTS studies_patient ON studies (PatientID);
+CREATE INDEX IF NOT EXISTS series_study ON series (StudyInstanceUID);
+CREATE INDEX IF NOT EXISTS instances_series ON instances (SeriesInstanceUID);
+";
+
+/// Everything recorded about one repacked instance.
+pub(crate) struct IndexRecord<'a> {
+    pub patient: &'a PatientData<'a>,
+    pub study: &'a StudyDataMeta<'a>,
+    pub series: &'a SeriesDataMeta<'a>,
+    pub instance: &'a InstanceData<'a>,
+    pub SOPInstanceUID: &'a str,
+    pub SeriesBaseDir: &'a str,
+    pub path: &'a str,
+    /// Size of the DICOM file, or `None` if it was not written.
+    pub size: Option<u64>,
+}
+
+/// Open the index, creating it if it does not exist yet.
+fn open(p: &Utf8Path) -> Result<Connection, IndexError> {
+    let conn = Connection::open(p)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // readers do not block writers, and vice versa
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+/// Add an instance to the index at `p`.
+///
+/// Like the pypx JSON files, patients, studies and series are described by the
+/// first of their instances. The instance itself replaces any previous row for its path.
+pub(crate) fn record(p: &Utf8Path, r: &IndexRecord) -> Result<(), IndexError> {
+    let mut conn = open(p)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT OR IGNORE INTO patients VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            r.patient.PatientID,
+            r.patient.PatientName,
+            r.patient.PatientAge,
+            r.patient.PatientSex,
+            r.patient.PatientBirthDate,
+        ],
+    )?;
+    tx.execute(
+        "INSERT OR IGNORE INTO studies VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            r.study.StudyInstanceUID,
+            r.study.PatientID,
+            r.study.StudyDescription,
+            r.study.StudyDate,
+            r.study.PerformedStationAETitle,
+        ],
+    )?;
+    tx.execute(
+        "INSERT OR IGNORE INTO series VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            r.series.SeriesInstanceUID,
+            r.series.StudyInstanceUID,
+            r.series.PatientID,
+            r.series.SeriesDescription,
+            r.series.SeriesNumber,
+            r.series.SeriesDate,
+            r.series.Modality,
+            r.SeriesBaseDir,
+        ],
+    )?;
+    tx.execute(
+        "INSERT OR REPLACE INTO instances VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            r.path,
+            r.SOPInstanceUID,
+            r.instance.SeriesInstanceUID,
+            r.size,
+            r.instance.transferSyntax.map(|t| &t.stored),
+        ],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Replace the path of a DICOM file which was moved from `from` to `to`,
+/// and the directory of its series, in the index at `p`.
+pub(crate) fn relocate(p: &Utf8Path, from: &Utf8Path, to: &Utf8Path) -> Result<(), IndexError> {
+    let mut conn = open(p)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE instances SET path = ?2 WHERE path = ?1",
+        params![from.as_str(), to.as_str()],
+    )?;
+    if let (Some(from_dir), Some(to_dir)) = (from.parent(), to.parent()) {
+        tx.execute(
+            "UPDATE series SET SeriesBaseDir = ?2 WHERE SeriesBaseDir = ?1",
+            params![from_dir.as_str(), to_dir.as_str()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+impl ToSql for MaybeU32<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            MaybeU32::U32(n) => n.to_sql(),
+            MaybeU32::Str(s) => s.to_sql(),
+        }
+    }
+}
+
+/// What is listed by [query].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum QueryLevel {
+    Patients,
+    Studies,
+    Series,
+    Instances,
+}
+
+/// Conditions on what is listed by [query]. `None` matches anything.
+#[derive(Debug, Default, Clone)]
+pub struct QueryFilter {
+    pub PatientID: Option<String>,
+    pub StudyInstanceUID: Option<String>,
+    pub SeriesInstanceUID: Option<String>,
+    pub Modality: Option<String>,
+}
+
+/// Columns of each level, and how rows of instances are summarized.
+fn select(level: QueryLevel) -> &'static str {
+    match level {
+        QueryLevel::Patients => {
+            "SELECT p.PatientID, p.PatientName, p.PatientAge, p.PatientSex, p.PatientBirthDate,
+                COUNT(DISTINCT st.StudyInstanceUID) AS NumberOfStudies,
+                COUNT(*) AS NumberOfInstances, SUM(i.size) AS size"
+        }
+        QueryLevel::Studies => {
+            "SELECT st.PatientID, st.StudyInstanceUID, st.StudyDescription, st.StudyDate,
+                st.PerformedStationAETitle,
+                COUNT(DISTINCT se.SeriesInstanceUID) AS NumberOfSeries,
+                COUNT(*) AS NumberOfInstances, SUM(i.size) AS size"
+        }
+        QueryLevel::Series => {
+            "SELECT se.PatientID, se.StudyInstanceUID, se.SeriesInstanceUID, se.SeriesDescription,
+                se.SeriesNumber, se.SeriesDate, se.Modality, se.SeriesBaseDir,
+                COUNT(*) AS NumberOfInstances, SUM(i.size) AS size"
+        }
+        QueryLevel::Instances => {
+            "SELECT i.path, i.SOPInstanceUID, i.SeriesInstanceUID, i.size, i.TransferSyntaxUID"
+        }
+    }
+}
+
+fn group_by(level: QueryLevel) -> &'static str {
+    match level {
+        QueryLevel::Patients => "GROUP BY p.PatientID ORDER BY p.PatientID",
+        QueryLevel::Studies => {
+            "GROUP BY st.StudyInstanceUID ORDER BY st.PatientID, st.StudyDate, st.StudyInstanceUID"
+        }
+        QueryLevel::Series => {
+            "GROUP BY se.SeriesInstanceUID ORDER BY se.StudyInstanceUID, se.SeriesNumber"
+        }
+        QueryLevel::Instances => "ORDER BY i.path",
+    }
+}
+
+/// List the patients, studies, series or instances in the index at `p` which match `filter`.
+///
+/// Each row is a JSON object, which has the key tags of its level and, except for
+/// instances, the number of instances and their total size in bytes.
+pub fn query(
+    p: &Utf8Path,
+    level: QueryLevel,
+    filter: &QueryFilter,
+) -> Result<Vec<serde_json::Map<String, serde_json::Value>>, IndexError> {
+    let conn = Connection::open_with_flags(p, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    let conditions = [
+        ("p.PatientID", &filter.PatientID),
+        ("st.StudyInstanceUID", &filter.StudyInstanceUID),
+        ("se.SeriesInstanceUID", &filter.SeriesInstanceUID),
+        ("se.Modality", &filter.Modality),
+    ];
+    let (columns, values): (Vec<_>, Vec<_>) = conditions
+        .into_iter()
+        .filter_map(|(column, value)| value.as_ref().map(|v| (column, v)))
+        .unzip();
+    let mut sql = format!(
+        "{} FROM instances i
+        JOIN series se ON se.SeriesInstanceUID = i.SeriesInstanceUID
+        JOIN studies st ON st.StudyInstanceUID = se.StudyInstanceUID
+        JOIN patients p ON p.PatientID = st.PatientID",
+        select(level)
+    );
+    for (n, column) in columns.iter().enumerate() {
+        let keyword = if n == 0 { "WHERE" } else { "AND" };
+        sql.push_str(&format!(" {keyword} {column} = ?{}", n + 1));
+    }
+    sql.push(' ');
+    sql.push_str(group_by(level));
+
+    let mut stmt = conn.prepare(&sql)?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let rows = stmt.query_map(rusqlite::params_from_iter(values), |row| {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Ok((name.clone(), to_json(row.get_ref(i)?))))
+            .collect()
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn to_json(value: ValueRef<'_>) -> serde_json::Value {
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => serde_json::Value::Null,
+        ValueRef::Integer(n) => n.into(),
+        ValueRef::Real(x) => x.into(),
+        ValueRef::Text(s) => String::from_utf8_lossy(s).into(),
+    }
+}
@@ -1,12 +1,57 @@
+mod atomic_write;
+mod batch;
+mod bids;
+mod check;
+mod conflict;
+mod csa;
+mod deidentify;
 mod dicom_data;
+mod dicom_json;
+mod dry_run;
 mod errors;
 mod helpers;
+mod hook;
+mod index;
+mod listen;
 mod log_models;
 mod log_write;
+mod migrate;
 mod ndjson_log;
+mod nifti;
 mod pack_path;
+mod path_template;
+mod private_dict;
+mod quarantine;
+mod reindex;
 mod repack;
 mod serialize_seriesmeta;
+mod series_progress;
+mod tolerant_read;
+mod transcode;
 
-pub use ndjson_log::json_message;
-pub use repack::repack;
+pub use batch::{batch, BatchSummary};
+pub use bids::{BidsHeuristicsError, BidsLayout, DEFAULT_HEURISTICS};
+pub use check::{check, CheckReport, Problem, Severity};
+pub use conflict::{DestinationExists, OnConflict, Placement};
+pub use deidentify::{DeidProfile, Deidentifier};
+pub use dicom_data::DicomTagError;
+pub use dicom_json::DicomJsonMode;
+pub use dry_run::{FileAction, PlannedFile};
+pub use errors::{ErrorKind, RepackError};
+pub use hook::run_series_command;
+pub use index::{query, IndexError, QueryFilter, QueryLevel};
+pub use listen::{bind, listen};
+pub use migrate::{migrate, undo_migrate, MigrateSummary, Move};
+pub use ndjson_log::{json_message, migrate_message, reindex_message, series_complete_message};
+pub use nifti::{series_to_nifti, NiftiError, NiftiFiles};
+pub use path_template::{PathTemplate, TemplateError, DEFAULT_TEMPLATE};
+pub use private_dict::{PrivateDictionary, PrivateDictionaryError};
+pub use quarantine::Quarantined;
+pub use reindex::{reindex, ReindexOutcome, ReindexSummary};
+pub use repack::{repack, repack_object, RepackOptions, RepackOutcome};
+pub use serialize_seriesmeta::SequenceFormat;
+pub use series_progress::{
+    complete_idle_series, CompletionReason, SeriesComplete, SeriesCompleteCallback,
+};
+pub use tolerant_read::Repair;
+pub use transcode::{TranscodeError, TranscodeTarget};
@@ -0,0 +1,518 @@
+//! A DICOM C-STORE service class provider (SCP), so that incoming DICOM
+//! instances can be repacked without `storescp`.
+use crate::errors::RepackError;
+use crate::repack::{repack_object, RepackOptions, RepackOutcome};
+use anyhow::Context;
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::{DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject};
+use dicom::transfer_syntax::{entries, TransferSyntaxRegistry};
+use dicom::ul::association::server::ServerAssociation;
+use dicom::ul::pdu::{PDataValue, PDataValueType, Pdu};
+use dicom::ul::ServerAssociationOptions;
+use std::net::TcpListener;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Condvar, Mutex};
+
+/// Every storage SOP class of the standard, including retired ones, accepted by the SCP in
+/// addition to Verification.
+#[allow(deprecated)]
+const STORAGE_SOP_CLASSES: &[&str] = &[
+    uids::STORED_PRINT_STORAGE,
+    uids::HARDCOPY_GRAYSCALE_IMAGE_STORAGE,
+    uids::HARDCOPY_COLOR_IMAGE_STORAGE,
+    uids::COMPUTED_RADIOGRAPHY_IMAGE_STORAGE,
+    uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_MAMMOGRAPHY_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::DIGITAL_INTRA_ORAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DIGITAL_INTRA_ORAL_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::STANDALONE_MODALITY_LUT_STORAGE,
+    uids::ENCAPSULATED_PDF_STORAGE,
+    uids::ENCAPSULATED_CDA_STORAGE,
+    uids::ENCAPSULATED_STL_STORAGE,
+    uids::ENCAPSULATED_OBJ_STORAGE,
+    uids::ENCAPSULATED_MTL_STORAGE,
+    uids::STANDALONE_VOILUT_STORAGE,
+    uids::GRAYSCALE_SOFTCOPY_PRESENTATION_STATE_STORAGE,
+    uids::SEGMENTED_VOLUME_RENDERING_VOLUMETRIC_PRESENTATION_STATE_STORAGE,
+    uids::MULTIPLE_VOLUME_RENDERING_VOLUMETRIC_PRESENTATION_STATE_STORAGE,
+    uids::VARIABLE_MODALITY_LUT_PRESENTATION_STATE_STORAGE,
+    uids::COLOR_SOFTCOPY_PRESENTATION_STATE_STORAGE,
+    uids::PSEUDO_COLOR_SOFTCOPY_PRESENTATION_STATE_STORAGE,
+    uids::BLENDING_SOFTCOPY_PRESENTATION_STATE_STORAGE,
+    uids::XAXRF_GRAYSCALE_SOFTCOPY_PRESENTATION_STATE_STORAGE,
+    uids::GRAYSCALE_PLANAR_MPR_VOLUMETRIC_PRESENTATION_STATE_STORAGE,
+    uids::COMPOSITING_PLANAR_MPR_VOLUMETRIC_PRESENTATION_STATE_STORAGE,
+    uids::ADVANCED_BLENDING_PRESENTATION_STATE_STORAGE,
+    uids::VOLUME_RENDERING_VOLUMETRIC_PRESENTATION_STATE_STORAGE,
+    uids::X_RAY_ANGIOGRAPHIC_IMAGE_STORAGE,
+    uids::ENHANCED_XA_IMAGE_STORAGE,
+    uids::X_RAY_RADIOFLUOROSCOPIC_IMAGE_STORAGE,
+    uids::ENHANCED_XRF_IMAGE_STORAGE,
+    uids::X_RAY_ANGIOGRAPHIC_BI_PLANE_IMAGE_STORAGE,
+    uids::POSITRON_EMISSION_TOMOGRAPHY_IMAGE_STORAGE,
+    uids::LEGACY_CONVERTED_ENHANCED_PET_IMAGE_STORAGE,
+    uids::STANDALONE_PET_CURVE_STORAGE,
+    uids::X_RAY3_D_ANGIOGRAPHIC_IMAGE_STORAGE,
+    uids::X_RAY3_D_CRANIOFACIAL_IMAGE_STORAGE,
+    uids::BREAST_TOMOSYNTHESIS_IMAGE_STORAGE,
+    uids::BREAST_PROJECTION_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::BREAST_PROJECTION_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::ENHANCED_PET_IMAGE_STORAGE,
+    uids::BASIC_STRUCTURED_DISPLAY_STORAGE,
+    uids::INTRAVASCULAR_OPTICAL_COHERENCE_TOMOGRAPHY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::INTRAVASCULAR_OPTICAL_COHERENCE_TOMOGRAPHY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::CT_IMAGE_STORAGE,
+    uids::ENHANCED_CT_IMAGE_STORAGE,
+    uids::LEGACY_CONVERTED_ENHANCED_CT_IMAGE_STORAGE,
+    uids::NUCLEAR_MEDICINE_IMAGE_STORAGE,
+    uids::CT_DEFINED_PROCEDURE_PROTOCOL_STORAGE,
+    uids::CT_PERFORMED_PROCEDURE_PROTOCOL_STORAGE,
+    uids::PROTOCOL_APPROVAL_STORAGE,
+    uids::XA_DEFINED_PROCEDURE_PROTOCOL_STORAGE,
+    uids::XA_PERFORMED_PROCEDURE_PROTOCOL_STORAGE,
+    uids::INVENTORY_STORAGE,
+    uids::ULTRASOUND_MULTI_FRAME_IMAGE_STORAGE_RETIRED,
+    uids::ULTRASOUND_MULTI_FRAME_IMAGE_STORAGE,
+    uids::PARAMETRIC_MAP_STORAGE,
+    uids::MR_IMAGE_STORAGE,
+    uids::ENHANCED_MR_IMAGE_STORAGE,
+    uids::MR_SPECTROSCOPY_STORAGE,
+    uids::ENHANCED_MR_COLOR_IMAGE_STORAGE,
+    uids::LEGACY_CONVERTED_ENHANCED_MR_IMAGE_STORAGE,
+    uids::RT_IMAGE_STORAGE,
+    uids::RT_PHYSICIAN_INTENT_STORAGE,
+    uids::RT_SEGMENT_ANNOTATION_STORAGE,
+    uids::RT_RADIATION_SET_STORAGE,
+    uids::C_ARM_PHOTON_ELECTRON_RADIATION_STORAGE,
+    uids::TOMOTHERAPEUTIC_RADIATION_STORAGE,
+    uids::ROBOTIC_ARM_RADIATION_STORAGE,
+    uids::RT_RADIATION_RECORD_SET_STORAGE,
+    uids::RT_RADIATION_SALVAGE_RECORD_STORAGE,
+    uids::TOMOTHERAPEUTIC_RADIATION_RECORD_STORAGE,
+    uids::C_ARM_PHOTON_ELECTRON_RADIATION_RECORD_STORAGE,
+    uids::RT_DOSE_STORAGE,
+    uids::ROBOTIC_RADIATION_RECORD_STORAGE,
+    uids::RT_RADIATION_SET_DELIVERY_INSTRUCTION_STORAGE,
+    uids::RT_TREATMENT_PREPARATION_STORAGE,
+    uids::ENHANCED_RT_IMAGE_STORAGE,
+    uids::ENHANCED_CONTINUOUS_RT_IMAGE_STORAGE,
+    uids::RT_PATIENT_POSITION_ACQUISITION_INSTRUCTION_STORAGE,
+    uids::RT_STRUCTURE_SET_STORAGE,
+    uids::RT_BEAMS_TREATMENT_RECORD_STORAGE,
+    uids::RT_PLAN_STORAGE,
+    uids::RT_BRACHY_TREATMENT_RECORD_STORAGE,
+    uids::RT_TREATMENT_SUMMARY_RECORD_STORAGE,
+    uids::RT_ION_PLAN_STORAGE,
+    uids::RT_ION_BEAMS_TREATMENT_RECORD_STORAGE,
+    uids::NUCLEAR_MEDICINE_IMAGE_STORAGE_RETIRED,
+    uids::DICOSCT_IMAGE_STORAGE,
+    uids::DICOS_DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PRESENTATION,
+    uids::DICOS_DIGITAL_X_RAY_IMAGE_STORAGE_FOR_PROCESSING,
+    uids::DICOS_THREAT_DETECTION_REPORT_STORAGE,
+    uids::DICOS2DAIT_STORAGE,
+    uids::DICOS3DAIT_STORAGE,
+    uids::DICOS_QUADRUPOLE_RESONANCE_STORAGE,
+    uids::ULTRASOUND_IMAGE_STORAGE_RETIRED,
+    uids::ULTRASOUND_IMAGE_STORAGE,
+    uids::ENHANCED_US_VOLUME_STORAGE,
+    uids::EDDY_CURRENT_IMAGE_STORAGE,
+    uids::EDDY_CURRENT_MULTI_FRAME_IMAGE_STORAGE,
+    uids::RAW_DATA_STORAGE,
+    uids::SPATIAL_REGISTRATION_STORAGE,
+    uids::SPATIAL_FIDUCIALS_STORAGE,
+    uids::DEFORMABLE_SPATIAL_REGISTRATION_STORAGE,
+    uids::SEGMENTATION_STORAGE,
+    uids::SURFACE_SEGMENTATION_STORAGE,
+    uids::TRACTOGRAPHY_RESULTS_STORAGE,
+    uids::REAL_WORLD_VALUE_MAPPING_STORAGE,
+    uids::SURFACE_SCAN_MESH_STORAGE,
+    uids::SURFACE_SCAN_POINT_CLOUD_STORAGE,
+    uids::SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_SINGLE_BIT_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_GRAYSCALE_BYTE_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_GRAYSCALE_WORD_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::MULTI_FRAME_TRUE_COLOR_SECONDARY_CAPTURE_IMAGE_STORAGE,
+    uids::VL_IMAGE_STORAGE_TRIAL,
+    uids::VL_ENDOSCOPIC_IMAGE_STORAGE,
+    uids::VIDEO_ENDOSCOPIC_IMAGE_STORAGE,
+    uids::VL_MICROSCOPIC_IMAGE_STORAGE,
+    uids::VIDEO_MICROSCOPIC_IMAGE_STORAGE,
+    uids::VL_SLIDE_COORDINATES_MICROSCOPIC_IMAGE_STORAGE,
+    uids::VL_PHOTOGRAPHIC_IMAGE_STORAGE,
+    uids::VIDEO_PHOTOGRAPHIC_IMAGE_STORAGE,
+    uids::OPHTHALMIC_PHOTOGRAPHY8_BIT_IMAGE_STORAGE,
+    uids::OPHTHALMIC_PHOTOGRAPHY16_BIT_IMAGE_STORAGE,
+    uids::STEREOMETRIC_RELATIONSHIP_STORAGE,
+    uids::OPHTHALMIC_TOMOGRAPHY_IMAGE_STORAGE,
+    uids::WIDE_FIELD_OPHTHALMIC_PHOTOGRAPHY_STEREOGRAPHIC_PROJECTION_IMAGE_STORAGE,
+    uids::WIDE_FIELD_OPHTHALMIC_PHOTOGRAPHY3_D_COORDINATES_IMAGE_STORAGE,
+    uids::OPHTHALMIC_OPTICAL_COHERENCE_TOMOGRAPHY_EN_FACE_IMAGE_STORAGE,
+    uids::OPHTHALMIC_OPTICAL_COHERENCE_TOMOGRAPHY_BSCAN_VOLUME_ANALYSIS_STORAGE,
+    uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE,
+    uids::DERMOSCOPIC_PHOTOGRAPHY_IMAGE_STORAGE,
+    uids::VL_MULTI_FRAME_IMAGE_STORAGE_TRIAL,
+    uids::LENSOMETRY_MEASUREMENTS_STORAGE,
+    uids::AUTOREFRACTION_MEASUREMENTS_STORAGE,
+    uids::KERATOMETRY_MEASUREMENTS_STORAGE,
+    uids::SUBJECTIVE_REFRACTION_MEASUREMENTS_STORAGE,
+    uids::VISUAL_ACUITY_MEASUREMENTS_STORAGE,
+    uids::SPECTACLE_PRESCRIPTION_REPORT_STORAGE,
+    uids::OPHTHALMIC_AXIAL_MEASUREMENTS_STORAGE,
+    uids::INTRAOCULAR_LENS_CALCULATIONS_STORAGE,
+    uids::MACULAR_GRID_THICKNESS_AND_VOLUME_REPORT_STORAGE,
+    uids::STANDALONE_OVERLAY_STORAGE,
+    uids::OPHTHALMIC_VISUAL_FIELD_STATIC_PERIMETRY_MEASUREMENTS_STORAGE,
+    uids::OPHTHALMIC_THICKNESS_MAP_STORAGE,
+    uids::CORNEAL_TOPOGRAPHY_MAP_STORAGE,
+    uids::TEXT_SR_STORAGE_TRIAL,
+    uids::BASIC_TEXT_SR_STORAGE,
+    uids::AUDIO_SR_STORAGE_TRIAL,
+    uids::ENHANCED_SR_STORAGE,
+    uids::DETAIL_SR_STORAGE_TRIAL,
+    uids::COMPREHENSIVE_SR_STORAGE,
+    uids::COMPREHENSIVE3_DSR_STORAGE,
+    uids::EXTENSIBLE_SR_STORAGE,
+    uids::COMPREHENSIVE_SR_STORAGE_TRIAL,
+    uids::PROCEDURE_LOG_STORAGE,
+    uids::MAMMOGRAPHY_CADSR_STORAGE,
+    uids::KEY_OBJECT_SELECTION_DOCUMENT_STORAGE,
+    uids::CHEST_CADSR_STORAGE,
+    uids::X_RAY_RADIATION_DOSE_SR_STORAGE,
+    uids::RADIOPHARMACEUTICAL_RADIATION_DOSE_SR_STORAGE,
+    uids::COLON_CADSR_STORAGE,
+    uids::IMPLANTATION_PLAN_SR_STORAGE,
+    uids::ACQUISITION_CONTEXT_SR_STORAGE,
+    uids::SIMPLIFIED_ADULT_ECHO_SR_STORAGE,
+    uids::PATIENT_RADIATION_DOSE_SR_STORAGE,
+    uids::PLANNED_IMAGING_AGENT_ADMINISTRATION_SR_STORAGE,
+    uids::PERFORMED_IMAGING_AGENT_ADMINISTRATION_SR_STORAGE,
+    uids::ENHANCED_X_RAY_RADIATION_DOSE_SR_STORAGE,
+    uids::STANDALONE_CURVE_STORAGE,
+    uids::WAVEFORM_STORAGE_TRIAL,
+    uids::TWELVE_LEAD_ECG_WAVEFORM_STORAGE,
+    uids::GENERAL_ECG_WAVEFORM_STORAGE,
+    uids::AMBULATORY_ECG_WAVEFORM_STORAGE,
+    uids::HEMODYNAMIC_WAVEFORM_STORAGE,
+    uids::CARDIAC_ELECTROPHYSIOLOGY_WAVEFORM_STORAGE,
+    uids::BASIC_VOICE_AUDIO_WAVEFORM_STORAGE,
+    uids::GENERAL_AUDIO_WAVEFORM_STORAGE,
+    uids::ARTERIAL_PULSE_WAVEFORM_STORAGE,
+    uids::RESPIRATORY_WAVEFORM_STORAGE,
+    uids::MULTICHANNEL_RESPIRATORY_WAVEFORM_STORAGE,
+    uids::ROUTINE_SCALP_ELECTROENCEPHALOGRAM_WAVEFORM_STORAGE,
+    uids::ELECTROMYOGRAM_WAVEFORM_STORAGE,
+    uids::ELECTROOCULOGRAM_WAVEFORM_STORAGE,
+    uids::SLEEP_ELECTROENCEPHALOGRAM_WAVEFORM_STORAGE,
+    uids::BODY_POSITION_WAVEFORM_STORAGE,
+    uids::CONTENT_ASSESSMENT_RESULTS_STORAGE,
+    uids::MICROSCOPY_BULK_SIMPLE_ANNOTATIONS_STORAGE,
+    uids::RT_BEAMS_DELIVERY_INSTRUCTION_STORAGE_TRIAL,
+    uids::RT_BRACHY_APPLICATION_SETUP_DELIVERY_INSTRUCTION_STORAGE,
+    uids::RT_BEAMS_DELIVERY_INSTRUCTION_STORAGE,
+    uids::HANGING_PROTOCOL_STORAGE,
+    uids::COLOR_PALETTE_STORAGE,
+    uids::GENERIC_IMPLANT_TEMPLATE_STORAGE,
+    uids::IMPLANT_ASSEMBLY_TEMPLATE_STORAGE,
+    uids::IMPLANT_TEMPLATE_GROUP_STORAGE,
+];
+
+const C_STORE_RQ: u16 = 0x0001;
+const C_STORE_RSP: u16 = 0x8001;
+const C_ECHO_RQ: u16 = 0x0030;
+const C_ECHO_RSP: u16 = 0x8030;
+/// Bit which is set in the Command Field of every response.
+const RSP: u16 = 0x8000;
+/// Command Data Set Type value meaning "no data set is present".
+const NO_DATA_SET: u16 = 0x0101;
+
+const STATUS_SUCCESS: u16 = 0x0000;
+/// "Error: Cannot understand", used when the instance could not be repacked.
+const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+/// "Refused: Unrecognized operation", used for any DIMSE command other than C-ECHO and C-STORE.
+const STATUS_UNRECOGNIZED_OPERATION: u16 = 0x0211;
+
+/// Accept associations forever, repacking every DICOM instance received by C-STORE.
+///
+/// Each association is handled by its own thread, of which there are at most
+/// `max_associations` at a time: further connections wait until an association ends.
+/// Any DIMSE command other than C-ECHO and C-STORE is refused with the status
+/// "unrecognized operation". Received instances are repacked in memory by
+/// [repack_object]. A connection which cannot be accepted is skipped, and an instance
+/// whose repacking panicked is answered with a failure status. The outcome of every
+/// instance is passed to `on_outcome`, along with a pseudo-path
+/// `<calling AE title>/<SOP Instance UID>` describing its source.
+pub fn listen<F>(
+    listener: TcpListener,
+    ae_title: &str,
+    max_associations: usize,
+    options: &RepackOptions,
+    on_outcome: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&Utf8Path, &Result<RepackOutcome, RepackError>) + Sync,
+{
+    let mut association_options = ServerAssociationOptions::new()
+        .accept_any()
+        .ae_title(ae_title)
+        .with_abstract_syntax(uids::VERIFICATION);
+    for uid in STORAGE_SOP_CLASSES {
+        association_options = association_options.with_abstract_syntax(*uid);
+    }
+    for ts in TransferSyntaxRegistry
+        .iter()
+        .filter(|ts| !ts.is_unsupported())
+    {
+        association_options = association_options.with_transfer_syntax(ts.uid());
+    }
+    let scp = Scp {
+        options,
+        on_outcome: &on_outcome,
+    };
+    let active = (Mutex::new(0_usize), Condvar::new());
+    std::thread::scope(|s| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {e}");
+                    continue;
+                }
+            };
+            let (count, ended) = &active;
+            let mut count_guard = ended
+                .wait_while(count.lock().unwrap(), |n| *n >= max_associations.max(1))
+                .unwrap();
+            *count_guard += 1;
+            drop(count_guard);
+            let association_options = &association_options;
+            let scp = &scp;
+            s.spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = association_options
+                    .establish(stream)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|association| scp.handle(association))
+                {
+                    eprintln!("Association with {peer:?} failed: {e:?}");
+                }
+                *count.lock().unwrap() -= 1;
+                ended.notify_one();
+            });
+        }
+        anyhow::Ok(())
+    })
+}
+
+/// Everything needed to handle an association.
+struct Scp<'a, F> {
+    options: &'a RepackOptions,
+    on_outcome: &'a F,
+}
+
+/// A DIMSE command which was received, and is possibly waiting for its data set.
+struct Command {
+    field: u16,
+    message_id: u16,
+    sop_class_uid: String,
+    sop_instance_uid: String,
+    has_data_set: bool,
+}
+
+impl<'a, F> Scp<'a, F>
+where
+    F: Fn(&Utf8Path, &Result<RepackOutcome, RepackError>) + Sync,
+{
+    fn handle(&self, mut association: ServerAssociation) -> anyhow::Result<()> {
+        let mut command_buffer = Vec::new();
+        let mut data_buffer = Vec::new();
+        let mut command: Option<Command> = None;
+        loop {
+            let pdu = match association.receive() {
+                Ok(pdu) => pdu,
+                // the peer closed the connection without releasing the association
+                Err(dicom::ul::association::server::Error::Receive { .. }) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            match pdu {
+                Pdu::PData { data } => {
+                    for pdv in data {
+                        let pc_id = pdv.presentation_context_id;
+                        match pdv.value_type {
+                            PDataValueType::Command => {
+                                command_buffer.extend_from_slice(&pdv.data);
+                                if !pdv.is_last {
+                                    continue;
+                                }
+                                let cmd = read_command(&command_buffer)?;
+                                command_buffer.clear();
+                                if cmd.field == C_ECHO_RQ {
+                                    let rsp = response(&cmd, C_ECHO_RSP, STATUS_SUCCESS);
+                                    send_command(&mut association, pc_id, rsp)?;
+                                } else if cmd.has_data_set {
+                                    data_buffer.clear();
+                                    command = Some(cmd);
+                                } else {
+                                    let rsp = response(
+                                        &cmd,
+                                        cmd.field | RSP,
+                                        STATUS_UNRECOGNIZED_OPERATION,
+                                    );
+                                    send_command(&mut association, pc_id, rsp)?;
+                                }
+                            }
+                            PDataValueType::Data => {
+                                data_buffer.extend_from_slice(&pdv.data);
+                                if !pdv.is_last {
+                                    continue;
+                                }
+                                let cmd = command
+                                    .take()
+                                    .context("Received data set without a command")?;
+                                let rsp = if cmd.field == C_STORE_RQ {
+                                    let status =
+                                        self.store(&association, pc_id, &cmd, &data_buffer);
+                                    response(&cmd, C_STORE_RSP, status)
+                                } else {
+                                    response(&cmd, cmd.field | RSP, STATUS_UNRECOGNIZED_OPERATION)
+                                };
+                                data_buffer.clear();
+                                send_command(&mut association, pc_id, rsp)?;
+                            }
+                        }
+                    }
+                }
+                Pdu::ReleaseRQ => {
+                    association.send(&Pdu::ReleaseRP)?;
+                    return Ok(());
+                }
+                Pdu::AbortRQ { .. } => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Repack a received data set, returning the status for the C-STORE response.
+    fn store(&self, association: &ServerAssociation, pc_id: u8, cmd: &Command, data: &[u8]) -> u16 {
+        let src =
+            Utf8PathBuf::from(association.client_ae_title().trim()).join(&cmd.sop_instance_uid);
+        let outcome = read_received(association, pc_id, cmd, data)
+            .map_err(|e| RepackError::InvalidDicom(format!("{e:#}")))
+            .and_then(|dcm| {
+                std::panic::catch_unwind(AssertUnwindSafe(|| repack_object(dcm, self.options)))
+                    .unwrap_or(Err(RepackError::Panic))
+            });
+        (self.on_outcome)(&src, &outcome);
+        if outcome.is_ok() {
+            STATUS_SUCCESS
+        } else {
+            STATUS_CANNOT_UNDERSTAND
+        }
+    }
+}
+
+/// Decode a received data set, adding file meta information.
+fn read_received(
+    association: &ServerAssociation,
+    pc_id: u8,
+    cmd: &Command,
+    data: &[u8],
+) -> anyhow::Result<DefaultDicomObject> {
+    let ts_uid = association
+        .presentation_contexts()
+        .iter()
+        .find(|pc| pc.id == pc_id)
+        .map(|pc| pc.transfer_syntax.trim_end_matches('\0'))
+        .with_context(|| format!("Unknown presentation context: {pc_id}"))?;
+    let ts = TransferSyntaxRegistry
+        .get(ts_uid)
+        .with_context(|| format!("Unsupported transfer syntax: {ts_uid}"))?;
+    let obj = InMemDicomObject::read_dataset_with_ts(data, ts)?;
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(cmd.sop_class_uid.as_str())
+        .media_storage_sop_instance_uid(cmd.sop_instance_uid.as_str())
+        .transfer_syntax(ts_uid)
+        .source_application_entity_title(association.client_ae_title())
+        .build()?;
+    Ok(obj.with_exact_meta(meta))
+}
+
+/// Parse a DIMSE command set, which is always encoded in implicit VR little endian.
+fn read_command(data: &[u8]) -> anyhow::Result<Command> {
+    let ts = entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+    let obj = InMemDicomObject::read_dataset_with_ts(data, &ts)?;
+    let get_str = |tag| -> String {
+        obj.element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|s| s.trim_end_matches(['\0', ' ']).to_string())
+            .unwrap_or_default()
+    };
+    Ok(Command {
+        field: obj.element(tags::COMMAND_FIELD)?.uint16()?,
+        message_id: obj.element(tags::MESSAGE_ID)?.uint16()?,
+        sop_class_uid: get_str(tags::AFFECTED_SOP_CLASS_UID),
+        sop_instance_uid: get_str(tags::AFFECTED_SOP_INSTANCE_UID),
+        has_data_set: obj.element(tags::COMMAND_DATA_SET_TYPE)?.uint16()? != NO_DATA_SET,
+    })
+}
+
+/// Create a response command set for a request.
+fn response(cmd: &Command, field: u16, status: u16) -> InMemDicomObject {
+    let mut elements = vec![
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            PrimitiveValue::from(cmd.sop_class_uid.as_str()),
+        ),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, PrimitiveValue::from(field)),
+        DataElement::new(
+            tags::MESSAGE_ID_BEING_RESPONDED_TO,
+            VR::US,
+            PrimitiveValue::from(cmd.message_id),
+        ),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            PrimitiveValue::from(NO_DATA_SET),
+        ),
+        DataElement::new(tags::STATUS, VR::US, PrimitiveValue::from(status)),
+    ];
+    if !cmd.sop_instance_uid.is_empty() {
+        elements.push(DataElement::new(
+            tags::AFFECTED_SOP_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from(cmd.sop_instance_uid.as_str()),
+        ));
+    }
+    InMemDicomObject::command_from_element_iter(elements)
+}
+
+fn send_command(
+    association: &mut ServerAssociation,
+    pc_id: u8,
+    obj: InMemDicomObject,
+) -> anyhow::Result<()> {
+    let ts = entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+    let mut data = Vec::new();
+    obj.write_dataset_with_ts(&mut data, &ts)?;
+    let pdu = Pdu::PData {
+        data: vec![PDataValue {
+            presentation_context_id: pc_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data,
+        }],
+    };
+    association.send(&pdu)?;
+    Ok(())
+}
+
+/// Bind to a TCP port on all interfaces.
+pub fn bind(port: u16) -> anyhow::Result<TcpListener> {
+    TcpListener::bind(("0.0.0.0", port)).with_context(|| format!("Cannot listen on port {port}"))
+}
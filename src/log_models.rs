@@ -1,6 +1,8 @@
 //! Models of what gets written to `/home/dicom/log`.
 #![allow(non_snake_case)]
+use crate::csa::CsaHeader;
 use crate::dicom_data::{CommonElements, MaybeU32, TagExtractor, NOT_DEFINED};
+use crate::transcode::TransferSyntaxChange;
 use dicom::dictionary_std::tags;
 use hashbrown::{HashMap, HashSet};
 use serde::{Deserialize, Serialize};
@@ -34,11 +36,11 @@ impl<'a> PatientData<'a> {
 
 #[derive(Debug, Serialize)]
 pub(crate) struct StudyDataMeta<'a> {
-    PatientID: &'a str,
-    StudyDescription: &'a str,
-    StudyDate: &'a str,
-    StudyInstanceUID: &'a str,
-    PerformedStationAETitle: Cow<'a, str>,
+    pub PatientID: &'a str,
+    pub StudyDescription: &'a str,
+    pub StudyDate: &'a str,
+    pub StudyInstanceUID: &'a str,
+    pub PerformedStationAETitle: Cow<'a, str>,
 }
 
 impl<'a> StudyDataMeta<'a> {
@@ -55,13 +57,13 @@ impl<'a> StudyDataMeta<'a> {
 
 #[derive(Debug, Serialize)]
 pub(crate) struct SeriesDataMeta<'a> {
-    PatientID: &'a str,
-    StudyInstanceUID: &'a str,
-    SeriesInstanceUID: &'a str,
-    SeriesDescription: &'a str,
-    SeriesNumber: MaybeU32<'a>,
-    SeriesDate: &'a str,
-    Modality: Cow<'a, str>,
+    pub PatientID: &'a str,
+    pub StudyInstanceUID: &'a str,
+    pub SeriesInstanceUID: &'a str,
+    pub SeriesDescription: &'a str,
+    pub SeriesNumber: MaybeU32<'a>,
+    pub SeriesDate: &'a str,
+    pub Modality: Cow<'a, str>,
 }
 
 impl<'a> SeriesDataMeta<'a> {
@@ -80,17 +82,23 @@ impl<'a> SeriesDataMeta<'a> {
 
 #[derive(Debug, Serialize)]
 pub(crate) struct InstanceData<'a> {
-    PatientID: &'a str,
-    StudyInstanceUID: &'a str,
-    SeriesInstanceUID: &'a str,
-    SeriesDescription: Cow<'a, str>,
-    SeriesNumber: MaybeU32<'a>,
-    SeriesDate: Cow<'a, str>,
-    Modality: Cow<'a, str>,
-    outputFile: &'a str,
+    pub PatientID: &'a str,
+    pub StudyInstanceUID: &'a str,
+    pub SeriesInstanceUID: &'a str,
+    pub SeriesDescription: Cow<'a, str>,
+    pub SeriesNumber: MaybeU32<'a>,
+    pub SeriesDate: Cow<'a, str>,
+    pub Modality: Cow<'a, str>,
+    pub outputFile: &'a str,
     // TODO we don't include imageObj because I don't think it's used anywhwere.
     // Trying to get this information is annoying.
     imageObj: HashMap<&'a str, FileStat<'a>>,
+    /// Decoded Siemens CSA Image Header, e.g. B-value and diffusion direction of this instance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub CSAImageHeader: Option<&'a CsaHeader>,
+    /// Original and stored transfer syntax UIDs, if transcoding is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transferSyntax: Option<&'a TransferSyntaxChange>,
 }
 
 impl<'a> InstanceData<'a> {
@@ -99,6 +107,8 @@ impl<'a> InstanceData<'a> {
         e: &'a CommonElements,
         outputFile: &'a str,
         FSlocation: &'a str,
+        CSAImageHeader: Option<&'a CsaHeader>,
+        transferSyntax: Option<&'a TransferSyntaxChange>,
     ) -> Self {
         let imageObj = [(outputFile, FileStat { FSlocation })]
             .into_iter()
@@ -113,6 +123,8 @@ impl<'a> InstanceData<'a> {
             Modality: d.get(tags::MODALITY),
             outputFile,
             imageObj,
+            CSAImageHeader,
+            transferSyntax,
         }
     }
 }
@@ -1,27 +1,78 @@
+use crate::atomic_write::{hidden_sibling, remove_stale_partials, write_atomically};
+use crate::csa::CsaHeaders;
 use crate::log_models::*;
 use crate::pack_path::PypxPath;
 use camino::Utf8Path;
 
 use crate::dicom_data::{CommonElements, DicomTagAndError, TagExtractor};
+use crate::dicom_json::{to_dicom_json, DicomJsonMode};
+use crate::dry_run::Output;
+use crate::errors::RepackError;
+use crate::index::IndexRecord;
+use crate::repack::RepackOptions;
 use crate::serialize_seriesmeta::StudyDataSeriesMeta;
+use crate::series_progress::{expected_instances, record_instance, Received, SeriesComplete};
+use crate::transcode::TransferSyntaxChange;
 use dicom::object::DefaultDicomObject;
+use fs4::FileExt;
 use hashbrown::HashMap;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::io;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Write};
 use std::path::PathBuf;
 
 /// Write *pypx* "stuff" to `/home/dicom/log/{patientData,seriesData,studyData}`.
 /// The "stuff" is read by downstream _pypx_ programs such as `px-register`, `px-status`.
+///
+/// Also records the instance in the [RepackOptions::index], if it is set.
+///
+/// Returns the elements which could not be read, and the series if this instance completed it.
+/// Nothing is written if neither [RepackOptions::log_dir] nor [RepackOptions::index] is set.
 #[allow(non_snake_case)]
 pub(crate) fn write_logs(
     dcm: &DefaultDicomObject,
     common: &CommonElements,
     unpack: &PypxPath,
-    log_dir: &Utf8Path,
-) -> anyhow::Result<Vec<DicomTagAndError>> {
+    received: Received,
+    transfer_syntax: Option<&TransferSyntaxChange>,
+    options: &RepackOptions,
+    out: &Output,
+) -> Result<(Vec<DicomTagAndError>, Option<SeriesComplete>), RepackError> {
+    if options.log_dir.is_none() && options.index.is_none() {
+        return Ok((Vec::new(), None));
+    }
     let dcmtags = TagExtractor::new(dcm);
+    let csa = CsaHeaders::from_dicom(dcm);
+    let img_data = InstanceData::new(
+        &dcmtags,
+        common,
+        &unpack.fname,
+        unpack.path.as_str(),
+        csa.as_ref().and_then(|h| h.Image.as_ref()),
+        transfer_syntax,
+    );
+
+    if let Some(index) = &options.index {
+        let record = IndexRecord {
+            patient: &PatientData::new(&dcmtags, common),
+            study: &StudyDataMeta::new(&dcmtags, common),
+            series: &SeriesDataMeta::new(&dcmtags, common),
+            instance: &img_data,
+            SOPInstanceUID: common.SOPInstanceUID,
+            SeriesBaseDir: unpack.dir.as_str(),
+            path: unpack.path.as_str(),
+            size: fs_err::metadata(&unpack.path).ok().map(|m| m.len()),
+        };
+        if let Some(parent) = index.parent() {
+            out.create_dir_all(parent)?;
+        }
+        out.record_in_index(index, &record)?;
+    }
+
+    let Some(log_dir) = &options.log_dir else {
+        return Ok((dcmtags.errors.take(), None));
+    };
     let patient_data_dir = log_dir.join("patientData");
     let series_data_dir = log_dir.join("seriesData");
     let study_data_dir = log_dir.join("studyData");
@@ -30,25 +81,42 @@ pub(crate) fn write_logs(
     let patient_data_fname = patient_data_dir
         .join(common.PatientID)
         .with_extension("json");
-    let mut patient_data: HashMap<String, PatientData> =
-        load_json_carelessly(&patient_data_fname).unwrap_or_else(|| HashMap::with_capacity(1));
-    patient_data
-        .entry_ref(common.PatientID)
-        .or_insert_with(|| PatientData::new(&dcmtags, common))
-        .StudyList
-        .insert(common.StudyInstanceUID.to_string());
-    write_json(patient_data, patient_data_fname)?;
+    // Many rx-repack processes may be updating the same file concurrently,
+    // e.g. when dispatched by `storescp --fork`
+    out.with_lock(&patient_data_fname, || {
+        let mut patient_data: HashMap<String, PatientData> =
+            out.load_json(&patient_data_fname, log_dir, || {
+                [(
+                    common.PatientID.to_string(),
+                    PatientData::new(&dcmtags, common),
+                )]
+                .into()
+            })?;
+        patient_data
+            .entry_ref(common.PatientID)
+            .or_insert_with(|| PatientData::new(&dcmtags, common))
+            .StudyList
+            .insert(common.StudyInstanceUID.to_string());
+        out.write_json(patient_data, &patient_data_fname)
+    })?;
 
     // write stuff to studyData/X.X.X.XXXXX-series/Y.Y.Y.YYYYY-meta.json
     let study_series_meta_dir = study_data_dir.join(format!("{}-series", &common.StudyInstanceUID));
-    fs_err::create_dir_all(&study_series_meta_dir)?;
+    out.create_dir_all(&study_series_meta_dir)?;
     let study_series_meta_fname =
         study_series_meta_dir.join(format!("{}-meta.json", &common.SeriesInstanceUID));
     if !study_series_meta_fname.is_file() {
-        let study_series_meta =
-            StudyDataSeriesMeta::new(&common.SeriesInstanceUID, unpack.dir.as_str(), dcm);
+        let study_series_meta = StudyDataSeriesMeta::new(
+            &common.SeriesInstanceUID,
+            unpack.dir.as_str(),
+            dcm,
+            options.element_format(),
+            csa.as_ref(),
+        );
         let data: HashMap<_, _> = [(&common.StudyInstanceUID, study_series_meta)].into();
-        write_json(data, study_series_meta_fname)?;
+        out.write_json(data, &study_series_meta_fname)?;
+    } else {
+        out.untouched(&study_series_meta_fname);
     }
 
     // write stuff to studyData/X.X.X.XXXXX-meta.json
@@ -56,7 +124,9 @@ pub(crate) fn write_logs(
     if !study_meta_fname.is_file() {
         let study_meta_data = StudyDataMeta::new(&dcmtags, common);
         let data: HashMap<_, _> = [(&common.StudyInstanceUID, study_meta_data)].into();
-        write_json(data, study_meta_fname)?;
+        out.write_json(data, &study_meta_fname)?;
+    } else {
+        out.untouched(&study_meta_fname);
     }
 
     // write stuff to seriesData/Y.Y.Y.YYYYY-meta.json
@@ -64,24 +134,52 @@ pub(crate) fn write_logs(
         series_data_dir.join(format!("{}-meta.json", &common.SeriesInstanceUID));
     if !series_meta_fname.is_file() {
         let series_meta_data = SeriesDataMeta::new(&dcmtags, common);
-        write_json(series_meta_data, series_meta_fname)?;
+        out.write_json(series_meta_data, &series_meta_fname)?;
+    } else {
+        out.untouched(&series_meta_fname);
     }
 
     // write stuff to seriesData/Y.Y.Y.YYYYY-img/Z.Z.Z.ZZZZZ.dcm.json
     let img_data_dir = series_data_dir.join(format!("{}-img", &common.SeriesInstanceUID));
-    fs_err::create_dir_all(&img_data_dir)?;
+    out.create_dir_all(&img_data_dir)?;
     let img_data_fname = img_data_dir.join(format!("{}.json", unpack.fname));
-    let img_data = InstanceData::new(&dcmtags, common, &unpack.fname, unpack.path.as_str());
     let data: HashMap<_, _> = [(&common.SeriesInstanceUID, img_data)].into();
-    write_json(data, img_data_fname)?;
+    out.write_json(data, &img_data_fname)?;
+
+    // write stuff to seriesData/Y.Y.Y.YYYYY-progress.json and seriesData/Y.Y.Y.YYYYY-pack.json
+    let series_complete = record_instance(
+        &series_data_dir,
+        common,
+        &unpack.dir,
+        expected_instances(dcm),
+        received,
+        options.series_timeout,
+        out,
+    )?;
 
-    // write stuff to seriesData/Y.Y.Y.YYYYY-pack.json
-    let pack_fname = series_data_dir.join(format!("{}-pack.json", &common.SeriesInstanceUID));
-    if !pack_fname.is_file() {
-        write_json(SERIES_PACK, pack_fname)?;
+    // write stuff to dicomJSON/Y.Y.Y.YYYYY/Z.Z.Z.ZZZZZ.dcm.json or dicomJSON/Y.Y.Y.YYYYY.json
+    let dicom_json_dir = log_dir.join("dicomJSON");
+    let dicom_json_fname = match options.dicom_json {
+        Some(DicomJsonMode::Instance) => Some(
+            dicom_json_dir
+                .join(&common.SeriesInstanceUID)
+                .join(format!("{}.json", unpack.fname)),
+        ),
+        Some(DicomJsonMode::Series) => {
+            Some(dicom_json_dir.join(format!("{}.json", &common.SeriesInstanceUID)))
+        }
+        None => None,
+    };
+    if let Some(p) = dicom_json_fname {
+        // in series mode, only the first instance of a series is written
+        if options.dicom_json == Some(DicomJsonMode::Series) && p.is_file() {
+            out.untouched(&p);
+        } else {
+            out.write_json(to_dicom_json(dcm, &unpack.path), &p)?;
+        }
     }
 
-    Ok(dcmtags.errors.into_inner())
+    Ok((dcmtags.errors.into_inner(), series_complete))
 }
 
 /// Read and deserialize a JSON file. In case of any error, return `None`.
@@ -89,7 +187,7 @@ pub(crate) fn write_logs(
 /// Some possible errors:
 /// - File does not exist
 /// - JSON data not well formed or not valid
-fn load_json_carelessly<P: Into<PathBuf>, D: DeserializeOwned>(p: P) -> Option<D> {
+pub(crate) fn load_json_carelessly<P: Into<PathBuf>, D: DeserializeOwned>(p: P) -> Option<D> {
     let file = fs_err::File::open(p).ok()?;
     let reader = BufReader::new(file);
     let data = serde_json::from_reader(reader).ok()?;
@@ -97,12 +195,39 @@ fn load_json_carelessly<P: Into<PathBuf>, D: DeserializeOwned>(p: P) -> Option<D
 }
 
 /// Write data to a JSON file. Will create parent directories as needed.
-fn write_json<S: Serialize, P: AsRef<Utf8Path>>(data: S, p: P) -> io::Result<()> {
-    if let Some(parent) = p.as_ref().parent() {
+///
+/// The data is written by [write_atomically], so that readers never see a partially
+/// written file. Partial files left behind in the parent directory by a crash are removed.
+pub(crate) fn write_json<S: Serialize, P: AsRef<Utf8Path>>(data: S, p: P) -> io::Result<()> {
+    let p = p.as_ref();
+    if let Some(parent) = p.parent().filter(|d| !d.as_str().is_empty()) {
+        fs_err::create_dir_all(parent)?;
+        remove_stale_partials(parent)?;
+    }
+    write_atomically(p, false, |file| {
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &data)?;
+        writer.flush()
+    })
+}
+
+/// Call `f` while holding an exclusive advisory lock (see `flock(2)`) associated with `p`.
+///
+/// The lock is taken on a separate file, since `p` itself is replaced by [write_json].
+pub(crate) fn with_lock<T, E, F>(p: &Utf8Path, f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E>,
+    E: From<io::Error>,
+{
+    if let Some(parent) = p.parent() {
         fs_err::create_dir_all(parent)?;
     }
-    let file = fs_err::File::create(p.as_ref())?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, &data).unwrap();
-    Ok(())
+    let lock_file = fs_err::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(hidden_sibling(p, "lock"))?;
+    lock_file.file().lock_exclusive()?;
+    // the lock is released when lock_file is closed
+    f()
 }
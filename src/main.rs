@@ -1,7 +1,19 @@
 use anyhow::Context;
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
-use rx_repack::{json_message, repack};
+use rx_repack::{
+    batch, bind, check, complete_idle_series, json_message, listen, migrate, migrate_message,
+    query, reindex, reindex_message, repack, run_series_command, series_complete_message,
+    series_to_nifti, undo_migrate, BidsLayout, DeidProfile, Deidentifier, DicomJsonMode, ErrorKind,
+    OnConflict, PathTemplate, PrivateDictionary, QueryFilter, QueryLevel, RepackError,
+    RepackOptions, RepackOutcome, SequenceFormat, SeriesComplete, SeriesCompleteCallback, Severity,
+    TranscodeTarget,
+};
+use std::panic::AssertUnwindSafe;
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 #[derive(clap::Parser)]
 #[clap(
@@ -11,15 +23,66 @@ px-repack is typically dispatched by storescp. Its purpose is to reoganize
 the specified DICOM file to a path under the given data directory, putting
 DICOM tag information into its new path.
 
-The path template is:
+The default path template is:
 
  %PatientID-%PatientName-%PatientBirthDate
  └──%StudyDescription-%AccessionNumber-%StudyDate
-    └──%_pad|5,0_SeriesNumber-%SeriesDescription-%_hash|%SeriesInstanceUID
+    └──%_pad|5,0_SeriesNumber-%SeriesDescription-%_hash|7_SeriesInstanceUID
        └──%_pad|4,0_InstanceNumber-%SOPInstanceUID.dcm
-"#
+
+A different template can be given using --template or --template-file.
+Placeholders are written as %Tag or %_fn|args_Tag, where Tag is any DICOM
+keyword and fn is one of: pad, hash, trunc, upper, lower, nospc, strmsk.
+
+To repack all files under a directory in one process, use the batch subcommand.
+To receive DICOM instances over the network without storescp, use the listen subcommand.
+To find problems in DICOM files without repacking them, use the check subcommand.
+To list what was repacked with --index, use the query subcommand.
+To rebuild the log directory from an existing data directory, use the reindex subcommand.
+To move files into the layout of a changed path template, use the migrate subcommand.
+"#,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    instance: Option<InstanceArgs>,
+
+    #[clap(flatten)]
+    repack: Option<RepackArgs>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Repack every file under a directory, in parallel
+    Batch(BatchArgs),
+    /// Receive DICOM instances by C-STORE and repack them
+    Listen(ListenArgs),
+    /// Report problems with DICOM files as NDJSON, without repacking them
+    Check(CheckArgs),
+    /// List patients, studies, series or instances recorded in an --index database as NDJSON
+    Query(QueryArgs),
+    /// Write the logs of every DICOM file in the data directory, as if they had just been repacked
+    ///
+    /// Reindexed files are listed in .reindex.journal in the log directory, so that an
+    /// interrupted reindex is resumed by running it again. Options which change DICOM files,
+    /// such as --deidentify and --transcode, are ignored.
+    Reindex(ReindexArgs),
+    /// Move DICOM files in the data directory to where they would be repacked to today
+    ///
+    /// Use this after changing the path template, e.g. to or from px-repack's template without
+    /// hashes of SeriesInstanceUID. Paths in the log directory (and --index) are updated.
+    /// Every move is recorded in .migrate.journal in the data directory, and can be undone
+    /// with --undo.
+    Migrate(MigrateArgs),
+}
+
+/// Options for repacking a single DICOM instance, as called by storescp.
+#[derive(clap::Args)]
+struct InstanceArgs {
     /// Parent directory of DICOM instance
     #[clap(long)]
     xcrdir: Utf8PathBuf,
@@ -27,6 +90,111 @@ struct Cli {
     #[clap(long)]
     xcrfile: Utf8PathBuf,
 
+    /// Remove DICOM file from source location
+    #[clap(long, default_value_t = false)]
+    cleanup: bool,
+
+    /// Deprecated option
+    #[clap(long)]
+    verbosity: Option<u8>,
+
+    /// Write to stdout the outcome JSON
+    #[clap(short, long, default_value_t = false)]
+    log_ndjson: bool,
+}
+
+#[derive(clap::Args)]
+struct BatchArgs {
+    /// Directory of DICOM files to repack
+    dir: Utf8PathBuf,
+
+    #[clap(flatten)]
+    repack: RepackArgs,
+
+    /// Remove DICOM files from source location
+    #[clap(long, default_value_t = false)]
+    cleanup: bool,
+
+    /// Number of worker threads [default: number of CPUs]
+    #[clap(short, long, default_value_t = 0, hide_default_value = true)]
+    jobs: usize,
+}
+
+#[derive(clap::Args)]
+struct ListenArgs {
+    /// TCP port to listen on
+    #[clap(short, long, default_value_t = 11113)]
+    port: u16,
+
+    /// Application entity title of this SCP
+    #[clap(long, default_value = "ANY-SCP")]
+    ae_title: String,
+
+    /// Maximum number of associations handled at the same time
+    #[clap(long, default_value_t = 16)]
+    max_associations: usize,
+
+    #[clap(flatten)]
+    repack: RepackArgs,
+}
+
+#[derive(clap::Args)]
+struct CheckArgs {
+    /// DICOM files to check
+    #[clap(required = true)]
+    files: Vec<Utf8PathBuf>,
+}
+
+#[derive(clap::Args)]
+struct ReindexArgs {
+    #[clap(flatten)]
+    repack: RepackArgs,
+
+    /// Number of worker threads [default: number of CPUs]
+    #[clap(short, long, default_value_t = 0, hide_default_value = true)]
+    jobs: usize,
+}
+
+#[derive(clap::Args)]
+struct MigrateArgs {
+    #[clap(flatten)]
+    repack: RepackArgs,
+
+    /// Move files back to where they were, according to the journal
+    #[clap(long, default_value_t = false)]
+    undo: bool,
+}
+
+#[derive(clap::Args)]
+struct QueryArgs {
+    /// SQLite database written by --index
+    #[clap(long)]
+    index: Utf8PathBuf,
+
+    /// What to list
+    #[clap(value_enum)]
+    level: QueryLevel,
+
+    /// Only list what belongs to this PatientID
+    #[clap(long)]
+    patient_id: Option<String>,
+
+    /// Only list what belongs to this StudyInstanceUID
+    #[clap(long)]
+    study: Option<String>,
+
+    /// Only list what belongs to this SeriesInstanceUID
+    #[clap(long)]
+    series: Option<String>,
+
+    /// Only list what belongs to series of this modality, e.g. MR
+    #[clap(long)]
+    modality: Option<String>,
+}
+
+/// Options shared by all modes of repacking.
+#[derive(clap::Args)]
+struct RepackArgs {
     /// Output directory for DICOM files
     #[clap(long)]
     datadir: Utf8PathBuf,
@@ -35,38 +203,458 @@ struct Cli {
     #[clap(long)]
     logdir: Option<Utf8PathBuf>,
 
-    /// Remove DICOM file from source location
+    /// SQLite database in which to record repacked instances, in addition to the log directory
+    #[clap(long, value_name = "DB")]
+    index: Option<Utf8PathBuf>,
+
+    /// Also write DICOM tag data in the DICOM JSON Model to the log directory
+    #[clap(long, value_enum, requires = "logdir")]
+    dicom_json: Option<DicomJsonMode>,
+
+    /// How sequence elements are serialized in pypx studyData JSON files
+    #[clap(long, value_enum, default_value_t = SequenceFormat::Legacy)]
+    sequence_format: SequenceFormat,
+
+    /// Private tag dictionary in the format of DCMTK's private.dic (can be given multiple times)
+    #[clap(long)]
+    private_dict: Vec<Utf8PathBuf>,
+
+    /// Include private elements not found in any private dictionary, labeled by their tag
     #[clap(long, default_value_t = false)]
-    cleanup: bool,
+    keep_unknown_private: bool,
 
-    /// Deprecated option
+    /// Path template for DICOM files under the data directory
+    #[clap(long, conflicts_with = "template_file")]
+    template: Option<PathTemplate>,
+
+    /// File containing the path template
     #[clap(long)]
-    verbosity: Option<u8>,
+    template_file: Option<Utf8PathBuf>,
 
-    /// Write to stdout the outcome JSON
-    #[clap(short, long, default_value_t = false)]
-    log_ndjson: bool,
+    /// Put DICOM files in a BIDS layout instead of using a path template
+    #[clap(long, default_value_t = false, conflicts_with_all = ["template", "template_file"])]
+    bids: bool,
+
+    /// JSON file of heuristics for classifying series in the BIDS layout
+    #[clap(long, requires = "bids")]
+    bids_heuristics: Option<Utf8PathBuf>,
+
+    /// What to do when a DICOM file already exists at its destination
+    #[clap(long, value_enum, default_value_t = OnConflict::Overwrite)]
+    on_conflict: OnConflict,
+
+    /// Consider a series complete after receiving no instances of it for this many seconds
+    #[clap(long, value_name = "SECONDS", requires = "logdir")]
+    series_timeout: Option<u64>,
+
+    /// Command to run when a series is complete, which is given the series directory,
+    /// SeriesInstanceUID and StudyInstanceUID as arguments. It runs in the background,
+    /// one series at a time, without delaying repacking
+    #[clap(long, value_name = "COMMAND", requires = "logdir")]
+    on_series_complete: Option<String>,
+
+    /// Convert complete series to NIfTI, with BIDS-style JSON sidecars, next to their directories.
+    /// The conversion runs in the background, before --on-series-complete
+    #[clap(long, default_value_t = false, requires = "logdir")]
+    nifti: bool,
+
+    /// De-identify DICOM files using the given profile
+    #[clap(long, value_enum, requires = "deid_key_file")]
+    deidentify: Option<DeidProfile>,
+
+    /// File containing the secret key for remapping UIDs and PatientID during de-identification
+    #[clap(long)]
+    deid_key_file: Option<Utf8PathBuf>,
+
+    /// Re-encode DICOM files to this transfer syntax, or store them unchanged if they cannot be decoded
+    #[clap(long, value_enum, value_name = "TS")]
+    transcode: Option<TranscodeTarget>,
+
+    /// Print which files would be created, overwritten or left untouched, without writing anything
+    #[clap(long, default_value_t = false)]
+    dry_run: bool,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     let args: Cli = Cli::parse();
+    let result = match (args.command, args.instance, args.repack) {
+        (Some(Command::Batch(args)), _, _) => main_batch(args),
+        (Some(Command::Listen(args)), _, _) => main_listen(args),
+        (Some(Command::Check(args)), _, _) => main_check(args),
+        (Some(Command::Query(args)), _, _) => main_query(args),
+        (Some(Command::Reindex(args)), _, _) => main_reindex(args),
+        (Some(Command::Migrate(args)), _, _) => main_migrate(args),
+        (None, Some(args), Some(repack_args)) => main_instance(args, repack_args),
+        _ => unreachable!("clap should require instance arguments"),
+    };
+    wait_for_series_jobs();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            exit_code_of(&e)
+        }
+    }
+}
+
+/// Exit code for an error: see [ErrorKind::exit_code], or 1 for any other error.
+fn exit_code_of(e: &anyhow::Error) -> ExitCode {
+    let kind = e
+        .chain()
+        .find_map(|c| c.downcast_ref::<RepackError>())
+        .map(RepackError::kind)
+        .or_else(|| e.downcast_ref::<BatchFailed>().and_then(|b| b.kind));
+    kind.map(|k| ExitCode::from(k.exit_code()))
+        .unwrap_or(ExitCode::FAILURE)
+}
+
+/// Error for when some files could not be repacked by the batch subcommand.
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to pack {failed} of {total} files")]
+struct BatchFailed {
+    failed: usize,
+    total: usize,
+    /// Kind of error, if all failures were of the same kind.
+    kind: Option<ErrorKind>,
+}
+
+fn main_instance(args: InstanceArgs, repack_args: RepackArgs) -> anyhow::Result<()> {
     let dicom_file = args.xcrdir.join(&args.xcrfile);
-    let outcome = repack(
-        &dicom_file,
-        &args.datadir,
-        args.logdir.as_ref().map(|p| p.as_path()),
-        args.cleanup,
-    );
+    let options = repack_args.options()?;
+    let outcome = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        repack(&dicom_file, args.cleanup, &options)
+    }))
+    .unwrap_or(Err(RepackError::Panic));
 
     if args.log_ndjson {
         // 12-factor app recommends writing to stdout (not stderr)
         // https://12factor.net/logs
         // NDJson is a best practice for logging:
         // http://ndjson.org/
-        println!("{}", json_message(&dicom_file, &outcome)?);
+        print_outcome(&dicom_file, &outcome);
     }
+    complete_idle(&options, |series| {
+        if args.log_ndjson {
+            print_series_complete(series)
+        }
+    });
 
     outcome
         .with_context(|| format!("Failed to pack: {}", &dicom_file))
         .map(|_| ())
 }
+
+fn main_batch(args: BatchArgs) -> anyhow::Result<()> {
+    let options = args.repack.options()?;
+    let summary = batch(&args.dir, args.cleanup, args.jobs, &options, print_outcome)?;
+    complete_idle(&options, print_series_complete);
+    println!("{}", serde_json::to_string(&summary)?);
+    if summary.failed > 0 {
+        let kind = match summary.errors.keys().collect::<Vec<_>>()[..] {
+            [kind] => Some(*kind),
+            _ => None,
+        };
+        return Err(BatchFailed {
+            failed: summary.failed,
+            total: summary.total,
+            kind,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn main_check(args: CheckArgs) -> anyhow::Result<()> {
+    let mut failed = 0;
+    for file in &args.files {
+        let report = check(file);
+        if report.severity == Some(Severity::Error) {
+            failed += 1;
+        }
+        println!("{}", serde_json::to_string(&report)?);
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} files cannot be repacked", args.files.len());
+    }
+    Ok(())
+}
+
+fn main_reindex(args: ReindexArgs) -> anyhow::Result<()> {
+    let options = args.repack.options()?;
+    let summary = reindex(args.jobs, &options, |src, outcome| {
+        match reindex_message(src, outcome) {
+            Ok(msg) => println!("{msg}"),
+            Err(e) => eprintln!("Failed to serialize outcome of {src}: {e}"),
+        }
+    })?;
+    summary
+        .series_complete
+        .iter()
+        .for_each(print_series_complete);
+    complete_idle(&options, print_series_complete);
+    println!("{}", serde_json::to_string(&summary)?);
+    if summary.failed > 0 {
+        anyhow::bail!(
+            "Failed to reindex {} of {} files",
+            summary.failed,
+            summary.total
+        );
+    }
+    Ok(())
+}
+
+fn main_migrate(args: MigrateArgs) -> anyhow::Result<()> {
+    let options = args.repack.options()?;
+    if args.undo {
+        for m in undo_migrate(&options)?.iter().rev() {
+            println!("{}", serde_json::to_string(m)?);
+        }
+        return Ok(());
+    }
+    let summary = migrate(&options, |src, outcome| {
+        // files which stay where they are are only counted
+        if matches!(outcome, Ok(None)) {
+            return;
+        }
+        match migrate_message(src, outcome) {
+            Ok(msg) => println!("{msg}"),
+            Err(e) => eprintln!("Failed to serialize outcome of {src}: {e}"),
+        }
+    })?;
+    println!("{}", serde_json::to_string(&summary)?);
+    if summary.failed > 0 {
+        anyhow::bail!(
+            "Failed to migrate {} of {} files",
+            summary.failed,
+            summary.total
+        );
+    }
+    Ok(())
+}
+
+fn main_query(args: QueryArgs) -> anyhow::Result<()> {
+    if !args.index.is_file() {
+        anyhow::bail!("Index does not exist: {}", args.index);
+    }
+    let filter = QueryFilter {
+        PatientID: args.patient_id,
+        StudyInstanceUID: args.study,
+        SeriesInstanceUID: args.series,
+        Modality: args.modality,
+    };
+    for row in query(&args.index, args.level, &filter)? {
+        println!("{}", serde_json::to_string(&row)?);
+    }
+    Ok(())
+}
+
+fn main_listen(args: ListenArgs) -> anyhow::Result<()> {
+    let listener = bind(args.port)?;
+    let options = Arc::new(args.repack.options()?);
+    if let Some(timeout) = options.series_timeout {
+        let options = Arc::clone(&options);
+        let interval = (timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            complete_idle(&options, print_series_complete);
+        });
+    }
+    listen(
+        listener,
+        &args.ae_title,
+        args.max_associations,
+        &options,
+        print_outcome,
+    )
+}
+
+/// Mark series as complete which have been idle for longer than `--series-timeout`.
+fn complete_idle<F: FnMut(&SeriesComplete)>(options: &RepackOptions, on_complete: F) {
+    match complete_idle_series(options) {
+        Ok(completed) => completed.iter().for_each(on_complete),
+        Err(e) => eprintln!("Failed to check for idle series: {e}"),
+    }
+}
+
+/// Print the outcome of repacking a file as NDJSON,
+/// followed by an event if it completed its series.
+fn print_outcome(dicom_file: &Utf8Path, outcome: &Result<RepackOutcome, RepackError>) {
+    match json_message(dicom_file, outcome) {
+        Ok(msg) => println!("{msg}"),
+        Err(e) => eprintln!("Failed to serialize outcome of {dicom_file}: {e}"),
+    }
+    if let Some(series) = outcome
+        .as_ref()
+        .ok()
+        .and_then(|o| o.series_complete.as_ref())
+    {
+        print_series_complete(series);
+    }
+}
+
+/// Convert a complete series to NIfTI.
+fn convert_to_nifti(series: &SeriesComplete) {
+    if let Err(e) = series_to_nifti(&series.SeriesBaseDir) {
+        eprintln!(
+            "Failed to convert {} to NIfTI: {e}",
+            series.SeriesInstanceUID
+        );
+    }
+}
+
+/// A job run when a series is complete, see [RepackArgs::on_series_complete].
+type SeriesJob = Box<dyn FnOnce() + Send>;
+
+/// Queue of [SeriesJob]s and the thread which runs them one by one.
+static SERIES_JOBS: Mutex<Option<(mpsc::Sender<SeriesJob>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// Run `job` on a background thread, so that it does not hold up repacking, e.g. the
+/// C-STORE response to the sender. Jobs are run in the order they were queued.
+fn run_in_background(job: SeriesJob) {
+    let mut jobs = SERIES_JOBS.lock().unwrap();
+    let (sender, _) = jobs.get_or_insert_with(|| {
+        let (sender, receiver) = mpsc::channel::<SeriesJob>();
+        let worker = std::thread::spawn(move || {
+            for job in receiver {
+                // the panic is printed, and the next job is run regardless
+                std::panic::catch_unwind(AssertUnwindSafe(job)).ok();
+            }
+        });
+        (sender, worker)
+    });
+    sender
+        .send(job)
+        .expect("the worker runs until the queue is closed");
+}
+
+/// Wait for the jobs queued by [run_in_background] to finish, before exiting.
+fn wait_for_series_jobs() {
+    let jobs = SERIES_JOBS.lock().unwrap().take();
+    if let Some((sender, worker)) = jobs {
+        drop(sender);
+        worker.join().ok();
+    }
+}
+
+/// Run `command` for a complete series.
+fn run_command(command: &str, series: &SeriesComplete) {
+    match run_series_command(command, series) {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!(
+            "--on-series-complete command for {} failed: {status}",
+            series.SeriesInstanceUID
+        ),
+        Err(e) => eprintln!(
+            "--on-series-complete command for {} could not be run: {e}",
+            series.SeriesInstanceUID
+        ),
+    }
+}
+
+/// Print that a series is complete as NDJSON.
+fn print_series_complete(series: &SeriesComplete) {
+    match series_complete_message(series) {
+        Ok(msg) => println!("{msg}"),
+        Err(e) => eprintln!(
+            "Failed to serialize completion of {}: {e}",
+            series.SeriesInstanceUID
+        ),
+    }
+}
+
+impl RepackArgs {
+    /// What to do when a series is complete: convert it to NIfTI, then run the command,
+    /// so that the command can use the NIfTI file. Both are done by [run_in_background].
+    fn on_series_complete(&self) -> Option<SeriesCompleteCallback> {
+        let nifti = self.nifti;
+        let command = self.on_series_complete.clone();
+        if !nifti && command.is_none() {
+            return None;
+        }
+        Some(Box::new(move |series| {
+            let (command, series) = (command.clone(), series.clone());
+            run_in_background(Box::new(move || {
+                if nifti {
+                    convert_to_nifti(&series);
+                }
+                if let Some(command) = &command {
+                    run_command(command, &series);
+                }
+            }));
+        }))
+    }
+
+    fn options(&self) -> anyhow::Result<RepackOptions> {
+        Ok(RepackOptions {
+            data_dir: self.datadir.clone(),
+            log_dir: self.logdir.clone(),
+            index: self.index.clone(),
+            dicom_json: self.dicom_json,
+            sequence_format: self.sequence_format,
+            private_dictionary: self.private_dictionary()?,
+            keep_unknown_private: self.keep_unknown_private,
+            template: self.template()?,
+            bids: self.bids_layout()?,
+            on_conflict: self.on_conflict,
+            deidentify: self.deidentifier()?,
+            transcode: self.transcode,
+            series_timeout: self.series_timeout.map(Duration::from_secs),
+            on_series_complete: self.on_series_complete(),
+            dry_run: self.dry_run,
+        })
+    }
+
+    fn deidentifier(&self) -> anyhow::Result<Option<Deidentifier>> {
+        let (Some(profile), Some(p)) = (self.deidentify, &self.deid_key_file) else {
+            return Ok(None);
+        };
+        let mut key = fs_err::read(p)?;
+        // e.g. newline added by a text editor
+        while key.last().is_some_and(u8::is_ascii_whitespace) {
+            key.pop();
+        }
+        if key.is_empty() {
+            anyhow::bail!("De-identification key file is empty: {p}");
+        }
+        Ok(Some(Deidentifier::new(profile, &key)))
+    }
+
+    fn private_dictionary(&self) -> anyhow::Result<PrivateDictionary> {
+        let mut dictionary = PrivateDictionary::default();
+        for p in &self.private_dict {
+            let s = fs_err::read_to_string(p)?;
+            let other = s
+                .parse()
+                .with_context(|| format!("Invalid private dictionary {p}"))?;
+            dictionary.extend(other);
+        }
+        Ok(dictionary)
+    }
+
+    fn bids_layout(&self) -> anyhow::Result<Option<BidsLayout>> {
+        if !self.bids {
+            return Ok(None);
+        }
+        let Some(p) = &self.bids_heuristics else {
+            return Ok(Some(BidsLayout::default()));
+        };
+        let s = fs_err::read_to_string(p)?;
+        let layout = s
+            .parse()
+            .with_context(|| format!("Invalid BIDS heuristics in {p}"))?;
+        Ok(Some(layout))
+    }
+
+    fn template(&self) -> anyhow::Result<PathTemplate> {
+        if let Some(t) = &self.template {
+            return Ok(t.clone());
+        }
+        if let Some(p) = &self.template_file {
+            let s = fs_err::read_to_string(p)?;
+            return s
+                .parse()
+                .with_context(|| format!("Invalid template in {p}"));
+        }
+        Ok(PathTemplate::default())
+    }
+}
@@ -0,0 +1,422 @@
+//! Moving DICOM files of a data dir into the layout of the current path template.
+#![allow(non_snake_case)]
+use crate::batch::{find_files, FoundFiles};
+use crate::conflict::DestinationExists;
+use crate::dicom_data::CommonElements;
+use crate::dicom_json::file_uri;
+use crate::errors::{ErrorKind, RepackError};
+use crate::index;
+use crate::log_write::{load_json_carelessly, with_lock, write_json};
+use crate::repack::{copy_or_mv, destination, RepackOptions};
+use crate::tolerant_read::{self, looks_like_dicom};
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::dictionary_std::tags;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::panic::AssertUnwindSafe;
+
+/// Name of the file in the data dir which lists every move, so that they can be undone.
+const JOURNAL_NAME: &str = ".migrate.journal";
+
+/// A DICOM file moved by [migrate]. The journal has one per line, as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Move {
+    pub src: Utf8PathBuf,
+    pub dst: Utf8PathBuf,
+    pub SeriesInstanceUID: String,
+    pub StudyInstanceUID: String,
+}
+
+/// Counts of what happened during [migrate].
+#[derive(Debug, Default, Serialize)]
+pub struct MigrateSummary {
+    /// Number of files found.
+    pub total: usize,
+    /// Number of files which were moved, or would be moved in a dry run.
+    pub moved: usize,
+    /// Number of files which are already where they belong.
+    pub unchanged: usize,
+    /// Number of files which are not DICOM, e.g. NIfTI files.
+    pub ignored: usize,
+    /// Number of DICOM files which could not be moved. A file which cannot be read
+    /// counts as a DICOM file if it looks like one, i.e. by its extension or magic code.
+    pub failed: usize,
+    /// Number of files which could not be moved, by kind of error.
+    pub errors: BTreeMap<ErrorKind, usize>,
+}
+
+/// Move every DICOM file under [RepackOptions::data_dir] to where it would be repacked to
+/// today, e.g. after the path template changed, and update the paths in the logs.
+///
+/// Files are never moved onto existing files. Every move is recorded in a journal in the
+/// data dir before it is done, and can be undone by [undo_migrate]. In a
+/// [RepackOptions::dry_run], files which would be moved are reported, but not moved.
+///
+/// `on_outcome` is called for every DICOM file, with the move if the file was moved.
+pub fn migrate<F>(options: &RepackOptions, mut on_outcome: F) -> anyhow::Result<MigrateSummary>
+where
+    F: FnMut(&Utf8Path, &Result<Option<Move>, RepackError>),
+{
+    let FoundFiles { files, unreadable } = find_files(&options.data_dir)?;
+    let files: Vec<_> = files
+        .into_iter()
+        .filter(|p| !p.file_name().is_some_and(|n| n.starts_with('.')))
+        .collect();
+    let mut journal = if options.dry_run {
+        None
+    } else {
+        let file = fs_err::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(options.data_dir.join(JOURNAL_NAME))?;
+        Some(file)
+    };
+    let mut summary = MigrateSummary {
+        total: files.len() + unreadable.len(),
+        ..Default::default()
+    };
+    for (src, e) in unreadable {
+        let outcome = Err(RepackError::from(e));
+        summary.failed += 1;
+        *summary.errors.entry(ErrorKind::Io).or_insert(0) += 1;
+        on_outcome(&src, &outcome);
+    }
+    for src in &files {
+        let outcome = if is_nifti_of_series(src) {
+            // moved along with its series directory
+            Err(RepackError::InvalidDicom(format!("{src} is a NIfTI file")))
+        } else {
+            plan_move(src, options)
+        }
+        .and_then(|m| {
+            if let (Some(m), Some(journal)) = (&m, &mut journal) {
+                // the move is journaled first, so that it can be undone even if interrupted
+                writeln!(
+                    journal,
+                    "{}",
+                    serde_json::to_string(m).map_err(io::Error::from)?
+                )?;
+                journal.sync_data()?;
+                relocate(&m.src, &m.dst, m, options)?;
+            }
+            Ok(m)
+        });
+        match &outcome {
+            Ok(Some(_)) => summary.moved += 1,
+            Ok(None) => summary.unchanged += 1,
+            Err(e) if e.kind() == ErrorKind::NotDicom && !looks_like_dicom(src) => {
+                summary.ignored += 1
+            }
+            Err(e) => {
+                summary.failed += 1;
+                *summary.errors.entry(e.kind()).or_insert(0) += 1;
+            }
+        }
+        on_outcome(src, &outcome);
+    }
+    Ok(summary)
+}
+
+/// Undo the moves in the journal of [migrate], latest first, then delete the journal.
+/// A last line which cannot be parsed is ignored, as [migrate] was interrupted writing it.
+///
+/// Returns the moves which were undone.
+pub fn undo_migrate(options: &RepackOptions) -> anyhow::Result<Vec<Move>> {
+    let journal = options.data_dir.join(JOURNAL_NAME);
+    let file = match fs_err::File::open(&journal) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let lines = io::BufReader::new(file)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut moves = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str::<Move>(line) {
+            Ok(m) => moves.push(m),
+            // the last move was being journaled when migrate was interrupted,
+            // so it was not done
+            Err(_) if i + 1 == lines.len() => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    for m in moves.iter().rev() {
+        relocate(&m.dst, &m.src, m, options)?;
+    }
+    fs_err::remove_file(journal)?;
+    Ok(moves)
+}
+
+/// Decide whether a DICOM file should be moved.
+fn plan_move(src: &Utf8Path, options: &RepackOptions) -> Result<Option<Move>, RepackError> {
+    let (dcm, _) = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        tolerant_read::open_file(src, Some(tags::PIXEL_DATA))
+    }))
+    .map_err(|_| RepackError::Panic)??;
+    let common: CommonElements = (&dcm).try_into()?;
+    let dst = destination(&dcm, &common, options).path;
+    if dst == src {
+        return Ok(None);
+    }
+    if dst.exists() {
+        return Err(DestinationExists(dst).into());
+    }
+    Ok(Some(Move {
+        src: src.to_path_buf(),
+        dst,
+        SeriesInstanceUID: common.SeriesInstanceUID,
+        StudyInstanceUID: common.StudyInstanceUID,
+    }))
+}
+
+/// Move a DICOM file from `from` to `to`, which are the two ends of `m`,
+/// and replace its paths in the logs and the index.
+///
+/// Moving a file which is already at `to` only updates the logs, so that an
+/// interrupted [migrate] or [undo_migrate] can be repeated.
+fn relocate(
+    from: &Utf8Path,
+    to: &Utf8Path,
+    m: &Move,
+    options: &RepackOptions,
+) -> Result<(), RepackError> {
+    match (from.exists(), to.exists()) {
+        (true, true) => return Err(DestinationExists(to.to_path_buf()).into()),
+        // moved before being interrupted
+        (false, true) => (),
+        _ => {
+            if let Some(parent) = to.parent() {
+                fs_err::create_dir_all(parent)?;
+            }
+            copy_or_mv(from, to, true)?;
+        }
+    }
+    if let (Some(from_dir), Some(to_dir)) = (from.parent(), to.parent()) {
+        if from_dir != to_dir {
+            move_nifti(from_dir, to_dir)?;
+        }
+    }
+    remove_empty_dirs(from, &options.data_dir);
+    let (from_dir, to_dir) = (parent_str(from), parent_str(to));
+    let (from_name, to_name) = (file_name(from), file_name(to));
+    if let Some(log_dir) = &options.log_dir {
+        let series_data_dir = log_dir.join("seriesData");
+        let img_dir = series_data_dir.join(format!("{}-img", m.SeriesInstanceUID));
+        rename_json(
+            &img_dir.join(format!("{from_name}.json")),
+            &img_dir.join(format!("{to_name}.json")),
+            &[(from.as_str(), to.as_str()), (from_name, to_name)],
+        )?;
+
+        let dicom_json_dir = log_dir.join("dicomJSON");
+        let instance_dir = dicom_json_dir.join(&m.SeriesInstanceUID);
+        let instance_json = instance_dir.join(format!("{to_name}.json"));
+        rename_json(
+            &instance_dir.join(format!("{from_name}.json")),
+            &instance_json,
+            &[],
+        )?;
+        for p in [
+            instance_json,
+            dicom_json_dir.join(format!("{}.json", m.SeriesInstanceUID)),
+        ] {
+            replace_bulk_data_uri(&p, &file_uri(from), &file_uri(to))?;
+        }
+
+        let dirs = [(from_dir, to_dir)];
+        let study_series_meta = log_dir
+            .join("studyData")
+            .join(format!("{}-series", m.StudyInstanceUID))
+            .join(format!("{}-meta.json", m.SeriesInstanceUID));
+        replace_in_json(&study_series_meta, &dirs)?;
+        let progress = series_data_dir.join(format!("{}-progress.json", m.SeriesInstanceUID));
+        replace_in_json(&progress, &dirs)?;
+    }
+    if let Some(db) = options.index.as_ref().filter(|db| db.is_file()) {
+        index::relocate(db, from, to)?;
+    }
+    Ok(())
+}
+
+/// Suffixes of the files written by [crate::series_to_nifti] next to a series directory.
+const NIFTI_SUFFIXES: [&str; 2] = [".nii.gz", ".json"];
+
+/// Move the NIfTI files of the series directory `from_dir` next to `to_dir`, unless they
+/// are there already. They move with the first file of the series, so that an interrupted
+/// migrate does not leave them behind.
+fn move_nifti(from_dir: &Utf8Path, to_dir: &Utf8Path) -> io::Result<()> {
+    let (Some(from_name), Some(to_name)) = (from_dir.file_name(), to_dir.file_name()) else {
+        return Ok(());
+    };
+    for suffix in NIFTI_SUFFIXES {
+        let src = from_dir.with_file_name(format!("{from_name}{suffix}"));
+        let dst = to_dir.with_file_name(format!("{to_name}{suffix}"));
+        if src.is_file() && !dst.exists() {
+            fs_err::rename(src, dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `p` is a NIfTI file of a series directory, or was one before it moved
+/// along with its series.
+fn is_nifti_of_series(p: &Utf8Path) -> bool {
+    let Some(name) = p.file_name() else {
+        return false;
+    };
+    NIFTI_SUFFIXES.iter().any(|suffix| {
+        name.strip_suffix(suffix)
+            .is_some_and(|dir| !p.is_file() || p.with_file_name(dir).is_dir())
+    })
+}
+
+fn parent_str(p: &Utf8Path) -> &str {
+    p.parent().map(Utf8Path::as_str).unwrap_or_default()
+}
+
+fn file_name(p: &Utf8Path) -> &str {
+    p.file_name().unwrap_or_default()
+}
+
+/// Remove the directory of a moved file, and its parents under `data_dir`, if they are empty.
+fn remove_empty_dirs(moved: &Utf8Path, data_dir: &Utf8Path) {
+    for dir in moved.ancestors().skip(1) {
+        if !dir.starts_with(data_dir) || dir == data_dir || fs_err::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// [replace_in_json], then rename the file from `from` to `to`.
+fn rename_json(from: &Utf8Path, to: &Utf8Path, replacements: &[(&str, &str)]) -> io::Result<()> {
+    if !from.is_file() {
+        // already renamed, or never written
+        return replace_in_json(to, replacements);
+    }
+    replace_in_json(from, replacements)?;
+    if from != to {
+        fs_err::rename(from, to)?;
+    }
+    Ok(())
+}
+
+/// Replace strings which are exactly equal to the first of a pair of `replacements`
+/// with the second, in both keys and values of a JSON file. Does nothing if the file
+/// does not exist or is not valid JSON.
+fn replace_in_json(p: &Utf8Path, replacements: &[(&str, &str)]) -> io::Result<()> {
+    if !p.is_file() {
+        return Ok(());
+    }
+    with_lock(p, || {
+        let Some(mut data) = load_json_carelessly::<_, serde_json::Value>(p) else {
+            return Ok(());
+        };
+        if replace(&mut data, replacements) {
+            write_json(data, p)?;
+        }
+        Ok(())
+    })
+}
+
+/// Point the BulkDataURI of PixelData in a DICOM JSON file at `to_uri` if it points at
+/// `from_uri`, keeping its query. Does nothing if the file does not exist or is not valid JSON.
+fn replace_bulk_data_uri(p: &Utf8Path, from_uri: &str, to_uri: &str) -> io::Result<()> {
+    if !p.is_file() {
+        return Ok(());
+    }
+    with_lock(p, || {
+        let Some(mut data) = load_json_carelessly::<_, serde_json::Value>(p) else {
+            return Ok(());
+        };
+        let Some(serde_json::Value::String(uri)) = data
+            .get_mut("7FE00010")
+            .and_then(|pixel_data| pixel_data.get_mut("BulkDataURI"))
+        else {
+            return Ok(());
+        };
+        let (path, query) = uri.split_once('?').unwrap_or((uri.as_str(), ""));
+        if path != from_uri {
+            return Ok(());
+        }
+        *uri = if query.is_empty() {
+            to_uri.to_string()
+        } else {
+            format!("{to_uri}?{query}")
+        };
+        write_json(data, p)
+    })
+}
+
+/// Returns whether anything was replaced.
+fn replace(value: &mut serde_json::Value, replacements: &[(&str, &str)]) -> bool {
+    let replacement_of = |s: &str| {
+        replacements
+            .iter()
+            .find(|(from, _)| *from == s)
+            .map(|(_, to)| to.to_string())
+    };
+    match value {
+        serde_json::Value::String(s) => match replacement_of(s) {
+            Some(to) => {
+                *s = to;
+                true
+            }
+            None => false,
+        },
+        serde_json::Value::Array(values) => {
+            let mut replaced = false;
+            for v in values {
+                replaced |= replace(v, replacements);
+            }
+            replaced
+        }
+        serde_json::Value::Object(map) => {
+            let mut replaced = false;
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(key, mut v)| {
+                    replaced |= replace(&mut v, replacements);
+                    match replacement_of(&key) {
+                        Some(to) => {
+                            replaced = true;
+                            (to, v)
+                        }
+                        None => (key, v),
+                    }
+                })
+                .collect();
+            replaced
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_replace() {
+        let mut data = json!({
+            "1.2.3.4": {
+                "outputFile": "a.dcm",
+                "imageObj": {"a.dcm": {"FSlocation": "/data/old/a.dcm"}},
+                "SeriesDescription": "a.dcm.bak",
+            }
+        });
+        let replacements = [("/data/old/a.dcm", "/data/new/b.dcm"), ("a.dcm", "b.dcm")];
+        assert!(replace(&mut data, &replacements));
+        let expected = json!({
+            "1.2.3.4": {
+                "outputFile": "b.dcm",
+                "imageObj": {"b.dcm": {"FSlocation": "/data/new/b.dcm"}},
+                "SeriesDescription": "a.dcm.bak",
+            }
+        });
+        assert_eq!(data, expected);
+        assert!(!replace(&mut data, &replacements));
+    }
+}
@@ -1,5 +1,13 @@
+use crate::conflict::Placement;
 use crate::dicom_data::{name_of, DicomTagAndError};
+use crate::dry_run::PlannedFile;
+use crate::errors::{ErrorKind, RepackError};
+use crate::migrate::Move;
+use crate::quarantine::Quarantined;
+use crate::reindex::ReindexOutcome;
 use crate::repack::RepackOutcome;
+use crate::series_progress::SeriesComplete;
+use crate::tolerant_read::Repair;
 use camino::Utf8Path;
 use serde::Serialize;
 use std::os::unix::fs::MetadataExt;
@@ -7,18 +15,88 @@ use std::os::unix::fs::MetadataExt;
 /// Produce a JSON string which describes the outcome of `rx-repack`.
 pub fn json_message(
     src: &Utf8Path,
-    result: &anyhow::Result<RepackOutcome>,
+    result: &Result<RepackOutcome, RepackError>,
 ) -> serde_json::Result<String> {
     let msg = Message::new(src, result);
     serde_json::to_string(&msg)
 }
 
+/// Produce a JSON string which describes what `rx-repack reindex` did with a DICOM file.
+pub fn reindex_message(
+    src: &Utf8Path,
+    result: &Result<ReindexOutcome, RepackError>,
+) -> serde_json::Result<String> {
+    let msg = match result {
+        Ok(outcome) => ReindexMessage {
+            src,
+            expected: outcome.expected.as_deref(),
+            error: None,
+            error_kind: None,
+            missing: outcome
+                .missing
+                .iter()
+                .map(DicomTagNameAndError::from)
+                .collect(),
+            plan: outcome.plan.as_deref(),
+            quarantined: &outcome.quarantined,
+            repairs: &outcome.repairs,
+        },
+        Err(e) => ReindexMessage {
+            src,
+            expected: None,
+            error: Some(e.to_string()),
+            error_kind: Some(e.kind()),
+            missing: Vec::new(),
+            plan: None,
+            quarantined: &[],
+            repairs: &[],
+        },
+    };
+    serde_json::to_string(&msg)
+}
+
+/// Produce a JSON string which describes what `rx-repack migrate` did with a DICOM file.
+pub fn migrate_message(
+    src: &Utf8Path,
+    result: &Result<Option<Move>, RepackError>,
+) -> serde_json::Result<String> {
+    let msg = MigrateMessage {
+        src,
+        dst: result
+            .as_ref()
+            .ok()
+            .and_then(|m| m.as_ref())
+            .map(|m| m.dst.as_path()),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        error_kind: result.as_ref().err().map(RepackError::kind),
+    };
+    serde_json::to_string(&msg)
+}
+
+/// Produce a JSON string which announces that a series is complete.
+pub fn series_complete_message(series: &SeriesComplete) -> serde_json::Result<String> {
+    let event = Event {
+        event: "series-complete",
+        series,
+    };
+    serde_json::to_string(&event)
+}
+
+#[derive(Serialize, Debug)]
+struct Event<'a, T> {
+    event: &'static str,
+    #[serde(flatten)]
+    series: &'a T,
+}
+
 #[allow(non_snake_case)]
 #[derive(Serialize, Debug)]
 struct Message<'a> {
     src: &'a Utf8Path,
     dst: Option<&'a Utf8Path>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    placement: Option<Placement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<u64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -28,8 +106,47 @@ struct Message<'a> {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<ErrorKind>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing: Vec<DicomTagNameAndError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan: Option<&'a [PlannedFile]>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    quarantined: &'a [Quarantined],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    repairs: &'a [Repair],
+}
+
+#[derive(Serialize, Debug)]
+struct ReindexMessage<'a> {
+    src: &'a Utf8Path,
+    /// Where the file would be repacked to today, if that is not where it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<&'a Utf8Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<ErrorKind>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     missing: Vec<DicomTagNameAndError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan: Option<&'a [PlannedFile]>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    quarantined: &'a [Quarantined],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    repairs: &'a [Repair],
+}
+
+#[derive(Serialize, Debug)]
+struct MigrateMessage<'a> {
+    src: &'a Utf8Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst: Option<&'a Utf8Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_kind: Option<ErrorKind>,
 }
 
 #[derive(Serialize, Debug)]
@@ -50,17 +167,24 @@ impl From<&DicomTagAndError> for DicomTagNameAndError {
 }
 
 impl<'a> Message<'a> {
-    fn new(src: &'a Utf8Path, result: &'a anyhow::Result<RepackOutcome>) -> Self {
+    fn new(src: &'a Utf8Path, result: &'a Result<RepackOutcome, RepackError>) -> Self {
         match result {
             Ok(outcome) => {
-                let (size, error) = fs_err::metadata(&outcome.dst)
-                    .map(|metadata| (Some(metadata.size()), None))
-                    .unwrap_or_else(|error| (None, Some(error.to_string())));
+                let (size, error) = if outcome.plan.is_some() {
+                    // nothing was written in a dry run
+                    (None, None)
+                } else {
+                    fs_err::metadata(&outcome.dst)
+                        .map(|metadata| (Some(metadata.size()), None))
+                        .unwrap_or_else(|error| (None, Some(RepackError::from(error))))
+                };
                 Self {
                     src,
                     dst: Some(&outcome.dst),
+                    placement: Some(outcome.placement),
                     size,
-                    error,
+                    error: error.as_ref().map(|e| e.to_string()),
+                    error_kind: error.as_ref().map(RepackError::kind),
                     missing: outcome
                         .missing
                         .iter()
@@ -68,16 +192,24 @@ impl<'a> Message<'a> {
                         .collect(),
                     PatientID: Some(&outcome.PatientID),
                     SeriesInstanceUID: Some(&outcome.SeriesInstanceUID),
+                    plan: outcome.plan.as_deref(),
+                    quarantined: &outcome.quarantined,
+                    repairs: &outcome.repairs,
                 }
             }
             Err(e) => Self {
                 src,
                 dst: None,
+                placement: None,
                 size: None,
                 error: Some(e.to_string()),
+                error_kind: Some(e.kind()),
                 missing: Vec::new(),
                 PatientID: None,
                 SeriesInstanceUID: None,
+                plan: None,
+                quarantined: &[],
+                repairs: &[],
             },
         }
     }
@@ -0,0 +1,774 @@
+//! Conversion of a repacked series to a NIfTI-1 volume with a BIDS-style JSON sidecar,
+//! like what `dcm2niix` does for series of single-frame images.
+//!
+//! Slices are ordered by their position along the normal of `ImageOrientationPatient`.
+//! When several instances share a position (e.g. fMRI or multi-echo), they become the
+//! volumes of a 4D image, ordered by `InstanceNumber`.
+use crate::atomic_write::write_atomically;
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::dictionary_std::tags;
+use dicom::object::DefaultDicomObject;
+use dicom::pixeldata::{ConvertOptions, ModalityLutOption, PixelDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+
+/// Size of the NIfTI-1 header, plus 4 bytes saying there are no header extensions.
+const VOX_OFFSET: usize = 352;
+
+/// Tolerance in mm for slices being at the same position.
+const POSITION_TOLERANCE: f64 = 0.01;
+
+/// Files written by [series_to_nifti].
+#[derive(Debug, Clone, PartialEq)]
+pub struct NiftiFiles {
+    /// The NIfTI-1 image, `<series dir>.nii.gz`.
+    pub nifti: Utf8PathBuf,
+    /// The BIDS-style JSON sidecar, `<series dir>.json`.
+    pub sidecar: Utf8PathBuf,
+}
+
+/// Error converting a series to NIfTI.
+#[derive(thiserror::Error, Debug)]
+pub enum NiftiError {
+    #[error(transparent)]
+    ReadDicom(#[from] dicom::object::ReadError),
+    #[error("Cannot decode pixel data of {path}: {source}")]
+    PixelData {
+        path: Utf8PathBuf,
+        source: dicom::pixeldata::Error,
+    },
+    #[error("Missing or invalid {name} in {path}")]
+    MissingElement {
+        name: &'static str,
+        path: Utf8PathBuf,
+    },
+    #[error("No DICOM files in {0}")]
+    Empty(Utf8PathBuf),
+    #[error("Series cannot be converted to NIfTI: {0}")]
+    Unsupported(&'static str),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Reconstruct the volume of the DICOM files in a series directory, and write it as
+/// `<series dir>.nii.gz` with a BIDS-style sidecar `<series dir>.json` next to the directory.
+///
+/// Stored pixel values are kept as-is, with `RescaleSlope` and `RescaleIntercept` in the
+/// `scl_slope` and `scl_inter` fields of the header. If they differ between slices,
+/// they are applied instead, and the image is written as floats.
+pub fn series_to_nifti(series_dir: &Utf8Path) -> Result<NiftiFiles, NiftiError> {
+    let mut files = Vec::new();
+    for entry in fs_err::read_dir(series_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        // hidden files are partial files being written
+        let Some(name) = name.to_str().filter(|n| !n.starts_with('.')) else {
+            continue;
+        };
+        if entry.file_type()?.is_file() {
+            files.push(series_dir.join(name));
+        }
+    }
+    files.sort();
+    let slices = files
+        .iter()
+        .map(|path| Slice::read(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let volume =
+        Volume::new(slices).ok_or_else(|| NiftiError::Empty(series_dir.to_path_buf()))??;
+
+    let name = series_dir.file_name().unwrap_or("series");
+    let files = NiftiFiles {
+        nifti: series_dir.with_file_name(format!("{name}.nii.gz")),
+        sidecar: series_dir.with_file_name(format!("{name}.json")),
+    };
+    let first = dicom::object::open_file(&volume.slices[0].path)?;
+    let sidecar = sidecar(&first, &volume);
+    write_atomically(&files.nifti, true, |file| {
+        let mut gz = GzEncoder::new(file, Compression::default());
+        volume.write(&mut gz)?;
+        gz.finish().map(|_| ())
+    })?;
+    write_atomically(&files.sidecar, true, |file| {
+        serde_json::to_writer_pretty(file, &sidecar).map_err(io::Error::from)
+    })?;
+    Ok(files)
+}
+
+/// A single-frame image of a series.
+struct Slice {
+    path: Utf8PathBuf,
+    rows: u16,
+    columns: u16,
+    /// Direction cosines of the rows and columns, from `ImageOrientationPatient`.
+    orientation: [f64; 6],
+    position: [f64; 3],
+    /// Spacing between rows and between columns, from `PixelSpacing`.
+    spacing: [f64; 2],
+    instance_number: i32,
+    slope: f64,
+    intercept: f64,
+    /// Stored pixel values, row by row.
+    pixels: Vec<i32>,
+    /// Whether the stored pixel values are 8-bit unsigned.
+    is_u8: bool,
+}
+
+impl Slice {
+    fn read(path: &Utf8Path) -> Result<Self, NiftiError> {
+        let dcm = dicom::object::open_file(path)?;
+        let missing = |name| NiftiError::MissingElement {
+            name,
+            path: path.to_path_buf(),
+        };
+        let orientation = floats(&dcm, tags::IMAGE_ORIENTATION_PATIENT)
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| missing("ImageOrientationPatient"))?;
+        let position = floats(&dcm, tags::IMAGE_POSITION_PATIENT)
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| missing("ImagePositionPatient"))?;
+        let spacing = floats(&dcm, tags::PIXEL_SPACING)
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| missing("PixelSpacing"))?;
+        let instance_number = floats(&dcm, tags::INSTANCE_NUMBER)
+            .and_then(|v| v.first().copied())
+            .unwrap_or(0.0) as i32;
+
+        let pixel_error = |source| NiftiError::PixelData {
+            path: path.to_path_buf(),
+            source,
+        };
+        let decoded = dcm.decode_pixel_data().map_err(pixel_error)?;
+        if decoded.samples_per_pixel() != 1 {
+            return Err(NiftiError::Unsupported("color images"));
+        }
+        if decoded.number_of_frames() != 1 {
+            return Err(NiftiError::Unsupported("multi-frame images"));
+        }
+        let raw = ConvertOptions::new().with_modality_lut(ModalityLutOption::None);
+        let pixels = decoded.to_vec_with_options(&raw).map_err(pixel_error)?;
+        let rescale = decoded.rescale();
+        Ok(Self {
+            path: path.to_path_buf(),
+            rows: decoded.rows() as u16,
+            columns: decoded.columns() as u16,
+            orientation,
+            position,
+            spacing,
+            instance_number,
+            slope: rescale.slope,
+            intercept: rescale.intercept,
+            pixels,
+            is_u8: decoded.bits_allocated() == 8,
+        })
+    }
+
+    /// Direction cosines of the rows.
+    fn row_cosines(&self) -> [f64; 3] {
+        [
+            self.orientation[0],
+            self.orientation[1],
+            self.orientation[2],
+        ]
+    }
+
+    /// Direction cosines of the columns.
+    fn column_cosines(&self) -> [f64; 3] {
+        [
+            self.orientation[3],
+            self.orientation[4],
+            self.orientation[5],
+        ]
+    }
+}
+
+/// Get the values of a decimal or integer string element.
+fn floats(dcm: &DefaultDicomObject, tag: dicom::core::Tag) -> Option<Vec<f64>> {
+    let value = dcm.element(tag).ok()?.to_str().ok()?;
+    value.split('\\').map(|s| s.trim().parse().ok()).collect()
+}
+
+/// Data types of the NIfTI-1 format.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DataType {
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Float32,
+}
+
+impl DataType {
+    /// Value of the `datatype` header field.
+    fn code(self) -> i16 {
+        match self {
+            DataType::Uint8 => 2,
+            DataType::Int16 => 4,
+            DataType::Int32 => 8,
+            DataType::Float32 => 16,
+            DataType::Uint16 => 512,
+        }
+    }
+
+    fn bits(self) -> i16 {
+        match self {
+            DataType::Uint8 => 8,
+            DataType::Int16 | DataType::Uint16 => 16,
+            DataType::Int32 | DataType::Float32 => 32,
+        }
+    }
+
+    /// Smallest type which can hold the stored values of all slices.
+    fn of(slices: &[Slice]) -> Self {
+        let (min, max) = slices
+            .iter()
+            .flat_map(|s| s.pixels.iter().copied())
+            .fold((0, 0), |(min, max), p| (p.min(min), p.max(max)));
+        if slices.iter().all(|s| s.is_u8) && min >= 0 && max <= u8::MAX as i32 {
+            DataType::Uint8
+        } else if min >= i16::MIN as i32 && max <= i16::MAX as i32 {
+            DataType::Int16
+        } else if min >= 0 && max <= u16::MAX as i32 {
+            DataType::Uint16
+        } else {
+            DataType::Int32
+        }
+    }
+}
+
+/// Slices of a series, in the order of their voxels in the NIfTI image.
+struct Volume {
+    slices: Vec<Slice>,
+    /// Number of columns, rows, slices and volumes.
+    dim: [usize; 4],
+    /// Voxel to RAS+ world coordinates.
+    affine: [[f64; 4]; 3],
+    /// Size of voxels in mm.
+    pixdim: [f64; 3],
+    datatype: DataType,
+    /// Rescale slope and intercept, if the same for all slices.
+    rescale: Option<(f64, f64)>,
+    /// `RepetitionTime` in seconds, for 4D images.
+    repetition_time: Option<f64>,
+}
+
+impl Volume {
+    /// Sort slices into a volume, or return `None` if there are none.
+    fn new(slices: Vec<Slice>) -> Option<Result<Self, NiftiError>> {
+        let first = slices.first()?;
+        let geometry = (first.rows, first.columns, first.spacing);
+        let same_orientation = |s: &Slice| {
+            s.orientation
+                .iter()
+                .zip(first.orientation)
+                .all(|(a, b)| (a - b).abs() < 1e-4)
+        };
+        if !slices
+            .iter()
+            .all(|s| (s.rows, s.columns, s.spacing) == geometry && same_orientation(s))
+        {
+            return Some(Err(NiftiError::Unsupported(
+                "slices have different dimensions or orientations",
+            )));
+        }
+        Some(Self::from_parallel_slices(slices))
+    }
+
+    /// Sort slices which have the same dimensions and orientation into a volume.
+    fn from_parallel_slices(mut slices: Vec<Slice>) -> Result<Self, NiftiError> {
+        let normal = cross(slices[0].row_cosines(), slices[0].column_cosines());
+        let distance = |s: &Slice| dot(normal, s.position);
+        slices.sort_by(|a, b| {
+            distance(a)
+                .total_cmp(&distance(b))
+                .then(a.instance_number.cmp(&b.instance_number))
+        });
+
+        // group slices by position
+        let mut positions: Vec<Vec<Slice>> = Vec::new();
+        for slice in slices {
+            match positions.last_mut() {
+                Some(group) if distance(&slice) - distance(&group[0]) < POSITION_TOLERANCE => {
+                    group.push(slice)
+                }
+                _ => positions.push(vec![slice]),
+            }
+        }
+        let n_volumes = positions[0].len();
+        if positions.iter().any(|group| group.len() != n_volumes) {
+            return Err(NiftiError::Unsupported(
+                "positions have different numbers of slices",
+            ));
+        }
+        let n_slices = positions.len();
+        let distances: Vec<_> = positions.iter().map(|group| distance(&group[0])).collect();
+        let (step, slice_spacing) = if n_slices > 1 {
+            let first = positions[0][0].position;
+            let last = positions[n_slices - 1][0].position;
+            let step = sub(last, first).map(|d| d / (n_slices - 1) as f64);
+            let spacing = (distances[n_slices - 1] - distances[0]) / (n_slices - 1) as f64;
+            let regular = distances
+                .windows(2)
+                .all(|w| ((w[1] - w[0]) - spacing).abs() < spacing * 0.01);
+            if !regular {
+                return Err(NiftiError::Unsupported("slice spacing is irregular"));
+            }
+            (step, spacing)
+        } else {
+            let spacing = dicom::object::open_file(&positions[0][0].path)
+                .ok()
+                .and_then(|dcm| floats(&dcm, tags::SLICE_THICKNESS))
+                .and_then(|v| v.first().copied())
+                .unwrap_or(1.0);
+            (normal.map(|n| n * spacing), spacing)
+        };
+
+        let first = &positions[0][0];
+        let [row_spacing, column_spacing] = first.spacing;
+        let origin = first.position;
+        let columns = first.row_cosines().map(|c| c * column_spacing);
+        let rows = first.column_cosines().map(|c| c * row_spacing);
+        // DICOM patient coordinates are LPS+, NIfTI's are RAS+
+        let affine = [0, 1, 2].map(|axis| {
+            let sign = if axis < 2 { -1.0 } else { 1.0 };
+            [columns[axis], rows[axis], step[axis], origin[axis]].map(|x| x * sign)
+        });
+        let dim = [
+            first.columns as usize,
+            first.rows as usize,
+            n_slices,
+            n_volumes,
+        ];
+        let pixdim = [column_spacing, row_spacing, slice_spacing];
+        let repetition_time = (n_volumes > 1)
+            .then(|| dicom::object::open_file(&first.path).ok())
+            .flatten()
+            .and_then(|dcm| floats(&dcm, tags::REPETITION_TIME))
+            .and_then(|v| v.first().map(|ms| ms / 1000.0));
+
+        // slices in volume order: all positions of the first volume, then the second...
+        let mut groups: Vec<_> = positions.into_iter().map(Vec::into_iter).collect();
+        let slices: Vec<_> = (0..n_volumes)
+            .flat_map(|_| {
+                groups
+                    .iter_mut()
+                    .filter_map(Iterator::next)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let rescale = (slices[0].slope, slices[0].intercept);
+        let rescale = slices
+            .iter()
+            .all(|s| (s.slope, s.intercept) == rescale)
+            .then_some(rescale);
+        let datatype = if rescale.is_some() {
+            DataType::of(&slices)
+        } else {
+            DataType::Float32
+        };
+        Ok(Self {
+            slices,
+            dim,
+            affine,
+            pixdim,
+            datatype,
+            rescale,
+            repetition_time,
+        })
+    }
+
+    /// Encode the NIfTI-1 header.
+    fn header(&self) -> Vec<u8> {
+        let mut h = Header(vec![0; VOX_OFFSET]);
+        h.i32(0, 348);
+        h.0[38] = b'r';
+        let ndim = if self.dim[3] > 1 { 4 } else { 3 };
+        h.i16(40, ndim);
+        for (i, d) in self.dim.iter().enumerate() {
+            h.i16(42 + 2 * i, *d as i16);
+        }
+        h.i16(70, self.datatype.code());
+        h.i16(72, self.datatype.bits());
+
+        let (quatern, qfac) = quaternion(&self.affine, self.pixdim);
+        h.f32(76, qfac);
+        for (i, d) in self.pixdim.iter().enumerate() {
+            h.f32(80 + 4 * i, *d);
+        }
+        h.f32(92, self.repetition_time.unwrap_or(0.0));
+        h.f32(108, VOX_OFFSET as f64);
+        let (slope, intercept) = self.rescale.unwrap_or((1.0, 0.0));
+        h.f32(112, slope);
+        h.f32(116, intercept);
+        // millimeters and seconds
+        h.0[123] = 2 | 8;
+        h.0[148..157].copy_from_slice(b"rx-repack");
+        // scanner-based coordinates
+        h.i16(252, 1);
+        h.i16(254, 1);
+        for (i, x) in quatern.iter().enumerate() {
+            h.f32(256 + 4 * i, *x);
+        }
+        for axis in 0..3 {
+            h.f32(268 + 4 * axis, self.affine[axis][3]);
+            for (i, x) in self.affine[axis].iter().enumerate() {
+                h.f32(280 + 16 * axis + 4 * i, *x);
+            }
+        }
+        h.0[344..348].copy_from_slice(b"n+1\0");
+        h.0
+    }
+
+    /// Write the NIfTI-1 header and voxels.
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.header())?;
+        let mut buf = Vec::new();
+        for slice in &self.slices {
+            buf.clear();
+            for p in &slice.pixels {
+                match self.datatype {
+                    DataType::Uint8 => buf.push(*p as u8),
+                    DataType::Int16 => buf.extend((*p as i16).to_le_bytes()),
+                    DataType::Uint16 => buf.extend((*p as u16).to_le_bytes()),
+                    DataType::Int32 => buf.extend(p.to_le_bytes()),
+                    DataType::Float32 => buf
+                        .extend(((*p as f64 * slice.slope + slice.intercept) as f32).to_le_bytes()),
+                }
+            }
+            w.write_all(&buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// NIfTI-1 header under construction, in little-endian byte order.
+struct Header(Vec<u8>);
+
+impl Header {
+    fn i16(&mut self, offset: usize, value: i16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, offset: usize, value: i32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, offset: usize, value: f64) {
+        self.0[offset..offset + 4].copy_from_slice(&(value as f32).to_le_bytes());
+    }
+}
+
+/// Get the quaternion parameters `(b, c, d)` and `qfac` of the rotation of an affine,
+/// like `nifti_mat44_to_quatern` of nifti1_io.
+fn quaternion(affine: &[[f64; 4]; 3], pixdim: [f64; 3]) -> ([f64; 3], f64) {
+    let mut r = [[0.0; 3]; 3];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = affine[i][j] / pixdim[j];
+        }
+    }
+    let det = dot(r[0], cross(r[1], r[2]));
+    let qfac = if det < 0.0 {
+        for row in r.iter_mut() {
+            row[2] = -row[2];
+        }
+        -1.0
+    } else {
+        1.0
+    };
+    let [[r11, r12, r13], [r21, r22, r23], [r31, r32, r33]] = r;
+    let a = r11 + r22 + r33 + 1.0;
+    let (a, b, c, d) = if a > 0.5 {
+        let a = 0.5 * a.sqrt();
+        (
+            a,
+            0.25 * (r32 - r23) / a,
+            0.25 * (r13 - r31) / a,
+            0.25 * (r21 - r12) / a,
+        )
+    } else {
+        let xd = 1.0 + r11 - (r22 + r33);
+        let yd = 1.0 + r22 - (r11 + r33);
+        let zd = 1.0 + r33 - (r11 + r22);
+        if xd > 1.0 {
+            let b = 0.5 * xd.sqrt();
+            (
+                0.25 * (r32 - r23) / b,
+                b,
+                0.25 * (r12 + r21) / b,
+                0.25 * (r13 + r31) / b,
+            )
+        } else if yd > 1.0 {
+            let c = 0.5 * yd.sqrt();
+            (
+                0.25 * (r13 - r31) / c,
+                0.25 * (r12 + r21) / c,
+                c,
+                0.25 * (r23 + r32) / c,
+            )
+        } else {
+            let d = 0.5 * zd.sqrt();
+            (
+                0.25 * (r21 - r12) / d,
+                0.25 * (r13 + r31) / d,
+                0.25 * (r23 + r32) / d,
+                d,
+            )
+        }
+    };
+    let sign = if a < 0.0 { -1.0 } else { 1.0 };
+    ([b * sign, c * sign, d * sign], qfac)
+}
+
+/// Create the BIDS-style sidecar of a volume from its first slice.
+fn sidecar(dcm: &DefaultDicomObject, volume: &Volume) -> Value {
+    let string = |tag| {
+        let value = dcm.element(tag).ok()?.to_str().ok()?;
+        Some(value.trim().to_string()).filter(|s| !s.is_empty())
+    };
+    let number = |tag| floats(dcm, tag)?.first().copied();
+    let seconds = |tag| number(tag).map(|ms| ms / 1000.0);
+    let fields = [
+        ("Modality", string(tags::MODALITY).map(Value::from)),
+        (
+            "MagneticFieldStrength",
+            number(tags::MAGNETIC_FIELD_STRENGTH).map(Value::from),
+        ),
+        ("Manufacturer", string(tags::MANUFACTURER).map(Value::from)),
+        (
+            "ManufacturersModelName",
+            string(tags::MANUFACTURER_MODEL_NAME).map(Value::from),
+        ),
+        (
+            "SeriesDescription",
+            string(tags::SERIES_DESCRIPTION).map(Value::from),
+        ),
+        ("ProtocolName", string(tags::PROTOCOL_NAME).map(Value::from)),
+        (
+            "ImageType",
+            string(tags::IMAGE_TYPE).map(|s| s.split('\\').collect::<Vec<_>>().into()),
+        ),
+        (
+            "SeriesNumber",
+            number(tags::SERIES_NUMBER).map(|n| Value::from(n as i64)),
+        ),
+        (
+            "AcquisitionTime",
+            string(tags::ACQUISITION_TIME).map(|t| format_time(&t).into()),
+        ),
+        (
+            "SliceThickness",
+            number(tags::SLICE_THICKNESS).map(Value::from),
+        ),
+        (
+            "SpacingBetweenSlices",
+            number(tags::SPACING_BETWEEN_SLICES).map(Value::from),
+        ),
+        ("EchoTime", seconds(tags::ECHO_TIME).map(Value::from)),
+        (
+            "RepetitionTime",
+            seconds(tags::REPETITION_TIME).map(Value::from),
+        ),
+        (
+            "InversionTime",
+            seconds(tags::INVERSION_TIME).map(Value::from),
+        ),
+        ("FlipAngle", number(tags::FLIP_ANGLE).map(Value::from)),
+        (
+            "PhaseEncodingAxis",
+            string(tags::IN_PLANE_PHASE_ENCODING_DIRECTION).and_then(|d| match d.as_str() {
+                "ROW" => Some("i".into()),
+                "COL" => Some("j".into()),
+                _ => None,
+            }),
+        ),
+    ];
+    let mut sidecar: Map<_, _> = fields
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .collect();
+    if volume.dim[3] > 1 {
+        sidecar.insert("NumberOfVolumes".to_string(), json!(volume.dim[3]));
+    }
+    sidecar.insert("ConversionSoftware".to_string(), json!("rx-repack"));
+    sidecar.insert(
+        "ConversionSoftwareVersion".to_string(),
+        json!(env!("CARGO_PKG_VERSION")),
+    );
+    Value::Object(sidecar)
+}
+
+/// Format a DICOM TM value `HHMMSS.FFFFFF` as `HH:MM:SS.FFFFFF`.
+fn format_time(tm: &str) -> String {
+    match (tm.get(0..2), tm.get(2..4), tm.get(4..)) {
+        (Some(h), Some(m), Some(s)) if !s.is_empty() => format!("{h}:{m}:{s}"),
+        _ => tm.to_string(),
+    }
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Rotation matrix of quaternion parameters, like `nifti_quatern_to_mat44`.
+    fn rotation_of(quatern: [f64; 3], qfac: f64) -> [[f64; 3]; 3] {
+        let [b, c, d] = quatern;
+        let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+        [
+            [
+                a * a + b * b - c * c - d * d,
+                2.0 * (b * c - a * d),
+                2.0 * (b * d + a * c) * qfac,
+            ],
+            [
+                2.0 * (b * c + a * d),
+                a * a + c * c - b * b - d * d,
+                2.0 * (c * d - a * b) * qfac,
+            ],
+            [
+                2.0 * (b * d - a * c),
+                2.0 * (c * d + a * b),
+                (a * a + d * d - c * c - b * b) * qfac,
+            ],
+        ]
+    }
+
+    #[test]
+    fn test_quaternion() {
+        let s = 0.5_f64.sqrt();
+        let rotations = [
+            [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            [[-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]],
+            // sagittal, as from LPS+ row (0, 1, 0) and column (0, 0, -1)
+            [[0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]],
+            [[s, -s, 0.0], [s, s, 0.0], [0.0, 0.0, -1.0]],
+            [[1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, -1.0]],
+        ];
+        let pixdim = [0.5, 2.0, 1.2];
+        for rotation in rotations {
+            let affine = [0, 1, 2].map(|i| {
+                let [x, y, z] = rotation[i];
+                [x * pixdim[0], y * pixdim[1], z * pixdim[2], 0.0]
+            });
+            let (quatern, qfac) = quaternion(&affine, pixdim);
+            let actual = rotation_of(quatern, qfac);
+            for i in 0..3 {
+                for j in 0..3 {
+                    assert!(
+                        (actual[i][j] - rotation[i][j]).abs() < 1e-9,
+                        "{rotation:?} became {actual:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn slice(position: [f64; 3], instance_number: i32, slope: f64) -> Slice {
+        Slice {
+            path: Utf8PathBuf::from(format!("{instance_number}.dcm")),
+            rows: 2,
+            columns: 3,
+            orientation: [0.0, 1.0, 0.0, 0.0, 0.0, -1.0],
+            position,
+            spacing: [0.5, 0.25],
+            instance_number,
+            slope,
+            intercept: 0.0,
+            pixels: vec![instance_number; 6],
+            is_u8: false,
+        }
+    }
+
+    #[test]
+    fn test_volume_geometry() {
+        let slices = (0..4)
+            .rev()
+            .map(|i| slice([i as f64 * 1.5 - 10.0, 5.0, 7.0], i + 1, 1.0))
+            .collect();
+        let volume = Volume::new(slices).unwrap().unwrap();
+        assert_eq!(volume.dim, [3, 2, 4, 1]);
+        assert_eq!(volume.pixdim, [0.25, 0.5, 1.5]);
+        // the slice normal is -x, so slices with greater x come first
+        assert_eq!(
+            volume.affine,
+            [
+                [0.0, 0.0, 1.5, 5.5],
+                [-0.25, 0.0, 0.0, -5.0],
+                [0.0, -0.5, 0.0, 7.0],
+            ]
+        );
+        let order: Vec<_> = volume.slices.iter().map(|s| s.instance_number).collect();
+        assert_eq!(order, [4, 3, 2, 1]);
+        assert_eq!(volume.datatype, DataType::Int16);
+        assert_eq!(volume.rescale, Some((1.0, 0.0)));
+
+        let mut data = Vec::new();
+        volume.write(&mut data).unwrap();
+        assert_eq!(data.len(), VOX_OFFSET + 3 * 2 * 4 * 2);
+        assert_eq!(&data[344..348], b"n+1\0");
+        assert_eq!(&data[VOX_OFFSET..VOX_OFFSET + 2], &4_i16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_volume_of_repeated_positions() {
+        let slices = [(0.0, 2), (0.0, 4), (2.0, 1), (2.0, 3)]
+            .into_iter()
+            .map(|(x, n)| slice([x, 0.0, 0.0], n, n as f64))
+            .collect();
+        let volume = Volume::new(slices).unwrap().unwrap();
+        assert_eq!(volume.dim, [3, 2, 2, 2]);
+        let order: Vec<_> = volume.slices.iter().map(|s| s.instance_number).collect();
+        assert_eq!(order, [1, 2, 3, 4]);
+        // rescale differs between slices, so it is applied
+        assert_eq!(volume.datatype, DataType::Float32);
+        assert_eq!(volume.rescale, None);
+    }
+
+    #[test]
+    fn test_irregular_volume() {
+        let slices = [0.0, 1.0, 3.0]
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| slice([x, 0.0, 0.0], i as i32, 1.0))
+            .collect();
+        assert!(matches!(
+            Volume::new(slices),
+            Some(Err(NiftiError::Unsupported(_)))
+        ));
+        let slices = [(0.0, 1), (0.0, 2), (1.0, 3)]
+            .into_iter()
+            .map(|(x, n)| slice([x, 0.0, 0.0], n, 1.0))
+            .collect();
+        assert!(matches!(
+            Volume::new(slices),
+            Some(Err(NiftiError::Unsupported(_)))
+        ));
+        assert!(Volume::new(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time("143025.123"), "14:30:25.123");
+        assert_eq!(format_time("1430"), "1430");
+    }
+}
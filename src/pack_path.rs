@@ -1,7 +1,7 @@
 //! Functions for deciding where to copy the received DICOM to.
-use crate::dicom_data::CommonElements;
-use crate::helpers::sanitize;
+use crate::path_template::PathTemplate;
 use camino::{Utf8Path, Utf8PathBuf};
+use dicom::object::DefaultDicomObject;
 
 /// Destination directory and file name for the DICOM file.
 pub(crate) struct PypxPath {
@@ -11,40 +11,16 @@ pub(crate) struct PypxPath {
 }
 
 impl PypxPath {
-    /// Equivalent Python implementation `pypx.repack.Process.packPath_resolve`:
+    /// Evaluate the path template against a DICOM object. The default template
+    /// is equivalent to the Python implementation `pypx.repack.Process.packPath_resolve`:
     /// https://github.com/FNNDSC/pypx/blob/d4791598f65b257cbf6b17d6b5b05db777844db4/pypx/repack.py#L412-L459
     ///
     /// Missing DICOM element values are replaced with the name of the DICOM tag.
     /// See https://github.com/FNNDSC/pypx/wiki/How-pypx-handles-missing-elements
-    pub fn new(dcm: &CommonElements, data_dir: &Utf8Path) -> Self {
-        let root_dir = sanitize(format!(
-            "{}-{}-{}",
-            dcm.PatientID,
-            dcm.PatientName.unwrap_or("PatientName"),
-            dcm.PatientBirthDate.unwrap_or("PatientBirthDate")
-        ));
-        let study_dir = sanitize(format!(
-            "{}-{}-{}",
-            dcm.StudyDescription.unwrap_or("StudyDescription"),
-            dcm.AccessionNumber.unwrap_or("AccessionNumber"),
-            dcm.StudyDate.unwrap_or("StudyDate")
-        ));
-        let series_dir = sanitize(format!(
-            "{:0>5}-{}-{}",
-            dcm.SeriesNumber
-                .map(|s| s.to_string())
-                .as_deref()
-                .unwrap_or("SeriesNumber"),
-            dcm.SeriesDescription.unwrap_or("SeriesDescription"),
-            &hash(&dcm.SeriesInstanceUID)[..7]
-        ));
-
-        let pack_dir_rel = Utf8PathBuf::from(root_dir).join(study_dir).join(series_dir);
-        let fname = sanitize(format!(
-            "{:0>4}-{}.dcm",
-            dcm.InstanceNumber.unwrap_or("InstanceNumber"), dcm.SOPInstanceUID
-        ));
-        let pack_dir = data_dir.join(pack_dir_rel);
+    pub fn new(template: &PathTemplate, dcm: &DefaultDicomObject, data_dir: &Utf8Path) -> Self {
+        let mut components = template.render(dcm);
+        let fname = components.pop().unwrap();
+        let pack_dir = data_dir.join(components.into_iter().collect::<Utf8PathBuf>());
         let path = pack_dir.join(&fname);
         Self {
             fname,
@@ -54,23 +30,84 @@ impl PypxPath {
     }
 }
 
-/// Produces the hash of the data as a hexidecimal string.
-fn hash(data: &str) -> String {
-    format!("{:x}", seahash::hash(data.as_bytes()))
-}
-
 #[cfg(test)]
 mod test {
-
-    const EXPECTED: &str = "a27cf06";
-    const EXAMPLE_UID: &str = "1.3.12.2.1107.5.2.19.45152.2013030808061520200285270.0.0.0";
     use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::{tags, uids};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
 
-    #[test]
-    fn test_seahash_stability() {
-        assert!(
-            hash(EXAMPLE_UID).starts_with(EXPECTED),
-            "Hash algorithm was changed from what was originally used by Jennings on 2023-08-06"
+    fn example_dicom() -> DefaultDicomObject {
+        let sop_instance_uid = "1.3.12.2.1107.5.2.19.45152.2013030808110087109885915";
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, PrimitiveValue::from("1449c1d")),
+            DataElement::new(
+                tags::PATIENT_NAME,
+                VR::PN,
+                PrimitiveValue::from("anonymized"),
+            ),
+            DataElement::new(
+                tags::PATIENT_BIRTH_DATE,
+                VR::DA,
+                PrimitiveValue::from("20090701"),
+            ),
+            DataElement::new(
+                tags::STUDY_DESCRIPTION,
+                VR::LO,
+                PrimitiveValue::from("MR-Brain w/o Contrast "),
+            ),
+            DataElement::new(tags::STUDY_DATE, VR::DA, PrimitiveValue::from("20130308")),
+            DataElement::new(tags::SERIES_NUMBER, VR::IS, PrimitiveValue::from("5 ")),
+            DataElement::new(
+                tags::SERIES_DESCRIPTION,
+                VR::LO,
+                PrimitiveValue::from("SAG MPRAGE 220 FOV"),
+            ),
+            DataElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from(
+                    "1.3.12.2.1107.5.2.19.45152.2013030808061520200285270.0.0.0\0",
+                ),
+            ),
+            DataElement::new(tags::INSTANCE_NUMBER, VR::IS, PrimitiveValue::from("61")),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from(sop_instance_uid),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::MR_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid(sop_instance_uid)
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
         )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_default_template() {
+        let dcm = example_dicom();
+        let actual = PypxPath::new(&PathTemplate::default(), &dcm, Utf8Path::new("/data"));
+        let expected_dir = "/data/1449c1d-anonymized-20090701/MR-Brain_w_o_Contrast-AccessionNumber-20130308/00005-SAG_MPRAGE_220_FOV-a27cf06";
+        let expected_fname = "0061-1.3.12.2.1107.5.2.19.45152.2013030808110087109885915.dcm";
+        assert_eq!(actual.dir, expected_dir);
+        assert_eq!(actual.fname, expected_fname);
+        assert_eq!(
+            actual.path,
+            Utf8Path::new(expected_dir).join(expected_fname)
+        );
+    }
+
+    #[test]
+    fn test_custom_template() {
+        let dcm = example_dicom();
+        let template = "%_upper_PatientID/%Modality/%_trunc|8_SOPInstanceUID"
+            .parse()
+            .unwrap();
+        let actual = PypxPath::new(&template, &dcm, Utf8Path::new("/data"));
+        assert_eq!(actual.dir, "/data/1449C1D/Modality");
+        assert_eq!(actual.fname, "1.3.12.2");
     }
 }
@@ -0,0 +1,449 @@
+//! Parser and evaluator for pypx-style path templates.
+//!
+//! A template is a `/`-separated list of path components. Each component is
+//! literal text mixed with DICOM element placeholders:
+//!
+//! - `%Tag` is replaced by the value of the element with keyword `Tag`
+//! - `%_fn|args_Tag` is replaced by the value of `Tag`, transformed by `fn`
+//! - `%_fn_Tag` and `%_fn|Tag` are shorthands for calling `fn` without arguments
+//!
+//! Missing element values are replaced with the keyword of the DICOM tag.
+//! See https://github.com/FNNDSC/pypx/wiki/How-pypx-handles-missing-elements
+use crate::helpers::sanitize;
+use dicom::core::DataDictionary;
+use dicom::dictionary_std::StandardDataDictionary;
+use dicom::object::{DefaultDicomObject, Tag};
+use regex::Regex;
+use std::borrow::Cow;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// The layout which was hard-coded before templates were configurable.
+///
+/// Equivalent Python implementation `pypx.repack.Process.packPath_resolve`:
+/// https://github.com/FNNDSC/pypx/blob/d4791598f65b257cbf6b17d6b5b05db777844db4/pypx/repack.py#L412-L459
+pub const DEFAULT_TEMPLATE: &str = "%PatientID-%PatientName-%PatientBirthDate/\
+%StudyDescription-%AccessionNumber-%StudyDate/\
+%_pad|5,0_SeriesNumber-%SeriesDescription-%_hash|7_SeriesInstanceUID/\
+%_pad|4,0_InstanceNumber-%SOPInstanceUID.dcm";
+
+/// A parsed path template.
+///
+/// The last component is the file name, the components before it are directories.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    components: Vec<Vec<Token>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Element {
+        tag: Tag,
+        keyword: String,
+        func: Option<TemplateFn>,
+    },
+}
+
+/// Functions which can be applied to a DICOM element value.
+#[derive(Debug, Clone, PartialEq)]
+enum TemplateFn {
+    /// `%_pad|5,0_Tag`: left-pad to a width using a fill character.
+    Pad { width: usize, fill: char },
+    /// `%_hash|7_Tag`: hexadecimal seahash, truncated to the given length.
+    Hash { len: usize },
+    /// `%_trunc|8_Tag`: keep only the first N characters.
+    Trunc { len: usize },
+    /// `%_upper_Tag`
+    Upper,
+    /// `%_lower_Tag`
+    Lower,
+    /// `%_nospc|-_Tag`: replace whitespace with the given string.
+    Nospc { replacement: String },
+    /// `%_strmsk|******01_Tag`: replace characters with those of the mask,
+    /// except where the mask has a `*`.
+    Strmsk { mask: String },
+}
+
+/// Error parsing a [PathTemplate].
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum TemplateError {
+    #[error("template is empty")]
+    Empty,
+    #[error("template component {0} is empty")]
+    EmptyComponent(usize),
+    #[error("unknown DICOM tag keyword: {0:?}")]
+    UnknownTag(String),
+    #[error("unknown template function: {0:?}")]
+    UnknownFunction(String),
+    #[error("invalid arguments {args:?} for function {func:?}")]
+    InvalidArguments { func: String, args: String },
+    #[error("stray '%' at position {0}")]
+    StrayPercent(usize),
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = TemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().trim_matches('/');
+        if s.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        let components = s
+            .split('/')
+            .enumerate()
+            .map(|(i, c)| {
+                if c.is_empty() {
+                    Err(TemplateError::EmptyComponent(i))
+                } else {
+                    parse_component(c)
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { components })
+    }
+}
+
+impl PathTemplate {
+    /// Evaluate the template against a DICOM object, producing
+    /// sanitized directory names followed by the file name.
+    ///
+    /// A component which would be empty, `.` or `..` (e.g. `%PatientID` of a PatientID `..`)
+    /// is replaced by `_`, so that files are never written outside of the data dir.
+    pub fn render(&self, dcm: &DefaultDicomObject) -> Vec<String> {
+        self.components
+            .iter()
+            .map(|tokens| {
+                let s: String = tokens.iter().map(|t| t.render(dcm)).collect();
+                let s = sanitize(s);
+                if matches!(s.as_str(), "" | "." | "..") {
+                    "_".to_string()
+                } else {
+                    s
+                }
+            })
+            .collect()
+    }
+}
+
+impl Token {
+    fn render<'a>(&'a self, dcm: &'a DefaultDicomObject) -> Cow<'a, str> {
+        match self {
+            Token::Literal(s) => Cow::Borrowed(s),
+            Token::Element { tag, keyword, func } => {
+                let value = element_str(dcm, *tag).unwrap_or_else(|| Cow::Borrowed(keyword));
+                match func {
+                    Some(f) => Cow::Owned(f.apply(&value)),
+                    None => value,
+                }
+            }
+        }
+    }
+}
+
+/// Get the trimmed string value of an element without NUL bytes.
+fn element_str(dcm: &DefaultDicomObject, tag: Tag) -> Option<Cow<'_, str>> {
+    let ele = dcm.element(tag).ok()?;
+    let value = ele.to_str().ok()?;
+    let trimmed = value.trim();
+    if trimmed.contains('\0') {
+        Some(Cow::Owned(trimmed.replace('\0', "")))
+    } else if trimmed.len() == value.len() {
+        Some(value)
+    } else {
+        Some(Cow::Owned(trimmed.to_string()))
+    }
+}
+
+impl TemplateFn {
+    fn parse(name: &str, args: Option<&str>) -> Result<Self, TemplateError> {
+        let invalid = || TemplateError::InvalidArguments {
+            func: name.to_string(),
+            args: args.unwrap_or("").to_string(),
+        };
+        let f = match name {
+            "pad" => {
+                let args = args.ok_or_else(invalid)?;
+                let (width, fill) = args.split_once(',').unwrap_or((args, "0"));
+                let width = width.parse().map_err(|_| invalid())?;
+                let mut fill_chars = fill.chars();
+                let fill = fill_chars.next().unwrap_or(' ');
+                if fill_chars.next().is_some() {
+                    return Err(invalid());
+                }
+                TemplateFn::Pad { width, fill }
+            }
+            "hash" => {
+                let len = args.map(|a| a.parse()).unwrap_or(Ok(16));
+                TemplateFn::Hash {
+                    len: len.map_err(|_| invalid())?,
+                }
+            }
+            "trunc" => TemplateFn::Trunc {
+                len: args.ok_or_else(invalid)?.parse().map_err(|_| invalid())?,
+            },
+            "upper" => TemplateFn::Upper,
+            "lower" => TemplateFn::Lower,
+            "nospc" => TemplateFn::Nospc {
+                replacement: args.unwrap_or("_").to_string(),
+            },
+            "strmsk" => TemplateFn::Strmsk {
+                mask: args.ok_or_else(invalid)?.to_string(),
+            },
+            _ => return Err(TemplateError::UnknownFunction(name.to_string())),
+        };
+        Ok(f)
+    }
+
+    fn apply(&self, value: &str) -> String {
+        match self {
+            TemplateFn::Pad { width, fill } => {
+                let n = value.chars().count();
+                fill.to_string().repeat(width.saturating_sub(n)) + value
+            }
+            TemplateFn::Hash { len } => {
+                let h = hash(value);
+                h.get(..*len).unwrap_or(&h).to_string()
+            }
+            TemplateFn::Trunc { len } => value.chars().take(*len).collect(),
+            TemplateFn::Upper => value.to_uppercase(),
+            TemplateFn::Lower => value.to_lowercase(),
+            TemplateFn::Nospc { replacement } => value
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(replacement),
+            TemplateFn::Strmsk { mask } => {
+                let mut mask_chars = mask.chars();
+                value
+                    .chars()
+                    .map(|c| match mask_chars.next() {
+                        Some('*') | None => c,
+                        Some(m) => m,
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+fn parse_component(component: &str) -> Result<Vec<Token>, TemplateError> {
+    let re = PLACEHOLDER_RE.get_or_init(|| {
+        Regex::new(concat!(
+            r"%(?:_(?P<fn>[a-z]+)(?:\|(?P<args>[^_%/]*))?_)?(?P<tag>[A-Za-z][A-Za-z0-9]*)",
+            r"|%_(?P<fn_short>[a-z]+)\|(?P<tag_short>[A-Za-z][A-Za-z0-9]*)",
+        ))
+        .unwrap()
+    });
+    let mut tokens = Vec::new();
+    let mut last = 0;
+    for caps in re.captures_iter(component) {
+        let m = caps.get(0).unwrap();
+        push_literal(&mut tokens, &component[last..m.start()], last)?;
+        last = m.end();
+
+        let (func, args, keyword) = if let Some(tag) = caps.name("tag") {
+            (
+                caps.name("fn").map(|m| m.as_str()),
+                caps.name("args").map(|m| m.as_str()),
+                tag.as_str(),
+            )
+        } else {
+            (
+                caps.name("fn_short").map(|m| m.as_str()),
+                None,
+                caps.name("tag_short").unwrap().as_str(),
+            )
+        };
+        let tag = StandardDataDictionary
+            .parse_tag(keyword)
+            .ok_or_else(|| TemplateError::UnknownTag(keyword.to_string()))?;
+        let func = func.map(|f| TemplateFn::parse(f, args)).transpose()?;
+        tokens.push(Token::Element {
+            tag,
+            keyword: keyword.to_string(),
+            func,
+        });
+    }
+    push_literal(&mut tokens, &component[last..], last)?;
+    Ok(tokens)
+}
+
+fn push_literal(tokens: &mut Vec<Token>, s: &str, offset: usize) -> Result<(), TemplateError> {
+    if let Some(i) = s.find('%') {
+        return Err(TemplateError::StrayPercent(offset + i));
+    }
+    if !s.is_empty() {
+        tokens.push(Token::Literal(s.to_string()));
+    }
+    Ok(())
+}
+
+static PLACEHOLDER_RE: OnceLock<Regex> = OnceLock::new();
+
+/// Produces the hash of the data as a hexidecimal string.
+pub(crate) fn hash(data: &str) -> String {
+    format!("{:x}", seahash::hash(data.as_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+
+    const EXPECTED: &str = "a27cf06";
+    const EXAMPLE_UID: &str = "1.3.12.2.1107.5.2.19.45152.2013030808061520200285270.0.0.0";
+    use super::*;
+    use dicom::dictionary_std::tags;
+
+    #[test]
+    fn test_seahash_stability() {
+        assert!(
+            hash(EXAMPLE_UID).starts_with(EXPECTED),
+            "Hash algorithm was changed from what was originally used by Jennings on 2023-08-06"
+        )
+    }
+
+    #[test]
+    fn test_parse_default() {
+        let template = PathTemplate::default();
+        assert_eq!(template.components.len(), 4);
+        assert_eq!(
+            template.components[2][0],
+            Token::Element {
+                tag: tags::SERIES_NUMBER,
+                keyword: "SeriesNumber".to_string(),
+                func: Some(TemplateFn::Pad {
+                    width: 5,
+                    fill: '0'
+                })
+            }
+        );
+        assert_eq!(
+            template.components[2][4],
+            Token::Element {
+                tag: tags::SERIES_INSTANCE_UID,
+                keyword: "SeriesInstanceUID".to_string(),
+                func: Some(TemplateFn::Hash { len: 7 })
+            }
+        );
+        assert_eq!(
+            template.components[3][3],
+            Token::Literal(".dcm".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_shorthand() {
+        let template: PathTemplate = "%_hash|SeriesInstanceUID/%_upper_Modality".parse().unwrap();
+        assert_eq!(
+            template.components[0][0],
+            Token::Element {
+                tag: tags::SERIES_INSTANCE_UID,
+                keyword: "SeriesInstanceUID".to_string(),
+                func: Some(TemplateFn::Hash { len: 16 })
+            }
+        );
+        assert_eq!(
+            template.components[1][0],
+            Token::Element {
+                tag: tags::MODALITY,
+                keyword: "Modality".to_string(),
+                func: Some(TemplateFn::Upper)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "%NotATag".parse::<PathTemplate>(),
+            Err(TemplateError::UnknownTag("NotATag".to_string()))
+        );
+        assert_eq!(
+            "%_frobnicate_PatientID".parse::<PathTemplate>(),
+            Err(TemplateError::UnknownFunction("frobnicate".to_string()))
+        );
+        assert_eq!(
+            "%_pad|x_PatientID".parse::<PathTemplate>(),
+            Err(TemplateError::InvalidArguments {
+                func: "pad".to_string(),
+                args: "x".to_string()
+            })
+        );
+        assert_eq!(
+            "%PatientID//x".parse::<PathTemplate>(),
+            Err(TemplateError::EmptyComponent(1))
+        );
+        assert_eq!(
+            "100%".parse::<PathTemplate>(),
+            Err(TemplateError::StrayPercent(3))
+        );
+    }
+
+    #[test]
+    fn test_render_dot_components() {
+        use dicom::core::{DataElement, PrimitiveValue, VR};
+        use dicom::dictionary_std::uids;
+        use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+
+        let template: PathTemplate = "%PatientID/%StudyID/%AccessionNumber/%SOPInstanceUID.dcm"
+            .parse()
+            .unwrap();
+        let dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, PrimitiveValue::from("..")),
+            DataElement::new(tags::STUDY_ID, VR::SH, PrimitiveValue::from(".")),
+            DataElement::new(tags::ACCESSION_NUMBER, VR::SH, PrimitiveValue::from("")),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3"),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::MR_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid("1.2.3")
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap();
+        assert_eq!(template.render(&dcm), ["_", "_", "_", "1.2.3.dcm"]);
+    }
+
+    #[test]
+    fn test_functions() {
+        let pad = TemplateFn::Pad {
+            width: 5,
+            fill: '0',
+        };
+        assert_eq!(pad.apply("12"), "00012");
+        assert_eq!(pad.apply("123456"), "123456");
+        assert_eq!(TemplateFn::Trunc { len: 3 }.apply("abcdef"), "abc");
+        assert_eq!(
+            TemplateFn::Nospc {
+                replacement: "-".to_string()
+            }
+            .apply("SAG  MPRAGE 220"),
+            "SAG-MPRAGE-220"
+        );
+        assert_eq!(
+            TemplateFn::Strmsk {
+                mask: "******01".to_string()
+            }
+            .apply("20090701"),
+            "20090701"
+        );
+        assert_eq!(
+            TemplateFn::Strmsk {
+                mask: "****0101".to_string()
+            }
+            .apply("20090725"),
+            "20090101"
+        );
+        assert_eq!(TemplateFn::Hash { len: 7 }.apply(EXAMPLE_UID), EXPECTED);
+    }
+}
@@ -0,0 +1,150 @@
+//! Data dictionary of private DICOM tags, e.g. Siemens CSA, GE and Philips private elements.
+use dicom::core::Tag;
+use dicom::object::InMemDicomObject;
+use hashbrown::HashMap;
+use std::str::FromStr;
+
+/// Names of private tags, by their Private Creator.
+///
+/// It is parsed from text in the format of DCMTK's `private.dic`, where each line is:
+///
+/// ```text
+/// (0029,"SIEMENS CSA HEADER",10)    OB    CSAImageHeaderInfo    1    PrivateTag
+/// ```
+///
+/// i.e. the group, Private Creator, and last byte of the element number, followed by
+/// the VR and name of the element. Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrivateDictionary {
+    /// Names by Private Creator and (group, element byte)
+    entries: HashMap<String, HashMap<(u16, u8), String>>,
+}
+
+/// Error parsing a [PrivateDictionary].
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("invalid private dictionary entry on line {line}: {text:?}")]
+pub struct PrivateDictionaryError {
+    line: usize,
+    text: String,
+}
+
+impl FromStr for PrivateDictionary {
+    type Err = PrivateDictionaryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut dictionary = Self::default();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (group, creator, element, name) =
+                parse_entry(line).ok_or_else(|| PrivateDictionaryError {
+                    line: i + 1,
+                    text: line.to_string(),
+                })?;
+            dictionary
+                .entries
+                .entry(creator.to_string())
+                .or_default()
+                .insert((group, element), name.to_string());
+        }
+        Ok(dictionary)
+    }
+}
+
+/// Parse a line of `private.dic`.
+fn parse_entry(line: &str) -> Option<(u16, &str, u8, &str)> {
+    let (tag, rest) = line.strip_prefix('(')?.split_once(')')?;
+    let (group, rest_of_tag) = tag.split_once(',')?;
+    let (creator, element) = rest_of_tag.strip_prefix('"')?.rsplit_once("\",")?;
+    let group = u16::from_str_radix(group.trim(), 16).ok()?;
+    let element = u8::from_str_radix(element.trim(), 16).ok()?;
+    // rest is: VR, name, VM, version
+    let name = rest.split_whitespace().nth(1)?;
+    Some((group, creator.trim(), element, name))
+}
+
+impl PrivateDictionary {
+    /// Add the entries of another dictionary, replacing existing entries of the same tags.
+    pub fn extend(&mut self, other: PrivateDictionary) {
+        for (creator, names) in other.entries {
+            self.entries.entry(creator).or_default().extend(names);
+        }
+    }
+
+    /// Get the name of a private tag, which is found in `dcm` along with its Private Creator.
+    pub(crate) fn name_of(&self, dcm: &InMemDicomObject, tag: Tag) -> Option<&str> {
+        if !is_private(tag) || tag.element() < 0x1000 {
+            return None;
+        }
+        let block = tag.element() >> 8;
+        let creator = private_creator(dcm, tag.group(), block)?;
+        self.entries
+            .get(creator.as_str())?
+            .get(&(tag.group(), (tag.element() & 0xff) as u8))
+            .map(String::as_str)
+    }
+}
+
+/// Get the Private Creator of a block of private elements.
+fn private_creator(dcm: &InMemDicomObject, group: u16, block: u16) -> Option<String> {
+    let creator = dcm.element(Tag(group, block)).ok()?.to_str().ok()?;
+    Some(creator.trim_end_matches(['\0', ' ']).to_string())
+}
+
+/// Find the block of private elements reserved by `creator`, e.g. `0x10` for elements (gggg,10xx).
+pub(crate) fn private_block(dcm: &InMemDicomObject, group: u16, creator: &str) -> Option<u16> {
+    (0x10..=0xff).find(|block| private_creator(dcm, group, *block).as_deref() == Some(creator))
+}
+
+/// Private tags are those with an odd group number.
+pub(crate) fn is_private(tag: Tag) -> bool {
+    tag.group() % 2 == 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+
+    const EXAMPLE: &str = r#"
+# Siemens
+(0019,"SIEMENS MR HEADER",0a)	US	NumberOfImagesInMosaic	1	PrivateTag
+(0029,"SIEMENS CSA HEADER",10)	OB	CSAImageHeaderInfo	1	PrivateTag
+"#;
+
+    #[test]
+    fn test_name_of() {
+        let dictionary: PrivateDictionary = EXAMPLE.parse().unwrap();
+        let dcm = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                Tag(0x0019, 0x0011),
+                VR::LO,
+                PrimitiveValue::from("SIEMENS MR HEADER "),
+            ),
+            DataElement::new(Tag(0x0019, 0x110a), VR::US, PrimitiveValue::from(4_u16)),
+            DataElement::new(Tag(0x0019, 0x120a), VR::US, PrimitiveValue::from(4_u16)),
+        ]);
+        let cases = [
+            (Tag(0x0019, 0x110a), Some("NumberOfImagesInMosaic")),
+            // no Private Creator for block 0x12
+            (Tag(0x0019, 0x120a), None),
+            // Private Creator element itself
+            (Tag(0x0019, 0x0011), None),
+            // right element byte, but wrong creator
+            (Tag(0x0029, 0x1010), None),
+        ];
+        for (tag, expected) in cases {
+            assert_eq!(dictionary.name_of(&dcm, tag), expected, "{tag}");
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = "\n(0019,SIEMENS MR HEADER,0a)\tUS\tNumberOfImagesInMosaic"
+            .parse::<PrivateDictionary>()
+            .unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+}
@@ -0,0 +1,201 @@
+//! Quarantine of corrupt log files.
+//!
+//! Some log files, e.g. `patientData/<PatientID>.json`, are read, modified and written back.
+//! If such a file cannot be read, it is moved to `quarantine/` in the log dir instead of being
+//! overwritten, and the values which can be salvaged from it are merged into its replacement.
+use camino::{Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::OnceLock;
+
+/// Name of the directory in the log dir where corrupt files are moved to.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// A log file which could not be read, and was moved to quarantine.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Quarantined {
+    /// Where the file was.
+    pub path: Utf8PathBuf,
+    /// Where the file was moved to.
+    pub quarantine: Utf8PathBuf,
+    /// Why the file could not be read.
+    pub reason: String,
+    /// Keys of the values which were salvaged from the file, and merged into its replacement.
+    pub salvaged: Vec<String>,
+}
+
+/// Where to move a corrupt file `p` to: its path relative to `log_dir`, under
+/// `log_dir/quarantine`, suffixed with the Unix time in seconds.
+///
+/// If `p` was already quarantined at the same time, a number is appended as well,
+/// e.g. `p1.json.100-1`, so that the earlier file is not overwritten. The path stays free
+/// until used, since `p` is only quarantined while holding its lock
+/// (see [crate::dry_run::Output::with_lock]).
+pub(crate) fn quarantine_path(p: &Utf8Path, log_dir: &Utf8Path, time: u64) -> Utf8PathBuf {
+    let relative = p
+        .strip_prefix(log_dir)
+        .ok()
+        .or_else(|| p.file_name().map(Utf8Path::new))
+        .unwrap_or(p);
+    let quarantined = log_dir.join(QUARANTINE_DIR).join(relative);
+    let name = format!("{}.{time}", quarantined.file_name().unwrap_or_default());
+    std::iter::once(name.clone())
+        .chain((1..).map(|n| format!("{name}-{n}")))
+        .map(|name| quarantined.with_file_name(name))
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+/// A key followed by a string, a number, `true`, `false`, `null` or the start of an array.
+/// Numbers must be followed by a delimiter, so that a truncated number is not salvaged.
+const KEY_VALUE: &str = r#""((?:[^"\\]|\\.)*)"\s*:\s*("(?:[^"\\]|\\.)*"|true|false|null|\[[^\[\]{}]*|-?\d+(?:\.\d+)?(?:[eE][+-]?\d+)?\s*[,}\]])"#;
+
+/// A string or a number in an array.
+const ELEMENT: &str = r#""(?:[^"\\]|\\.)*"|-?\d+(?:\.\d+)?(?:[eE][+-]?\d+)?\s*[,\]]"#;
+
+static KEY_VALUE_RE: OnceLock<Regex> = OnceLock::new();
+static ELEMENT_RE: OnceLock<Regex> = OnceLock::new();
+
+/// Find the values of keys in text which is not valid JSON, e.g. a truncated JSON file.
+///
+/// Only strings, numbers, booleans, nulls and arrays thereof can be salvaged. Arrays are
+/// salvaged up to the first element which is incomplete. Objects are flattened, so a key
+/// which occurs more than once keeps its first value.
+pub(crate) fn salvage(text: &str) -> Map<String, Value> {
+    let mut salvaged = Map::new();
+    let key_value_re = KEY_VALUE_RE.get_or_init(|| Regex::new(KEY_VALUE).unwrap());
+    let element_re = ELEMENT_RE.get_or_init(|| Regex::new(ELEMENT).unwrap());
+    for captures in key_value_re.captures_iter(text) {
+        let Ok(key) = serde_json::from_str::<String>(&format!("\"{}\"", &captures[1])) else {
+            continue;
+        };
+        if salvaged.contains_key(&key) {
+            continue;
+        }
+        let raw = &captures[2];
+        let value = if let Some(elements) = raw.strip_prefix('[') {
+            let elements = element_re
+                .find_iter(elements)
+                .filter_map(|m| parse_scalar(m.as_str()))
+                .collect();
+            Some(Value::Array(elements))
+        } else {
+            parse_scalar(raw)
+        };
+        if let Some(value) = value {
+            salvaged.insert(key, value);
+        }
+    }
+    salvaged
+}
+
+fn parse_scalar(raw: &str) -> Option<Value> {
+    let raw = raw.trim_end_matches(|c: char| c == ',' || c == '}' || c == ']' || c.is_whitespace());
+    serde_json::from_str(raw).ok()
+}
+
+/// Merge salvaged values into `data`, in place of the values of the same key and type.
+/// Arrays are merged by appending the salvaged elements which they do not have.
+/// Nulls are replaced by salvaged values of any type.
+///
+/// Objects are merged recursively, since [salvage] flattens them. Returns the keys merged.
+pub(crate) fn merge_salvaged(data: &mut Value, salvaged: &Map<String, Value>) -> Vec<String> {
+    let mut merged = Vec::new();
+    let Value::Object(map) = data else {
+        return merged;
+    };
+    for (key, value) in map.iter_mut() {
+        match (value, salvaged.get(key)) {
+            (value @ Value::Object(_), _) => merged.extend(merge_salvaged(value, salvaged)),
+            (Value::Array(values), Some(Value::Array(more))) => {
+                for v in more {
+                    if !values.contains(v) {
+                        values.push(v.clone());
+                    }
+                }
+                merged.push(key.clone());
+            }
+            (value, Some(s)) if value.is_null() || same_type(value, s) => {
+                *value = s.clone();
+                merged.push(key.clone());
+            }
+            _ => (),
+        }
+    }
+    merged
+}
+
+fn same_type(a: &Value, b: &Value) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_salvage_truncated() {
+        let text = r#"{
+  "patient1": {
+    "PatientID": "patient1",
+    "PatientAge": 42,
+    "StudyList": [
+      "1.2.3",
+      "1.2.4",
+      "1.2."#;
+        let expected = json!({
+            "PatientID": "patient1",
+            "PatientAge": 42,
+            "StudyList": ["1.2.3", "1.2.4"],
+        });
+        assert_eq!(Value::Object(salvage(text)), expected);
+        assert_eq!(Value::Object(salvage(r#"{"received": 12"#)), json!({}));
+        assert!(salvage("\u{0}garbage\u{ff}").is_empty());
+    }
+
+    #[test]
+    fn test_merge_salvaged() {
+        let mut data = json!({
+            "patient1": {"PatientName": "NEW", "StudyList": ["1.2.5"], "expected": null},
+            "received": 0,
+        });
+        let salvaged = salvage(
+            r#"{"StudyList": ["1.2.3", "1.2.5"], "PatientName": "OLD", "expected": 3, "received": "x""#,
+        );
+        let merged = merge_salvaged(&mut data, &salvaged);
+        let expected = json!({
+            "patient1": {"PatientName": "OLD", "StudyList": ["1.2.5", "1.2.3"], "expected": 3},
+            "received": 0,
+        });
+        assert_eq!(data, expected);
+        assert_eq!(merged, ["PatientName", "StudyList", "expected"]);
+    }
+
+    #[test]
+    fn test_quarantine_path_is_free() {
+        let tmp_dir = TempDir::new("quarantine").unwrap();
+        let log_dir = Utf8Path::from_path(tmp_dir.path()).unwrap();
+        let p = log_dir.join("patientData/p1.json");
+        let first = quarantine_path(&p, log_dir, 100);
+        fs_err::create_dir_all(first.parent().unwrap()).unwrap();
+        fs_err::write(&first, "corrupt").unwrap();
+        let second = quarantine_path(&p, log_dir, 100);
+        assert_eq!(second, log_dir.join("quarantine/patientData/p1.json.100-1"));
+    }
+
+    #[test]
+    fn test_quarantine_path() {
+        let log_dir = Utf8Path::new("/log");
+        assert_eq!(
+            quarantine_path(&log_dir.join("patientData/p1.json"), log_dir, 100),
+            "/log/quarantine/patientData/p1.json.100"
+        );
+        assert_eq!(
+            quarantine_path(Utf8Path::new("/elsewhere/p1.json"), log_dir, 100),
+            "/log/quarantine/p1.json.100"
+        );
+    }
+}
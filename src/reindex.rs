@@ -0,0 +1,232 @@
+//! Rebuilding the pypx log dir from the DICOM files in a data dir.
+use crate::batch::{find_files, FoundFiles};
+use crate::dicom_data::{CommonElements, DicomTagAndError};
+use crate::dry_run::{Output, PlannedFile};
+use crate::errors::{ErrorKind, RepackError};
+use crate::log_write::write_logs;
+use crate::pack_path::PypxPath;
+use crate::quarantine::Quarantined;
+use crate::repack::{destination, notify_series_complete, RepackOptions};
+use crate::series_progress::{logged_series, recount_series, Received, SeriesComplete};
+use crate::tolerant_read::{self, looks_like_dicom, Repair};
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::dictionary_std::tags;
+use hashbrown::HashSet;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+
+/// Name of the file in the log dir which lists the DICOM files already reindexed.
+const JOURNAL_NAME: &str = ".reindex.journal";
+
+/// Counts of what happened during [reindex].
+#[derive(Debug, Default, Serialize)]
+pub struct ReindexSummary {
+    /// Number of files found.
+    pub total: usize,
+    /// Number of files which were reindexed.
+    pub reindexed: usize,
+    /// Number of files which were reindexed by a previous, interrupted [reindex].
+    pub skipped: usize,
+    /// Number of files which are not where they would be repacked to today.
+    pub mismatched: usize,
+    /// Number of files which are not DICOM, e.g. NIfTI files.
+    pub ignored: usize,
+    /// Number of DICOM files which could not be reindexed. A file which cannot be read
+    /// counts as a DICOM file if it looks like one, i.e. by its extension or magic code.
+    pub failed: usize,
+    /// Number of files which could not be reindexed, by kind of error.
+    pub errors: BTreeMap<ErrorKind, usize>,
+    /// Series which became complete when their instances were recounted.
+    #[serde(skip)]
+    pub series_complete: Vec<SeriesComplete>,
+}
+
+/// What [reindex] did with a DICOM file.
+pub struct ReindexOutcome {
+    /// Where the file would be repacked to today, if that is not where it is.
+    pub expected: Option<Utf8PathBuf>,
+    /// Elements which could not be read.
+    pub missing: Vec<DicomTagAndError>,
+    /// In a dry run, the files which would have been written, or left as they are.
+    pub plan: Option<Vec<PlannedFile>>,
+    /// Log files which were corrupt, and were moved to quarantine.
+    pub quarantined: Vec<Quarantined>,
+    /// Non-conformance of the DICOM file which was worked around to read it.
+    pub repairs: Vec<Repair>,
+}
+
+/// Write the logs of every DICOM file under [RepackOptions::data_dir] to
+/// [RepackOptions::log_dir], as if the files had just been repacked,
+/// using a pool of `jobs` worker threads (or the number of CPUs, if `jobs` is zero).
+///
+/// Reindexed files are listed in a journal in the log dir, so that an interrupted
+/// reindex can be resumed by running it again. Options which change DICOM objects,
+/// i.e. [RepackOptions::deidentify] and [RepackOptions::transcode], are ignored.
+/// Once every file is reindexed, the instances received of every series in the log dir are
+/// recounted as the distinct instances whose logged files still exist, so that reindexing into
+/// a log dir which already has some of them does not count them twice. Series which became
+/// complete are returned in [ReindexSummary::series_complete], and passed to
+/// [RepackOptions::on_series_complete]. In a [RepackOptions::dry_run], nothing is recounted.
+///
+/// The outcome of each file is passed to `on_outcome`, which is called from the worker threads.
+pub fn reindex<F>(
+    jobs: usize,
+    options: &RepackOptions,
+    on_outcome: F,
+) -> anyhow::Result<ReindexSummary>
+where
+    F: Fn(&Utf8Path, &Result<ReindexOutcome, RepackError>) + Sync,
+{
+    let Some(log_dir) = &options.log_dir else {
+        anyhow::bail!("Cannot reindex without a log dir");
+    };
+    let journal_path = log_dir.join(JOURNAL_NAME);
+    let done = read_journal(&journal_path)?;
+    let FoundFiles { files, unreadable } = find_files(&options.data_dir)?;
+    let files: Vec<_> = files
+        .into_iter()
+        .filter(|p| !p.file_name().is_some_and(|n| n.starts_with('.')))
+        .collect();
+    let journal = if options.dry_run {
+        None
+    } else {
+        fs_err::create_dir_all(log_dir)?;
+        let file = fs_err::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)?;
+        Some(Mutex::new(file))
+    };
+
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    let summary = Mutex::new(ReindexSummary {
+        total: files.len() + unreadable.len(),
+        ..Default::default()
+    });
+    for (dicom_file, e) in unreadable {
+        let outcome = Err(RepackError::from(e));
+        let mut summary = summary.lock().unwrap();
+        summary.failed += 1;
+        *summary.errors.entry(ErrorKind::Io).or_insert(0) += 1;
+        on_outcome(&dicom_file, &outcome);
+    }
+    pool.install(|| {
+        files.par_iter().for_each(|dicom_file| {
+            if done.contains(dicom_file) {
+                summary.lock().unwrap().skipped += 1;
+                return;
+            }
+            let outcome =
+                std::panic::catch_unwind(AssertUnwindSafe(|| reindex_file(dicom_file, options)))
+                    .unwrap_or(Err(RepackError::Panic));
+            let journaled = match (&outcome, &journal) {
+                (Ok(_), Some(journal)) => append(journal, dicom_file),
+                _ => Ok(()),
+            };
+            let outcome = journaled.map_err(RepackError::from).and(outcome);
+            on_outcome(dicom_file, &outcome);
+
+            let mut summary = summary.lock().unwrap();
+            match &outcome {
+                Ok(o) => {
+                    summary.reindexed += 1;
+                    if o.expected.is_some() {
+                        summary.mismatched += 1;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotDicom && !looks_like_dicom(dicom_file) => {
+                    summary.ignored += 1
+                }
+                Err(e) => {
+                    summary.failed += 1;
+                    *summary.errors.entry(e.kind()).or_insert(0) += 1;
+                }
+            }
+        })
+    });
+    let mut summary = summary.into_inner().unwrap();
+    if !options.dry_run {
+        let series_data_dir = log_dir.join("seriesData");
+        let series = logged_series(&series_data_dir)?;
+        let completed = pool.install(|| {
+            series
+                .par_iter()
+                .map(|uid| {
+                    recount_series(
+                        &series_data_dir,
+                        uid,
+                        options.series_timeout,
+                        &Output::new(false),
+                    )
+                })
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        summary.series_complete = completed.into_iter().flatten().collect();
+        for series in &summary.series_complete {
+            notify_series_complete(Some(series), options);
+        }
+    }
+    Ok(summary)
+}
+
+/// Write the logs of one DICOM file, which is already in the data dir.
+fn reindex_file(
+    dicom_file: &Utf8Path,
+    options: &RepackOptions,
+) -> Result<ReindexOutcome, RepackError> {
+    // pixel data is only needed for its BulkDataURI in DICOM JSON
+    let read_until = Some(tags::PIXEL_DATA).filter(|_| options.dicom_json.is_none());
+    let (dcm, repairs) = tolerant_read::open_file(dicom_file, read_until)?;
+    let common: CommonElements = (&dcm).try_into()?;
+    let out = Output::new(options.dry_run);
+    let unpack = PypxPath {
+        path: dicom_file.to_path_buf(),
+        dir: dicom_file
+            .parent()
+            .unwrap_or(Utf8Path::new(""))
+            .to_path_buf(),
+        fname: dicom_file.file_name().unwrap_or_default().to_string(),
+    };
+    let expected = Some(destination(&dcm, &common, options).path).filter(|p| p != dicom_file);
+    // series are not completed until they are recounted
+    let (missing, _) = write_logs(
+        &dcm,
+        &common,
+        &unpack,
+        Received::Recount,
+        None,
+        options,
+        &out,
+    )?;
+    let (plan, quarantined) = out.into_parts();
+    Ok(ReindexOutcome {
+        expected,
+        missing,
+        plan,
+        quarantined,
+        repairs,
+    })
+}
+
+/// Read the DICOM files already reindexed.
+fn read_journal(p: &Utf8Path) -> io::Result<HashSet<Utf8PathBuf>> {
+    let file = match fs_err::File::open(p) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+    io::BufReader::new(file)
+        .lines()
+        .map(|line| line.map(Utf8PathBuf::from))
+        .collect()
+}
+
+/// Record that a DICOM file was reindexed.
+fn append(journal: &Mutex<fs_err::File>, dicom_file: &Utf8Path) -> io::Result<()> {
+    let mut file = journal.lock().unwrap();
+    file.write_all(format!("{dicom_file}\n").as_bytes())
+}
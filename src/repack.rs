@@ -1,68 +1,352 @@
+use crate::atomic_write::write_atomically;
+use crate::bids::{self, BidsLayout};
+use crate::conflict::{self, OnConflict, Placement};
+use crate::deidentify::Deidentifier;
+use crate::dicom_json::DicomJsonMode;
+use crate::dry_run::{Output, PlannedFile};
+use crate::errors::RepackError;
 use crate::log_write::write_logs;
 use crate::pack_path::PypxPath;
+use crate::path_template::PathTemplate;
+use crate::private_dict::PrivateDictionary;
+use crate::quarantine::Quarantined;
+use crate::serialize_seriesmeta::{ElementFormat, SequenceFormat};
+use crate::series_progress::{SeriesComplete, SeriesCompleteCallback};
+use crate::tolerant_read::{self, Repair};
+use crate::transcode::{transcode, TranscodeError, TranscodeTarget, TransferSyntaxChange};
 use camino::{Utf8Path, Utf8PathBuf};
 
-use crate::dicom_data::DicomTagAndError;
+use crate::dicom_data::{CommonElements, DicomTagAndError};
+use dicom::dictionary_std::tags;
+use dicom::object::DefaultDicomObject;
 use std::path::Path;
+use std::time::Duration;
 
+/// Where and how DICOM files are repacked.
+pub struct RepackOptions {
+    /// Output directory for DICOM files.
+    pub data_dir: Utf8PathBuf,
+    /// Output directory for pypx DICOM tag data JSON files.
+    pub log_dir: Option<Utf8PathBuf>,
+    /// SQLite database in which to record repacked instances, see [crate::query].
+    pub index: Option<Utf8PathBuf>,
+    /// Also write DICOM tag data in the DICOM JSON Model to the log dir.
+    pub dicom_json: Option<DicomJsonMode>,
+    /// How sequence elements are serialized in the pypx studyData series meta files.
+    pub sequence_format: SequenceFormat,
+    /// Dictionary for naming private elements in the pypx studyData series meta files.
+    pub private_dictionary: PrivateDictionary,
+    /// Include private elements not found in `private_dictionary`, labeled by their tag.
+    pub keep_unknown_private: bool,
+    /// Path template for DICOM files under `data_dir`.
+    pub template: PathTemplate,
+    /// Put DICOM files in a BIDS layout instead of using `template`.
+    pub bids: Option<BidsLayout>,
+    /// What to do when a DICOM file already exists at its destination.
+    pub on_conflict: OnConflict,
+    /// De-identify DICOM objects before deciding their paths and writing them and their logs.
+    pub deidentify: Option<Deidentifier>,
+    /// Re-encode DICOM objects to this transfer syntax before writing them.
+    pub transcode: Option<TranscodeTarget>,
+    /// Consider a series complete when it has not received instances for this long.
+    /// See [crate::complete_idle_series].
+    pub series_timeout: Option<Duration>,
+    /// Called when a series becomes complete, e.g. to start processing it.
+    pub on_series_complete: Option<SeriesCompleteCallback>,
+    /// Only plan which files would be written, see [RepackOutcome::plan].
+    pub dry_run: bool,
+}
+
+impl RepackOptions {
+    /// Options for repacking to `data_dir` with the default template, without writing logs.
+    pub fn new(data_dir: impl Into<Utf8PathBuf>) -> Self {
+        Self {
+            data_dir: data_dir.into(),
+            log_dir: None,
+            index: None,
+            dicom_json: None,
+            sequence_format: SequenceFormat::default(),
+            private_dictionary: PrivateDictionary::default(),
+            keep_unknown_private: false,
+            template: PathTemplate::default(),
+            bids: None,
+            on_conflict: OnConflict::default(),
+            deidentify: None,
+            transcode: None,
+            series_timeout: None,
+            on_series_complete: None,
+            dry_run: false,
+        }
+    }
+
+    /// How elements are serialized in the pypx studyData series meta files.
+    pub(crate) fn element_format(&self) -> ElementFormat<'_> {
+        ElementFormat {
+            sequences: self.sequence_format,
+            private_dictionary: &self.private_dictionary,
+            keep_unknown_private: self.keep_unknown_private,
+        }
+    }
+}
+
+/// Copy (or move, if `cleanup` is true) a DICOM file to the data dir,
+/// and write its tag data to the log dir.
+///
+/// A file which does not conform to the DICOM file format is repaired if possible,
+/// see [RepackOutcome::repairs].
 pub fn repack(
     dicom_file: &Utf8Path,
-    data_dir: &Utf8Path,
-    log_dir: Option<&Utf8Path>,
     cleanup: bool,
-) -> anyhow::Result<RepackOutcome> {
-    let dcm = dicom::object::open_file(dicom_file)?;
-    let common = (&dcm).try_into()?;
-    let unpack = PypxPath::new(&common, data_dir);
+    options: &RepackOptions,
+) -> Result<RepackOutcome, RepackError> {
+    let (dcm, repairs) = tolerant_read::open_file(dicom_file, None)?;
+    let (dcm, transcoded) = prepare(dcm, options);
+    // a repaired file is written as it was read, so that it conforms to the DICOM file format
+    let changed = !repairs.is_empty()
+        || options.deidentify.is_some()
+        || transcoded
+            .as_ref()
+            .is_some_and(|(change, _)| change.is_changed());
+    let source = if changed {
+        // the file is not copied as-is, since its content is changed
+        Source::Object {
+            dcm: &dcm,
+            consumes: Some(dicom_file).filter(|_| cleanup),
+        }
+    } else {
+        Source::File {
+            path: dicom_file,
+            cleanup,
+        }
+    };
+    let outcome = place(&dcm, source, transcoded, options)?;
+    Ok(RepackOutcome { repairs, ..outcome })
+}
 
-    fs_err::create_dir_all(&unpack.dir)?;
-    copy_or_mv(dicom_file, &unpack.path, cleanup)?;
+/// Write an already parsed DICOM object to the data dir,
+/// and write its tag data to the log dir.
+///
+/// Same as [repack], but for DICOM objects which are not (or not yet) files.
+pub fn repack_object(
+    dcm: DefaultDicomObject,
+    options: &RepackOptions,
+) -> Result<RepackOutcome, RepackError> {
+    let (dcm, transcoded) = prepare(dcm, options);
+    let source = Source::Object {
+        dcm: &dcm,
+        consumes: None,
+    };
+    place(&dcm, source, transcoded, options)
+}
 
-    let missing = if let Some(d) = log_dir {
-        write_logs(&dcm, &common, &unpack, d)?
-    } else {
-        Vec::new()
+/// Transfer syntax change from transcoding, and why the transfer syntax was not changed.
+type Transcoded = (TransferSyntaxChange, Option<TranscodeError>);
+
+/// De-identify and transcode a DICOM object, as configured by `options`.
+fn prepare(
+    dcm: DefaultDicomObject,
+    options: &RepackOptions,
+) -> (DefaultDicomObject, Option<Transcoded>) {
+    let mut dcm = match &options.deidentify {
+        Some(deid) => deid.apply(dcm),
+        None => dcm,
     };
+    let transcoded = options.transcode.map(|target| transcode(&mut dcm, target));
+    (dcm, transcoded)
+}
+
+/// Where the data of a DICOM object being repacked comes from.
+enum Source<'a> {
+    /// A file, which is moved instead of copied if `cleanup` is true.
+    File { path: &'a Utf8Path, cleanup: bool },
+    /// A DICOM object in memory, which may have been read from a file that
+    /// should be removed after the object is written.
+    Object {
+        dcm: &'a DefaultDicomObject,
+        consumes: Option<&'a Utf8Path>,
+    },
+}
+
+impl Source<'_> {
+    /// Write the data to `dst` atomically, so that a crash never leaves
+    /// a truncated DICOM file in the data dir.
+    fn write(&self, dst: &Utf8Path, out: &Output) -> Result<(), RepackError> {
+        match self {
+            Source::File { path, cleanup } => out.copy_file(path, dst, *cleanup)?,
+            Source::Object { dcm, consumes } => {
+                out.write_with(dst, |file| dcm.write_all(file).map_err(RepackError::from))?;
+                if let Some(p) = consumes {
+                    out.remove_file(p)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Called instead of [Source::write] when the data is not needed.
+    fn discard(&self, out: &Output) -> std::io::Result<()> {
+        match self {
+            Source::File {
+                path,
+                cleanup: true,
+            } => out.remove_file(path),
+            Source::Object {
+                consumes: Some(path),
+                ..
+            } => out.remove_file(path),
+            _ => Ok(()),
+        }
+    }
+
+    /// Hash the data, for comparison with [conflict::hash_file].
+    fn content_hash(&self) -> Result<u64, RepackError> {
+        match self {
+            Source::File { path, .. } => Ok(conflict::hash_file(path)?),
+            Source::Object { dcm, .. } => {
+                let mut data = Vec::new();
+                dcm.write_all(&mut data)?;
+                Ok(seahash::hash(&data))
+            }
+        }
+    }
+}
+
+/// Where a DICOM object goes, before checking for a file already there.
+pub(crate) fn destination(
+    dcm: &DefaultDicomObject,
+    common: &CommonElements,
+    options: &RepackOptions,
+) -> PypxPath {
+    match &options.bids {
+        Some(bids) => bids.path(dcm, common, &options.data_dir),
+        None => PypxPath::new(&options.template, dcm, &options.data_dir),
+    }
+}
+
+/// Decide where a DICOM object goes, put it there, then write logs.
+fn place(
+    dcm: &DefaultDicomObject,
+    source: Source,
+    transcoded: Option<Transcoded>,
+    options: &RepackOptions,
+) -> Result<RepackOutcome, RepackError> {
+    let (transfer_syntax, transcode_error) = transcoded.unzip();
+    let common = dcm.try_into()?;
+    let out = Output::new(options.dry_run);
+    let mut unpack = destination(dcm, &common, options);
+
+    if options.bids.is_some() && !unpack.dir.is_dir() {
+        // first instance of a series
+        bids::write_dataset_files(dcm, &common, &options.data_dir, &out)?;
+    }
+    out.create_dir_all(&unpack.dir)?;
+    out.remove_stale_partials(&unpack.dir)?;
+    let resolution =
+        conflict::resolve(&unpack.path, options.on_conflict, || source.content_hash())?;
+    if let Some(dst) = resolution.dst {
+        source.write(&dst, &out)?;
+        unpack.fname = dst.file_name().unwrap_or_default().to_string();
+        unpack.path = dst;
+    } else {
+        source.discard(&out)?;
+        out.untouched(&unpack.path);
+    }
+
+    let (mut missing, series_complete) = write_logs(
+        dcm,
+        &common,
+        &unpack,
+        resolution.placement.into(),
+        transfer_syntax.as_ref(),
+        options,
+        &out,
+    )?;
+    // the file was stored in its original transfer syntax
+    if let Some(e) = transcode_error.flatten() {
+        missing.push(DicomTagAndError {
+            tag: tags::TRANSFER_SYNTAX_UID,
+            error: e.into(),
+        });
+    }
+    notify_series_complete(series_complete.as_ref(), options);
+    let (plan, quarantined) = out.into_parts();
     let outcome = RepackOutcome {
         dst: unpack.path,
+        placement: resolution.placement,
         missing,
+        series_complete,
         PatientID: common.PatientID.to_string(),
+        SOPInstanceUID: common.SOPInstanceUID.to_string(),
         SeriesInstanceUID: common.SeriesInstanceUID,
+        plan,
+        quarantined,
+        repairs: Vec::new(),
     };
-    anyhow::Ok(outcome)
+    Ok(outcome)
+}
+
+/// Call [RepackOptions::on_series_complete] if a series became complete.
+pub(crate) fn notify_series_complete(series: Option<&SeriesComplete>, options: &RepackOptions) {
+    // a dry run does not start processing of series
+    if options.dry_run {
+        return;
+    }
+    if let (Some(series), Some(on_series_complete)) = (series, &options.on_series_complete) {
+        on_series_complete(series);
+    }
 }
 
-/// Information about what the function [repack] did, for logging purposes.
+/// Information about what the functions [repack] and [repack_object] did, for logging purposes.
 #[allow(non_snake_case)]
 pub struct RepackOutcome {
     pub dst: Utf8PathBuf,
+    /// What was done about a possibly already existing file at `dst`.
+    pub placement: Placement,
+    /// Elements which could not be read, or could not be transcoded.
     pub missing: Vec<DicomTagAndError>,
+    /// The series of the DICOM file, if the DICOM file completed it.
+    pub series_complete: Option<SeriesComplete>,
     pub PatientID: String,
+    pub SOPInstanceUID: String,
     pub SeriesInstanceUID: String,
+    /// In a dry run, the files which would have been written, or left as they are.
+    pub plan: Option<Vec<PlannedFile>>,
+    /// Log files which were corrupt, and were moved to quarantine.
+    pub quarantined: Vec<Quarantined>,
+    /// Non-conformance of the DICOM file which was worked around to read it.
+    pub repairs: Vec<Repair>,
 }
 
-fn copy_or_mv<P: AsRef<Path>, Q: AsRef<Path>>(
+pub(crate) fn copy_or_mv<P: AsRef<Path>>(
     src: P,
-    dst: Q,
+    dst: &Utf8Path,
     cleanup: bool,
 ) -> std::io::Result<()> {
     if cleanup {
-        mv(&src, &dst)?;
+        mv(&src, dst)?;
     } else {
-        fs_err::copy(src, dst)?;
+        copy(&src, dst)?;
     }
 
     Ok(())
 }
 
+/// Copy a file atomically: readers of `dst` see either nothing or the complete file.
+fn copy<P: AsRef<Path>>(src: P, dst: &Utf8Path) -> std::io::Result<()> {
+    let mut reader = fs_err::File::open(src.as_ref())?;
+    write_atomically(dst, true, |file| {
+        std::io::copy(&mut reader, file).map(|_| ())
+    })
+}
+
 /// Rename a file.
-fn mv<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> std::io::Result<()> {
-    if fs_err::rename(&src, &dst).is_ok() {
+fn mv<P: AsRef<Path>>(src: P, dst: &Utf8Path) -> std::io::Result<()> {
+    if fs_err::rename(&src, dst).is_ok() {
         return Ok(());
     }
     // std::fs::rename is efficient, but will fail when src and dst are on different mount points
     // https://doc.rust-lang.org/std/fs/fn.rename.html
-    fs_err::copy(&src, &dst).and_then(|_| fs_err::remove_file(src))
+    copy(&src, dst).and_then(|_| fs_err::remove_file(src.as_ref()))
 }
 
 #[cfg(test)]
@@ -74,7 +358,9 @@ mod test {
     fn test_copy() {
         let tempdir = TempDir::new("repack_unit_test").unwrap();
         let src = tempdir.path().join("favorite_drink.txt");
-        let dst = tempdir.path().join("destination.txt");
+        let dst = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("destination.txt");
         fs_err::write(&src, "i enjoy bubble tea").unwrap();
         copy_or_mv(&src, &dst, false).unwrap();
 
@@ -89,9 +375,11 @@ mod test {
     fn test_mv() {
         let tempdir = TempDir::new("repack_unit_test").unwrap();
         let src = tempdir.path().join("favorite_drink.txt");
-        let dst = tempdir.path().join("destination.txt");
+        let dst = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("destination.txt");
         let data = "i enjoy bubble tea";
-        fs_err::write(&src, &data).unwrap();
+        fs_err::write(&src, data).unwrap();
         copy_or_mv(&src, &dst, true).unwrap();
 
         let copied_data =
@@ -1,12 +1,13 @@
 //! Helpers for serializing the stuff which goes into e.g.
 //! `/home/dicom/log/studyData/1.2.840.113845.11.1000000001785349915.20130308061609.6346698-series/1.3.12.2.1107.5.2.19.45152.2013030808061520200285270.0.0.0-meta.json`
 #![allow(non_snake_case)]
-use crate::dicom_data::name_of;
+use crate::csa::CsaHeaders;
+use crate::dicom_data::{header_elements, name_of};
 use crate::errors::ElementSerializationError;
+use crate::private_dict::{is_private, PrivateDictionary};
 use dicom::core::header::Header;
 use dicom::core::value::{DataSetSequence, Value};
-use dicom::core::{PrimitiveValue, VR};
-use dicom::dictionary_std::tags;
+use dicom::core::{PrimitiveValue, Tag, VR};
 use dicom::object::mem::InMemElement;
 use dicom::object::{DefaultDicomObject, InMemDicomObject};
 use hashbrown::HashMap;
@@ -14,11 +15,35 @@ use itertools::Itertools;
 use serde::Serialize;
 use std::borrow::Cow;
 
+/// How sequence elements, e.g. `ReferencedImageSequence`, are serialized
+/// in the pypx studyData series meta files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SequenceFormat {
+    /// A string resembling pydicom's repr, as written by px-repack
+    #[default]
+    Legacy,
+    /// JSON arrays of objects, which are keyed by label like the `DICOM` object itself
+    Nested,
+}
+
+/// How elements are serialized in [StudyDataSeriesMeta].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ElementFormat<'a> {
+    pub sequences: SequenceFormat,
+    /// Dictionary for labeling private elements.
+    pub private_dictionary: &'a PrivateDictionary,
+    /// Label private elements not found in `private_dictionary` by their tag, instead of dropping them.
+    pub keep_unknown_private: bool,
+}
+
 #[derive(Debug, Serialize)]
 pub(crate) struct StudyDataSeriesMeta<'a> {
     SeriesInstanceUID: &'a str,
     SeriesBaseDir: &'a str,
     DICOM: HashMap<String, ValueAndLabel<'a>>,
+    /// Decoded Siemens CSA headers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    CSA: Option<&'a CsaHeaders>,
 }
 
 impl<'a> StudyDataSeriesMeta<'a> {
@@ -26,40 +51,103 @@ impl<'a> StudyDataSeriesMeta<'a> {
         SeriesInstanceUID: &'a str,
         SeriesBaseDir: &'a str,
         dcm: &'a DefaultDicomObject,
+        format: ElementFormat<'a>,
+        CSA: Option<&'a CsaHeaders>,
     ) -> Self {
-        let DICOM = dcm
-            .iter()
-            .filter(|ele| ele.tag() != tags::PIXEL_DATA)
-            .map(ValueAndLabel::try_from)
-            .filter_map(|r| r.ok())
-            .map(|v| (v.label.to_string(), v))
-            .collect::<HashMap<String, ValueAndLabel>>();
+        let DICOM = serialize_elements(dcm, header_elements(dcm), format);
         Self {
             SeriesInstanceUID,
             SeriesBaseDir,
             DICOM,
+            CSA,
         }
     }
 }
 
+/// Serialize elements of `dcm` to a map keyed by their labels,
+/// skipping elements which cannot be serialized.
+fn serialize_elements<'a>(
+    dcm: &'a InMemDicomObject,
+    elements: impl Iterator<Item = &'a InMemElement>,
+    format: ElementFormat<'a>,
+) -> HashMap<String, ValueAndLabel<'a>> {
+    elements
+        .map(|ele| ValueAndLabel::new(dcm, ele, format))
+        .filter_map(|r| r.ok())
+        .map(|v| (v.label.to_string(), v))
+        .collect()
+}
+
+/// Get the label of an element: its standard name, its name from the private dictionary,
+/// or (if the private tag is unknown and `keep_unknown_private`) its tag.
+fn label_of<'a>(
+    dcm: &'a InMemDicomObject,
+    tag: Tag,
+    format: ElementFormat<'a>,
+) -> Result<Cow<'a, str>, ElementSerializationError> {
+    if !is_private(tag) {
+        return name_of(tag)
+            .map(Cow::Borrowed)
+            .ok_or(ElementSerializationError::UnknownTagError(tag));
+    }
+    if let Some(name) = format.private_dictionary.name_of(dcm, tag) {
+        Ok(Cow::Borrowed(name))
+    } else if format.keep_unknown_private {
+        Ok(Cow::Owned(tag.to_string()))
+    } else {
+        Err(ElementSerializationError::UnknownTagError(tag))
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct ValueAndLabel<'a> {
-    value: Cow<'a, str>,
-    label: &'a str,
+    value: SerializedValue<'a>,
+    label: Cow<'a, str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum SerializedValue<'a> {
+    Str(Cow<'a, str>),
+    Items(Vec<HashMap<String, ValueAndLabel<'a>>>),
 }
 
-impl<'a> TryFrom<&'a InMemElement> for ValueAndLabel<'a> {
-    type Error = ElementSerializationError;
-    fn try_from(ele: &'a InMemElement) -> Result<Self, Self::Error> {
+impl std::fmt::Display for SerializedValue<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerializedValue::Str(s) => f.write_str(s),
+            SerializedValue::Items(items) => {
+                let s = serde_json::to_string(items).map_err(|_| std::fmt::Error)?;
+                f.write_str(&s)
+            }
+        }
+    }
+}
+
+impl<'a> ValueAndLabel<'a> {
+    fn new(
+        dcm: &'a InMemDicomObject,
+        ele: &'a InMemElement,
+        format: ElementFormat<'a>,
+    ) -> Result<Self, ElementSerializationError> {
         let tag = ele.tag();
-        let label = name_of(tag).ok_or_else(|| ElementSerializationError::UnknownTagError(tag))?;
+        let label = label_of(dcm, tag, format)?;
         let value = match ele.value() {
-            Value::Primitive(value) => Ok(serialize_primitive(value, ele.vr())),
-            Value::Sequence(seq) => {
-                // e.g. tags with complex data such as ReferencedImageSequence and RequestAttributesSequence
-                let s = serialize_sequence(seq);
-                Ok(Cow::Owned(s))
+            Value::Primitive(value) => {
+                Ok(SerializedValue::Str(serialize_primitive(value, ele.vr())))
             }
+            // e.g. tags with complex data such as ReferencedImageSequence and RequestAttributesSequence
+            Value::Sequence(seq) => match format.sequences {
+                SequenceFormat::Legacy => Ok(SerializedValue::Str(Cow::Owned(serialize_sequence(
+                    seq, format,
+                )))),
+                SequenceFormat::Nested => Ok(SerializedValue::Items(
+                    seq.items()
+                        .iter()
+                        .map(|item| serialize_elements(item, item.iter(), format))
+                        .collect(),
+                )),
+            },
             Value::PixelSequence(_) => Err(ElementSerializationError::Excluded(tag)),
         }?;
         // println!("{} {} {:?} {}", label, ele.vr(), ele.value(), value);
@@ -74,7 +162,7 @@ impl<'a> TryFrom<&'a InMemElement> for ValueAndLabel<'a> {
 /// strings instead of floats. Thus we need to do some custom logic of our own.
 ///
 /// See discussion on Github: https://github.com/Enet4/dicom-rs/discussions/401
-fn serialize_primitive(value: &PrimitiveValue, vr: VR) -> Cow<str> {
+fn serialize_primitive(value: &PrimitiveValue, vr: VR) -> Cow<'_, str> {
     match value {
         PrimitiveValue::Strs(strs) => {
             if matches!(vr, VR::IS | VR::SS | VR::DS) {
@@ -113,10 +201,16 @@ fn serialize_nums<'a, D: std::fmt::Display>(
 }
 
 /// Serializer for [Value::Sequence].
-fn serialize_sequence(seq: &DataSetSequence<InMemDicomObject>) -> String {
+fn serialize_sequence(
+    seq: &DataSetSequence<InMemDicomObject>,
+    format: ElementFormat<'_>,
+) -> String {
     format!(
         "[{}]",
-        seq.items().iter().flat_map(serialize_subdicom).join("\n")
+        seq.items()
+            .iter()
+            .flat_map(|item| serialize_subdicom(item, format))
+            .join("\n")
     )
 }
 
@@ -125,11 +219,19 @@ fn serialize_sequence(seq: &DataSetSequence<InMemDicomObject>) -> String {
 /// Example for `RequestAttributesSequence`
 ///
 /// `"[(0040, 0007) Scheduled Procedure Step Descriptio LO: 'MR Brain'\n(0040, 0009) Scheduled Procedure Step ID         SH: '4101374'\n(0040, 1001) Requested Procedure ID              SH: '4101374']"`
-fn serialize_subdicom(dcm: &InMemDicomObject) -> impl Iterator<Item = String> + '_ {
-    dcm.iter().map(serialize_subdicom_element)
+fn serialize_subdicom<'a>(
+    dcm: &'a InMemDicomObject,
+    format: ElementFormat<'a>,
+) -> impl Iterator<Item = String> + 'a {
+    dcm.iter()
+        .map(move |ele| serialize_subdicom_element(dcm, ele, format))
 }
 
-fn serialize_subdicom_element(ele: &InMemElement) -> String {
+fn serialize_subdicom_element(
+    dcm: &InMemDicomObject,
+    ele: &InMemElement,
+    format: ElementFormat<'_>,
+) -> String {
     let tag = ele.tag();
     let vr = ele.vr();
 
@@ -137,9 +239,135 @@ fn serialize_subdicom_element(ele: &InMemElement) -> String {
     // human-readable name from the "Attribute Name" column of this table:
     // https://dicom.nema.org/medical/dicom/2020b/output/chtml/part03/sect_C.4.10.html
     // e.g. "Scheduled Procedure Step Descriptio"
-    let (label, value) = match ValueAndLabel::try_from(ele) {
+    let (label, value) = match ValueAndLabel::new(dcm, ele, format) {
         Ok(vl) => (vl.label, vl.value),
-        Err(e) => ("RX_REPACK_ERROR", Cow::Owned(e.to_string())),
+        Err(e) => (
+            Cow::Borrowed("RX_REPACK_ERROR"),
+            SerializedValue::Str(Cow::Owned(e.to_string())),
+        ),
     };
     format!("{tag} {label} {vr}: {value}")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dicom::core::{DataElement, Length};
+    use dicom::dictionary_std::{tags, uids};
+    use dicom::object::FileMetaTableBuilder;
+    use serde_json::json;
+
+    fn to_dicom(elements: Vec<InMemElement>) -> DefaultDicomObject {
+        InMemDicomObject::from_element_iter(elements)
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid(uids::MR_IMAGE_STORAGE)
+                    .media_storage_sop_instance_uid("1.2.3")
+                    .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+            )
+            .unwrap()
+    }
+
+    fn format(
+        sequences: SequenceFormat,
+        private_dictionary: &PrivateDictionary,
+    ) -> ElementFormat<'_> {
+        ElementFormat {
+            sequences,
+            private_dictionary,
+            keep_unknown_private: false,
+        }
+    }
+
+    #[test]
+    fn test_sequence_format() {
+        let item = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::REFERENCED_SOP_CLASS_UID,
+                VR::UI,
+                PrimitiveValue::from(uids::MR_IMAGE_STORAGE),
+            ),
+            DataElement::new(
+                tags::REFERENCED_SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from("1.2.3.4.5"),
+            ),
+        ]);
+        let dcm = to_dicom(vec![DataElement::new(
+            tags::REFERENCED_IMAGE_SEQUENCE,
+            VR::SQ,
+            Value::Sequence(DataSetSequence::new(vec![item], Length::UNDEFINED)),
+        )]);
+        let dictionary = PrivateDictionary::default();
+
+        let nested = StudyDataSeriesMeta::new(
+            "1.2.3.4",
+            "dir",
+            &dcm,
+            format(SequenceFormat::Nested, &dictionary),
+            None,
+        );
+        let actual = serde_json::to_value(nested).unwrap();
+        let expected = json!([{
+            "ReferencedSOPClassUID": {"value": uids::MR_IMAGE_STORAGE, "label": "ReferencedSOPClassUID"},
+            "ReferencedSOPInstanceUID": {"value": "1.2.3.4.5", "label": "ReferencedSOPInstanceUID"},
+        }]);
+        assert_eq!(
+            actual["DICOM"]["ReferencedImageSequence"]["value"],
+            expected
+        );
+
+        let legacy = StudyDataSeriesMeta::new(
+            "1.2.3.4",
+            "dir",
+            &dcm,
+            format(SequenceFormat::Legacy, &dictionary),
+            None,
+        );
+        let actual = serde_json::to_value(legacy).unwrap();
+        let value = actual["DICOM"]["ReferencedImageSequence"]["value"]
+            .as_str()
+            .unwrap();
+        assert!(value.starts_with("[(0008,1150) ReferencedSOPClassUID UI: "));
+        assert!(value.ends_with("\n(0008,1155) ReferencedSOPInstanceUID UI: 1.2.3.4.5]"));
+    }
+
+    #[test]
+    fn test_private_elements() {
+        let dcm = to_dicom(vec![
+            DataElement::new(
+                Tag(0x0019, 0x0010),
+                VR::LO,
+                PrimitiveValue::from("SIEMENS MR HEADER"),
+            ),
+            DataElement::new(Tag(0x0019, 0x100a), VR::US, PrimitiveValue::from(4_u16)),
+            DataElement::new(Tag(0x0019, 0x100b), VR::DS, PrimitiveValue::from("2.5")),
+        ]);
+        let dictionary: PrivateDictionary =
+            r#"(0019,"SIEMENS MR HEADER",0a)	US	NumberOfImagesInMosaic	1	PrivateTag"#
+                .parse()
+                .unwrap();
+
+        let meta = StudyDataSeriesMeta::new(
+            "1.2.3.4",
+            "dir",
+            &dcm,
+            format(SequenceFormat::Legacy, &dictionary),
+            None,
+        );
+        let labels: Vec<_> = meta.DICOM.keys().sorted().collect();
+        assert_eq!(labels, ["NumberOfImagesInMosaic"]);
+
+        let format = ElementFormat {
+            keep_unknown_private: true,
+            ..format(SequenceFormat::Legacy, &dictionary)
+        };
+        let meta = StudyDataSeriesMeta::new("1.2.3.4", "dir", &dcm, format, None);
+        let labels: Vec<_> = meta.DICOM.keys().sorted().collect();
+        assert_eq!(
+            labels,
+            ["(0019,0010)", "(0019,100B)", "NumberOfImagesInMosaic"]
+        );
+        assert_eq!(meta.DICOM["(0019,100B)"].value.to_string(), "2.5");
+    }
+}
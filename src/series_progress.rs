@@ -0,0 +1,502 @@
+//! Tracking of how many instances of each series have been received, to tell when a series is
+//! complete. Progress is stored in `seriesData/<SeriesInstanceUID>-progress.json`, and
+//! `seriesData/<SeriesInstanceUID>-pack.json` is written when the series is complete.
+#![allow(non_snake_case)]
+use crate::conflict::Placement;
+use crate::dicom_data::CommonElements;
+use crate::dry_run::Output;
+use crate::log_models::SERIES_PACK;
+use crate::log_write::load_json_carelessly;
+use crate::repack::RepackOptions;
+use crate::tolerant_read;
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::dictionary_std::tags;
+use dicom::object::DefaultDicomObject;
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PROGRESS_SUFFIX: &str = "-progress.json";
+
+/// Progress of receiving a series.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct SeriesProgress {
+    SeriesInstanceUID: String,
+    StudyInstanceUID: String,
+    SeriesBaseDir: Utf8PathBuf,
+    /// Number of distinct instances received so far
+    received: usize,
+    /// Number of instances in the series, if known
+    expected: Option<u32>,
+    /// Unix time in seconds of when the last instance was received
+    lastReceived: u64,
+    complete: bool,
+}
+
+/// How an instance counts towards the number of instances received of its series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Received {
+    /// The instance is received for the first time.
+    New,
+    /// The instance was received before, so it is not counted again.
+    Again,
+    /// It is unknown whether the instance was counted before, e.g. when reindexing into a log
+    /// dir which already has some of the instances. The count is left as it is, to be corrected
+    /// by [recount_series] once every instance is logged.
+    Recount,
+}
+
+impl From<Placement> for Received {
+    fn from(placement: Placement) -> Self {
+        if placement == Placement::New {
+            Received::New
+        } else {
+            Received::Again
+        }
+    }
+}
+
+/// Why a series is considered complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompletionReason {
+    /// The expected number of instances were received.
+    Count,
+    /// No instances were received for a while.
+    IdleTimeout,
+}
+
+/// A series which is complete, i.e. which is not expected to receive more instances.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SeriesComplete {
+    pub SeriesInstanceUID: String,
+    pub StudyInstanceUID: String,
+    /// Directory of the series under the data dir.
+    pub SeriesBaseDir: Utf8PathBuf,
+    /// Number of distinct instances received.
+    pub received: usize,
+    /// Number of instances in the series, if known.
+    pub expected: Option<u32>,
+    pub reason: CompletionReason,
+}
+
+/// Function called when a series becomes complete.
+pub type SeriesCompleteCallback = Box<dyn Fn(&SeriesComplete) + Send + Sync>;
+
+impl SeriesProgress {
+    /// Mark the series as complete if the expected number of instances have been received,
+    /// unless it already was.
+    fn complete_by_count(&mut self) -> Option<SeriesComplete> {
+        let reached = self.expected.is_some_and(|n| self.received >= n as usize);
+        (!self.complete && reached).then(|| {
+            self.complete = true;
+            self.to_complete(CompletionReason::Count)
+        })
+    }
+
+    fn to_complete(&self, reason: CompletionReason) -> SeriesComplete {
+        SeriesComplete {
+            SeriesInstanceUID: self.SeriesInstanceUID.clone(),
+            StudyInstanceUID: self.StudyInstanceUID.clone(),
+            SeriesBaseDir: self.SeriesBaseDir.clone(),
+            received: self.received,
+            expected: self.expected,
+            reason,
+        }
+    }
+}
+
+/// Get the number of instances in a series, from `NumberOfSeriesRelatedInstances`
+/// or `ImagesInAcquisition`.
+pub(crate) fn expected_instances(dcm: &DefaultDicomObject) -> Option<u32> {
+    [
+        tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+        tags::IMAGES_IN_ACQUISITION,
+    ]
+    .into_iter()
+    .find_map(|tag| dcm.element(tag).ok()?.to_int::<u32>().ok())
+    .filter(|n| *n > 0)
+}
+
+/// Record that an instance of a series was received, and write `-pack.json` if that made
+/// the series complete. Returns the series if it became complete.
+///
+/// `received` tells whether the instance was received before, so that it is not counted twice.
+///
+/// Without an `idle_timeout`, `-pack.json` is written immediately, like px-repack does,
+/// because a series which never reaches its expected number of instances, e.g. when
+/// some are lost, would otherwise never be packed. Completion by count is still returned.
+pub(crate) fn record_instance(
+    series_data_dir: &Utf8Path,
+    common: &CommonElements,
+    series_dir: &Utf8Path,
+    expected: Option<u32>,
+    received: Received,
+    idle_timeout: Option<Duration>,
+    out: &Output,
+) -> io::Result<Option<SeriesComplete>> {
+    let progress_fname =
+        series_data_dir.join(format!("{}{PROGRESS_SUFFIX}", &common.SeriesInstanceUID));
+    let complete = out.with_lock(&progress_fname, || {
+        let log_dir = series_data_dir.parent().unwrap_or(series_data_dir);
+        let mut progress = out.load_json(&progress_fname, log_dir, || SeriesProgress {
+            SeriesInstanceUID: common.SeriesInstanceUID.to_string(),
+            StudyInstanceUID: common.StudyInstanceUID.to_string(),
+            SeriesBaseDir: series_dir.to_path_buf(),
+            received: 0,
+            expected: None,
+            lastReceived: 0,
+            complete: false,
+        })?;
+        if received == Received::New {
+            progress.received += 1;
+        }
+        progress.expected = progress.expected.max(expected);
+        progress.lastReceived = unix_time();
+        let complete = if received == Received::Recount {
+            None
+        } else {
+            progress.complete_by_count()
+        };
+        out.write_json(&progress, &progress_fname)?;
+        io::Result::Ok(complete)
+    })?;
+    let pack_now = complete.is_some() || (idle_timeout.is_none() && received != Received::Recount);
+    if pack_now {
+        write_pack(series_data_dir, &common.SeriesInstanceUID, out)?;
+    }
+    Ok(complete)
+}
+
+/// Mark series as complete which have not received any instances for
+/// [RepackOptions::series_timeout], writing their `-pack.json` and calling
+/// [RepackOptions::on_series_complete]. Returns the series which became complete.
+///
+/// Does nothing in a [RepackOptions::dry_run].
+pub fn complete_idle_series(options: &RepackOptions) -> io::Result<Vec<SeriesComplete>> {
+    let (Some(log_dir), Some(timeout)) = (&options.log_dir, options.series_timeout) else {
+        return Ok(Vec::new());
+    };
+    if options.dry_run {
+        return Ok(Vec::new());
+    }
+    let completed = complete_idle(log_dir, timeout)?;
+    if let Some(on_series_complete) = &options.on_series_complete {
+        completed.iter().for_each(on_series_complete);
+    }
+    Ok(completed)
+}
+
+/// Mark series as complete which have not received any instances for `timeout`,
+/// writing their `-pack.json`. Returns the series which became complete.
+///
+/// Progress files modified within `timeout` are skipped without being read.
+fn complete_idle(log_dir: &Utf8Path, timeout: Duration) -> io::Result<Vec<SeriesComplete>> {
+    let series_data_dir = log_dir.join("seriesData");
+    let entries = match fs_err::read_dir(&series_data_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let now = unix_time();
+    let started = SystemTime::now();
+    let out = Output::new(false);
+    let mut completed = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(progress_fname) = entry
+            .file_name()
+            .to_str()
+            .filter(|name| name.ends_with(PROGRESS_SUFFIX) && !name.starts_with('.'))
+            .map(|name| series_data_dir.join(name))
+        else {
+            continue;
+        };
+        // a file modified within the timeout was received into recently, so it need not be
+        // locked and read. This keeps the scan cheap when it runs after every instance.
+        // an entry whose metadata cannot be read, e.g. because it was just removed,
+        // is left for the next scan
+        let Ok(modified) = entry.metadata().and_then(|m| m.modified()) else {
+            continue;
+        };
+        if modified + timeout > started {
+            continue;
+        }
+        let complete = out.with_lock(&progress_fname, || {
+            // corrupt files are left to be quarantined by the next instance of their series
+            let Some(mut progress) = load_json_carelessly::<_, SeriesProgress>(&progress_fname)
+            else {
+                return io::Result::Ok(None);
+            };
+            if progress.complete || now.saturating_sub(progress.lastReceived) < timeout.as_secs() {
+                return Ok(None);
+            }
+            progress.complete = true;
+            out.write_json(&progress, &progress_fname)?;
+            Ok(Some(progress.to_complete(CompletionReason::IdleTimeout)))
+        })?;
+        if let Some(complete) = complete {
+            write_pack(&series_data_dir, &complete.SeriesInstanceUID, &out)?;
+            completed.push(complete);
+        }
+    }
+    Ok(completed)
+}
+
+/// Set the number of instances received of a series to the number of distinct instances
+/// which are logged in `seriesData/<SeriesInstanceUID>-img` and still exist, see
+/// [count_instances]. Then mark the series as complete if it reached its expected number of
+/// instances, and write `-pack.json` if it did or if there is no `idle_timeout`.
+/// Returns the series if it became complete.
+///
+/// This corrects the counts of series after their instances were logged with
+/// [Received::Recount].
+pub(crate) fn recount_series(
+    series_data_dir: &Utf8Path,
+    series_instance_uid: &str,
+    idle_timeout: Option<Duration>,
+    out: &Output,
+) -> io::Result<Option<SeriesComplete>> {
+    let progress_fname = series_data_dir.join(format!("{series_instance_uid}{PROGRESS_SUFFIX}"));
+    let img_dir = series_data_dir.join(format!("{series_instance_uid}-img"));
+    let received = count_instances(&img_dir)?;
+    let complete = out.with_lock(&progress_fname, || {
+        // corrupt files are left to be quarantined by the next instance of their series
+        let Some(mut progress) = load_json_carelessly::<_, SeriesProgress>(&progress_fname) else {
+            return io::Result::Ok(None);
+        };
+        progress.received = received;
+        let complete = progress.complete_by_count();
+        out.write_json(&progress, &progress_fname)?;
+        Ok(complete)
+    })?;
+    if complete.is_some() || idle_timeout.is_none() {
+        write_pack(series_data_dir, series_instance_uid, out)?;
+    }
+    Ok(complete)
+}
+
+/// SeriesInstanceUIDs of the series which have instance logs in `seriesData`.
+pub(crate) fn logged_series(series_data_dir: &Utf8Path) -> io::Result<Vec<String>> {
+    let entries = match series_data_dir.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut series = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(uid) = entry.file_name().strip_suffix("-img") {
+            if !uid.starts_with('.') && entry.file_type()?.is_dir() {
+                series.push(uid.to_string());
+            }
+        }
+    }
+    Ok(series)
+}
+
+/// Count the distinct SOPInstanceUIDs of the DICOM files at the `FSlocation`s of the instance
+/// logs `<file name>.json` in `seriesData/<SeriesInstanceUID>-img`. Logs of files which no
+/// longer exist are not counted, nor are numbered copies of the same instance.
+fn count_instances(img_dir: &Utf8Path) -> io::Result<usize> {
+    let entries = match img_dir.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut instances = HashSet::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !name.ends_with(".json") || name.starts_with('.') {
+            continue;
+        }
+        let Some(log) = load_json_carelessly::<_, serde_json::Value>(entry.path()) else {
+            continue;
+        };
+        let locations = log
+            .as_object()
+            .into_iter()
+            .flat_map(|series| series.values())
+            .filter_map(|data| data.get("imageObj")?.as_object())
+            .flat_map(|files| files.values())
+            .filter_map(|file| file.get("FSlocation")?.as_str());
+        for location in locations {
+            // only the header up to SOPInstanceUID is needed
+            if let Ok((dcm, _)) = tolerant_read::open_file(location.into(), Some(tags::STUDY_DATE))
+            {
+                let uid = dcm.element(tags::SOP_INSTANCE_UID).ok();
+                if let Some(uid) = uid.and_then(|e| e.to_str().ok()) {
+                    instances.insert(uid.trim_end_matches(['\0', ' ']).to_string());
+                }
+            }
+        }
+    }
+    Ok(instances.len())
+}
+
+/// Write `seriesData/<SeriesInstanceUID>-pack.json`, which tells pypx that the series is packed.
+fn write_pack(
+    series_data_dir: &Utf8Path,
+    series_instance_uid: &str,
+    out: &Output,
+) -> io::Result<()> {
+    let pack_fname = series_data_dir.join(format!("{series_instance_uid}-pack.json"));
+    if !pack_fname.is_file() {
+        out.write_json(SERIES_PACK, &pack_fname)?;
+    } else {
+        out.untouched(&pack_fname);
+    }
+    Ok(())
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::log_write::write_json;
+    use tempdir::TempDir;
+
+    fn common(series_uid: &str) -> CommonElements<'static> {
+        CommonElements {
+            SOPInstanceUID: "1.2.3.4.5",
+            PatientID: "patient1",
+            PatientBirthDate: None,
+            StudyDescription: None,
+            StudyDate: None,
+            SeriesNumber: None,
+            SeriesDescription: None,
+            StudyInstanceUID: "1.2.3".to_string(),
+            SeriesInstanceUID: series_uid.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_complete_by_count() {
+        let tmp_dir = TempDir::new("series_progress").unwrap();
+        let dir = Utf8Path::from_path(tmp_dir.path()).unwrap();
+        let common = common("1.2.3.4");
+        let pack = dir.join("1.2.3.4-pack.json");
+        let record = |received| {
+            record_instance(
+                dir,
+                &common,
+                Utf8Path::new("series"),
+                Some(2),
+                received,
+                Some(Duration::from_secs(60)),
+                &Output::new(false),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(record(Received::New), None);
+        assert!(!pack.exists());
+        // duplicate instance is not counted
+        assert_eq!(record(Received::Again), None);
+        assert!(!pack.exists());
+        let complete = record(Received::New).unwrap();
+        assert_eq!(complete.reason, CompletionReason::Count);
+        assert_eq!(complete.received, 2);
+        assert!(pack.is_file());
+        // completion is only reported once
+        assert_eq!(record(Received::New), None);
+    }
+
+    #[test]
+    fn test_complete_idle_series() {
+        let tmp_dir = TempDir::new("series_progress").unwrap();
+        let log_dir = Utf8Path::from_path(tmp_dir.path()).unwrap();
+        let series_data_dir = log_dir.join("seriesData");
+        let timeout = Some(Duration::from_secs(60));
+        for uid in ["1.2.3.4", "1.2.3.5"] {
+            record_instance(
+                &series_data_dir,
+                &common(uid),
+                Utf8Path::new("series"),
+                None,
+                Received::New,
+                timeout,
+                &Output::new(false),
+            )
+            .unwrap();
+        }
+        assert!(!series_data_dir.join("1.2.3.4-pack.json").exists());
+        assert!(complete_idle(log_dir, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+
+        let completed = complete_idle(log_dir, Duration::ZERO).unwrap();
+        let mut uids: Vec<_> = completed
+            .iter()
+            .map(|c| c.SeriesInstanceUID.as_str())
+            .collect();
+        uids.sort();
+        assert_eq!(uids, ["1.2.3.4", "1.2.3.5"]);
+        assert!(completed
+            .iter()
+            .all(|c| c.reason == CompletionReason::IdleTimeout));
+        assert!(series_data_dir.join("1.2.3.4-pack.json").is_file());
+        assert!(complete_idle(log_dir, Duration::ZERO).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recently_modified_progress_is_not_read() {
+        let tmp_dir = TempDir::new("series_progress").unwrap();
+        let log_dir = Utf8Path::from_path(tmp_dir.path()).unwrap();
+        let progress_fname = log_dir.join("seriesData/1.2.3.4-progress.json");
+        let progress = SeriesProgress {
+            SeriesInstanceUID: "1.2.3.4".to_string(),
+            StudyInstanceUID: "1.2.3".to_string(),
+            SeriesBaseDir: "series".into(),
+            received: 1,
+            expected: None,
+            lastReceived: 0,
+            complete: false,
+        };
+        write_json(&progress, &progress_fname).unwrap();
+        assert!(complete_idle(log_dir, Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+        assert_eq!(complete_idle(log_dir, Duration::ZERO).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_packed_immediately_without_timeout() {
+        let tmp_dir = TempDir::new("series_progress").unwrap();
+        let dir = Utf8Path::from_path(tmp_dir.path()).unwrap();
+        for (uid, expected) in [("1.2.3.4", None), ("1.2.3.5", Some(2))] {
+            let complete = record_instance(
+                dir,
+                &common(uid),
+                Utf8Path::new("series"),
+                expected,
+                Received::New,
+                None,
+                &Output::new(false),
+            )
+            .unwrap();
+            assert_eq!(complete, None);
+            assert!(dir.join(format!("{uid}-pack.json")).is_file());
+        }
+        // completion by count is still reported
+        let complete = record_instance(
+            dir,
+            &common("1.2.3.5"),
+            Utf8Path::new("series"),
+            Some(2),
+            Received::New,
+            None,
+            &Output::new(false),
+        )
+        .unwrap();
+        assert_eq!(complete.unwrap().reason, CompletionReason::Count);
+    }
+}
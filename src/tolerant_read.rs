@@ -0,0 +1,365 @@
+//! Reading of DICOM files which do not conform to the DICOM file format (DICOM PS3.10),
+//! as found in exports of some PACS.
+//!
+//! Files are read by dicom-rs as usual. Only if that fails, the file is read again while
+//! working around the problems listed by [Repair].
+use crate::check::serialize_tag;
+use crate::errors::RepackError;
+use camino::Utf8Path;
+use dicom::core::{Tag, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::transfer_syntax::{Codec, Endianness};
+use dicom::encoding::{TransferSyntax, TransferSyntaxIndex};
+use dicom::object::file::ReadPreamble;
+use dicom::object::{
+    DefaultDicomObject, FileMetaTable, FileMetaTableBuilder, InMemDicomObject, OpenFileOptions,
+};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use serde::Serialize;
+use std::fmt;
+use std::io::Read;
+
+const MAGIC_CODE: &[u8; 4] = b"DICM";
+const PREAMBLE_LENGTH: usize = 128;
+
+/// A deviation from the DICOM file format which was worked around to read a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "repair", rename_all = "kebab-case")]
+pub enum Repair {
+    /// The file has no 128-byte preamble.
+    MissingPreamble,
+    /// The file has no `DICM` magic code before its file meta group.
+    MissingMagicCode,
+    /// The file meta group could not be read, and was rebuilt from what could be read of it
+    /// and from the data set.
+    InvalidMeta { reason: String },
+    /// The file has no file meta group, i.e. it is a raw data set. A file meta group was
+    /// synthesized for the transfer syntax the data set looks like it is encoded in.
+    MissingMeta { transfer_syntax: String },
+    /// The value of an element is longer than what is left of the file, e.g. because the file
+    /// is truncated, or an element could not be read. It and all elements after it were dropped.
+    Truncated {
+        /// The element, unless its header is incomplete too.
+        #[serde(
+            serialize_with = "serialize_tag",
+            skip_serializing_if = "Option::is_none"
+        )]
+        tag: Option<Tag>,
+        /// Position of the element in the data set, in bytes.
+        offset: usize,
+    },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::MissingPreamble => write!(f, "The 128-byte preamble is missing"),
+            Repair::MissingMagicCode => write!(f, "The DICM magic code is missing"),
+            Repair::InvalidMeta { reason } => {
+                write!(f, "The file meta group is invalid, and was rebuilt: {reason}")
+            }
+            Repair::MissingMeta { transfer_syntax } => write!(
+                f,
+                "The file meta group is missing, and was synthesized for transfer syntax {transfer_syntax}"
+            ),
+            Repair::Truncated { tag, offset } => {
+                write!(f, "The data set is truncated at byte {offset}")?;
+                if let Some(tag) = tag {
+                    write!(f, " in element {tag}")?;
+                }
+                write!(f, ", the rest was dropped")
+            }
+        }
+    }
+}
+
+/// Read a DICOM file, up to `read_until` if given, working around non-conformance to the
+/// DICOM file format if needed. Returns the repairs which were made.
+///
+/// If the file cannot be read even with repairs, the error of reading it as usual is returned.
+/// A file which was repaired is always read completely.
+pub(crate) fn open_file(
+    path: &Utf8Path,
+    read_until: Option<Tag>,
+) -> Result<(DefaultDicomObject, Vec<Repair>), RepackError> {
+    // the preamble is never guessed, so that its absence is repaired and reported
+    let options = OpenFileOptions::new().read_preamble(ReadPreamble::Always);
+    let options = match read_until {
+        Some(tag) => options.read_until(tag),
+        None => options.read_all(),
+    };
+    let error = match options.open_file(path) {
+        Ok(dcm) => return Ok((dcm, Vec::new())),
+        Err(e) => e,
+    };
+    let Ok(data) = fs_err::read(path) else {
+        return Err(error.into());
+    };
+    read_with_repairs(&data).ok_or_else(|| error.into())
+}
+
+/// Whether a file is meant to be a DICOM file, even if it cannot be read: it has the
+/// extension `.dcm`, or starts with the magic code `DICM` with or without a preamble.
+pub(crate) fn looks_like_dicom(path: &Utf8Path) -> bool {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("dcm"))
+    {
+        return true;
+    }
+    let mut start = Vec::new();
+    if let Ok(file) = fs_err::File::open(path) {
+        let len = PREAMBLE_LENGTH + MAGIC_CODE.len();
+        file.take(len as u64).read_to_end(&mut start).ok();
+    }
+    start.starts_with(MAGIC_CODE) || start.get(PREAMBLE_LENGTH..) == Some(&MAGIC_CODE[..])
+}
+
+/// Read the contents of a DICOM file, working around the problems listed by [Repair].
+fn read_with_repairs(data: &[u8]) -> Option<(DefaultDicomObject, Vec<Repair>)> {
+    let mut repairs = Vec::new();
+    let start = if data.get(PREAMBLE_LENGTH..PREAMBLE_LENGTH + 4) == Some(MAGIC_CODE) {
+        PREAMBLE_LENGTH + 4
+    } else if data.starts_with(MAGIC_CODE) {
+        repairs.push(Repair::MissingPreamble);
+        MAGIC_CODE.len()
+    } else if group_at(data, PREAMBLE_LENGTH) == Some(0x0002) {
+        repairs.push(Repair::MissingMagicCode);
+        PREAMBLE_LENGTH
+    } else if group_at(data, 0) == Some(0x0002) {
+        repairs.extend([Repair::MissingPreamble, Repair::MissingMagicCode]);
+        0
+    } else {
+        0
+    };
+    let data = &data[start..];
+
+    let (meta, dataset) = if group_at(data, 0) == Some(0x0002) {
+        let (meta_length, _) = walk(data, true, |tag| tag.group() == 0x0002);
+        let (meta, dataset) = data.split_at(meta_length);
+        (Some(meta), dataset)
+    } else {
+        (None, data)
+    };
+    let (meta, ts_uid) = match meta {
+        Some(meta) => match read_meta(meta) {
+            Ok(meta) => {
+                let ts_uid = meta.transfer_syntax().to_string();
+                (Meta::Exact(meta), ts_uid)
+            }
+            Err(reason) => {
+                repairs.push(Repair::InvalidMeta { reason });
+                let (builder, ts_uid) = rebuild_meta(meta)?;
+                (Meta::Rebuilt(builder), ts_uid)
+            }
+        },
+        // a raw data set starts with the identifying elements, maybe with their group length
+        None if group_at(dataset, 0) == Some(0x0008) => {
+            let ts_uid = guess_transfer_syntax(dataset);
+            repairs.push(Repair::MissingMeta {
+                transfer_syntax: ts_uid.to_string(),
+            });
+            let builder = FileMetaTableBuilder::new().transfer_syntax(ts_uid);
+            (Meta::Rebuilt(builder), ts_uid.to_string())
+        }
+        None => return None,
+    };
+    let ts = TransferSyntaxRegistry.get(ts_uid.trim_end_matches('\0'))?;
+    let obj = read_dataset(dataset, ts, &mut repairs)?;
+    let raw = repairs
+        .iter()
+        .any(|r| matches!(r, Repair::MissingMeta { .. }));
+    if raw && obj.get(tags::SOP_INSTANCE_UID).is_none() {
+        // probably not DICOM at all
+        return None;
+    }
+    let dcm = match meta {
+        Meta::Exact(meta) => obj.with_exact_meta(meta),
+        // SOP class and instance are taken from the data set
+        Meta::Rebuilt(builder) => obj.with_meta(builder).ok()?,
+    };
+    Some((dcm, repairs))
+}
+
+/// A file meta group which was read as is, or which is to be completed from the data set.
+enum Meta {
+    Exact(FileMetaTable),
+    Rebuilt(FileMetaTableBuilder),
+}
+
+fn read_meta(meta: &[u8]) -> Result<FileMetaTable, String> {
+    let with_magic_code = MAGIC_CODE.iter().chain(meta).copied().collect::<Vec<_>>();
+    FileMetaTable::from_reader(with_magic_code.as_slice()).map_err(|e| e.to_string())
+}
+
+/// Rebuild a file meta group from whatever elements of it can be read.
+/// Returns it with its transfer syntax, which is required.
+fn rebuild_meta(meta: &[u8]) -> Option<(FileMetaTableBuilder, String)> {
+    let explicit_vr_le = TransferSyntaxRegistry.get(uids::EXPLICIT_VR_LITTLE_ENDIAN)?;
+    let obj = InMemDicomObject::read_dataset_with_ts(meta, explicit_vr_le).ok()?;
+    let value = |tag| {
+        obj.get(tag)
+            .and_then(|e| e.to_str().ok())
+            .map(|s| s.trim_end_matches('\0').to_string())
+            .filter(|s| !s.is_empty())
+    };
+    let ts_uid = value(tags::TRANSFER_SYNTAX_UID)?;
+    let mut builder = FileMetaTableBuilder::new().transfer_syntax(&ts_uid);
+    if let Some(uid) = value(tags::MEDIA_STORAGE_SOP_CLASS_UID) {
+        builder = builder.media_storage_sop_class_uid(uid);
+    }
+    if let Some(uid) = value(tags::MEDIA_STORAGE_SOP_INSTANCE_UID) {
+        builder = builder.media_storage_sop_instance_uid(uid);
+    }
+    Some((builder, ts_uid))
+}
+
+/// Raw data sets are usually implicit VR little endian, the default transfer syntax of DICOM.
+/// They are taken to be explicit VR little endian if the first element has a valid VR.
+fn guess_transfer_syntax(dataset: &[u8]) -> &'static str {
+    let vr = dataset
+        .get(4..6)
+        .and_then(|vr| VR::from_binary([vr[0], vr[1]]));
+    if vr.is_some() {
+        uids::EXPLICIT_VR_LITTLE_ENDIAN
+    } else {
+        uids::IMPLICIT_VR_LITTLE_ENDIAN
+    }
+}
+
+/// Read a data set, dropping the elements from where it is truncated.
+fn read_dataset(
+    dataset: &[u8],
+    ts: &TransferSyntax,
+    repairs: &mut Vec<Repair>,
+) -> Option<InMemDicomObject> {
+    let read = |dataset| InMemDicomObject::read_dataset_with_ts(dataset, ts).ok();
+    // element headers can only be walked when the data set is not deflated
+    let walkable =
+        ts.endianness() == Endianness::Little && !matches!(ts.codec(), Codec::Dataset(_));
+    if !walkable {
+        return read(dataset);
+    }
+    let explicit_vr = ts.uid() != uids::IMPLICIT_VR_LITTLE_ENDIAN;
+    let mut truncated = |tag, offset: usize| {
+        repairs.push(Repair::Truncated { tag, offset });
+        read(&dataset[..offset])
+    };
+    match walk(dataset, explicit_vr, |_| true) {
+        (_, Stop::End) => read(dataset),
+        (offset, Stop::Incomplete(tag)) => truncated(tag, offset),
+        // the end of the element is not known, so it is only dropped if it cannot be read
+        (offset, Stop::UndefinedLength(tag)) => {
+            read(dataset).or_else(|| truncated(Some(tag), offset))
+        }
+    }
+}
+
+/// Why [walk] stopped.
+enum Stop {
+    /// At the end of the data set, or at an element which is not included.
+    End,
+    /// At an element which is incomplete. Its tag is `None` if that is incomplete too.
+    Incomplete(Option<Tag>),
+    /// At an element of undefined length, whose end can only be found by parsing it.
+    UndefinedLength(Tag),
+}
+
+/// Walk the headers of top-level elements in a little endian data set while `include` is true.
+/// Returns the position of the element where the walk stopped, and why.
+fn walk(dataset: &[u8], explicit_vr: bool, include: impl Fn(Tag) -> bool) -> (usize, Stop) {
+    let u16_at = |pos: usize| {
+        dataset
+            .get(pos..pos + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |pos: usize| {
+        dataset
+            .get(pos..pos + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    // same as the decoders of dicom-rs, which read unknown VRs as UN
+    let long_vr = |vr: &[u8]| {
+        use VR::*;
+        matches!(
+            VR::from_binary([vr[0], vr[1]]).unwrap_or(UN),
+            OB | OD | OF | OL | OW | SQ | UC | UR | UT | UN
+        )
+    };
+    let mut pos = 0;
+    loop {
+        if pos == dataset.len() {
+            return (pos, Stop::End);
+        }
+        let (Some(group), Some(element)) = (u16_at(pos), u16_at(pos + 2)) else {
+            return (pos, Stop::Incomplete(None));
+        };
+        let tag = Tag(group, element);
+        if !include(tag) {
+            return (pos, Stop::End);
+        }
+        let header = if !explicit_vr || group == 0xFFFE {
+            u32_at(pos + 4).map(|len| (8, len))
+        } else {
+            match dataset.get(pos + 4..pos + 6) {
+                Some(vr) if long_vr(vr) => u32_at(pos + 8).map(|len| (12, len)),
+                Some(_) => u16_at(pos + 6).map(|len| (8, u32::from(len))),
+                None => None,
+            }
+        };
+        let Some((header_length, length)) = header else {
+            return (pos, Stop::Incomplete(Some(tag)));
+        };
+        if length == u32::MAX {
+            return (pos, Stop::UndefinedLength(tag));
+        }
+        let next = pos + header_length + length as usize;
+        if next > dataset.len() {
+            return (pos, Stop::Incomplete(Some(tag)));
+        }
+        pos = next;
+    }
+}
+
+fn group_at(data: &[u8], pos: usize) -> Option<u16> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn element(data: &mut Vec<u8>, tag: Tag, vr: &[u8; 2], value: &[u8]) {
+        data.extend(tag.group().to_le_bytes());
+        data.extend(tag.element().to_le_bytes());
+        data.extend(vr);
+        data.extend((value.len() as u16).to_le_bytes());
+        data.extend(value);
+    }
+
+    #[test]
+    fn test_truncated_undefined_length() {
+        let mut dataset = Vec::new();
+        element(&mut dataset, tags::SOP_INSTANCE_UID, b"UI", b"1.2.3\0");
+        // encapsulated pixel data, with an item which is cut short
+        dataset.extend([0xE0, 0x7F, 0x10, 0x00, b'O', b'B', 0, 0]);
+        dataset.extend(u32::MAX.to_le_bytes());
+        dataset.extend([0xFE, 0xFF, 0x00, 0xE0]);
+        dataset.extend(100u32.to_le_bytes());
+        dataset.extend([0; 10]);
+
+        let ts = TransferSyntaxRegistry
+            .get(uids::EXPLICIT_VR_LITTLE_ENDIAN)
+            .unwrap();
+        let mut repairs = Vec::new();
+        let obj = read_dataset(&dataset, ts, &mut repairs).unwrap();
+        let expected = Repair::Truncated {
+            tag: Some(tags::PIXEL_DATA),
+            offset: 14,
+        };
+        assert_eq!(repairs, [expected]);
+        assert!(obj.get(tags::SOP_INSTANCE_UID).is_some());
+        assert!(obj.get(tags::PIXEL_DATA).is_none());
+    }
+}
@@ -0,0 +1,373 @@
+//! Re-encoding DICOM objects to another transfer syntax before they are written.
+//!
+//! Encapsulated (compressed) pixel data is decoded using the codecs of dicom-rs.
+//! RLE Lossless is encoded and decoded here, since dicom-rs 0.6 has no RLE encoder, and its
+//! RLE decoder gets the byte order of samples wrong.
+//! JPEG-LS is not supported, because dicom-rs has neither a decoder nor an encoder for it.
+use dicom::core::value::{PixelFragmentSequence, Value};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::mem::InMemElement;
+use dicom::object::DefaultDicomObject;
+use dicom::pixeldata::{PixelDecoder, PlanarConfiguration};
+use dicom::transfer_syntax::entries;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Transfer syntax to re-encode DICOM files to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TranscodeTarget {
+    /// Explicit VR Little Endian, uncompressed
+    ExplicitVrLittleEndian,
+    /// Implicit VR Little Endian, uncompressed
+    ImplicitVrLittleEndian,
+    /// RLE Lossless
+    RleLossless,
+}
+
+impl TranscodeTarget {
+    /// The transfer syntax UID.
+    pub fn uid(self) -> &'static str {
+        match self {
+            TranscodeTarget::ExplicitVrLittleEndian => entries::EXPLICIT_VR_LITTLE_ENDIAN.uid(),
+            TranscodeTarget::ImplicitVrLittleEndian => entries::IMPLICIT_VR_LITTLE_ENDIAN.uid(),
+            TranscodeTarget::RleLossless => entries::RLE_LOSSLESS.uid(),
+        }
+    }
+
+    fn is_native(self) -> bool {
+        self != TranscodeTarget::RleLossless
+    }
+}
+
+/// Error re-encoding a DICOM object, in which case it is stored unchanged.
+#[derive(thiserror::Error, Debug)]
+pub enum TranscodeError {
+    #[error("Cannot decode pixel data: {0}")]
+    Decode(#[from] dicom::pixeldata::Error),
+    #[error("Cannot decode pixel data: decoder panicked")]
+    DecodePanic,
+    #[error("Invalid RLE Lossless pixel data: {0}")]
+    InvalidRle(&'static str),
+    #[error("Cannot encode pixel data as {target:?}: {reason}")]
+    Encode {
+        target: TranscodeTarget,
+        reason: &'static str,
+    },
+}
+
+/// Transfer syntax of a DICOM object before and after transcoding.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct TransferSyntaxChange {
+    pub original: String,
+    pub stored: String,
+}
+
+impl TransferSyntaxChange {
+    pub fn is_changed(&self) -> bool {
+        self.original != self.stored
+    }
+}
+
+/// Re-encode `dcm` to `target`. On error, `dcm` is left unchanged.
+pub(crate) fn transcode(
+    dcm: &mut DefaultDicomObject,
+    target: TranscodeTarget,
+) -> (TransferSyntaxChange, Option<TranscodeError>) {
+    let original = dcm.meta().transfer_syntax().to_string();
+    let error = convert(dcm, target).err();
+    let stored = dcm.meta().transfer_syntax().to_string();
+    (TransferSyntaxChange { original, stored }, error)
+}
+
+fn convert(dcm: &mut DefaultDicomObject, target: TranscodeTarget) -> Result<(), TranscodeError> {
+    if dcm.meta().transfer_syntax() == target.uid() {
+        return Ok(());
+    }
+    let is_native = dcm
+        .element_opt(tags::PIXEL_DATA)
+        .ok()
+        .flatten()
+        .map(|e| matches!(e.value(), Value::Primitive(_)));
+    // without pixel data, or between native transfer syntaxes, only the encoding of
+    // the data set changes, which is done when writing it.
+    if is_native.unwrap_or(true) && target.is_native() {
+        set_transfer_syntax(dcm, target);
+        return Ok(());
+    }
+
+    let source = dcm.meta().transfer_syntax().to_string();
+    let (data, layout, frames) = if source == entries::RLE_LOSSLESS.uid() {
+        decode_rle(dcm).map_err(TranscodeError::InvalidRle)?
+    } else {
+        decode(dcm)?
+    };
+    let pixel_data: InMemElement = if target.is_native() {
+        let vr = if layout.bytes > 1 { VR::OW } else { VR::OB };
+        DataElement::new(tags::PIXEL_DATA, vr, PrimitiveValue::U8(data.into()))
+    } else {
+        let fragments = encode_rle(&data, frames, &layout)
+            .map_err(|reason| TranscodeError::Encode { target, reason })?;
+        let fragments = PixelFragmentSequence::new(Vec::new(), fragments);
+        DataElement::new(tags::PIXEL_DATA, VR::OB, Value::PixelSequence(fragments))
+    };
+    // JPEG decoders convert YCbCr to RGB
+    let to_rgb = layout.samples == 3 && source.starts_with("1.2.840.10008.1.2.4.");
+    // segments of RLE are always ordered by color plane
+    let planar = layout.planar || !target.is_native();
+
+    dcm.put(pixel_data);
+    if to_rgb {
+        dcm.put(DataElement::new(
+            tags::PHOTOMETRIC_INTERPRETATION,
+            VR::CS,
+            PrimitiveValue::from("RGB"),
+        ));
+    }
+    if layout.samples > 1 {
+        dcm.put(DataElement::new(
+            tags::PLANAR_CONFIGURATION,
+            VR::US,
+            PrimitiveValue::from(planar as u16),
+        ));
+    }
+    set_transfer_syntax(dcm, target);
+    Ok(())
+}
+
+/// Native pixel data, its layout and number of frames.
+type NativePixelData = (Vec<u8>, Layout, u32);
+
+/// Decode pixel data using dicom-rs.
+fn decode(dcm: &DefaultDicomObject) -> Result<NativePixelData, TranscodeError> {
+    // the decoders of dicom-rs may panic on malformed data
+    let decoded = catch_unwind(AssertUnwindSafe(|| dcm.decode_pixel_data()))
+        .map_err(|_| TranscodeError::DecodePanic)??;
+    let layout = Layout {
+        rows: decoded.rows() as usize,
+        columns: decoded.columns() as usize,
+        samples: decoded.samples_per_pixel() as usize,
+        bytes: decoded.bits_allocated() as usize / 8,
+        planar: decoded.planar_configuration() == PlanarConfiguration::PixelFirst,
+    };
+    Ok((decoded.data().to_vec(), layout, decoded.number_of_frames()))
+}
+
+/// Decode RLE Lossless pixel data, with one fragment per frame, to pixel-interleaved data.
+///
+/// The RLE decoder of dicom-rs 0.6 is not used, since it takes the first segment of
+/// each sample to be the least significant byte, while it is the most significant byte.
+fn decode_rle(dcm: &DefaultDicomObject) -> Result<NativePixelData, &'static str> {
+    let attribute = |tag| {
+        dcm.element_opt(tag)
+            .ok()
+            .flatten()
+            .and_then(|e| e.to_int::<usize>().ok())
+    };
+    let layout = Layout {
+        rows: attribute(tags::ROWS).ok_or("missing Rows")?,
+        columns: attribute(tags::COLUMNS).ok_or("missing Columns")?,
+        samples: attribute(tags::SAMPLES_PER_PIXEL).unwrap_or(1),
+        bytes: attribute(tags::BITS_ALLOCATED).ok_or("missing BitsAllocated")? / 8,
+        planar: false,
+    };
+    let Some(fragments) = dcm
+        .element(tags::PIXEL_DATA)
+        .ok()
+        .and_then(|e| e.fragments())
+    else {
+        return Err("no fragments");
+    };
+    let segments = layout.samples * layout.bytes;
+    let pixels = layout.rows * layout.columns;
+    let frame_len = pixels * segments;
+    let mut data = vec![0; frame_len * fragments.len()];
+    for (fragment, frame) in fragments.iter().zip(data.chunks_exact_mut(frame_len)) {
+        let header = |i: usize| {
+            fragment
+                .get(4 * i..4 * i + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+        };
+        if header(0) != Some(segments) {
+            return Err("unexpected number of segments");
+        }
+        for segment in 0..segments {
+            let start = header(segment + 1).ok_or("truncated header")?;
+            let end = if segment + 1 < segments {
+                header(segment + 2).ok_or("truncated header")?
+            } else {
+                fragment.len()
+            };
+            let encoded = fragment.get(start..end).ok_or("invalid segment offset")?;
+            let plane = unpack_bits(encoded, pixels).ok_or("truncated segment")?;
+            let (sample, byte) = (segment / layout.bytes, segment % layout.bytes);
+            for (p, value) in plane.into_iter().enumerate() {
+                frame[(p * layout.samples + sample) * layout.bytes + layout.bytes - 1 - byte] =
+                    value;
+            }
+        }
+    }
+    Ok((data, layout, fragments.len() as u32))
+}
+
+fn set_transfer_syntax(dcm: &mut DefaultDicomObject, target: TranscodeTarget) {
+    let meta = dcm.meta_mut();
+    meta.transfer_syntax = target.uid().to_string();
+    meta.update_information_group_length();
+}
+
+/// Dimensions and memory layout of native pixel data.
+struct Layout {
+    rows: usize,
+    columns: usize,
+    samples: usize,
+    /// Bytes per sample, i.e. BitsAllocated / 8.
+    bytes: usize,
+    /// Whether samples are stored color-by-plane (PlanarConfiguration = 1).
+    planar: bool,
+}
+
+/// Encode native little endian pixel data as RLE Lossless, one fragment per frame.
+///
+/// See DICOM PS3.5 Annex G:
+/// https://dicom.nema.org/medical/dicom/current/output/chtml/part05/chapter_G.html
+fn encode_rle(data: &[u8], frames: u32, layout: &Layout) -> Result<Vec<Vec<u8>>, &'static str> {
+    if layout.bytes == 0 {
+        return Err("BitsAllocated must be a multiple of 8");
+    }
+    let segments = layout.samples * layout.bytes;
+    if segments > 15 {
+        return Err("too many segments");
+    }
+    let pixels = layout.rows * layout.columns;
+    let frame_len = pixels * segments;
+    if data.len() < frame_len * frames as usize {
+        return Err("pixel data is shorter than expected");
+    }
+    let fragments = data
+        .chunks_exact(frame_len)
+        .take(frames as usize)
+        .map(|frame| {
+            let mut header = vec![0_u8; 64];
+            header[..4].copy_from_slice(&(segments as u32).to_le_bytes());
+            let mut body = Vec::new();
+            // one segment per byte of each sample, most significant byte first
+            for (i, (sample, byte)) in (0..layout.samples)
+                .flat_map(|s| (0..layout.bytes).rev().map(move |b| (s, b)))
+                .enumerate()
+            {
+                let offset = (64 + body.len()) as u32;
+                header[4 + 4 * i..8 + 4 * i].copy_from_slice(&offset.to_le_bytes());
+                let plane: Vec<u8> = (0..pixels)
+                    .map(|p| {
+                        let index = if layout.planar {
+                            sample * pixels + p
+                        } else {
+                            p * layout.samples + sample
+                        };
+                        frame[index * layout.bytes + byte]
+                    })
+                    .collect();
+                for row in plane.chunks(layout.columns) {
+                    pack_bits(row, &mut body);
+                }
+                if body.len() % 2 == 1 {
+                    body.push(0);
+                }
+            }
+            header.extend(body);
+            header
+        })
+        .collect();
+    Ok(fragments)
+}
+
+/// Decode the first `len` bytes of PackBits encoded `data`.
+fn unpack_bits(data: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while out.len() < len && i < data.len() {
+        let n = data[i] as i8;
+        i += 1;
+        match n {
+            0.. => {
+                let literal = data.get(i..i + n as usize + 1)?;
+                out.extend_from_slice(literal);
+                i += literal.len();
+            }
+            -127..=-1 => {
+                let value = *data.get(i)?;
+                out.resize(out.len() + (1 - n as isize) as usize, value);
+                i += 1;
+            }
+            // no operation
+            -128 => {}
+        }
+    }
+    out.truncate(len);
+    Some(out).filter(|out| out.len() == len)
+}
+
+/// Append the PackBits encoding of `data` to `out`.
+fn pack_bits(data: &[u8], out: &mut Vec<u8>) {
+    let run_length = |s: &[u8]| s.iter().take(128).take_while(|b| **b == s[0]).count();
+    let mut i = 0;
+    while i < data.len() {
+        let run = run_length(&data[i..]);
+        if run > 1 {
+            out.push((257 - run) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            let start = i;
+            while i < data.len() && i - start < 128 && run_length(&data[i..]) < 3 {
+                i += 1;
+            }
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pack_bits() {
+        let mut out = Vec::new();
+        pack_bits(&[1, 1, 1, 2, 3, 4, 4, 5], &mut out);
+        assert_eq!(out, [254, 1, 4, 2, 3, 4, 4, 5]);
+
+        let mut decoded = unpack_bits(&out, 8).unwrap();
+        assert_eq!(decoded, [1, 1, 1, 2, 3, 4, 4, 5]);
+
+        let long_run = [7; 130];
+        let mut out = Vec::new();
+        pack_bits(&long_run, &mut out);
+        assert_eq!(out, [129, 7, 255, 7]);
+        decoded = unpack_bits(&out, 130).unwrap();
+        assert_eq!(decoded, long_run);
+        assert_eq!(unpack_bits(&out, 131), None);
+    }
+
+    #[test]
+    fn test_encode_rle() {
+        // 2x2, 16-bit
+        let data = [0x01, 0x02, 0x01, 0x02, 0x03, 0x00, 0x04, 0x00];
+        let layout = Layout {
+            rows: 2,
+            columns: 2,
+            samples: 1,
+            bytes: 2,
+            planar: false,
+        };
+        let fragments = encode_rle(&data, 1, &layout).unwrap();
+        assert_eq!(fragments.len(), 1);
+        let fragment = &fragments[0];
+        assert_eq!(&fragment[..12], [2, 0, 0, 0, 64, 0, 0, 0, 68, 0, 0, 0]);
+        // high bytes: rows [2, 2] and [0, 0], then low bytes: rows [1, 1] and [3, 4]
+        assert_eq!(&fragment[64..68], [255, 2, 255, 0]);
+        // padded to even length
+        assert_eq!(&fragment[68..], [255, 1, 1, 3, 4, 0]);
+    }
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{glob_files, write_series};
+use rx_repack::{batch, ErrorKind, RepackOptions};
+use std::sync::Mutex;
+use tempdir::TempDir;
+
+#[test]
+fn test_batch() {
+    let tmp_dir = TempDir::new("batch").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+
+    write_series(&input_dir.join("a"), "patient1", "1.2.3", "1.2.3.4", 10);
+    write_series(&input_dir.join("b"), "patient2", "1.2.5", "1.2.5.6", 5);
+    fs_err::write(input_dir.join("README.txt"), "not a DICOM file").unwrap();
+
+    let outcomes = Mutex::new(Vec::new());
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        ..RepackOptions::new(&data_dir)
+    };
+    let summary = batch(&input_dir, false, 4, &options, |src, outcome| {
+        outcomes
+            .lock()
+            .unwrap()
+            .push((src.to_path_buf(), outcome.is_ok()))
+    })
+    .unwrap();
+
+    assert_eq!(summary.total, 16);
+    assert_eq!(summary.succeeded, 15);
+    assert_eq!(summary.failed, 1);
+    assert_eq!(summary.errors.get(&ErrorKind::NotDicom), Some(&1));
+
+    let outcomes = outcomes.into_inner().unwrap();
+    assert_eq!(outcomes.len(), 16);
+    let failed: Vec<_> = outcomes.iter().filter(|(_, ok)| !ok).collect();
+    assert_eq!(failed[0].0, input_dir.join("README.txt"));
+
+    assert_eq!(glob_files(&data_dir, "dcm").len(), 15);
+    assert_eq!(
+        glob_files(&log_dir.join("seriesData"), "dcm.json").len(),
+        15
+    );
+}
+
+#[test]
+fn test_batch_non_utf8_name() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let tmp_dir = TempDir::new("batch").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 2);
+    let name = std::ffi::OsStr::from_bytes(b"bubble\xfftea.dcm");
+    fs_err::write(input_dir.as_std_path().join(name), "not a DICOM file").unwrap();
+
+    let failed = Mutex::new(Vec::new());
+    let options = RepackOptions::new(tmp_path.join("data"));
+    let summary = batch(&input_dir, false, 2, &options, |src, outcome| {
+        if let Err(e) = outcome {
+            failed.lock().unwrap().push((src.to_path_buf(), e.kind()));
+        }
+    })
+    .unwrap();
+    assert_eq!(summary.total, 3);
+    assert_eq!(summary.succeeded, 2);
+    assert_eq!(summary.errors.get(&ErrorKind::Io), Some(&1));
+    let failed = failed.into_inner().unwrap();
+    assert_eq!(failed[0].0, input_dir.join("bubble\u{fffd}tea.dcm"));
+}
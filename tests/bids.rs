@@ -0,0 +1,64 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{glob_files, Instance};
+use rx_repack::{repack_object, BidsLayout, RepackOptions};
+use tempdir::TempDir;
+
+#[test]
+fn test_bids_layout() {
+    let tmp_dir = TempDir::new("bids").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let options = RepackOptions {
+        bids: Some(BidsLayout::default()),
+        ..RepackOptions::new(&data_dir)
+    };
+    // the second study of patient1 is on the same date, with the same SeriesNumber
+    let instances = [
+        ("patient1", "1.2.3", "1.2.3.4", 1, 1),
+        ("patient1", "1.2.3", "1.2.3.4", 1, 2),
+        ("patient1", "1.2.3", "1.2.3.5", 2, 1),
+        ("patient1", "1.2.8", "1.2.8.9", 1, 1),
+        ("patient-2", "1.2.3", "1.2.6.7", 1, 1),
+    ];
+    for (patient_id, study_uid, series_uid, series_number, instance_number) in instances {
+        let dcm = Instance {
+            patient_id,
+            study_uid,
+            series_uid,
+            series_number,
+            instance_number,
+        }
+        .to_dicom();
+        repack_object(dcm, &options).unwrap();
+    }
+
+    let mut actual: Vec<_> = glob_files(&data_dir, "dcm")
+        .into_iter()
+        .map(|p| p.strip_prefix(&data_dir).unwrap().to_string())
+        .collect();
+    actual.sort();
+    assert_eq!(
+        actual,
+        [
+            "sub-patient1/ses-20130308/anat/sub-patient1_ses-20130308_acq-334b080_run-1_T1w/0001-1.2.8.9.1.dcm",
+            "sub-patient1/ses-20130308/anat/sub-patient1_ses-20130308_acq-7317393_run-2_T1w/0001-1.2.3.5.1.dcm",
+            "sub-patient1/ses-20130308/anat/sub-patient1_ses-20130308_acq-eaf4f78_run-1_T1w/0001-1.2.3.4.1.dcm",
+            "sub-patient1/ses-20130308/anat/sub-patient1_ses-20130308_acq-eaf4f78_run-1_T1w/0002-1.2.3.4.2.dcm",
+            "sub-patient2/ses-20130308/anat/sub-patient2_ses-20130308_acq-830ecef_run-1_T1w/0001-1.2.6.7.1.dcm",
+        ]
+    );
+
+    let description: serde_json::Value = serde_json::from_str(
+        &fs_err::read_to_string(data_dir.join("dataset_description.json")).unwrap(),
+    )
+    .unwrap();
+    assert!(description["BIDSVersion"].is_string());
+    let participants = fs_err::read_to_string(data_dir.join("participants.tsv")).unwrap();
+    assert_eq!(
+        participants,
+        "participant_id\tsex\tage\nsub-patient1\tM\tn/a\nsub-patient2\tM\tn/a\n"
+    );
+    assert!(data_dir.join(".bidsignore").is_file());
+}
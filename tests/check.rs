@@ -0,0 +1,84 @@
+mod common;
+
+use camino::Utf8Path;
+use common::Instance;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use std::process::Command;
+use tempdir::TempDir;
+
+#[test]
+fn test_check() {
+    let tmp_dir = TempDir::new("check").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let instance = Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid: "1.2.3.4",
+        series_number: 1,
+        instance_number: 1,
+    };
+
+    // PatientID with NUL bytes, and a lowercase code string
+    let mut dcm = instance.to_dicom();
+    dcm.put(DataElement::new(
+        tags::PATIENT_ID,
+        VR::LO,
+        PrimitiveValue::from("patient1\0\0"),
+    ));
+    dcm.put(DataElement::new(
+        tags::MODALITY,
+        VR::CS,
+        PrimitiveValue::from("mr"),
+    ));
+    let odd = tmp_path.join("odd.dcm");
+    dcm.write_to_file(&odd).unwrap();
+
+    let mut dcm = instance.to_dicom();
+    dcm.remove_element(tags::SOP_INSTANCE_UID);
+    let no_uid = tmp_path.join("no_uid.dcm");
+    dcm.write_to_file(&no_uid).unwrap();
+
+    let not_dicom = tmp_path.join("not_dicom.txt");
+    fs_err::write(&not_dicom, "i enjoy bubble tea").unwrap();
+
+    let before: Vec<_> = fs_err::read_dir(tmp_path).unwrap().collect();
+    let output = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .arg("check")
+        .args([&odd, &no_uid, &not_dicom])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let after: Vec<_> = fs_err::read_dir(tmp_path).unwrap().collect();
+    assert_eq!(before.len(), after.len());
+
+    let reports: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(reports.len(), 3);
+
+    let odd_report = &reports[0];
+    assert_eq!(odd_report["src"], odd.as_str());
+    assert_eq!(odd_report["severity"], "warning");
+    let problems = odd_report["problems"].as_array().unwrap();
+    let problem_of = |name: &str| {
+        problems
+            .iter()
+            .find(|p| p["name"] == name)
+            .unwrap_or_else(|| panic!("no problem with {name} in {problems:?}"))
+    };
+    assert_eq!(problem_of("PatientID")["severity"], "warning");
+    assert_eq!(problem_of("PatientID")["tag"], "(0010,0020)");
+    assert_eq!(problem_of("Modality")["severity"], "warning");
+    // Type 1 attribute of the MR Image module
+    assert_eq!(problem_of("ScanningSequence")["severity"], "warning");
+    // written as "Not defined" in logs
+    assert_eq!(problem_of("SeriesDate")["severity"], "info");
+
+    assert_eq!(reports[1]["severity"], "error");
+    assert_eq!(reports[1]["error_kind"], "missing-element");
+    assert_eq!(reports[2]["severity"], "error");
+    assert_eq!(reports[2]["error_kind"], "not-dicom");
+}
@@ -0,0 +1,154 @@
+//! Helpers for creating small synthetic DICOM files, for tests which should not
+//! depend on the examples downloaded by `get_examples.sh`.
+#![allow(dead_code)]
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::object::{DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject};
+
+/// Identifiers of a synthetic DICOM instance.
+pub struct Instance<'a> {
+    pub patient_id: &'a str,
+    pub study_uid: &'a str,
+    pub series_uid: &'a str,
+    pub series_number: u32,
+    pub instance_number: u32,
+}
+
+impl<'a> Instance<'a> {
+    pub fn sop_instance_uid(&self) -> String {
+        format!("{}.{}", self.series_uid, self.instance_number)
+    }
+
+    /// Create a DICOM object.
+    pub fn to_dicom(&self) -> DefaultDicomObject {
+        let sop_instance_uid = self.sop_instance_uid();
+        InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                PrimitiveValue::from(uids::MR_IMAGE_STORAGE),
+            ),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from(sop_instance_uid.as_str()),
+            ),
+            DataElement::new(tags::STUDY_DATE, VR::DA, PrimitiveValue::from("20130308")),
+            DataElement::new(tags::MODALITY, VR::CS, PrimitiveValue::from("MR")),
+            DataElement::new(
+                tags::STUDY_DESCRIPTION,
+                VR::LO,
+                PrimitiveValue::from("MR-Brain w/o Contrast"),
+            ),
+            DataElement::new(
+                tags::SERIES_DESCRIPTION,
+                VR::LO,
+                PrimitiveValue::from("SAG MPRAGE 220 FOV"),
+            ),
+            DataElement::new(
+                tags::PATIENT_NAME,
+                VR::PN,
+                PrimitiveValue::from("Anon^Patient"),
+            ),
+            DataElement::new(
+                tags::PATIENT_ID,
+                VR::LO,
+                PrimitiveValue::from(self.patient_id),
+            ),
+            DataElement::new(
+                tags::PATIENT_BIRTH_DATE,
+                VR::DA,
+                PrimitiveValue::from("20090701"),
+            ),
+            DataElement::new(tags::PATIENT_SEX, VR::CS, PrimitiveValue::from("M")),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from(self.study_uid),
+            ),
+            DataElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                PrimitiveValue::from(self.series_uid),
+            ),
+            DataElement::new(
+                tags::SERIES_NUMBER,
+                VR::IS,
+                PrimitiveValue::from(self.series_number.to_string()),
+            ),
+            DataElement::new(
+                tags::INSTANCE_NUMBER,
+                VR::IS,
+                PrimitiveValue::from(self.instance_number.to_string()),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::MR_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid(sop_instance_uid)
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap()
+    }
+
+    /// Write a DICOM file to a directory.
+    pub fn write_to(&self, dir: &Utf8Path) -> Utf8PathBuf {
+        fs_err::create_dir_all(dir).unwrap();
+        let path = dir.join(format!("{}.dcm", self.sop_instance_uid()));
+        self.to_dicom().write_to_file(&path).unwrap();
+        path
+    }
+}
+
+/// Write `n` instances of one series to a directory.
+pub fn write_series(
+    dir: &Utf8Path,
+    patient_id: &str,
+    study_uid: &str,
+    series_uid: &str,
+    n: u32,
+) -> Vec<Utf8PathBuf> {
+    (1..=n)
+        .map(|instance_number| {
+            Instance {
+                patient_id,
+                study_uid,
+                series_uid,
+                series_number: 1,
+                instance_number,
+            }
+            .write_to(dir)
+        })
+        .collect()
+}
+
+/// Read the bytes referred to by a DICOM JSON BulkDataURI with `offset` and `length`
+/// query parameters, e.g. `file:///data/a.dcm?offset=1234&length=8`.
+pub fn read_bulk_data(uri: &str) -> Vec<u8> {
+    let (path, query) = uri
+        .strip_prefix("file://")
+        .unwrap()
+        .split_once('?')
+        .unwrap();
+    let param = |name: &str| -> usize {
+        query
+            .split('&')
+            .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let (offset, length) = (param("offset"), param("length"));
+    fs_err::read(path).unwrap()[offset..offset + length].to_vec()
+}
+
+/// Find all files under a directory which have the given extension.
+pub fn glob_files(dir: &Utf8Path, ext: &str) -> Vec<Utf8PathBuf> {
+    glob::glob(dir.join(format!("**/*.{ext}")).as_str())
+        .unwrap()
+        .map(|r| Utf8PathBuf::from_path_buf(r.unwrap()).unwrap())
+        .collect()
+}
+
+pub mod scu;
@@ -0,0 +1,176 @@
+//! A minimal C-STORE service class user (SCU), for testing `rx-repack listen`.
+use camino::Utf8Path;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::InMemDicomObject;
+use dicom::transfer_syntax::{entries, TransferSyntaxRegistry};
+use dicom::ul::pdu::{PDataValue, PDataValueType, Pdu};
+use dicom::ul::{ClientAssociation, ClientAssociationOptions};
+
+/// Send DICOM files by C-STORE, returning the status of each response.
+pub fn store_files(
+    address: &str,
+    called_ae_title: &str,
+    files: &[impl AsRef<Utf8Path>],
+) -> Vec<u16> {
+    let mut association = ClientAssociationOptions::new()
+        .calling_ae_title("RX-TEST-SCU")
+        .called_ae_title(called_ae_title)
+        .with_abstract_syntax(uids::MR_IMAGE_STORAGE)
+        .establish(address)
+        .unwrap();
+    let statuses = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| store_file(&mut association, i as u16 + 1, file.as_ref()))
+        .collect();
+    association.release().unwrap();
+    statuses
+}
+
+/// Send a DIMSE request without a data set, returning the status of its response.
+///
+/// The association is released afterwards, so the SCP must still be responsive.
+pub fn send_request(address: &str, called_ae_title: &str, command_field: u16) -> u16 {
+    let mut association = ClientAssociationOptions::new()
+        .calling_ae_title("RX-TEST-SCU")
+        .called_ae_title(called_ae_title)
+        .with_abstract_syntax(uids::MR_IMAGE_STORAGE)
+        .establish(address)
+        .unwrap();
+    let pc_id = association.presentation_contexts()[0].id;
+    let command = InMemDicomObject::command_from_element_iter([
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            PrimitiveValue::from(uids::MR_IMAGE_STORAGE),
+        ),
+        DataElement::new(
+            tags::COMMAND_FIELD,
+            VR::US,
+            PrimitiveValue::from(command_field),
+        ),
+        DataElement::new(tags::MESSAGE_ID, VR::US, PrimitiveValue::from(1_u16)),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            PrimitiveValue::from(0x0101_u16),
+        ),
+    ]);
+    let mut command_data = Vec::new();
+    command
+        .write_dataset_with_ts(
+            &mut command_data,
+            &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+        )
+        .unwrap();
+    association
+        .send(&Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id: pc_id,
+                value_type: PDataValueType::Command,
+                is_last: true,
+                data: command_data,
+            }],
+        })
+        .unwrap();
+    let status = receive_status(&mut association);
+    association.release().unwrap();
+    status
+}
+
+fn store_file(association: &mut ClientAssociation, message_id: u16, file: &Utf8Path) -> u16 {
+    let dcm = dicom::object::open_file(file).unwrap();
+    let sop_class_uid = dcm
+        .meta()
+        .media_storage_sop_class_uid()
+        .trim_end_matches('\0');
+    let sop_instance_uid = dcm
+        .meta()
+        .media_storage_sop_instance_uid()
+        .trim_end_matches('\0');
+    let pc = association
+        .presentation_contexts()
+        .first()
+        .unwrap_or_else(|| panic!("rejected: {:?}", association.presentation_contexts()))
+        .clone();
+    let ts = TransferSyntaxRegistry
+        .get(pc.transfer_syntax.trim_end_matches('\0'))
+        .unwrap();
+
+    let command = InMemDicomObject::command_from_element_iter([
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            PrimitiveValue::from(sop_class_uid),
+        ),
+        DataElement::new(
+            tags::COMMAND_FIELD,
+            VR::US,
+            PrimitiveValue::from(0x0001_u16),
+        ),
+        DataElement::new(tags::MESSAGE_ID, VR::US, PrimitiveValue::from(message_id)),
+        DataElement::new(tags::PRIORITY, VR::US, PrimitiveValue::from(0x0000_u16)),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            PrimitiveValue::from(0x0000_u16),
+        ),
+        DataElement::new(
+            tags::AFFECTED_SOP_INSTANCE_UID,
+            VR::UI,
+            PrimitiveValue::from(sop_instance_uid),
+        ),
+    ]);
+    let mut command_data = Vec::new();
+    command
+        .write_dataset_with_ts(
+            &mut command_data,
+            &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+        )
+        .unwrap();
+    let mut data = Vec::new();
+    dcm.write_dataset_with_ts(&mut data, ts).unwrap();
+
+    association
+        .send(&Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id: pc.id,
+                value_type: PDataValueType::Command,
+                is_last: true,
+                data: command_data,
+            }],
+        })
+        .unwrap();
+    let max_len = (association.acceptor_max_pdu_length() as usize).saturating_sub(64);
+    let chunks: Vec<_> = data.chunks(max_len.max(1)).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        association
+            .send(&Pdu::PData {
+                data: vec![PDataValue {
+                    presentation_context_id: pc.id,
+                    value_type: PDataValueType::Data,
+                    is_last: i == chunks.len() - 1,
+                    data: chunk.to_vec(),
+                }],
+            })
+            .unwrap();
+    }
+
+    receive_status(association)
+}
+
+fn receive_status(association: &mut ClientAssociation) -> u16 {
+    match association.receive().unwrap() {
+        Pdu::PData { data } => {
+            let rsp = InMemDicomObject::read_dataset_with_ts(
+                data[0].data.as_slice(),
+                &entries::IMPLICIT_VR_LITTLE_ENDIAN.erased(),
+            )
+            .unwrap();
+            rsp.element(tags::STATUS).unwrap().uint16().unwrap()
+        }
+        pdu => panic!("unexpected response: {pdu:?}"),
+    }
+}
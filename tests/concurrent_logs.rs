@@ -0,0 +1,74 @@
+mod common;
+
+use camino::Utf8Path;
+use common::write_series;
+use std::process::Command;
+use tempdir::TempDir;
+
+const STUDIES: usize = 16;
+const INSTANCES_PER_STUDY: u32 = 4;
+
+/// Many rx-repack processes writing to the same `patientData/<MRN>.json`
+/// should not lose any StudyInstanceUIDs.
+#[test]
+fn test_concurrent_patient_data() {
+    let tmp_dir = TempDir::new("concurrent_logs").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+
+    let study_uids: Vec<_> = (0..STUDIES).map(|i| format!("1.2.3.{i}")).collect();
+    let files: Vec<_> = study_uids
+        .iter()
+        .flat_map(|study_uid| {
+            let series_uid = format!("{study_uid}.1");
+            write_series(
+                &input_dir.join(study_uid),
+                "patient1",
+                study_uid,
+                &series_uid,
+                INSTANCES_PER_STUDY,
+            )
+        })
+        .collect();
+
+    let children: Vec<_> = files
+        .iter()
+        .map(|file| {
+            Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+                .arg("--xcrdir")
+                .arg(file.parent().unwrap())
+                .arg("--xcrfile")
+                .arg(file.file_name().unwrap())
+                .arg("--datadir")
+                .arg(&data_dir)
+                .arg("--logdir")
+                .arg(&log_dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    let patient_data_file = log_dir.join("patientData/patient1.json");
+    let patient_data: serde_json::Value =
+        serde_json::from_str(&fs_err::read_to_string(patient_data_file).unwrap()).unwrap();
+    let mut study_list: Vec<_> = patient_data["patient1"]["StudyList"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v.as_str().unwrap().to_string())
+        .collect();
+    study_list.sort();
+    let mut expected = study_uids.clone();
+    expected.sort();
+    assert_eq!(study_list, expected);
+
+    let leftover_partial_files: Vec<_> = glob::glob(log_dir.join("**/.*.partial").as_str())
+        .unwrap()
+        .collect();
+    assert!(leftover_partial_files.is_empty());
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{glob_files, write_series};
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::dictionary_std::tags;
+use rx_repack::{batch, DeidProfile, Deidentifier, RepackOptions};
+use tempdir::TempDir;
+
+/// OtherPatientIDs, which is retired.
+const OTHER_PATIENT_IDS: Tag = Tag(0x0010, 0x1000);
+
+const PHI: &[&str] = &[
+    "patient1",
+    "Anon^Patient",
+    "20090701",
+    "1.2.3.4",
+    "bubble tea protocol",
+    "Reviewer^Rex",
+];
+
+#[test]
+fn test_deidentify() {
+    let tmp_dir = TempDir::new("deidentify").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    for file in write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 3) {
+        let mut dcm = dicom::object::open_file(&file).unwrap();
+        for (tag, vr, value) in [
+            (OTHER_PATIENT_IDS, VR::LO, "patient1-other"),
+            (tags::PROTOCOL_NAME, VR::LO, "bubble tea protocol"),
+            (tags::REVIEWER_NAME, VR::PN, "Reviewer^Rex"),
+        ] {
+            dcm.put(DataElement::new(tag, vr, PrimitiveValue::from(value)));
+        }
+        dcm.put(DataElement::new(
+            Tag(0x6000, 0x3000),
+            VR::OW,
+            PrimitiveValue::U16(vec![0; 8].into()),
+        ));
+        dcm.write_to_file(&file).unwrap();
+    }
+
+    let deid = Deidentifier::new(DeidProfile::Basic, b"secret");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        deidentify: Some(deid.clone()),
+        ..RepackOptions::new(&data_dir)
+    };
+    let summary = batch(&input_dir, true, 2, &options, |_, _| ()).unwrap();
+    assert_eq!(summary.succeeded, 3);
+    assert!(glob_files(&input_dir, "dcm").is_empty());
+
+    let pseudonym = deid.remap_patient_id("patient1");
+    let repacked = glob_files(&data_dir, "dcm");
+    assert_eq!(repacked.len(), 3);
+    for file in &repacked {
+        let relative = file.strip_prefix(&data_dir).unwrap().as_str();
+        assert!(relative.starts_with(&pseudonym));
+        for phi in PHI {
+            assert!(!relative.contains(phi), "{relative} contains {phi}");
+        }
+        let dcm = dicom::object::open_file(file).unwrap();
+        let get = |name| dcm.element_by_name(name).unwrap().to_str().unwrap();
+        assert_eq!(get("PatientID"), pseudonym);
+        assert_eq!(get("PatientName"), pseudonym);
+        assert_eq!(get("PatientBirthDate"), "");
+        assert_eq!(get("SeriesInstanceUID"), deid.remap_uid("1.2.3.4"));
+        assert_eq!(get("PatientIdentityRemoved"), "YES");
+        assert!(dcm.element_by_name("SeriesDescription").is_err());
+        assert!(dcm.element(OTHER_PATIENT_IDS).is_err());
+        assert!(dcm.element(tags::PROTOCOL_NAME).is_err());
+        assert_eq!(get("ReviewerName"), "");
+        assert!(dcm.element(Tag(0x6000, 0x3000)).is_err());
+        assert_eq!(
+            dcm.meta()
+                .media_storage_sop_instance_uid
+                .trim_end_matches('\0'),
+            get("SOPInstanceUID")
+        );
+    }
+
+    for file in glob_files(&log_dir, "json") {
+        let content = fs_err::read_to_string(&file).unwrap();
+        for phi in PHI {
+            assert!(!content.contains(phi), "{file} contains {phi}");
+        }
+    }
+    assert!(log_dir
+        .join("patientData")
+        .join(format!("{pseudonym}.json"))
+        .is_file());
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use camino::Utf8Path;
+use common::Instance;
+use rx_repack::{repack, FileAction, PlannedFile, RepackOptions};
+use std::process::Command;
+use tempdir::TempDir;
+
+const INSTANCE: Instance = Instance {
+    patient_id: "patient1",
+    study_uid: "1.2.3",
+    series_uid: "1.2.3.4",
+    series_number: 1,
+    instance_number: 1,
+};
+
+fn action_of(plan: &[PlannedFile], p: &Utf8Path) -> FileAction {
+    plan.iter()
+        .find(|f| f.path == p)
+        .unwrap_or_else(|| panic!("{p} is not in {plan:?}"))
+        .action
+}
+
+#[test]
+fn test_dry_run() {
+    let tmp_dir = TempDir::new("dry_run").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let src = INSTANCE.write_to(&input_dir);
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        dry_run: true,
+        ..RepackOptions::new(&data_dir)
+    };
+
+    let outcome = repack(&src, true, &options).unwrap();
+    let plan = outcome.plan.unwrap();
+    assert!(!data_dir.exists());
+    assert!(!log_dir.exists());
+    assert!(src.is_file());
+    assert_eq!(action_of(&plan, &outcome.dst), FileAction::Create);
+    assert_eq!(action_of(&plan, &src), FileAction::Remove);
+    assert!(plan.iter().any(|f| f.path.starts_with(&log_dir)));
+    assert!(plan
+        .iter()
+        .filter(|f| f.path != src)
+        .all(|f| f.action == FileAction::Create));
+
+    // the plan is what a real run does
+    let real = RepackOptions {
+        dry_run: false,
+        ..options
+    };
+    let written = repack(&src, false, &real).unwrap();
+    assert!(written.plan.is_none());
+    assert_eq!(written.dst, outcome.dst);
+    for f in plan.iter().filter(|f| f.path != src) {
+        assert!(f.path.is_file(), "{} was not written", f.path);
+    }
+
+    // repacking again overwrites the DICOM file, but not the logs which are the same,
+    // except -progress.json, whose timestamps depend on the clock
+    let options = RepackOptions {
+        dry_run: true,
+        ..real
+    };
+    let again = repack(&src, false, &options).unwrap().plan.unwrap();
+    assert_eq!(again.len(), plan.len() - 1);
+    assert_eq!(action_of(&again, &outcome.dst), FileAction::Overwrite);
+    assert!(again
+        .iter()
+        .filter(|f| f.path != outcome.dst && !f.path.as_str().ends_with("-progress.json"))
+        .all(|f| f.action == FileAction::Untouched));
+}
+
+#[test]
+fn test_dry_run_cli() {
+    let tmp_dir = TempDir::new("dry_run").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    INSTANCE.write_to(&input_dir);
+    let data_dir = tmp_path.join("data");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .arg("batch")
+        .arg(&input_dir)
+        .args(["--datadir", data_dir.as_str(), "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(!data_dir.exists());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let message: serde_json::Value = serde_json::from_str(stdout.lines().next().unwrap()).unwrap();
+    let plan = message["plan"].as_array().unwrap();
+    assert_eq!(plan.len(), 1);
+    assert_eq!(plan[0]["path"], message["dst"]);
+    assert_eq!(plan[0]["action"], "create");
+}
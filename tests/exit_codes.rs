@@ -0,0 +1,58 @@
+mod common;
+
+use camino::Utf8Path;
+use common::write_series;
+use std::process::Command;
+use tempdir::TempDir;
+
+/// Run rx-repack on a file, returning its exit code and NDJSON output.
+fn run_rx_repack(file: &Utf8Path, data_dir: &Utf8Path, extra_args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .arg("--xcrdir")
+        .arg(file.parent().unwrap())
+        .arg("--xcrfile")
+        .arg(file.file_name().unwrap())
+        .arg("--datadir")
+        .arg(data_dir)
+        .arg("--log-ndjson")
+        .args(extra_args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (output.status.code().unwrap(), stdout)
+}
+
+fn error_kind_of(stdout: &str) -> serde_json::Value {
+    let msg: serde_json::Value = serde_json::from_str(stdout.trim()).unwrap();
+    msg["error_kind"].clone()
+}
+
+#[test]
+fn test_exit_codes() {
+    let tmp_dir = TempDir::new("exit_codes").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let files = write_series(&tmp_path.join("input"), "patient1", "1.2.3", "1.2.3.4", 1);
+
+    let (code, stdout) = run_rx_repack(&files[0], &data_dir, &[]);
+    assert_eq!(code, 0);
+    assert_eq!(error_kind_of(&stdout), serde_json::Value::Null);
+
+    let (code, stdout) = run_rx_repack(&files[0], &data_dir, &["--on-conflict", "error"]);
+    assert_eq!(code, 12);
+    assert_eq!(error_kind_of(&stdout), "destination-exists");
+
+    let not_dicom = tmp_path.join("input/README.txt");
+    fs_err::write(
+        &not_dicom,
+        "this is a text file, not a DICOM file.\n".repeat(10),
+    )
+    .unwrap();
+    let (code, stdout) = run_rx_repack(&not_dicom, &data_dir, &[]);
+    assert_eq!(code, 10);
+    assert_eq!(error_kind_of(&stdout), "not-dicom");
+
+    let (code, stdout) = run_rx_repack(&tmp_path.join("input/missing.dcm"), &data_dir, &[]);
+    assert_eq!(code, 14);
+    assert_eq!(error_kind_of(&stdout), "io");
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use camino::Utf8Path;
+use common::write_series;
+use rx_repack::{query, repack, QueryFilter, QueryLevel, RepackOptions};
+use std::process::Command;
+use tempdir::TempDir;
+
+#[test]
+fn test_index() {
+    let tmp_dir = TempDir::new("index").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let db = tmp_path.join("index").join("rx-repack.sqlite");
+    let options = RepackOptions {
+        index: Some(db.clone()),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    let series = [
+        ("patient1", "1.2.3", "1.2.3.4", 3),
+        ("patient1", "1.2.3", "1.2.3.5", 2),
+        ("patient2", "1.2.6", "1.2.6.7", 1),
+    ];
+    for (patient_id, study_uid, series_uid, n) in series {
+        for src in write_series(&input_dir, patient_id, study_uid, series_uid, n) {
+            repack(&src, false, &options).unwrap();
+        }
+    }
+    // repacking an instance again does not count it twice
+    let src = &write_series(&input_dir, "patient2", "1.2.6", "1.2.6.7", 1)[0];
+    let outcome = repack(src, false, &options).unwrap();
+
+    let all = QueryFilter::default();
+    let patients = query(&db, QueryLevel::Patients, &all).unwrap();
+    assert_eq!(patients.len(), 2);
+    assert_eq!(patients[0]["PatientID"], "patient1");
+    assert_eq!(patients[0]["NumberOfStudies"], 1);
+    assert_eq!(patients[0]["NumberOfInstances"], 5);
+    assert_eq!(patients[1]["NumberOfInstances"], 1);
+
+    let filter = QueryFilter {
+        PatientID: Some("patient1".to_string()),
+        ..Default::default()
+    };
+    let series = query(&db, QueryLevel::Series, &filter).unwrap();
+    let uids: Vec<_> = series.iter().map(|s| &s["SeriesInstanceUID"]).collect();
+    assert_eq!(uids, ["1.2.3.4", "1.2.3.5"]);
+    assert_eq!(series[0]["NumberOfInstances"], 3);
+
+    let filter = QueryFilter {
+        SeriesInstanceUID: Some("1.2.6.7".to_string()),
+        ..Default::default()
+    };
+    let instances = query(&db, QueryLevel::Instances, &filter).unwrap();
+    assert_eq!(instances.len(), 1);
+    assert_eq!(instances[0]["path"], outcome.dst.as_str());
+    let size = fs_err::metadata(&outcome.dst).unwrap().len();
+    assert_eq!(instances[0]["size"], size);
+
+    let filter = QueryFilter {
+        Modality: Some("CT".to_string()),
+        ..Default::default()
+    };
+    assert!(query(&db, QueryLevel::Studies, &filter).unwrap().is_empty());
+}
+
+#[test]
+fn test_query_cli() {
+    let tmp_dir = TempDir::new("index").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let db = tmp_path.join("rx-repack.sqlite");
+    write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 2);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .arg("batch")
+        .arg(&input_dir)
+        .args(["--datadir", data_dir.as_str()])
+        .args(["--logdir", log_dir.as_str()])
+        .args(["--index", db.as_str()])
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success());
+    // the JSON tree is still written
+    assert!(log_dir.join("patientData").join("patient1.json").is_file());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .args(["query", "--index", db.as_str(), "studies"])
+        .args(["--patient-id", "patient1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rows: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["StudyInstanceUID"], "1.2.3");
+    assert_eq!(rows[0]["NumberOfSeries"], 1);
+    assert_eq!(rows[0]["NumberOfInstances"], 2);
+}
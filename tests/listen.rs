@@ -0,0 +1,54 @@
+mod common;
+
+use camino::Utf8Path;
+use common::scu::{send_request, store_files};
+use common::{glob_files, write_series};
+use rx_repack::{listen, RepackOptions};
+use std::net::TcpListener;
+use tempdir::TempDir;
+
+#[test]
+fn test_listen() {
+    let tmp_dir = TempDir::new("listen").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap().to_path_buf();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let files = write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 3);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        ..RepackOptions::new(&data_dir)
+    };
+    std::thread::spawn(move || listen(listener, "RX-TEST-SCP", 4, &options, |_, _| ()));
+
+    let statuses = store_files(&address, "RX-TEST-SCP", &files);
+    assert_eq!(statuses, vec![0, 0, 0]);
+    assert_eq!(glob_files(&data_dir, "dcm").len(), 3);
+    assert_eq!(glob_files(&log_dir.join("seriesData"), "dcm.json").len(), 3);
+
+    // received files must be repacked with the original data
+    let repacked = glob_files(&data_dir, "dcm");
+    let dcm = dicom::object::open_file(&repacked[0]).unwrap();
+    assert_eq!(
+        dcm.element_by_name("PatientID").unwrap().to_str().unwrap(),
+        "patient1"
+    );
+}
+
+#[test]
+fn test_listen_unrecognized_operation() {
+    let tmp_dir = TempDir::new("listen").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap().to_path_buf();
+    let data_dir = tmp_path.join("data");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let options = RepackOptions::new(&data_dir);
+    std::thread::spawn(move || listen(listener, "RX-TEST-SCP", 4, &options, |_, _| ()));
+
+    // C-FIND-RQ is not supported, but must be refused without dropping the association
+    assert_eq!(send_request(&address, "RX-TEST-SCP", 0x0020), 0x0211);
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use common::{glob_files, read_bulk_data, write_series, Instance};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use rx_repack::{
+    migrate, query, repack, repack_object, undo_migrate, DicomJsonMode, QueryFilter, QueryLevel,
+    RepackOptions, DEFAULT_TEMPLATE,
+};
+use std::collections::BTreeMap;
+use tempdir::TempDir;
+
+/// Contents of the JSON files in a log dir, by path relative to the log dir.
+fn read_logs(log_dir: &Utf8Path) -> BTreeMap<Utf8PathBuf, serde_json::Value> {
+    glob_files(log_dir, "json")
+        .into_iter()
+        .map(|p| {
+            let data = serde_json::from_str(&fs_err::read_to_string(&p).unwrap()).unwrap();
+            (p.strip_prefix(log_dir).unwrap().to_path_buf(), data)
+        })
+        .collect()
+}
+
+fn read_json(p: Utf8PathBuf) -> serde_json::Value {
+    serde_json::from_str(&fs_err::read_to_string(p).unwrap()).unwrap()
+}
+
+#[test]
+fn test_migrate_and_undo() {
+    let tmp_dir = TempDir::new("migrate").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let db = tmp_path.join("index.sqlite");
+
+    // layout of px-repack, without hashes of SeriesInstanceUID
+    let old_template = DEFAULT_TEMPLATE.replace("-%_hash|7_SeriesInstanceUID", "");
+    let old_options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        index: Some(db.clone()),
+        template: old_template.parse().unwrap(),
+        ..RepackOptions::new(&data_dir)
+    };
+    let old_paths: Vec<_> = write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 2)
+        .iter()
+        .map(|src| repack(src, false, &old_options).unwrap().dst)
+        .collect();
+    let old_dir = old_paths[0].parent().unwrap().to_path_buf();
+    let old_nifti = old_dir.with_extension("nii.gz");
+    let old_sidecar = old_dir.with_extension("json");
+    fs_err::write(&old_nifti, "NIfTI").unwrap();
+    fs_err::write(&old_sidecar, "{}").unwrap();
+    let original_logs = read_logs(&log_dir);
+    fs_err::write(data_dir.join("notes.txt"), "not a DICOM file").unwrap();
+    fs_err::write(data_dir.join("corrupt.dcm"), "not a DICOM file").unwrap();
+
+    let options = RepackOptions {
+        template: DEFAULT_TEMPLATE.parse().unwrap(),
+        ..old_options
+    };
+    let mut moves = Vec::new();
+    let summary = migrate(&options, |_, outcome| {
+        if let Ok(Some(m)) = outcome {
+            moves.push(m.clone());
+        }
+    })
+    .unwrap();
+    assert_eq!(summary.moved, 2);
+    assert_eq!(summary.ignored, 3);
+    assert_eq!(summary.failed, 1);
+    assert!(!old_dir.exists());
+    let new_path = &moves[0].dst;
+    let new_dir = new_path.parent().unwrap();
+    assert!(new_path.is_file());
+    assert!(new_dir.as_str().starts_with(old_dir.as_str()));
+    assert_ne!(new_dir, old_dir);
+    assert!(!old_nifti.exists() && !old_sidecar.exists());
+    assert_eq!(
+        fs_err::read_to_string(format!("{new_dir}.nii.gz")).unwrap(),
+        "NIfTI"
+    );
+    assert_eq!(
+        fs_err::read_to_string(format!("{new_dir}.json")).unwrap(),
+        "{}"
+    );
+
+    let img_json = read_json(
+        log_dir
+            .join("seriesData/1.2.3.4-img")
+            .join(format!("{}.json", new_path.file_name().unwrap())),
+    );
+    assert_eq!(
+        img_json["1.2.3.4"]["imageObj"][new_path.file_name().unwrap()]["FSlocation"],
+        new_path.as_str()
+    );
+    let series_meta = read_json(log_dir.join("studyData/1.2.3-series/1.2.3.4-meta.json"));
+    assert_eq!(series_meta["1.2.3"]["SeriesBaseDir"], new_dir.as_str());
+    let progress = read_json(log_dir.join("seriesData/1.2.3.4-progress.json"));
+    assert_eq!(progress["SeriesBaseDir"], new_dir.as_str());
+    let series = query(&db, QueryLevel::Series, &QueryFilter::default()).unwrap();
+    assert_eq!(series[0]["SeriesBaseDir"], new_dir.as_str());
+
+    // nothing left to do
+    let summary = migrate(&options, |_, _| ()).unwrap();
+    assert_eq!((summary.moved, summary.unchanged), (0, 2));
+
+    let undone = undo_migrate(&options).unwrap();
+    assert_eq!(undone, moves);
+    for p in &old_paths {
+        assert!(p.is_file(), "{p} was not moved back");
+    }
+    assert!(!new_dir.exists());
+    assert!(old_nifti.is_file() && old_sidecar.is_file());
+    assert_eq!(read_logs(&log_dir), original_logs);
+    let instances = query(&db, QueryLevel::Instances, &QueryFilter::default()).unwrap();
+    let paths: Vec<_> = instances.iter().map(|i| &i["path"]).collect();
+    assert_eq!(paths, [old_paths[0].as_str(), old_paths[1].as_str()]);
+}
+
+#[test]
+fn test_migrate_bulk_data_uri() {
+    let tmp_dir = TempDir::new("migrate").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let old_options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        dicom_json: Some(DicomJsonMode::Instance),
+        template: DEFAULT_TEMPLATE
+            .replace("-%_hash|7_SeriesInstanceUID", "")
+            .parse()
+            .unwrap(),
+        ..RepackOptions::new(&data_dir)
+    };
+    let instance = Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid: "1.2.3.4",
+        series_number: 1,
+        instance_number: 1,
+    };
+    let mut dcm = instance.to_dicom();
+    let pixel_bytes = vec![7_u8; 16];
+    dcm.put(DataElement::new(
+        tags::PIXEL_DATA,
+        VR::OB,
+        PrimitiveValue::from(pixel_bytes.clone()),
+    ));
+    repack_object(dcm, &old_options).unwrap();
+
+    let options = RepackOptions {
+        template: DEFAULT_TEMPLATE.parse().unwrap(),
+        ..old_options
+    };
+    let mut moves = Vec::new();
+    migrate(&options, |_, outcome| {
+        if let Ok(Some(m)) = outcome {
+            moves.push(m.clone());
+        }
+    })
+    .unwrap();
+    let new_path = &moves[0].dst;
+    let dicom_json = read_json(
+        log_dir
+            .join("dicomJSON/1.2.3.4")
+            .join(format!("{}.json", new_path.file_name().unwrap())),
+    );
+    let uri = dicom_json["7FE00010"]["BulkDataURI"].as_str().unwrap();
+    assert!(uri.starts_with(&format!("file://{new_path}?")));
+    assert_eq!(read_bulk_data(uri), pixel_bytes);
+}
+
+#[test]
+fn test_undo_migrate_ignores_partial_last_line() {
+    let tmp_dir = TempDir::new("migrate").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let old_options = RepackOptions {
+        template: DEFAULT_TEMPLATE
+            .replace("-%_hash|7_SeriesInstanceUID", "")
+            .parse()
+            .unwrap(),
+        ..RepackOptions::new(&data_dir)
+    };
+    let old_paths: Vec<_> = write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 1)
+        .iter()
+        .map(|src| repack(src, false, &old_options).unwrap().dst)
+        .collect();
+    let options = RepackOptions {
+        template: DEFAULT_TEMPLATE.parse().unwrap(),
+        ..old_options
+    };
+    migrate(&options, |_, _| ()).unwrap();
+
+    // interrupted while journaling the next move
+    let journal = data_dir.join(".migrate.journal");
+    let mut contents = fs_err::read_to_string(&journal).unwrap();
+    contents.push_str("{\"src\":\"/da");
+    fs_err::write(&journal, contents).unwrap();
+
+    let undone = undo_migrate(&options).unwrap();
+    assert_eq!(undone.len(), 1);
+    assert!(old_paths[0].is_file());
+    assert!(!journal.exists());
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{glob_files, Instance};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::process::Command;
+use tempdir::TempDir;
+
+const ROWS: u16 = 2;
+const COLUMNS: u16 = 3;
+const SLICES: u32 = 4;
+
+/// Write a sagittal slice of a synthetic series, with pixel values `100 * slice + pixel`.
+fn write_slice(dir: &Utf8Path, instance_number: u32) {
+    let instance = Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid: "1.2.3.4",
+        series_number: 1,
+        instance_number,
+    };
+    let mut dcm = instance.to_dicom();
+    let x = -10.0 + 1.5 * instance_number as f64;
+    let pixels: Vec<u16> = (0..ROWS * COLUMNS)
+        .map(|p| 100 * instance_number as u16 + p)
+        .collect();
+    let elements = [
+        (
+            tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+            VR::IS,
+            SLICES.to_string().into(),
+        ),
+        (tags::ECHO_TIME, VR::DS, "2.5".into()),
+        (tags::REPETITION_TIME, VR::DS, "2300".into()),
+        (tags::IMAGE_TYPE, VR::CS, "ORIGINAL\\PRIMARY\\M".into()),
+        (
+            tags::IN_PLANE_PHASE_ENCODING_DIRECTION,
+            VR::CS,
+            "ROW".into(),
+        ),
+        (tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)),
+        (
+            tags::PHOTOMETRIC_INTERPRETATION,
+            VR::CS,
+            "MONOCHROME2".into(),
+        ),
+        (tags::ROWS, VR::US, PrimitiveValue::from(ROWS)),
+        (tags::COLUMNS, VR::US, PrimitiveValue::from(COLUMNS)),
+        (tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(16_u16)),
+        (tags::BITS_STORED, VR::US, PrimitiveValue::from(12_u16)),
+        (tags::HIGH_BIT, VR::US, PrimitiveValue::from(11_u16)),
+        (
+            tags::PIXEL_REPRESENTATION,
+            VR::US,
+            PrimitiveValue::from(0_u16),
+        ),
+        (tags::RESCALE_SLOPE, VR::DS, "2".into()),
+        (tags::RESCALE_INTERCEPT, VR::DS, "-1".into()),
+        (tags::PIXEL_SPACING, VR::DS, "0.5\\0.25".into()),
+        (
+            tags::IMAGE_ORIENTATION_PATIENT,
+            VR::DS,
+            "0\\1\\0\\0\\0\\-1".into(),
+        ),
+        (
+            tags::IMAGE_POSITION_PATIENT,
+            VR::DS,
+            format!("{x}\\5\\7").into(),
+        ),
+        (tags::PIXEL_DATA, VR::OW, PrimitiveValue::U16(pixels.into())),
+    ];
+    for (tag, vr, value) in elements {
+        dcm.put(DataElement::new(tag, vr, value));
+    }
+    fs_err::create_dir_all(dir).unwrap();
+    dcm.write_to_file(dir.join(format!("{instance_number}.dcm")))
+        .unwrap();
+}
+
+fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn i16_at(bytes: &[u8], offset: usize) -> i16 {
+    i16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+#[test]
+fn test_nifti() {
+    let tmp_dir = TempDir::new("nifti").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    // slices are given out of order
+    for instance_number in [3, 1, 4, 2] {
+        write_slice(&input_dir, instance_number);
+    }
+    let output = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .arg("batch")
+        .arg(&input_dir)
+        .arg("--datadir")
+        .arg(&data_dir)
+        .arg("--logdir")
+        .arg(tmp_path.join("log"))
+        .arg("--nifti")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let nifti_files = glob_files(&data_dir, "nii.gz");
+    assert_eq!(nifti_files.len(), 1);
+    let nifti_file = &nifti_files[0];
+    let series_dir = glob_files(&data_dir, "dcm")[0]
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    assert_eq!(nifti_file.as_str(), format!("{series_dir}.nii.gz"));
+
+    let messages: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(messages.len(), SLICES as usize + 2);
+    for message in &messages[..SLICES as usize] {
+        assert!(message["dst"]
+            .as_str()
+            .unwrap()
+            .starts_with(series_dir.as_str()));
+    }
+    let complete = &messages[SLICES as usize];
+    assert_eq!(complete["event"], "series-complete");
+    assert_eq!(complete["SeriesInstanceUID"], "1.2.3.4");
+    assert_eq!(complete["SeriesBaseDir"], series_dir.as_str());
+    assert_eq!(
+        (complete["received"].as_u64(), complete["reason"].as_str()),
+        (Some(4), Some("count"))
+    );
+    assert_eq!(messages[SLICES as usize + 1]["succeeded"], SLICES);
+
+    let mut nifti = Vec::new();
+    GzDecoder::new(fs_err::File::open(nifti_file).unwrap())
+        .read_to_end(&mut nifti)
+        .unwrap();
+    assert_eq!(&nifti[344..348], b"n+1\0");
+    let dim: Vec<_> = (0..5).map(|i| i16_at(&nifti, 40 + 2 * i)).collect();
+    assert_eq!(dim, [3, COLUMNS as i16, ROWS as i16, SLICES as i16, 1]);
+    let pixdim: Vec<_> = (1..4).map(|i| f32_at(&nifti, 76 + 4 * i)).collect();
+    assert_eq!(pixdim, [0.25, 0.5, 1.5]);
+    // scl_slope and scl_inter
+    assert_eq!((f32_at(&nifti, 112), f32_at(&nifti, 116)), (2.0, -1.0));
+    let srow: Vec<_> = (0..12).map(|i| f32_at(&nifti, 280 + 4 * i)).collect();
+    assert_eq!(
+        srow,
+        [0.0, 0.0, 1.5, 4.0, -0.25, 0.0, 0.0, -5.0, 0.0, -0.5, 0.0, 7.0]
+    );
+    // slices are ordered from right to left, i.e. from instance 4 to 1
+    let vox_offset = f32_at(&nifti, 108) as usize;
+    let voxels: Vec<_> = nifti[vox_offset..]
+        .chunks(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    assert_eq!(voxels.len(), (ROWS * COLUMNS) as usize * SLICES as usize);
+    assert_eq!(&voxels[..7], [400, 401, 402, 403, 404, 405, 300]);
+
+    let sidecar: serde_json::Value =
+        serde_json::from_str(&fs_err::read_to_string(format!("{series_dir}.json")).unwrap())
+            .unwrap();
+    assert_eq!(sidecar["Modality"], "MR");
+    assert_eq!(sidecar["EchoTime"], 0.0025);
+    assert_eq!(sidecar["RepetitionTime"], 2.3);
+    assert_eq!(
+        sidecar["ImageType"],
+        serde_json::json!(["ORIGINAL", "PRIMARY", "M"])
+    );
+    assert_eq!(sidecar["PhaseEncodingAxis"], "i");
+    assert_eq!(sidecar["ConversionSoftware"], "rx-repack");
+}
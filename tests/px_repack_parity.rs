@@ -2,8 +2,8 @@ extern crate tempdir;
 
 use anyhow::{bail, Context};
 use camino::{Utf8Path, Utf8PathBuf};
-use rx_repack::repack;
-use std::io::BufReader;
+use rx_repack::{repack, series_to_nifti, DeidProfile, Deidentifier, RepackOptions};
+use std::io::{BufReader, Read};
 use std::path::Path;
 use std::process::Command;
 use tempdir::TempDir;
@@ -46,7 +46,7 @@ fn test_parity_with_px_repack() -> anyhow::Result<()> {
             actual_file.is_file(),
             "{} is not a file. Parent has files: {:?}",
             &actual_file,
-            glob::glob(&actual_file.with_file_name("*").as_str())
+            glob::glob(actual_file.with_file_name("*").as_str())
                 .unwrap()
                 .map(|r| r
                     .map(|p| p.to_string_lossy().to_string())
@@ -62,7 +62,7 @@ fn test_parity_with_px_repack() -> anyhow::Result<()> {
             actual_file.is_file(),
             "{} is not a file. Parent has files: {:?}",
             &actual_file,
-            glob::glob(&actual_file.with_file_name("*").as_str())
+            glob::glob(actual_file.with_file_name("*").as_str())
                 .unwrap()
                 .map(|r| r
                     .map(|p| p.to_string_lossy().to_string())
@@ -76,6 +76,10 @@ fn test_parity_with_px_repack() -> anyhow::Result<()> {
 
 /// Run rx-repack on all files in a directory.
 fn process_all(od_dir: &Utf8Path, data_dir: &Utf8Path, log_dir: &Utf8Path) -> anyhow::Result<()> {
+    let options = RepackOptions {
+        log_dir: Some(log_dir.to_path_buf()),
+        ..RepackOptions::new(data_dir)
+    };
     fs_err::read_dir(od_dir)
         .unwrap()
         .map(|r| r.unwrap())
@@ -84,9 +88,118 @@ fn process_all(od_dir: &Utf8Path, data_dir: &Utf8Path, log_dir: &Utf8Path) -> an
         .map(|e| e.path())
         .map(Utf8PathBuf::from_path_buf)
         .map(Result::unwrap)
-        .map(|dicom_file| (repack(&dicom_file, data_dir, Some(log_dir), false)))
-        .map(|e| e.map(|_| ())) // discard return values
-        .collect()
+        .try_for_each(|dicom_file| {
+            repack(&dicom_file, false, &options)?;
+            anyhow::Ok(())
+        })
+}
+
+/// De-identifying the SAG-anon examples should remove their identifiers
+/// from the output paths and logs.
+#[test]
+fn test_deidentify_examples() -> anyhow::Result<()> {
+    let (od_dir, _, _) = find_examples().with_context(examples_instructions)?;
+    let tmp_dir = TempDir::new("example")?;
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        deidentify: Some(Deidentifier::new(DeidProfile::Basic, b"example key")),
+        ..RepackOptions::new(&data_dir)
+    };
+
+    let mut original_series = std::collections::HashSet::new();
+    let mut deidentified_series = std::collections::HashSet::new();
+    for entry in fs_err::read_dir(&od_dir)? {
+        let dicom_file = Utf8PathBuf::from_path_buf(entry?.path()).unwrap();
+        if dicom_file.extension() != Some("dcm") {
+            continue;
+        }
+        let original = dicom::object::open_file(&dicom_file)?;
+        let outcome = repack(&dicom_file, false, &options)?;
+        let phi = ["PatientID", "PatientName", "PatientBirthDate"].map(|name| {
+            original
+                .element_by_name(name)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        });
+        for value in phi.iter().filter(|v| !v.is_empty()) {
+            assert!(!outcome.dst.as_str().contains(value.as_str()));
+        }
+        original_series.insert(
+            original
+                .element_by_name("SeriesInstanceUID")?
+                .to_str()?
+                .to_string(),
+        );
+        deidentified_series.insert(outcome.SeriesInstanceUID);
+    }
+    // UIDs are remapped consistently
+    assert_eq!(original_series.len(), deidentified_series.len());
+    assert!(original_series.is_disjoint(&deidentified_series));
+    anyhow::Ok(())
+}
+
+/// The SAG-anon examples are from a Siemens scanner, so their CSA headers should be decoded.
+#[test]
+fn test_csa_examples() -> anyhow::Result<()> {
+    let (od_dir, _, _) = find_examples().with_context(examples_instructions)?;
+    let tmp_dir = TempDir::new("example")?;
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+    process_all(&od_dir, &tmp_path.join("data"), &log_dir)?;
+
+    let series_meta_files = glob::glob(log_dir.join("studyData/*-series/*-meta.json").as_str())?;
+    for series_meta_file in series_meta_files {
+        let series_meta = load_json(series_meta_file?);
+        let (_, series_meta) = series_meta.as_object().unwrap().iter().next().unwrap();
+        assert!(series_meta["CSA"]["Series"]["MrPhoenixProtocol"].is_string());
+    }
+    let img_files = glob::glob(log_dir.join("seriesData/*-img/*.json").as_str())?;
+    for img_file in img_files {
+        let img = load_json(img_file?);
+        let (_, img) = img.as_object().unwrap().iter().next().unwrap();
+        assert!(img["CSAImageHeader"]["SliceNormalVector"].is_array());
+    }
+    anyhow::Ok(())
+}
+
+/// The SAG-anon examples are an MPRAGE volume, which should be reconstructed with the
+/// geometry of its DICOM files.
+#[test]
+fn test_nifti_examples() -> anyhow::Result<()> {
+    let (od_dir, _, _) = find_examples().with_context(examples_instructions)?;
+    let tmp_dir = TempDir::new("example")?;
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    process_all(&od_dir, &data_dir, &tmp_path.join("log"))?;
+
+    let series_dir = data_dir.join(get_first_dicom_dir(&data_dir));
+    let n_files = fs_err::read_dir(&series_dir)?.count();
+    let dcm = dicom::object::open_file(fs_err::read_dir(&series_dir)?.next().unwrap()?.path())?;
+    let rows = dcm.element_by_name("Rows")?.to_int::<i16>()?;
+    let columns = dcm.element_by_name("Columns")?.to_int::<i16>()?;
+    let pixel_spacing = dcm.element_by_name("PixelSpacing")?.to_multi_float32()?;
+
+    let files = series_to_nifti(&series_dir)?;
+    let mut nifti = Vec::new();
+    flate2::read::GzDecoder::new(fs_err::File::open(&files.nifti)?).read_to_end(&mut nifti)?;
+    let i16_at = |offset: usize| i16::from_le_bytes([nifti[offset], nifti[offset + 1]]);
+    let f32_at = |offset: usize| f32::from_le_bytes(nifti[offset..offset + 4].try_into().unwrap());
+    assert_eq!(i16_at(40), 3);
+    assert_eq!(
+        [i16_at(42), i16_at(44), i16_at(46)],
+        [columns, rows, n_files as i16]
+    );
+    assert_eq!(
+        [f32_at(80), f32_at(84)],
+        [pixel_spacing[1], pixel_spacing[0]]
+    );
+    assert!(files.sidecar.is_file());
+    anyhow::Ok(())
 }
 
 fn dirs_are_equal(expected: &Utf8Path, actual: &Utf8Path) -> bool {
@@ -112,7 +225,7 @@ fn get_first_dicom_dir(path: &Utf8Path) -> Utf8PathBuf {
     let abspath = glob::glob(path.join("**/*.dcm").as_str())
         .unwrap()
         .next()
-        .expect(&format!("*.dcm not found in {path}"))
+        .unwrap_or_else(|| panic!("*.dcm not found in {path}"))
         .map(|p| Utf8PathBuf::from_path_buf(p).unwrap())
         .unwrap();
     pathdiff::diff_utf8_paths(abspath.parent().unwrap(), path).unwrap()
@@ -175,7 +288,7 @@ fn find_examples() -> anyhow::Result<(Utf8PathBuf, Utf8PathBuf, Utf8PathBuf)> {
         } else if file_name_starts_with(&p, "px-repack-output") {
             data_dir = Some(p.join("data"));
             log_dir = Some(p.join("log"));
-            for dir in (&[&data_dir, &log_dir]).iter().filter_map(|f| f.as_ref()) {
+            for dir in [&data_dir, &log_dir].iter().filter_map(|f| f.as_ref()) {
                 if !dir.is_dir() {
                     bail!("{:?} is not a directory", dir);
                 }
@@ -0,0 +1,85 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{glob_files, write_series};
+use rx_repack::{json_message, repack, RepackOptions};
+use tempdir::TempDir;
+
+fn read_json(p: &Utf8Path) -> serde_json::Value {
+    serde_json::from_str(&fs_err::read_to_string(p).unwrap()).unwrap()
+}
+
+#[test]
+fn test_truncated_patient_data() {
+    let tmp_dir = TempDir::new("quarantine").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    for study in ["1.2.3", "1.2.4"] {
+        let src = write_series(&input_dir, "patient1", study, &format!("{study}.1"), 1);
+        repack(&src[0], false, &options).unwrap();
+    }
+    let patient_data = log_dir.join("patientData/patient1.json");
+    let content = fs_err::read_to_string(&patient_data).unwrap();
+    let truncated = &content[..content.rfind(']').unwrap()];
+    fs_err::write(&patient_data, truncated).unwrap();
+
+    let src = write_series(&input_dir, "patient1", "1.2.5", "1.2.5.1", 1);
+    let outcome = repack(&src[0], false, &options).unwrap();
+    assert_eq!(outcome.quarantined.len(), 1);
+    let quarantined = &outcome.quarantined[0];
+    assert_eq!(quarantined.path, patient_data);
+    assert!(quarantined.salvaged.contains(&"StudyList".to_string()));
+    assert_eq!(
+        quarantined.quarantine.parent().unwrap(),
+        log_dir.join("quarantine/patientData")
+    );
+    assert_eq!(
+        fs_err::read_to_string(&quarantined.quarantine).unwrap(),
+        truncated
+    );
+
+    let mut studies: Vec<_> = read_json(&patient_data)["patient1"]["StudyList"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s.as_str().unwrap().to_string())
+        .collect();
+    studies.sort();
+    assert_eq!(studies, ["1.2.3", "1.2.4", "1.2.5"]);
+
+    let msg: serde_json::Value =
+        serde_json::from_str(&json_message(&src[0], &Ok(outcome)).unwrap()).unwrap();
+    assert_eq!(msg["quarantined"][0]["path"], patient_data.as_str());
+}
+
+#[test]
+fn test_garbage_progress() {
+    let tmp_dir = TempDir::new("quarantine").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    let srcs = write_series(&tmp_path.join("input"), "patient1", "1.2.3", "1.2.3.4", 2);
+    let outcome = repack(&srcs[0], false, &options).unwrap();
+    assert!(outcome.quarantined.is_empty());
+    let progress = log_dir.join("seriesData/1.2.3.4-progress.json");
+    fs_err::write(&progress, b"\x00\xffnot JSON at all").unwrap();
+
+    let outcome = repack(&srcs[1], false, &options).unwrap();
+    assert_eq!(outcome.quarantined.len(), 1);
+    assert_eq!(outcome.quarantined[0].path, progress);
+    assert!(outcome.quarantined[0].salvaged.is_empty());
+    assert_eq!(read_json(&progress)["received"], 1);
+    assert_eq!(glob_files(&log_dir.join("quarantine"), "*").len(), 1);
+
+    // the replacement is valid, so nothing more is quarantined
+    let outcome = repack(&srcs[1], false, &options).unwrap();
+    assert!(outcome.quarantined.is_empty());
+}
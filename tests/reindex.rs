@@ -0,0 +1,183 @@
+mod common;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use common::{glob_files, write_series};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use rx_repack::{reindex, repack, ErrorKind, OnConflict, RepackOptions};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tempdir::TempDir;
+
+/// Contents of the JSON files in a log dir, by path relative to the log dir.
+fn read_logs(log_dir: &Utf8Path) -> BTreeMap<Utf8PathBuf, serde_json::Value> {
+    glob_files(log_dir, "json")
+        .into_iter()
+        .map(|p| {
+            let data = serde_json::from_str(&fs_err::read_to_string(&p).unwrap()).unwrap();
+            (p.strip_prefix(log_dir).unwrap().to_path_buf(), data)
+        })
+        .collect()
+}
+
+#[test]
+fn test_reindex() {
+    let tmp_dir = TempDir::new("reindex").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let input_dir = tmp_path.join("input");
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        ..RepackOptions::new(&data_dir)
+    };
+    for src in write_series(&input_dir, "patient1", "1.2.3", "1.2.3.4", 3) {
+        repack(&src, false, &options).unwrap();
+    }
+    let mut original = read_logs(&log_dir);
+    fs_err::remove_dir_all(&log_dir).unwrap();
+
+    let mismatched = write_series(&input_dir, "patient2", "1.2.5", "1.2.5.6", 1).remove(0);
+    let misplaced = data_dir.join("misplaced.dcm");
+    fs_err::copy(&mismatched, &misplaced).unwrap();
+    fs_err::write(data_dir.join("notes.txt"), "not a DICOM file").unwrap();
+    // DICOM files which cannot be read fail rather than being ignored
+    fs_err::write(data_dir.join("corrupt.dcm"), "not a DICOM file").unwrap();
+    let mut corrupt = vec![0; 128];
+    corrupt.extend(b"DICMnot a DICOM file");
+    fs_err::write(data_dir.join("corrupt"), corrupt).unwrap();
+
+    let outcomes = Mutex::new(BTreeMap::new());
+    let summary = reindex(2, &options, |src, outcome| {
+        let expected = outcome.as_ref().ok().and_then(|o| o.expected.clone());
+        outcomes.lock().unwrap().insert(src.to_path_buf(), expected);
+    })
+    .unwrap();
+    assert_eq!(summary.total, 7);
+    assert_eq!(summary.reindexed, 4);
+    assert_eq!(summary.ignored, 1);
+    assert_eq!(summary.failed, 2);
+    assert_eq!(summary.errors[&ErrorKind::NotDicom], 2);
+    assert_eq!(summary.mismatched, 1);
+    let outcomes = outcomes.into_inner().unwrap();
+    let expected = outcomes[&misplaced].as_ref().unwrap();
+    assert_ne!(expected, &misplaced);
+    assert!(expected.starts_with(&data_dir));
+
+    let mut reindexed = read_logs(&log_dir);
+    // the time of receiving differs
+    let progress = Utf8Path::new("seriesData/1.2.3.4-progress.json");
+    let received = |logs: &mut BTreeMap<_, serde_json::Value>| {
+        logs.remove(progress).unwrap()["received"].clone()
+    };
+    assert_eq!(received(&mut original), 3);
+    assert_eq!(received(&mut reindexed), 3);
+    // written by whichever instance of the series comes first, which differs between runs
+    let series_meta = Utf8Path::new("studyData/1.2.3-series/1.2.3.4-meta.json");
+    for logs in [&mut original, &mut reindexed] {
+        let dicom = &mut logs.get_mut(series_meta).unwrap()["1.2.3"]["DICOM"];
+        let dicom = dicom.as_object_mut().unwrap();
+        dicom.remove("InstanceNumber").unwrap();
+        dicom.remove("SOPInstanceUID").unwrap();
+    }
+    for (p, data) in &original {
+        assert_eq!(reindexed.get(p), Some(data), "{p} differs");
+    }
+
+    // resume: nothing is reindexed twice
+    let summary = reindex(2, &options, |_, _| ()).unwrap();
+    assert_eq!(summary.skipped, 4);
+    assert_eq!(summary.reindexed, 0);
+    assert_eq!(received(&mut read_logs(&log_dir)), 3);
+}
+
+#[test]
+fn test_reindex_into_existing_log_dir() {
+    let tmp_dir = TempDir::new("reindex").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        series_timeout: Some(Duration::from_secs(3600)),
+        ..RepackOptions::new(&data_dir)
+    };
+    // 3 of 4 instances were received
+    for src in write_series(&tmp_path.join("input"), "patient1", "1.2.3", "1.2.3.4", 3) {
+        let mut dcm = dicom::object::open_file(&src).unwrap();
+        dcm.put(DataElement::new(
+            tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+            VR::IS,
+            PrimitiveValue::from("4"),
+        ));
+        dcm.write_to_file(&src).unwrap();
+        repack(&src, false, &options).unwrap();
+    }
+
+    let summary = reindex(2, &options, |_, _| ()).unwrap();
+    assert_eq!(summary.reindexed, 3);
+    let progress = &read_logs(&log_dir)[Utf8Path::new("seriesData/1.2.3.4-progress.json")];
+    assert_eq!(progress["received"], 3);
+    assert_eq!(progress["expected"], 4);
+    assert_eq!(progress["complete"], false);
+    assert!(!log_dir.join("seriesData/1.2.3.4-pack.json").exists());
+}
+
+#[test]
+fn test_reindex_counts_distinct_instances_which_exist() {
+    let tmp_dir = TempDir::new("reindex").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        on_conflict: OnConflict::KeepBoth,
+        series_timeout: Some(Duration::from_secs(3600)),
+        ..RepackOptions::new(&data_dir)
+    };
+    let srcs = write_series(&tmp_path.join("input"), "patient1", "1.2.3", "1.2.3.4", 3);
+    for src in &srcs {
+        let mut dcm = dicom::object::open_file(src).unwrap();
+        dcm.put(DataElement::new(
+            tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+            VR::IS,
+            PrimitiveValue::from("3"),
+        ));
+        dcm.write_to_file(src).unwrap();
+    }
+    // the first instance is received twice and kept both times,
+    // the third instance is removed from the data dir but not from the logs
+    repack(&srcs[0], false, &options).unwrap();
+    repack(&srcs[0], false, &options).unwrap();
+    repack(&srcs[1], false, &options).unwrap();
+    let removed = repack(&srcs[2], false, &options).unwrap().dst;
+    fs_err::remove_file(&removed).unwrap();
+    assert_eq!(
+        glob_files(&log_dir.join("seriesData/1.2.3.4-img"), "json").len(),
+        4
+    );
+    // the series was complete, until its progress was lost
+    let progress_fname = log_dir.join("seriesData/1.2.3.4-progress.json");
+    let pack = log_dir.join("seriesData/1.2.3.4-pack.json");
+    fs_err::remove_file(&progress_fname).unwrap();
+    fs_err::remove_file(&pack).unwrap();
+    let progress = || -> serde_json::Value {
+        serde_json::from_str(&fs_err::read_to_string(&progress_fname).unwrap()).unwrap()
+    };
+
+    let summary = reindex(2, &options, |_, _| ()).unwrap();
+    assert_eq!(summary.reindexed, 3);
+    assert_eq!(progress()["received"], 2);
+    assert!(summary.series_complete.is_empty());
+    assert!(!pack.exists());
+
+    // resuming recounts the series, which is now complete
+    fs_err::copy(&srcs[2], &removed).unwrap();
+    let summary = reindex(2, &options, |_, _| ()).unwrap();
+    assert_eq!((summary.skipped, summary.reindexed), (3, 1));
+    assert_eq!(progress()["received"], 3);
+    assert_eq!(summary.series_complete.len(), 1);
+    assert_eq!(summary.series_complete[0].received, 3);
+    assert!(pack.is_file());
+}
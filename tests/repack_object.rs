@@ -0,0 +1,117 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{read_bulk_data, Instance};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use rx_repack::{repack_object, DicomJsonMode, OnConflict, Placement, RepackOptions};
+use tempdir::TempDir;
+
+#[test]
+fn test_repack_object() {
+    let tmp_dir = TempDir::new("repack_object").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let data_dir = tmp_path.join("data");
+    let log_dir = tmp_path.join("log");
+    let instance = Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid: "1.2.3.4",
+        series_number: 2,
+        instance_number: 7,
+    };
+
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        dicom_json: Some(DicomJsonMode::Instance),
+        ..RepackOptions::new(&data_dir)
+    };
+    let mut dcm = instance.to_dicom();
+    let pixels: Vec<u16> = (0..64).collect();
+    dcm.put(DataElement::new(
+        tags::PIXEL_DATA,
+        VR::OW,
+        PrimitiveValue::U16(pixels.as_slice().into()),
+    ));
+    let outcome = repack_object(dcm, &options).unwrap();
+
+    assert!(outcome.dst.starts_with(&data_dir));
+    assert_eq!(
+        outcome.dst.file_name().unwrap(),
+        format!("0007-{}.dcm", instance.sop_instance_uid())
+    );
+    assert_eq!(outcome.PatientID, "patient1");
+    assert_eq!(outcome.SeriesInstanceUID, "1.2.3.4");
+
+    let written = dicom::object::open_file(&outcome.dst).unwrap();
+    assert_eq!(
+        written
+            .element_by_name("SOPInstanceUID")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        instance.sop_instance_uid()
+    );
+    assert!(log_dir.join("patientData/patient1.json").is_file());
+    assert!(log_dir.join("seriesData/1.2.3.4-meta.json").is_file());
+
+    let dicom_json_file = log_dir
+        .join("dicomJSON/1.2.3.4")
+        .join(format!("{}.json", outcome.dst.file_name().unwrap()));
+    let dicom_json: serde_json::Value =
+        serde_json::from_str(&fs_err::read_to_string(dicom_json_file).unwrap()).unwrap();
+    assert_eq!(
+        dicom_json["0020000E"],
+        serde_json::json!({"vr": "UI", "Value": ["1.2.3.4"]})
+    );
+    assert_eq!(dicom_json["7FE00010"]["vr"], "OW");
+    let uri = dicom_json["7FE00010"]["BulkDataURI"].as_str().unwrap();
+    assert!(uri.starts_with(&format!("file://{}?", outcome.dst)));
+    let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+    assert_eq!(read_bulk_data(uri), pixel_bytes);
+}
+
+#[test]
+fn test_repack_object_verify_duplicates() {
+    let tmp_dir = TempDir::new("repack_object").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+    let instance = Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid: "1.2.3.4",
+        series_number: 2,
+        instance_number: 7,
+    };
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        on_conflict: OnConflict::Verify,
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+
+    let first = repack_object(instance.to_dicom(), &options).unwrap();
+    assert_eq!(first.placement, Placement::New);
+
+    let resent = repack_object(instance.to_dicom(), &options).unwrap();
+    assert_eq!(resent.placement, Placement::Identical);
+    assert_eq!(resent.dst, first.dst);
+
+    let mut different = instance.to_dicom();
+    different.put(DataElement::new(
+        tags::PATIENT_WEIGHT,
+        VR::DS,
+        PrimitiveValue::from("42"),
+    ));
+    let outcome = repack_object(different, &options).unwrap();
+    assert_eq!(outcome.placement, Placement::Differing);
+    assert_eq!(
+        outcome.dst.file_name().unwrap(),
+        format!("0007-{}-1.dcm", instance.sop_instance_uid())
+    );
+    assert!(first.dst.is_file());
+    assert!(outcome.dst.is_file());
+    assert!(log_dir
+        .join("seriesData/1.2.3.4-img")
+        .join(format!("{}.json", outcome.dst.file_name().unwrap()))
+        .is_file());
+}
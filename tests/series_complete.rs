@@ -0,0 +1,175 @@
+mod common;
+
+use camino::Utf8Path;
+use common::Instance;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::tags;
+use dicom::object::DefaultDicomObject;
+use rx_repack::{
+    complete_idle_series, repack_object, series_complete_message, CompletionReason, RepackOptions,
+    SeriesComplete,
+};
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempdir::TempDir;
+
+fn instance(series_uid: &str, instance_number: u32) -> Instance<'_> {
+    Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid,
+        series_number: 1,
+        instance_number,
+    }
+}
+
+/// An instance of a series of `n` instances.
+fn instance_of_n(series_uid: &str, instance_number: u32, n: u32) -> DefaultDicomObject {
+    let mut dcm = instance(series_uid, instance_number).to_dicom();
+    dcm.put(DataElement::new(
+        tags::NUMBER_OF_SERIES_RELATED_INSTANCES,
+        VR::IS,
+        PrimitiveValue::from(n.to_string()),
+    ));
+    dcm
+}
+
+#[test]
+fn test_series_complete_by_count() {
+    let tmp_dir = TempDir::new("series_complete").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        // without a timeout, -pack.json would be written on the first instance
+        series_timeout: Some(Duration::from_secs(3600)),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    let pack = log_dir.join("seriesData/1.2.3.4-pack.json");
+    let repack_nth = |instance_number| {
+        repack_object(instance_of_n("1.2.3.4", instance_number, 3), &options).unwrap()
+    };
+
+    assert!(repack_nth(1).series_complete.is_none());
+    assert!(repack_nth(2).series_complete.is_none());
+    // a re-sent instance does not count
+    assert!(repack_nth(1).series_complete.is_none());
+    assert!(!pack.exists());
+
+    let outcome = repack_nth(3);
+    let complete = outcome.series_complete.unwrap();
+    assert_eq!(complete.reason, CompletionReason::Count);
+    assert_eq!(complete.received, 3);
+    assert_eq!(complete.expected, Some(3));
+    assert_eq!(complete.StudyInstanceUID, "1.2.3");
+    assert_eq!(&complete.SeriesBaseDir, outcome.dst.parent().unwrap());
+    assert!(pack.is_file());
+
+    let msg: serde_json::Value =
+        serde_json::from_str(&series_complete_message(&complete).unwrap()).unwrap();
+    assert_eq!(msg["event"], "series-complete");
+    assert_eq!(msg["SeriesInstanceUID"], "1.2.3.4");
+    assert_eq!(msg["reason"], "count");
+}
+
+#[test]
+fn test_series_complete_by_idle_timeout() {
+    let tmp_dir = TempDir::new("series_complete").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        series_timeout: Some(Duration::from_secs(3600)),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    for instance_number in 1..=2 {
+        let outcome = repack_object(instance("1.2.3.5", instance_number).to_dicom(), &options);
+        assert!(outcome.unwrap().series_complete.is_none());
+    }
+    let pack = log_dir.join("seriesData/1.2.3.5-pack.json");
+    assert!(!pack.exists());
+    assert!(complete_idle_series(&options).unwrap().is_empty());
+
+    let options = RepackOptions {
+        series_timeout: Some(Duration::ZERO),
+        ..options
+    };
+    let completed = complete_idle_series(&options).unwrap();
+    assert_eq!(completed.len(), 1);
+    assert_eq!(completed[0].reason, CompletionReason::IdleTimeout);
+    assert_eq!(completed[0].received, 2);
+    assert_eq!(completed[0].expected, None);
+    assert!(pack.is_file());
+}
+
+#[test]
+fn test_on_series_complete_callback() {
+    let tmp_dir = TempDir::new("series_complete").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let called = Arc::new(Mutex::new(Vec::new()));
+    let on_series_complete = {
+        let called = Arc::clone(&called);
+        move |series: &SeriesComplete| {
+            let uid = series.SeriesInstanceUID.clone();
+            called.lock().unwrap().push(uid)
+        }
+    };
+    let options = RepackOptions {
+        log_dir: Some(tmp_path.join("log")),
+        series_timeout: Some(Duration::ZERO),
+        on_series_complete: Some(Box::new(on_series_complete)),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    for instance_number in 1..=2 {
+        repack_object(instance_of_n("1.2.3.6", instance_number, 2), &options).unwrap();
+    }
+    repack_object(instance("1.2.3.7", 1).to_dicom(), &options).unwrap();
+    assert_eq!(*called.lock().unwrap(), ["1.2.3.6"]);
+    complete_idle_series(&options).unwrap();
+    assert_eq!(*called.lock().unwrap(), ["1.2.3.6", "1.2.3.7"]);
+}
+
+#[test]
+fn test_on_series_complete_command() {
+    let tmp_dir = TempDir::new("series_complete").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let output = tmp_path.join("hook_output.txt");
+    let script = tmp_path.join("hook.sh");
+    fs_err::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho \"$@\" \"$RX_SERIES_RECEIVED\" \"$RX_SERIES_COMPLETE_REASON\" >> '{output}'\n"
+        ),
+    )
+    .unwrap();
+    fs_err::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let input_dir = tmp_path.join("input");
+    fs_err::create_dir(&input_dir).unwrap();
+    let dicom_file = input_dir.join("1.dcm");
+    instance_of_n("1.2.3.8", 1, 1)
+        .write_to_file(&dicom_file)
+        .unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_rx-repack"))
+        .arg("--xcrdir")
+        .arg(&input_dir)
+        .arg("--xcrfile")
+        .arg("1.dcm")
+        .arg("--datadir")
+        .arg(tmp_path.join("data"))
+        .arg("--logdir")
+        .arg(tmp_path.join("log"))
+        .arg("--on-series-complete")
+        .arg(&script)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = fs_err::read_to_string(&output).unwrap();
+    let words: Vec<_> = output.split_whitespace().collect();
+    assert_eq!(words.len(), 5);
+    assert!(words[0].starts_with(tmp_path.join("data").as_str()));
+    assert_eq!(&words[1..], ["1.2.3.8", "1.2.3", "1", "count"]);
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use common::Instance;
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use rx_repack::{check, json_message, repack, ErrorKind, RepackOptions, Repair, Severity};
+use tempdir::TempDir;
+
+const INSTANCE: Instance = Instance {
+    patient_id: "patient1",
+    study_uid: "1.2.3",
+    series_uid: "1.2.3.4",
+    series_number: 1,
+    instance_number: 1,
+};
+
+/// Repack a malformed DICOM file, returning its repairs, and check that it was written
+/// to the data dir as a DICOM file which conforms.
+fn repack_repaired(src: &Utf8Path, data_dir: &Utf8Path) -> Vec<Repair> {
+    let options = RepackOptions {
+        log_dir: Some(data_dir.with_file_name("log")),
+        ..RepackOptions::new(data_dir)
+    };
+    let outcome = repack(src, false, &options).unwrap();
+    let dcm = dicom::object::open_file(&outcome.dst).unwrap();
+    assert_eq!(
+        dcm.element(tags::PATIENT_ID).unwrap().to_str().unwrap(),
+        "patient1"
+    );
+    assert_eq!(
+        dcm.meta().media_storage_sop_instance_uid(),
+        INSTANCE.sop_instance_uid()
+    );
+    outcome.repairs
+}
+
+fn write(dir: &Utf8Path, name: &str, data: &[u8]) -> Utf8PathBuf {
+    let p = dir.join(name);
+    fs_err::write(&p, data).unwrap();
+    p
+}
+
+#[test]
+fn test_missing_preamble() {
+    let tmp_dir = TempDir::new("tolerant_read").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let mut data = Vec::new();
+    INSTANCE.to_dicom().write_all(&mut data).unwrap();
+
+    let no_preamble = write(tmp_path, "no_preamble.dcm", &data[128..]);
+    assert_eq!(
+        repack_repaired(&no_preamble, &tmp_path.join("data")),
+        [Repair::MissingPreamble]
+    );
+
+    // no magic code, and no group length in the file meta group
+    let no_group_length = &data[132 + 12..];
+    let no_magic_code = write(tmp_path, "no_magic_code.dcm", no_group_length);
+    let repairs = repack_repaired(&no_magic_code, &tmp_path.join("data2"));
+    assert_eq!(
+        repairs[..2],
+        [Repair::MissingPreamble, Repair::MissingMagicCode]
+    );
+    assert!(matches!(repairs[2], Repair::InvalidMeta { .. }));
+}
+
+#[test]
+fn test_raw_dataset() {
+    let tmp_dir = TempDir::new("tolerant_read").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let implicit_vr_le = TransferSyntaxRegistry
+        .get(uids::IMPLICIT_VR_LITTLE_ENDIAN)
+        .unwrap();
+    let mut data = Vec::new();
+    INSTANCE
+        .to_dicom()
+        .into_inner()
+        .write_dataset_with_ts(&mut data, implicit_vr_le)
+        .unwrap();
+    let raw = write(tmp_path, "raw.dcm", &data);
+    let repairs = repack_repaired(&raw, &tmp_path.join("data"));
+    let expected = Repair::MissingMeta {
+        transfer_syntax: uids::IMPLICIT_VR_LITTLE_ENDIAN.to_string(),
+    };
+    assert_eq!(repairs, [expected]);
+
+    // not DICOM, even though it starts like a raw data set
+    let mut not_dicom = vec![0x08, 0x00, 0x16, 0x00];
+    not_dicom.extend(b"i enjoy bubble tea");
+    let not_dicom = write(tmp_path, "not_dicom.dcm", &not_dicom);
+    let result = repack(&not_dicom, false, &RepackOptions::new(tmp_path));
+    assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::NotDicom));
+}
+
+#[test]
+fn test_truncated() {
+    let tmp_dir = TempDir::new("tolerant_read").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let mut dcm = INSTANCE.to_dicom();
+    dcm.put(DataElement::new(
+        tags::PIXEL_DATA,
+        VR::OW,
+        PrimitiveValue::U16(vec![0; 64].into()),
+    ));
+    let mut data = Vec::new();
+    dcm.write_all(&mut data).unwrap();
+    let truncated = write(tmp_path, "truncated.dcm", &data[..data.len() - 10]);
+
+    let report = check(&truncated);
+    assert_eq!(report.severity, Some(Severity::Warning));
+    assert!(report.problems[0].message.contains("truncated"));
+
+    let options = RepackOptions::new(tmp_path.join("data"));
+    let outcome = repack(&truncated, false, &options).unwrap();
+    assert!(matches!(
+        outcome.repairs[..],
+        [Repair::Truncated {
+            tag: Some(tags::PIXEL_DATA),
+            ..
+        }]
+    ));
+    let dcm = dicom::object::open_file(&outcome.dst).unwrap();
+    assert!(dcm.element(tags::PIXEL_DATA).is_err());
+    assert!(dcm.element(tags::INSTANCE_NUMBER).is_ok());
+
+    let msg: serde_json::Value =
+        serde_json::from_str(&json_message(&truncated, &Ok(outcome)).unwrap()).unwrap();
+    assert_eq!(msg["repairs"][0]["repair"], "truncated");
+    assert_eq!(msg["repairs"][0]["tag"], "(7FE0,0010)");
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use camino::Utf8Path;
+use common::{glob_files, Instance};
+use dicom::core::value::{PixelFragmentSequence, Value};
+use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::dictionary_std::{tags, uids};
+use dicom::object::DefaultDicomObject;
+use dicom::pixeldata::PixelDecoder;
+use rx_repack::{repack, repack_object, RepackOptions, TranscodeTarget};
+use tempdir::TempDir;
+
+const ROWS: u16 = 3;
+const COLUMNS: u16 = 4;
+
+/// A 16-bit monochrome image with runs of equal pixels and a high byte which varies.
+fn pixels() -> Vec<u16> {
+    [0, 0, 0, 300, 301, 302, 5, 5, 7, 7, 1000, 1000].into()
+}
+
+fn image_instance(instance_number: u32) -> DefaultDicomObject {
+    let mut dcm = Instance {
+        patient_id: "patient1",
+        study_uid: "1.2.3",
+        series_uid: "1.2.3.4",
+        series_number: 1,
+        instance_number,
+    }
+    .to_dicom();
+    let elements = [
+        (tags::SAMPLES_PER_PIXEL, VR::US, PrimitiveValue::from(1_u16)),
+        (
+            tags::PHOTOMETRIC_INTERPRETATION,
+            VR::CS,
+            "MONOCHROME2".into(),
+        ),
+        (tags::ROWS, VR::US, PrimitiveValue::from(ROWS)),
+        (tags::COLUMNS, VR::US, PrimitiveValue::from(COLUMNS)),
+        (tags::BITS_ALLOCATED, VR::US, PrimitiveValue::from(16_u16)),
+        (tags::BITS_STORED, VR::US, PrimitiveValue::from(16_u16)),
+        (tags::HIGH_BIT, VR::US, PrimitiveValue::from(15_u16)),
+        (
+            tags::PIXEL_REPRESENTATION,
+            VR::US,
+            PrimitiveValue::from(0_u16),
+        ),
+        (
+            tags::PIXEL_DATA,
+            VR::OW,
+            PrimitiveValue::U16(pixels().into()),
+        ),
+    ];
+    for (tag, vr, value) in elements {
+        dcm.put(DataElement::new(tag, vr, value));
+    }
+    dcm
+}
+
+fn decoded_pixels(dcm: &DefaultDicomObject) -> Vec<u16> {
+    dcm.decode_pixel_data().unwrap().data_ow()
+}
+
+fn instance_json(log_dir: &Utf8Path) -> serde_json::Value {
+    let files = glob_files(&log_dir.join("seriesData"), "json")
+        .into_iter()
+        .filter(|p| p.as_str().contains("-img/"))
+        .collect::<Vec<_>>();
+    assert_eq!(files.len(), 1);
+    let data: serde_json::Value =
+        serde_json::from_str(&fs_err::read_to_string(&files[0]).unwrap()).unwrap();
+    data["1.2.3.4"].clone()
+}
+
+#[test]
+fn test_transcode_rle_round_trip() {
+    let tmp_dir = TempDir::new("transcode").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        transcode: Some(TranscodeTarget::RleLossless),
+        ..RepackOptions::new(tmp_path.join("rle"))
+    };
+    let outcome = repack_object(image_instance(1), &options).unwrap();
+    assert!(outcome
+        .missing
+        .iter()
+        .all(|m| m.tag != tags::TRANSFER_SYNTAX_UID));
+
+    let rle = dicom::object::open_file(&outcome.dst).unwrap();
+    assert_eq!(rle.meta().transfer_syntax(), uids::RLE_LOSSLESS);
+    let fragments = rle.element(tags::PIXEL_DATA).unwrap().fragments().unwrap();
+    assert_eq!(fragments.len(), 1);
+    let transfer_syntax = &instance_json(&log_dir)["transferSyntax"];
+    assert_eq!(transfer_syntax["original"], uids::EXPLICIT_VR_LITTLE_ENDIAN);
+    assert_eq!(transfer_syntax["stored"], uids::RLE_LOSSLESS);
+
+    // decode it again, which also checks that the RLE data is valid
+    let options = RepackOptions {
+        transcode: Some(TranscodeTarget::ExplicitVrLittleEndian),
+        ..RepackOptions::new(tmp_path.join("native"))
+    };
+    let outcome = repack(&outcome.dst, false, &options).unwrap();
+    let native = dicom::object::open_file(&outcome.dst).unwrap();
+    assert_eq!(
+        native.meta().transfer_syntax(),
+        uids::EXPLICIT_VR_LITTLE_ENDIAN
+    );
+    assert_eq!(decoded_pixels(&native), pixels());
+}
+
+#[test]
+fn test_transcode_undecodable_is_copied() {
+    let tmp_dir = TempDir::new("transcode").unwrap();
+    let tmp_path = Utf8Path::from_path(tmp_dir.path()).unwrap();
+    let log_dir = tmp_path.join("log");
+
+    // RLE data which is not valid: the header says there are 2 segments, but there are none
+    let mut dcm = image_instance(1);
+    let mut header = vec![0_u8; 64];
+    header[0] = 2;
+    let fragments = PixelFragmentSequence::new(Vec::new(), vec![header]);
+    dcm.put(DataElement::new(
+        tags::PIXEL_DATA,
+        VR::OB,
+        Value::PixelSequence(fragments),
+    ));
+    dcm.meta_mut().transfer_syntax = uids::RLE_LOSSLESS.to_string();
+    let src = tmp_path.join("invalid.dcm");
+    dcm.write_to_file(&src).unwrap();
+
+    let options = RepackOptions {
+        log_dir: Some(log_dir.clone()),
+        transcode: Some(TranscodeTarget::ExplicitVrLittleEndian),
+        ..RepackOptions::new(tmp_path.join("data"))
+    };
+    let outcome = repack(&src, false, &options).unwrap();
+    assert_eq!(
+        fs_err::read(&outcome.dst).unwrap(),
+        fs_err::read(&src).unwrap()
+    );
+    let warnings = outcome
+        .missing
+        .iter()
+        .filter(|m| m.tag == tags::TRANSFER_SYNTAX_UID)
+        .count();
+    assert_eq!(warnings, 1);
+    let transfer_syntax = &instance_json(&log_dir)["transferSyntax"];
+    assert_eq!(transfer_syntax["original"], uids::RLE_LOSSLESS);
+    assert_eq!(transfer_syntax["stored"], uids::RLE_LOSSLESS);
+}